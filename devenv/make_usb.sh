@@ -25,6 +25,9 @@ sudo mkdir -p $MOUNT_POINT/EFI/BOOT
 sudo cp $EFI_FILE $MOUNT_POINT/EFI/BOOT/BOOTX64.EFI
 if [ "$ANOTHER_FILE" != "" ]; then
 	sudo cp $ANOTHER_FILE $MOUNT_POINT/
+	if [ -f $ANOTHER_FILE.crc32 ]; then
+		sudo cp $ANOTHER_FILE.crc32 $MOUNT_POINT/
+	fi
 fi
 sleep 0.5
 sudo umount $MOUNT_POINT
\ No newline at end of file
@@ -0,0 +1,201 @@
+#![allow(unused)]
+
+use core::{mem::size_of, ptr, slice};
+
+use crate::{
+    error, io::io_out_8, log, logger::LogLevel, make_error, paging, printk, printkln, rtc,
+    sync::OnceLock,
+};
+
+/// RSDP（Root System Description Pointer）。ローダが UEFI の構成テーブルから探して渡す。
+#[repr(C, packed)]
+pub struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// ACPI 1.0 の範囲の RSDP の長さ。`checksum` はここまでを対象にする。
+const RSDP_V1_LENGTH: usize = 20;
+
+impl Rsdp {
+    /// シグネチャと両方のチェックサムを確かめる。XSDT を持たない ACPI 1.0 のものは受け付けない。
+    fn is_valid(&self) -> bool {
+        if &self.signature != b"RSD PTR " {
+            log!(LogLevel::Debug, "invalid RSDP signature");
+            return false;
+        }
+        let revision = self.revision;
+        if revision < 2 {
+            log!(
+                LogLevel::Debug,
+                "ACPI revision must be 2 or later: {}",
+                revision
+            );
+            return false;
+        }
+        if unsafe { sum_bytes(self as *const Self as *const u8, RSDP_V1_LENGTH) } != 0 {
+            log!(LogLevel::Debug, "RSDP checksum mismatch");
+            return false;
+        }
+        if unsafe { sum_bytes(self as *const Self as *const u8, size_of::<Self>()) } != 0 {
+            log!(LogLevel::Debug, "RSDP extended checksum mismatch");
+            return false;
+        }
+        true
+    }
+}
+
+/// 各テーブルの先頭に共通して付くヘッダ。
+#[repr(C, packed)]
+struct DescriptionHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+impl DescriptionHeader {
+    /// シグネチャが `signature` で、テーブル全体のチェックサムが合っていれば true を返す。
+    fn is_valid(&self, signature: &[u8; 4]) -> bool {
+        if &self.signature != signature {
+            return false;
+        }
+        if unsafe { sum_bytes(self as *const Self as *const u8, self.length as usize) } != 0 {
+            log!(
+                LogLevel::Debug,
+                "{} checksum mismatch",
+                core::str::from_utf8(signature).unwrap_or("?")
+            );
+            return false;
+        }
+        true
+    }
+
+    /// XSDT として、並んでいる各テーブルを返す。
+    fn xsdt_entries(&'static self) -> impl Iterator<Item = &'static DescriptionHeader> {
+        let count = (self.length as usize).saturating_sub(size_of::<Self>()) / size_of::<u64>();
+        let entries = unsafe { (self as *const Self).add(1) as *const u64 };
+        // ヘッダが 36 バイトなので、エントリは 8 バイト境界に揃っていない
+        (0..count).filter_map(move |i| unsafe {
+            (paging::phys_to_virt(ptr::read_unaligned(entries.add(i))) as *const DescriptionHeader)
+                .as_ref()
+        })
+    }
+}
+
+/// レジスタの場所を表す Generic Address Structure。
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct GenericAddress {
+    address_space_id: u8,
+    register_bit_width: u8,
+    register_bit_offset: u8,
+    access_size: u8,
+    address: u64,
+}
+
+/// [GenericAddress::address_space_id] の値
+mod address_space {
+    pub(super) const SYSTEM_MEMORY: u8 = 0;
+    pub(super) const SYSTEM_IO: u8 = 1;
+}
+
+/// FADT（Fixed ACPI Description Table）。使うフィールドまでを定義する。
+#[repr(C, packed)]
+struct Fadt {
+    header: DescriptionHeader,
+    reserved1: [u8; 72],
+    /// RTC の世紀を保持する CMOS レジスタ番号。0 なら無し。
+    century: u8,
+    iapc_boot_arch: u16,
+    reserved2: u8,
+    flags: u32,
+    reset_reg: GenericAddress,
+    reset_value: u8,
+}
+
+/// [Fadt::flags] の RESET_REG_SUP ビット。立っていればリセットレジスタが使える。
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+static FADT: OnceLock<&'static Fadt> = OnceLock::new();
+
+/// `len` バイトを足し合わせた値の下位 8 ビットを返す。ACPI のチェックサムは 0 になる。
+unsafe fn sum_bytes(p: *const u8, len: usize) -> u8 {
+    slice::from_raw_parts(p, len)
+        .iter()
+        .fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// RSDP から XSDT をたどって FADT を探し、覚えておく。
+///
+/// 見つかった FADT の CENTURY フィールドは [rtc] へ渡す。RSDP が無いかおかしければ
+/// [error::Code::InvalidFormat]、FADT が見つからなければ [error::Code::NoSuchEntry] を返す。
+pub(crate) fn initialize(rsdp: Option<&'static Rsdp>) -> error::Error {
+    let Some(rsdp) = rsdp.filter(|rsdp| rsdp.is_valid()) else {
+        return make_error!(error::Code::InvalidFormat);
+    };
+
+    let xsdt = paging::phys_to_virt(rsdp.xsdt_address) as *const DescriptionHeader;
+    let Some(xsdt) = unsafe { xsdt.as_ref() }.filter(|xsdt| xsdt.is_valid(b"XSDT")) else {
+        log!(LogLevel::Debug, "invalid XSDT");
+        return make_error!(error::Code::InvalidFormat);
+    };
+
+    let Some(fadt) = xsdt.xsdt_entries().find(|entry| entry.is_valid(b"FACP")) else {
+        return make_error!(error::Code::NoSuchEntry);
+    };
+    let fadt = unsafe { &*(fadt as *const DescriptionHeader as *const Fadt) };
+    rtc::set_century_register(fadt.century);
+    let _ = FADT.set(fadt);
+    log!(
+        LogLevel::Info,
+        "ACPI: FADT found, reset register {}",
+        if reset_register().is_some() {
+            "available"
+        } else {
+            "unavailable"
+        }
+    );
+    make_error!(error::Code::Success)
+}
+
+/// FADT のリセットレジスタとそこへ書く値を返す。使えなければ None。
+fn reset_register() -> Option<(GenericAddress, u8)> {
+    let fadt = FADT.get()?;
+    // リセットレジスタは ACPI 2.0 で追加されたので、古い FADT では長さが足りない
+    if (fadt.header.length as usize) < size_of::<Fadt>() || fadt.flags & FADT_RESET_REG_SUP == 0 {
+        return None;
+    }
+    Some((fadt.reset_reg, fadt.reset_value))
+}
+
+/// FADT のリセットレジスタに書き込んで、マシンをリセットする。
+///
+/// リセットレジスタが無ければ [error::Code::NoSuchEntry] を、PCI 構成空間など対応していない
+/// 場所にあれば [error::Code::NotImplemented] を返す。書き込めた場合でも、リセットが
+/// 始まるまでに少し時間がかかることがあるので、戻ってきたら呼び出し側で別の方法を試すこと。
+pub(crate) fn reset() -> error::Error {
+    let Some((reg, value)) = reset_register() else {
+        return make_error!(error::Code::NoSuchEntry);
+    };
+    match reg.address_space_id {
+        address_space::SYSTEM_IO => unsafe { io_out_8(reg.address as u16, value) },
+        address_space::SYSTEM_MEMORY => unsafe {
+            ptr::write_volatile(paging::phys_to_virt(reg.address) as *mut u8, value)
+        },
+        _ => return make_error!(error::Code::NotImplemented),
+    }
+    make_error!(error::Code::Success)
+}
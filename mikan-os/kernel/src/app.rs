@@ -0,0 +1,122 @@
+#![allow(unused)]
+
+//! 動作確認用の小さなアプリ（フラットバイナリ）を読み込んで呼び出す。
+//!
+//! ELF のカーネルとは別物で、ローダはルートディレクトリの `\app.bin` をそのまま読んで渡すだけ。
+//! アプリとの取り決めは次のとおり。
+//!
+//! - 位置独立なフラットバイナリで、先頭（オフセット 0）がエントリポイント。
+//!   読み込む番地は決まっていないので、絶対番地を埋め込まないこと。
+//! - エントリポイントは `extern "sysv64" fn(callbacks: *const AppCallbacks) -> i64`。
+//!   戻り値は終了コードとしてログに出す。
+//! - カーネルの機能は [AppCallbacks] の関数を通してだけ使える。
+//!   [AppCallbacks::version] より後ろのフィールドは、版が上がるときに末尾へ追加するだけにする。
+//! - アプリはカーネルと同じ特権レベル・同じスタックで動くので、割り込みを止めたり戻らなかったり
+//!   すれば、そのままカーネルが止まる。
+
+use core::slice;
+
+use crate::{
+    error::{self, WithError},
+    log,
+    logger::LogLevel,
+    make_error, paging, pool, printk, printkln, string,
+    sync::OnceLock,
+    timer,
+};
+
+/// アプリのバイナリの最大長（バイト）。ローダ側の定義と合わせること。
+pub(crate) const APP_IMAGE_SIZE: usize = 16 * 1024;
+
+/// 現在の [AppCallbacks] の版
+pub(crate) const APP_ABI_VERSION: u32 = 1;
+
+/// ローダが `\app.bin` を読み込んだ領域。ローダ側の `AppImage` と同じ並び。
+///
+/// ファイルが無ければ `base` は null、`len` は 0 になっている。
+#[repr(C)]
+pub struct AppImage {
+    base: *const u8,
+    len: usize,
+}
+
+/// アプリのエントリポイントに渡すコールバックの表。
+#[repr(C)]
+pub(crate) struct AppCallbacks {
+    /// この表の版。[APP_ABI_VERSION]。
+    pub(crate) version: u32,
+    /// `len` バイトの UTF-8 文字列 `s` をコンソールへ出す。
+    pub(crate) print: extern "sysv64" fn(s: *const u8, len: usize),
+    /// [timer::uptime_ms] と同じ。
+    pub(crate) uptime_ms: extern "sysv64" fn() -> u64,
+}
+
+type AppEntry = extern "sysv64" fn(callbacks: *const AppCallbacks) -> i64;
+
+static CALLBACKS: AppCallbacks = AppCallbacks {
+    version: APP_ABI_VERSION,
+    print: app_print,
+    uptime_ms: app_uptime_ms,
+};
+
+/// カーネル側に写したアプリの先頭番地と長さ。
+static IMAGE: OnceLock<(usize, usize)> = OnceLock::new();
+
+extern "sysv64" fn app_print(s: *const u8, len: usize) {
+    if s.is_null() {
+        return;
+    }
+    let bytes = unsafe { slice::from_raw_parts(s, len) };
+    // 途中で UTF-8 として壊れていたら、そこまでを出す
+    printk!("{}", string::valid_utf8_prefix(bytes));
+}
+
+extern "sysv64" fn app_uptime_ms() -> u64 {
+    timer::uptime_ms()
+}
+
+/// ローダから渡されたアプリを、メモリプールへ写しておく。
+///
+/// 写した領域は [paging::make_executable] で読み込みと実行だけができるようにする。
+/// ローダの領域を後から再利用しても困らないよう、起動の早いうちに呼ぶこと。
+/// アプリが無ければ [error::Code::NoSuchEntry]、[APP_IMAGE_SIZE] より大きければ
+/// [error::Code::BufferTooSmall]、プールに空きが無ければ [error::Code::NoEnoughMemory] を返す。
+pub(crate) fn initialize(app_image: Option<&AppImage>) -> error::Error {
+    let Some(image) = app_image.filter(|image| !image.base.is_null() && image.len > 0) else {
+        return make_error!(error::Code::NoSuchEntry);
+    };
+    if image.len > APP_IMAGE_SIZE {
+        return make_error!(error::Code::BufferTooSmall);
+    }
+    // 写した後はページごと書き込めなくするので、他の確保とページを共有しないよう切り上げる
+    let buf = pool::alloc_aligned(image.len.next_multiple_of(4096), 4096);
+    if (&buf.error()).into() {
+        return buf.error();
+    }
+    let buf = *buf.value();
+    unsafe { core::ptr::copy_nonoverlapping(image.base, buf, image.len) };
+    let err = paging::make_executable(buf as u64, image.len);
+    if (&err).into() {
+        return err;
+    }
+    let _ = IMAGE.set((buf as usize, image.len));
+    log!(
+        LogLevel::Info,
+        "app: {} bytes loaded at {:#x}",
+        image.len,
+        buf as usize
+    );
+    make_error!(error::Code::Success)
+}
+
+/// [initialize] で読み込んだアプリを呼び出し、その終了コードを返す。
+///
+/// 読み込んでいなければ [error::Code::NoSuchEntry] を返す。
+pub(crate) fn run() -> WithError<i64> {
+    let Some(&(base, _)) = IMAGE.get() else {
+        return WithError::new(0, make_error!(error::Code::NoSuchEntry));
+    };
+    let entry: AppEntry = unsafe { core::mem::transmute(base) };
+    let code = entry(&CALLBACKS);
+    WithError::new(code, make_error!(error::Code::Success))
+}
@@ -0,0 +1,222 @@
+use core::arch::global_asm;
+
+extern "C" {
+    fn read_cpuid(
+        leaf: u32,
+        subleaf: u32,
+        eax: *mut u32,
+        ebx: *mut u32,
+        ecx: *mut u32,
+        edx: *mut u32,
+    );
+    pub(crate) fn get_cr0() -> u64;
+    pub(crate) fn set_cr0(value: u64);
+    pub(crate) fn get_cr2() -> u64;
+    pub(crate) fn get_cr3() -> u64;
+    pub(crate) fn set_cr3(value: u64);
+    pub(crate) fn invlpg(addr: u64);
+    pub(crate) fn monitor(addr: *const u8);
+    pub(crate) fn mwait();
+}
+
+extern "sysv64" {
+    /// SYSCALL の飛び先。IA32_LSTAR に設定する。
+    ///
+    /// カーネルのスタックに切り替えて、RDI、RSI、RDX、R10、R8、R9 をこの順に並べてスタックへ積み、
+    /// [crate::syscall::syscall_dispatch] へ RAX の番号とともに渡す。戻り値を RAX に入れて SYSRET する。
+    pub(crate) fn syscall_entry();
+    /// 呼び出し側のレジスタを退避し、`entry` からユーザモードで実行する。
+    ///
+    /// [exit_to_kernel] が呼ばれると、その引数を返して戻ってくる。
+    pub(crate) fn enter_user(entry: u64, user_rsp: u64) -> i64;
+    /// 最後に [enter_user] を呼んだところへ `code` を返す。
+    pub(crate) fn exit_to_kernel(code: i64) -> !;
+}
+
+extern "sysv64" {
+    /// 割り込みベクタごとの入口を [INTERRUPT_ENTRY_SIZE] バイト間隔で 256 個並べたものの先頭。
+    ///
+    /// 各入口はエラーコード（CPU が積まないベクタでは 0）とベクタ番号を積み、呼び出し側が保存する
+    /// 汎用レジスタを退避して [crate::interrupt::interrupt_dispatch] を呼ぶ。戻ってきたら
+    /// レジスタを戻して IRETQ する。
+    pub(crate) fn interrupt_entries();
+}
+
+/// [interrupt_entries] の 1 つ分の大きさ（バイト）
+pub(crate) const INTERRUPT_ENTRY_SIZE: usize = 16;
+
+/// CPUID 命令を実行し、(eax, ebx, ecx, edx) を返す。
+pub(crate) fn cpuid(leaf: u32, subleaf: u32) -> (u32, u32, u32, u32) {
+    let (mut eax, mut ebx, mut ecx, mut edx) = (0, 0, 0, 0);
+    unsafe {
+        read_cpuid(leaf, subleaf, &mut eax, &mut ebx, &mut ecx, &mut edx);
+    }
+    (eax, ebx, ecx, edx)
+}
+
+global_asm! { r#"
+.global read_cpuid
+read_cpuid:
+    push rbx
+    mov r10, rdx
+    mov r11, rcx
+    mov eax, edi
+    mov ecx, esi
+    cpuid
+    mov dword ptr [r10], eax
+    mov dword ptr [r11], ebx
+    mov dword ptr [r8], ecx
+    mov dword ptr [r9], edx
+    pop rbx
+    ret
+
+.global get_cr0
+get_cr0:
+    mov rax, cr0
+    ret
+
+.global set_cr0
+set_cr0:
+    mov cr0, rdi
+    ret
+
+.global get_cr2
+get_cr2:
+    mov rax, cr2
+    ret
+
+.global get_cr3
+get_cr3:
+    mov rax, cr3
+    ret
+
+.global set_cr3
+set_cr3:
+    mov cr3, rdi
+    ret
+
+.global invlpg
+invlpg:
+    invlpg [rdi]
+    ret
+
+.global monitor
+monitor:
+    mov rax, rdi
+    xor ecx, ecx
+    xor edx, edx
+    monitor
+    ret
+
+.global mwait
+mwait:
+    xor eax, eax
+    xor ecx, ecx
+    mwait
+    ret
+"# }
+
+global_asm! { r#"
+.global syscall_entry
+syscall_entry:
+    mov [rip + {user_rsp}], rsp
+    mov rsp, [rip + {kernel_rsp}]
+    push rcx
+    push r11
+    push r9
+    push r8
+    push r10
+    push rdx
+    push rsi
+    push rdi
+    mov rdi, rax
+    mov rsi, rsp
+    call {dispatch}
+    pop rdi
+    pop rsi
+    pop rdx
+    pop r10
+    pop r8
+    pop r9
+    pop r11
+    pop rcx
+    mov rsp, [rip + {user_rsp}]
+    sysretq
+
+.global enter_user
+enter_user:
+    push rbp
+    push rbx
+    push r12
+    push r13
+    push r14
+    push r15
+    mov [rip + {return_rsp}], rsp
+    mov rcx, rdi
+    mov r11, {rflags}
+    mov rsp, rsi
+    sysretq
+
+.global exit_to_kernel
+exit_to_kernel:
+    mov rsp, [rip + {return_rsp}]
+    mov rax, rdi
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbx
+    pop rbp
+    ret
+"#,
+    user_rsp = sym crate::syscall::SYSCALL_USER_RSP,
+    kernel_rsp = sym crate::syscall::SYSCALL_KERNEL_RSP,
+    return_rsp = sym crate::syscall::USER_RETURN_RSP,
+    dispatch = sym crate::syscall::syscall_dispatch,
+    rflags = const crate::syscall::USER_RFLAGS,
+}
+
+// エラーコードを積む例外は #DF（8）、#TS〜#PF（10〜14）、#AC（17）、#CP（21）、#VC（29）、#SX（30）。
+// それ以外では 0 を積み、どの入口からでも同じ形のスタックにする。
+global_asm! { r#"
+.global interrupt_entries
+.p2align 4
+interrupt_entries:
+.set interrupt_vector, 0
+.rept 256
+    .p2align 4
+    .if ((interrupt_vector == 8) | ((interrupt_vector >= 10) & (interrupt_vector <= 14)) | (interrupt_vector == 17) | (interrupt_vector == 21) | (interrupt_vector == 29) | (interrupt_vector == 30)) == 0
+    push 0
+    .endif
+    push interrupt_vector
+    jmp interrupt_common
+    .set interrupt_vector, interrupt_vector + 1
+.endr
+
+interrupt_common:
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    cld
+    mov rdi, rsp
+    call {dispatch}
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    add rsp, 16
+    iretq
+"#,
+    dispatch = sym crate::interrupt::interrupt_dispatch,
+}
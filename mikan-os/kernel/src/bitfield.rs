@@ -0,0 +1,119 @@
+#![allow(unused)]
+
+//! レジスタのビットフィールドを読み書きするための道具。
+//!
+//! PCI のコマンドやステータス、MSI の制御フィールド、xHCI のレジスタなど、シフトとマスクを
+//! その場で書くと位置や幅を間違えやすいので、ここにまとめる。フィールドの範囲は `lsb..msb + 1` の
+//! 半開区間で、仕様書の `[msb:lsb]` と同じ並びを指す。
+
+use core::ops::Range;
+
+/// 整数 `T` をビットの並びとして扱う。
+///
+/// 範囲が空のときや型の幅を超えるときはパニックする。定数の文脈ならコンパイルエラーになる。
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub(crate) struct BitField<T>(pub(crate) T);
+
+macro_rules! impl_bit_field {
+    ($($t:ty),*) => {$(
+        impl BitField<$t> {
+            /// `bits` の幅の、下位に詰めたマスク
+            const fn mask(bits: &Range<u32>) -> $t {
+                assert!(bits.start < bits.end && bits.end <= <$t>::BITS);
+                <$t>::MAX >> (<$t>::BITS - (bits.end - bits.start))
+            }
+
+            /// `bits` の範囲を、下位に詰めて取り出す。
+            pub(crate) const fn get_bits(self, bits: Range<u32>) -> $t {
+                (self.0 >> bits.start) & Self::mask(&bits)
+            }
+
+            /// `bits` の範囲を `value` にする。範囲の外のビットは変えず、`value` の収まらない上位のビットは捨てる。
+            pub(crate) const fn set_bits(&mut self, bits: Range<u32>, value: $t) {
+                let mask = Self::mask(&bits);
+                self.0 = (self.0 & !(mask << bits.start)) | ((value & mask) << bits.start);
+            }
+
+            /// [Self::set_bits] した値を返す。
+            pub(crate) const fn with_bits(mut self, bits: Range<u32>, value: $t) -> Self {
+                self.set_bits(bits, value);
+                self
+            }
+
+            pub(crate) const fn get_bit(self, bit: u32) -> bool {
+                self.get_bits(bit..bit + 1) != 0
+            }
+
+            pub(crate) const fn set_bit(&mut self, bit: u32, value: bool) {
+                self.set_bits(bit..bit + 1, value as $t);
+            }
+
+            /// [Self::set_bit] した値を返す。
+            pub(crate) const fn with_bit(mut self, bit: u32, value: bool) -> Self {
+                self.set_bit(bit, value);
+                self
+            }
+        }
+    )*};
+}
+
+impl_bit_field!(u8, u16, u32, u64);
+
+// 端のビット、型の幅いっぱいの範囲、重なる範囲への書き込みを確かめる
+const _: () = {
+    let v = BitField(0x8000_0001u32);
+    assert!(v.get_bit(0) && v.get_bit(31) && !v.get_bit(1));
+    assert!(v.get_bits(0..32) == 0x8000_0001);
+    assert!(v.get_bits(31..32) == 1);
+    assert!(v.get_bits(1..31) == 0);
+
+    let mut w = BitField(0u32);
+    w.set_bits(4..12, 0xff);
+    // 後から書いた範囲が重なった部分を上書きし、重ならない部分は残る
+    w.set_bits(8..16, 0);
+    assert!(w.0 == 0x0000_00f0);
+    w.set_bits(0..4, 0x1f);
+    assert!(w.0 == 0x0000_00ff);
+    w.set_bits(0..32, 0xdead_beef);
+    assert!(w.0 == 0xdead_beef);
+
+    assert!(BitField(0u64).with_bits(60..64, 0xf).0 == 0xf000_0000_0000_0000);
+    assert!(BitField(u64::MAX).get_bits(0..64) == u64::MAX);
+    assert!(BitField(0xffu8).with_bit(7, false).0 == 0x7f);
+    assert!(BitField(0u16).with_bit(15, true).0 == 0x8000);
+};
+
+/// 1 ビットのフィールドを読み書きするメソッドを定義する。
+///
+/// 整数を 1 つ包んだ `Copy` な型の impl の中で使う。`$get` で読み出し、`$set` で書き換えた値を返す。
+#[macro_export]
+macro_rules! bit_flag {
+    ($(#[$meta:meta])* $get:ident, $set:ident, $bit:expr) => {
+        $(#[$meta])*
+        pub(crate) const fn $get(self) -> bool {
+            $crate::bitfield::BitField(self.0).get_bit($bit)
+        }
+
+        pub(crate) const fn $set(self, value: bool) -> Self {
+            Self($crate::bitfield::BitField(self.0).with_bit($bit, value).0)
+        }
+    };
+}
+
+/// `$bits` の範囲のフィールドを `$ty` として読み書きするメソッドを定義する。
+///
+/// 使い方は [bit_flag] と同じ。書くときに範囲に収まらない上位のビットは捨てる。
+#[macro_export]
+macro_rules! bit_field {
+    ($(#[$meta:meta])* $get:ident, $set:ident, $bits:expr, $ty:ty) => {
+        $(#[$meta])*
+        pub(crate) const fn $get(self) -> $ty {
+            $crate::bitfield::BitField(self.0).get_bits($bits) as $ty
+        }
+
+        pub(crate) const fn $set(self, value: $ty) -> Self {
+            Self($crate::bitfield::BitField(self.0).with_bits($bits, value as _).0)
+        }
+    };
+}
@@ -0,0 +1,211 @@
+#![allow(unused)]
+
+use crate::{
+    graphics::DEFAULT_BRIGHTNESS,
+    keyboard::{DEFAULT_REPEAT_DELAY_MS, DEFAULT_REPEAT_INTERVAL_MS},
+    logger::LogLevel,
+    memory_map::MemoryMapFilter,
+    mouse::DEFAULT_DOUBLE_CLICK_MS,
+    panic_action::PanicAction,
+    render::DEFAULT_MAX_FPS,
+    selftest::SelfTestExit,
+    theme::ThemePreset,
+};
+
+/// 起動引数の最大長（バイト）。ローダ側の定義と合わせること。
+pub(crate) const BOOT_ARGS_SIZE: usize = 256;
+
+/// ローダから渡される起動引数。`key=value` や `flag` を空白区切りで並べた ASCII 文字列。
+#[repr(C)]
+pub struct BootArgs {
+    len: usize,
+    buf: [u8; BOOT_ARGS_SIZE],
+}
+
+impl BootArgs {
+    /// 起動引数の文字列を返す。
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len.min(BOOT_ARGS_SIZE)]
+    }
+}
+
+/// 起動引数を解釈した結果。
+#[derive(Clone, Copy)]
+pub(crate) struct BootOptions {
+    /// `loglevel=error|warn|info|debug`
+    pub(crate) log_level: LogLevel,
+    /// `nousb` で false になる
+    pub(crate) usb: bool,
+    /// `serial=on` で Some(true)、`serial=off` で Some(false)。指定が無ければ None。
+    pub(crate) serial: Option<bool>,
+    /// `safemode` で true になる
+    pub(crate) safe_mode: bool,
+    /// `nox2apic` で false になる。CPU が対応していなければ、指定に関わらず xAPIC を使う。
+    pub(crate) x2apic: bool,
+    /// `brightness=<percent>` で指定する画面の明るさ。100 で元の色のまま。
+    pub(crate) brightness: u8,
+    /// `cursorscale=<n>` で指定するマウスカーソルの倍率
+    pub(crate) cursor_scale: u32,
+    /// `panic=halt|reboot|monitor` で指定するパニック時の動作
+    pub(crate) panic_action: PanicAction,
+    /// `nosplash` で false になる
+    pub(crate) splash: bool,
+    /// `nofade` で false になる。起動時にデスクトップをフェードインさせない。
+    pub(crate) fade: bool,
+    /// `theme=default|high-contrast|deuteranopia` で指定する配色
+    pub(crate) theme: ThemePreset,
+    /// `runapp` で true になる。起動し終えたところで `\app.bin` を呼び出す。
+    pub(crate) run_app: bool,
+    /// `usertest` で true になる。起動し終えたところでユーザモードの確認用のプログラムを動かす。
+    pub(crate) user_test: bool,
+    /// `selftest` か `selftest=halt|reboot` で指定する。起動し終えたところで自己診断を動かし、
+    /// 通常の起動には戻らずに指定された動作をする。`selftest` だけなら止まる。
+    pub(crate) self_test: Option<SelfTestExit>,
+    /// `memmap=all|usable` で指定する、起動時に出すメモリマップの範囲
+    pub(crate) memory_map_filter: MemoryMapFilter,
+    /// `maxfps=<n>` で指定する 1 秒あたりの描画回数の上限。0 なら制限しない。
+    pub(crate) max_fps: u32,
+    /// `dblclick=<ms>` で指定する、ダブルクリックとみなす間隔。0 なら検出しない。
+    pub(crate) double_click_ms: u32,
+    /// `repeatdelay=<ms>` で指定する、キーを押し続けてからリピートが始まるまでの時間。0 ならリピートしない。
+    pub(crate) key_repeat_delay_ms: u32,
+    /// `repeatrate=<ms>` で指定する、キーのリピートの間隔
+    pub(crate) key_repeat_interval_ms: u32,
+}
+
+impl BootOptions {
+    /// 起動引数が無いときの設定。
+    pub(crate) const DEFAULT: Self = Self {
+        log_level: LogLevel::Warn,
+        usb: true,
+        serial: None,
+        safe_mode: false,
+        x2apic: true,
+        brightness: DEFAULT_BRIGHTNESS,
+        cursor_scale: 1,
+        panic_action: PanicAction::Halt,
+        splash: true,
+        fade: true,
+        theme: ThemePreset::Default,
+        run_app: false,
+        user_test: false,
+        self_test: None,
+        memory_map_filter: MemoryMapFilter::All,
+        max_fps: DEFAULT_MAX_FPS,
+        double_click_ms: DEFAULT_DOUBLE_CLICK_MS,
+        key_repeat_delay_ms: DEFAULT_REPEAT_DELAY_MS,
+        key_repeat_interval_ms: DEFAULT_REPEAT_INTERVAL_MS,
+    };
+
+    /// 起動引数の文字列を解釈する。知らないものや値がおかしいものは無視する。
+    pub(crate) fn parse(args: &[u8]) -> Self {
+        let mut options = Self::DEFAULT;
+        for token in args
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|token| !token.is_empty())
+        {
+            let (key, value) = match token.iter().position(|&b| b == b'=') {
+                Some(i) => (&token[..i], Some(&token[i + 1..])),
+                None => (token, None),
+            };
+
+            match (key, value) {
+                (b"loglevel", Some(value)) => {
+                    if let Some(level) = parse_log_level(value) {
+                        options.log_level = level;
+                    }
+                }
+                (b"brightness", Some(value)) => {
+                    if let Some(percent) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.brightness = percent;
+                    }
+                }
+                (b"cursorscale", Some(value)) => {
+                    if let Some(scale) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.cursor_scale = scale;
+                    }
+                }
+                (b"maxfps", Some(value)) => {
+                    if let Some(fps) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.max_fps = fps;
+                    }
+                }
+                (b"dblclick", Some(value)) => {
+                    if let Some(ms) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.double_click_ms = ms;
+                    }
+                }
+                (b"repeatdelay", Some(value)) => {
+                    if let Some(ms) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.key_repeat_delay_ms = ms;
+                    }
+                }
+                (b"repeatrate", Some(value)) => {
+                    if let Some(ms) = core::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse().ok())
+                    {
+                        options.key_repeat_interval_ms = ms;
+                    }
+                }
+                (b"panic", Some(value)) => {
+                    if let Some(action) = PanicAction::parse(value) {
+                        options.panic_action = action;
+                    }
+                }
+                (b"memmap", Some(value)) => {
+                    if let Some(filter) = MemoryMapFilter::parse(value) {
+                        options.memory_map_filter = filter;
+                    }
+                }
+                (b"theme", Some(value)) => {
+                    if let Some(preset) = ThemePreset::parse(value) {
+                        options.theme = preset;
+                    }
+                }
+                (b"nousb", None) => options.usb = false,
+                (b"nox2apic", None) => options.x2apic = false,
+                (b"safemode", None) => options.safe_mode = true,
+                (b"nosplash", None) => options.splash = false,
+                (b"nofade", None) => options.fade = false,
+                (b"runapp", None) => options.run_app = true,
+                (b"usertest", None) => options.user_test = true,
+                (b"selftest", None) => options.self_test = Some(SelfTestExit::Halt),
+                (b"selftest", Some(value)) => {
+                    if let Some(exit) = SelfTestExit::parse(value) {
+                        options.self_test = Some(exit);
+                    }
+                }
+                (b"serial", Some(b"on")) => options.serial = Some(true),
+                (b"serial", Some(b"off")) => options.serial = Some(false),
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+fn parse_log_level(value: &[u8]) -> Option<LogLevel> {
+    match value {
+        b"error" => Some(LogLevel::Error),
+        b"warn" => Some(LogLevel::Warn),
+        b"info" => Some(LogLevel::Info),
+        b"debug" => Some(LogLevel::Debug),
+        _ => None,
+    }
+}
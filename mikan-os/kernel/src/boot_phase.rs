@@ -0,0 +1,92 @@
+#![allow(unused)]
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
+
+use crate::{log, logger::LogLevel, printk, printkln, timer};
+
+/// 起動処理の段階。[kernel_entry](crate::kernel_entry) で上から順に進む。
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub(crate) enum BootPhase {
+    /// フレームバッファ、デスクトップ、コンソールの準備
+    Graphics = 1,
+    /// メモリマップの確認とメモリ保護の設定
+    Memory,
+    /// 割り込みベクタと Local APIC の設定
+    Interrupts,
+    /// PCI デバイスの探索
+    Pci,
+    /// xHC の初期化とポートの設定
+    Usb,
+    /// 起動処理が終わり、メインループに入った
+    Ready,
+}
+
+impl BootPhase {
+    const fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Graphics),
+            2 => Some(Self::Memory),
+            3 => Some(Self::Interrupts),
+            4 => Some(Self::Pci),
+            5 => Some(Self::Usb),
+            6 => Some(Self::Ready),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Self::Graphics => "graphics",
+            Self::Memory => "memory",
+            Self::Interrupts => "interrupts",
+            Self::Pci => "PCI",
+            Self::Usb => "USB",
+            Self::Ready => "ready",
+        }
+    }
+}
+
+impl fmt::Display for BootPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// 今の段階。0 ならまだどの段階にも入っていない。
+static CURRENT: AtomicU8 = AtomicU8::new(0);
+/// 今の段階に入った時刻（マイクロ秒）。TSC を測定する前に入った段階では 0。
+static ENTERED_AT: AtomicU64 = AtomicU64::new(0);
+
+/// `phase` に入ったことを記録する。
+///
+/// 前の段階を抜けたことと、そこにかかった時間を Info でログに出す。
+/// TSC を測定する前に入った段階では、時間は出さない。
+pub(crate) fn enter(phase: BootPhase) {
+    let now = timer::uptime_us();
+    if let Some(previous) = current() {
+        let entered_at = ENTERED_AT.load(Ordering::Relaxed);
+        match now {
+            Some(now) if entered_at != 0 => log!(
+                LogLevel::Info,
+                "boot: leaving {} ({} us)",
+                previous,
+                now - entered_at
+            ),
+            _ => log!(LogLevel::Info, "boot: leaving {}", previous),
+        }
+    }
+    log!(LogLevel::Info, "boot: entering {}", phase);
+    ENTERED_AT.store(now.unwrap_or(0), Ordering::Relaxed);
+    CURRENT.store(phase as u8, Ordering::Relaxed);
+}
+
+/// 最後に入った段階を返す。まだどの段階にも入っていなければ None。
+///
+/// パニックハンドラから、どの段階で止まったかを報告するのに使う。
+pub(crate) fn current() -> Option<BootPhase> {
+    BootPhase::from_u8(CURRENT.load(Ordering::Relaxed))
+}
@@ -0,0 +1,46 @@
+#![allow(unused)]
+
+//! コンソールで選択した文字列を覚えておくクリップボード。
+//!
+//! 入っているのは ASCII の文字列で、行の区切りは `\n`。マウスで選択を終えたところで
+//! 置き換わり、Ctrl + V で入力として貼り付ける。
+
+use crate::sync::SpinLock;
+
+/// クリップボードの容量（バイト）。画面 1 枚分より少し大きく、溢れた分は切り捨てる。
+pub(crate) const CLIPBOARD_SIZE: usize = 4096;
+
+struct Clipboard {
+    buf: [u8; CLIPBOARD_SIZE],
+    len: usize,
+}
+
+static CLIPBOARD: SpinLock<Clipboard> = SpinLock::new(Clipboard {
+    buf: [0; CLIPBOARD_SIZE],
+    len: 0,
+});
+
+/// `fill` に書き込ませた内容でクリップボードを置き換え、その長さを返す。
+///
+/// `fill` は渡されたバッファへ書き込み、書いたバイト数を返すこと。
+/// 大きな一時バッファを使わずに、コンソールの内容を直接書き込めるようにしている。
+pub(crate) fn store(fill: impl FnOnce(&mut [u8]) -> usize) -> usize {
+    let mut clipboard = CLIPBOARD.lock();
+    let len = fill(&mut clipboard.buf).min(CLIPBOARD_SIZE);
+    clipboard.len = len;
+    len
+}
+
+/// クリップボードの内容を `f` に渡す。空なら空のスライスを渡す。
+pub(crate) fn with<R>(f: impl FnOnce(&[u8]) -> R) -> R {
+    let clipboard = CLIPBOARD.lock();
+    f(&clipboard.buf[..clipboard.len])
+}
+
+pub(crate) fn len() -> usize {
+    CLIPBOARD.lock().len
+}
+
+pub(crate) fn is_empty() -> bool {
+    len() == 0
+}
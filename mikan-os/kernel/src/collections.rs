@@ -0,0 +1,77 @@
+#![allow(unused)]
+
+use crate::{error, make_error};
+
+/// 要素数の上限が決まった連想配列。ヒープを使わずに、小さな登録表を作るのに使う。
+///
+/// 探索は先頭からの線形探索なので、数十要素程度までを想定している。
+pub(crate) struct FixedMap<K, V, const N: usize> {
+    entries: [Option<(K, V)>; N],
+    len: usize,
+}
+
+impl<K: PartialEq, V, const N: usize> FixedMap<K, V, N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            entries: [const { None }; N],
+            len: 0,
+        }
+    }
+
+    /// `key` に `value` を対応付ける。
+    ///
+    /// 既に `key` があれば値を置き換える。無くて満杯なら何もせず [error::Code::Full] を返す。
+    pub(crate) fn insert(&mut self, key: K, value: V) -> error::Error {
+        if let Some(v) = self.get_mut(&key) {
+            *v = value;
+            return make_error!(error::Code::Success);
+        }
+        match self.entries.iter_mut().find(|entry| entry.is_none()) {
+            Some(entry) => {
+                *entry = Some((key, value));
+                self.len += 1;
+                make_error!(error::Code::Success)
+            }
+            None => make_error!(error::Code::Full),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &K) -> Option<&V> {
+        self.iter().find(|(k, _)| *k == key).map(|(_, v)| v)
+    }
+
+    pub(crate) fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.entries
+            .iter_mut()
+            .flatten()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// `key` を取り除き、対応していた値を返す。
+    pub(crate) fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.as_ref().is_some_and(|(k, _)| k == key))?;
+        self.len -= 1;
+        entry.take().map(|(_, v)| v)
+    }
+
+    /// 登録されている組を返す。順序は登録順とは限らない。
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().flatten().map(|(k, v)| (k, v))
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn is_full(&self) -> bool {
+        self.len == N
+    }
+}
@@ -1,91 +1,678 @@
 #![allow(unused)]
 
-use core::{
-    fmt::{self, Write},
-    ptr::copy_nonoverlapping,
-};
+use core::fmt::{self, Write};
 
 use crate::{
-    font::{write_ascii, write_string},
+    font::{self, GlyphCache, TextOrientation},
     graphics::{PixelColor, PixelWriter, Vector2D},
+    theme::Theme,
 };
 
 const ROW_NUM: usize = 25;
 const COLUMN_NUM: usize = 80;
+/// 画面外へ流れた行を保持しておく数
+const SCROLLBACK_ROWS: usize = 500;
+/// タブ幅の初期値（文字数）
+pub(crate) const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// printk! の出力先。
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum ConsoleBackend {
+    /// 画面上の [Console] のみ
+    Graphics,
+    /// シリアルポートのみ
+    Serial,
+    /// 両方
+    Both,
+}
+
+impl ConsoleBackend {
+    /// 画面へ出力するかどうか。
+    pub(crate) const fn graphics(&self) -> bool {
+        matches!(self, Self::Graphics | Self::Both)
+    }
+
+    /// シリアルポートへ出力するかどうか。
+    pub(crate) const fn serial(&self) -> bool {
+        matches!(self, Self::Serial | Self::Both)
+    }
+}
+
+static mut CONSOLE_BACKEND: ConsoleBackend = ConsoleBackend::Graphics;
+
+pub(crate) fn set_console_backend(backend: ConsoleBackend) {
+    unsafe {
+        CONSOLE_BACKEND = backend;
+    }
+}
+
+pub(crate) fn get_console_backend() -> ConsoleBackend {
+    unsafe { CONSOLE_BACKEND }
+}
+
+/// ANSI エスケープシーケンスの SGR で使う 8 色（30〜37、40〜47）
+const ANSI_COLORS: [PixelColor; 8] = [
+    PixelColor::new(0, 0, 0),
+    PixelColor::new(170, 0, 0),
+    PixelColor::new(0, 170, 0),
+    PixelColor::new(170, 85, 0),
+    PixelColor::new(0, 0, 170),
+    PixelColor::new(170, 0, 170),
+    PixelColor::new(0, 170, 170),
+    PixelColor::new(170, 170, 170),
+];
+/// ANSI エスケープシーケンスの SGR で使う明るい 8 色（90〜97）
+const ANSI_BRIGHT_COLORS: [PixelColor; 8] = [
+    PixelColor::new(85, 85, 85),
+    PixelColor::new(255, 85, 85),
+    PixelColor::new(85, 255, 85),
+    PixelColor::new(255, 255, 85),
+    PixelColor::new(85, 85, 255),
+    PixelColor::new(255, 85, 255),
+    PixelColor::new(85, 255, 255),
+    PixelColor::new(255, 255, 255),
+];
+
+/// コンソールの描画に使う文字のキャッシュ。
+/// 大きいので [Console] には持たせず、スタックを経由しないよう静的に確保する。
+static mut GLYPH_CACHE: GlyphCache = GlyphCache::new();
+
+/// CSI シーケンスで保持するパラメータの最大数
+const MAX_ESCAPE_PARAMS: usize = 4;
+
+/// エスケープシーケンスの解析状態。
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    /// 通常の文字
+    Normal,
+    /// ESC を受け取った直後
+    Escape,
+    /// ESC [ に続くパラメータの途中
+    Csi,
+}
+
+/// 画面上の 1 文字分の情報。
+#[derive(Clone, Copy)]
+struct Cell {
+    c: u8,
+    fg: PixelColor,
+    bg: PixelColor,
+}
+
+/// 画面の上端から流れ出た行の履歴。古いものから順に捨てるリングバッファ。
+struct Scrollback {
+    rows: [[Cell; COLUMN_NUM]; SCROLLBACK_ROWS],
+    /// 最も古い行の位置
+    start: usize,
+    len: usize,
+    /// これまでに積んだ行数。捨てた行も数える。
+    pushed: usize,
+}
+
+impl Scrollback {
+    const fn new() -> Self {
+        const BLANK: Cell = Cell {
+            c: 0,
+            fg: PixelColor::new(0, 0, 0),
+            bg: PixelColor::new(0, 0, 0),
+        };
+        Self {
+            rows: [[BLANK; COLUMN_NUM]; SCROLLBACK_ROWS],
+            start: 0,
+            len: 0,
+            pushed: 0,
+        }
+    }
+
+    fn push(&mut self, row: &[Cell; COLUMN_NUM]) {
+        if self.len < SCROLLBACK_ROWS {
+            self.rows[(self.start + self.len) % SCROLLBACK_ROWS] = *row;
+            self.len += 1;
+        } else {
+            self.rows[self.start] = *row;
+            self.start = (self.start + 1) % SCROLLBACK_ROWS;
+        }
+        self.pushed += 1;
+    }
+
+    /// 古い方から数えて `i` 番目の行を返す。
+    fn get(&self, i: usize) -> &[Cell; COLUMN_NUM] {
+        &self.rows[(self.start + i) % SCROLLBACK_ROWS]
+    }
+}
+
+/// マウスで選択している範囲。
+///
+/// 位置は (行番号, 列) で、行番号は起動してから画面に出た行の通し番号（[Scrollback::pushed] に
+/// 画面上の行を足したもの）。履歴をさかのぼったり新しい行が流れてきたりしても、同じ文字を指し続ける。
+/// 両端を含み、`anchor` と `head` が同じなら何も選択していない。
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Selection {
+    /// 選択を始めた位置
+    anchor: (usize, usize),
+    /// 今のマウスの位置
+    head: (usize, usize),
+}
+
+impl Selection {
+    /// 前にある方から順に、両端を返す。
+    fn range(&self) -> ((usize, usize), (usize, usize)) {
+        if self.anchor <= self.head {
+            (self.anchor, self.head)
+        } else {
+            (self.head, self.anchor)
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.anchor == self.head
+    }
+
+    fn contains(&self, line: usize, column: usize) -> bool {
+        let (start, end) = self.range();
+        !self.is_empty() && start <= (line, column) && (line, column) <= end
+    }
+}
+
+/// コンソールの履歴。大きいので [GLYPH_CACHE] と同じく静的に確保する。
+static mut SCROLLBACK: Scrollback = Scrollback::new();
+
+fn scrollback() -> &'static mut Scrollback {
+    unsafe { &mut *core::ptr::addr_of_mut!(SCROLLBACK) }
+}
 
 pub(crate) struct Console<'a> {
     writer: &'a dyn PixelWriter,
+    theme: &'a Theme,
     fg_color: &'a PixelColor,
     bg_color: &'a PixelColor,
-    buffer: [[u8; COLUMN_NUM]; ROW_NUM],
+    buffer: [[Cell; COLUMN_NUM]; ROW_NUM],
     cursor_row: usize,
     cursor_column: usize,
+    /// これから書く文字の前景色
+    current_fg: PixelColor,
+    /// これから書く文字の背景色
+    current_bg: PixelColor,
+    escape_state: EscapeState,
+    escape_params: [u16; MAX_ESCAPE_PARAMS],
+    num_escape_params: usize,
+    /// 最新の画面から何行分さかのぼって表示しているか。0 なら最新の画面。
+    scroll_offset: usize,
+    /// `\t` で次に進むタブ位置の間隔（文字数）
+    tab_width: usize,
+    /// true なら、改行後のカーソルを前の行の字下げに揃える
+    auto_indent: bool,
+    /// マウスで選択している範囲。選択は前景色と背景色を入れ替えて表示する。
+    selection: Option<Selection>,
 }
 
 impl<'a> Console<'a> {
-    pub(crate) fn new(
-        writer: &'a dyn PixelWriter,
-        fg_color: &'a PixelColor,
-        bg_color: &'a PixelColor,
-    ) -> Self {
+    pub(crate) fn new(writer: &'a dyn PixelWriter, theme: &'a Theme) -> Self {
+        let fg_color = &theme.foreground;
+        let bg_color = &theme.background;
         Self {
             writer,
+            theme,
             fg_color,
             bg_color,
-            buffer: [[0u8; COLUMN_NUM]; ROW_NUM],
+            buffer: [[Self::blank_cell(fg_color, bg_color); COLUMN_NUM]; ROW_NUM],
             cursor_row: 0,
             cursor_column: 0,
+            current_fg: *fg_color,
+            current_bg: *bg_color,
+            escape_state: EscapeState::Normal,
+            escape_params: [0; MAX_ESCAPE_PARAMS],
+            num_escape_params: 0,
+            scroll_offset: 0,
+            tab_width: DEFAULT_TAB_WIDTH,
+            auto_indent: false,
+            selection: None,
+        }
+    }
+
+    const fn blank_cell(fg_color: &PixelColor, bg_color: &PixelColor) -> Cell {
+        Cell {
+            c: 0,
+            fg: *fg_color,
+            bg: *bg_color,
         }
     }
 
     pub(crate) fn put_string(&mut self, s: &[u8]) {
         for &c in s {
-            if c == b'\n' {
-                self.new_line();
-            } else if (self.cursor_column < COLUMN_NUM) {
-                write_ascii(
-                    self.writer,
-                    Vector2D::new(8 * self.cursor_column as u32, 16 * self.cursor_row as u32),
-                    c,
-                    &self.fg_color,
-                );
-                self.buffer[self.cursor_row][self.cursor_column] = c;
-                self.cursor_column += 1;
+            match self.escape_state {
+                EscapeState::Normal => self.put_char(c),
+                EscapeState::Escape => {
+                    // ESC [ 以外のシーケンスは読み捨てる
+                    if c == b'[' {
+                        self.escape_state = EscapeState::Csi;
+                        self.escape_params = [0; MAX_ESCAPE_PARAMS];
+                        self.num_escape_params = 0;
+                    } else {
+                        self.escape_state = EscapeState::Normal;
+                    }
+                }
+                EscapeState::Csi => self.put_csi_byte(c),
             }
         }
     }
 
-    pub(crate) fn new_line(&mut self) {
-        self.cursor_column = 0;
+    /// エスケープシーケンス外の 1 文字を処理する。
+    fn put_char(&mut self, c: u8) {
+        if c == 0x1b {
+            self.escape_state = EscapeState::Escape;
+        } else if c == b'\n' {
+            let indent = if self.auto_indent {
+                self.indent_of(self.cursor_row)
+            } else {
+                0
+            };
+            self.new_line();
+            self.cursor_column = indent;
+        } else if c == b'\t' {
+            self.put_tab();
+        } else if c == 0x08 {
+            // Backspace はカーソルを 1 文字戻すだけで、文字は消さない
+            self.cursor_column = self.cursor_column.saturating_sub(1);
+        } else if (self.cursor_column < COLUMN_NUM) {
+            self.buffer[self.cursor_row][self.cursor_column] = Cell {
+                c,
+                fg: self.current_fg,
+                bg: self.current_bg,
+            };
+            if self.scroll_offset == 0 {
+                self.draw_cell(self.cursor_row, self.cursor_column);
+            }
+            self.cursor_column += 1;
+        }
+    }
 
-        if self.cursor_row < ROW_NUM - 1 {
-            self.cursor_row += 1;
+    /// 次のタブ位置まで空白で埋めてカーソルを進める。
+    ///
+    /// 通常の文字と同じく、行末を越えた分は書かずに捨てる。
+    fn put_tab(&mut self) {
+        let next = (self.cursor_column / self.tab_width + 1) * self.tab_width;
+        while self.cursor_column < next.min(COLUMN_NUM) {
+            self.put_char(b' ');
+        }
+    }
+
+    /// `row` 行目の先頭に続く空白の数を返す。空白しか無い行では 0 を返す。
+    fn indent_of(&self, row: usize) -> usize {
+        let line = &self.buffer[row];
+        match line.iter().position(|cell| cell.c != b' ') {
+            Some(i) if line[i].c != 0 => i,
+            _ => 0,
+        }
+    }
+
+    /// `\t` で進むタブ位置の間隔を設定する。0 は 1 として、画面の幅を越える値は画面の幅として扱う。
+    pub(crate) fn set_tab_width(&mut self, width: usize) {
+        self.tab_width = width.clamp(1, COLUMN_NUM);
+    }
+
+    pub(crate) fn tab_width(&self) -> usize {
+        self.tab_width
+    }
+
+    /// 自動字下げを設定する。有効にすると、改行したときにカーソルを前の行の字下げの位置へ進める。
+    pub(crate) fn set_auto_indent(&mut self, enabled: bool) {
+        self.auto_indent = enabled;
+    }
+
+    /// CSI シーケンス（ESC [ ...）の 1 バイトを処理する。
+    fn put_csi_byte(&mut self, c: u8) {
+        match c {
+            b'0'..=b'9' => {
+                if self.num_escape_params == 0 {
+                    self.num_escape_params = 1;
+                }
+                if let Some(param) = self.escape_params.get_mut(self.num_escape_params - 1) {
+                    *param = param.saturating_mul(10).saturating_add((c - b'0') as u16);
+                }
+            }
+            b';' => {
+                if self.num_escape_params == 0 {
+                    self.num_escape_params = 1;
+                }
+                self.num_escape_params += 1;
+            }
+            // 終端バイト
+            0x40..=0x7e => {
+                self.execute_csi(c);
+                self.escape_state = EscapeState::Normal;
+            }
+            // 中間バイトなどは無視する
+            _ => {}
+        }
+    }
+
+    /// 省略されたものは 0 として、i 番目の CSI パラメータを返す。
+    fn escape_param(&self, i: usize) -> u16 {
+        if i < self.num_escape_params.min(MAX_ESCAPE_PARAMS) {
+            self.escape_params[i]
         } else {
-            // 背景の描画
-            for y in 0..16 * ROW_NUM {
-                for x in 0..8 * COLUMN_NUM {
-                    self.writer
-                        .write(Vector2D::new(x as u32, y as u32), &self.bg_color);
+            0
+        }
+    }
+
+    /// 終端バイトに応じて CSI シーケンスを実行する。未対応のものは無視する。
+    fn execute_csi(&mut self, command: u8) {
+        match command {
+            b'm' => {
+                // パラメータ無しは 0（リセット）と同じ扱い
+                let n = self.num_escape_params.clamp(1, MAX_ESCAPE_PARAMS);
+                for i in 0..n {
+                    self.select_graphic_rendition(self.escape_param(i));
                 }
             }
+            b'H' | b'f' => {
+                // 行・列は 1 始まり
+                let row = self.escape_param(0).max(1) - 1;
+                let column = self.escape_param(1).max(1) - 1;
+                self.set_cursor(row as usize, column as usize);
+            }
+            b'J' if self.escape_param(0) == 2 => self.clear(),
+            _ => {}
+        }
+    }
+
+    /// SGR のパラメータ 1 つ分を適用する。
+    fn select_graphic_rendition(&mut self, param: u16) {
+        match param {
+            0 => {
+                self.current_fg = *self.fg_color;
+                self.current_bg = *self.bg_color;
+            }
+            // ログの Error と Warn に使う赤と黄は、配色で決めた色にする
+            31 => self.current_fg = self.theme.log_error,
+            33 => self.current_fg = self.theme.log_warn,
+            30..=37 => self.current_fg = ANSI_COLORS[(param - 30) as usize],
+            39 => self.current_fg = *self.fg_color,
+            40..=47 => self.current_bg = ANSI_COLORS[(param - 40) as usize],
+            49 => self.current_bg = *self.bg_color,
+            90..=97 => self.current_fg = ANSI_BRIGHT_COLORS[(param - 90) as usize],
+            _ => {}
+        }
+    }
+
+    /// 配色を切り替え、画面を消去する。
+    ///
+    /// 既に書いた文字はそのときの色を持っているので、残すと新旧の配色が混ざる。
+    pub(crate) fn set_theme(&mut self, theme: &'a Theme) {
+        self.theme = theme;
+        self.fg_color = &theme.foreground;
+        self.bg_color = &theme.background;
+        self.current_fg = theme.foreground;
+        self.current_bg = theme.background;
+        self.clear();
+    }
+
+    /// 画面とバッファを消去する。カーソル位置と履歴は変えない。選択は解除する。
+    pub(crate) fn clear(&mut self) {
+        self.buffer = [[Self::blank_cell(self.fg_color, self.bg_color); COLUMN_NUM]; ROW_NUM];
+        self.scroll_offset = 0;
+        self.selection = None;
+        self.redraw();
+    }
+
+    /// 表示を `rows` 行分さかのぼる。履歴の最初より前へは行かない。
+    pub(crate) fn scroll_up(&mut self, rows: usize) {
+        let offset = (self.scroll_offset + rows).min(scrollback().len);
+        if offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.redraw();
+        }
+    }
+
+    /// 表示を `rows` 行分新しい方へ進める。
+    pub(crate) fn scroll_down(&mut self, rows: usize) {
+        let offset = self.scroll_offset.saturating_sub(rows);
+        if offset != self.scroll_offset {
+            self.scroll_offset = offset;
+            self.redraw();
+        }
+    }
+
+    /// 最新の画面の表示へ戻る。
+    pub(crate) fn scroll_to_bottom(&mut self) {
+        self.scroll_down(self.scroll_offset);
+    }
+
+    /// 履歴をさかのぼって表示しているかどうか。
+    pub(crate) fn is_scrolled(&self) -> bool {
+        self.scroll_offset > 0
+    }
+
+    /// 画面の `row` 行目に表示する行を返す。
+    fn visible_row(&self, row: usize) -> &[Cell; COLUMN_NUM] {
+        let history_len = scrollback().len;
+        let i = history_len - self.scroll_offset + row;
+        if i < history_len {
+            scrollback().get(i)
+        } else {
+            &self.buffer[i - history_len]
+        }
+    }
+
+    /// 画面の `row` 行目に表示している行の、[Selection] と同じ通し番号。
+    fn line_of_row(&self, row: usize) -> usize {
+        scrollback().pushed - self.scroll_offset + row
+    }
+
+    /// 通し番号 `line` の行を返す。履歴から捨てられた行と、まだ出ていない行は None。
+    fn line(&self, line: usize) -> Option<&[Cell; COLUMN_NUM]> {
+        let history = scrollback();
+        let oldest = history.pushed - history.len;
+        if line < oldest {
+            None
+        } else if line < history.pushed {
+            Some(history.get(line - oldest))
+        } else {
+            self.buffer.get(line - history.pushed)
+        }
+    }
+
+    /// 指定された文字セルを、表示中の内容に従って描画する。選択中なら色を入れ替える。
+    fn draw_cell(&self, row: usize, column: usize) {
+        let cell = &self.visible_row(row)[column];
+        let selected = self
+            .selection
+            .is_some_and(|selection| selection.contains(self.line_of_row(row), column));
+        let (fg, bg) = if selected {
+            (&cell.bg, &cell.fg)
+        } else {
+            (&cell.fg, &cell.bg)
+        };
+        let glyph_cache = unsafe { &mut *core::ptr::addr_of_mut!(GLYPH_CACHE) };
+        glyph_cache.write_ascii(
+            self.writer,
+            Vector2D::new(8 * column as u32, 16 * row as u32),
+            cell.c,
+            fg,
+            bg,
+        );
+    }
+
+    /// 画面全体を描画し直す。履歴を表示中なら、右上にその旨を表示する。
+    fn redraw(&self) {
+        for row in 0..ROW_NUM {
+            for column in 0..COLUMN_NUM {
+                self.draw_cell(row, column);
+            }
+        }
+        if self.is_scrolled() {
+            self.draw_scroll_indicator();
+        }
+    }
+
+    /// 通し番号が `first` から `last` までの行のうち、画面に見えているものを描画し直す。
+    fn redraw_lines(&self, first: usize, last: usize) {
+        let top = self.line_of_row(0);
+        let first_row = first.saturating_sub(top);
+        let last_row = (last.saturating_sub(top) + 1).min(ROW_NUM);
+        if last < top || first_row >= last_row {
+            return;
+        }
+        for row in first_row..last_row {
+            for column in 0..COLUMN_NUM {
+                self.draw_cell(row, column);
+            }
+        }
+        // 右上の表示を上書きしてしまうので描き直す
+        if first_row == 0 && self.is_scrolled() {
+            self.draw_scroll_indicator();
+        }
+    }
+
+    /// 画面上の位置 `pos`（ピクセル）にある文字セルを (行, 列) で返す。コンソールの外なら None。
+    pub(crate) fn cell_at(&self, pos: Vector2D<u32>) -> Option<(usize, usize)> {
+        let (row, column) = (pos.y() as usize / 16, pos.x() as usize / 8);
+        (row < ROW_NUM && column < COLUMN_NUM).then_some((row, column))
+    }
+
+    /// それまでの選択を解除し、`pos`（ピクセル）の文字から選択を始める。
+    ///
+    /// `pos` がコンソールの外なら、解除するだけで偽を返す。
+    pub(crate) fn start_selection(&mut self, pos: Vector2D<u32>) -> bool {
+        self.clear_selection();
+        let Some((row, column)) = self.cell_at(pos) else {
+            return false;
+        };
+        let at = (self.line_of_row(row), column);
+        self.selection = Some(Selection {
+            anchor: at,
+            head: at,
+        });
+        true
+    }
+
+    /// 選択中なら、選択の終わりを `pos`（ピクセル）の文字まで動かす。
+    ///
+    /// コンソールの外へ出た分は、端の文字として扱う。
+    pub(crate) fn extend_selection(&mut self, pos: Vector2D<u32>) {
+        let Some(old) = self.selection else {
+            return;
+        };
+        let row = (pos.y() as usize / 16).min(ROW_NUM - 1);
+        let column = (pos.x() as usize / 8).min(COLUMN_NUM - 1);
+        let new = Selection {
+            head: (self.line_of_row(row), column),
+            ..old
+        };
+        if new == old {
+            return;
+        }
+        self.selection = Some(new);
+        // 選択が変わるのは、動く前と後の終わりの間の行だけ
+        let first = old.head.0.min(new.head.0);
+        let last = old.head.0.max(new.head.0);
+        self.redraw_lines(first, last);
+    }
+
+    /// 選択を解除する。
+    pub(crate) fn clear_selection(&mut self) {
+        if let Some(selection) = self.selection.take() {
+            let (start, end) = selection.range();
+            self.redraw_lines(start.0, end.0);
+        }
+    }
+
+    /// 何か選択しているかどうか。
+    pub(crate) fn has_selection(&self) -> bool {
+        self.selection
+            .is_some_and(|selection| !selection.is_empty())
+    }
 
-            // バッファの移動と描画
-            for row in 0..ROW_NUM - 1 {
-                unsafe {
-                    copy_nonoverlapping(
-                        self.buffer[row + 1].as_ptr(),
-                        self.buffer[row].as_mut_ptr(),
-                        COLUMN_NUM,
-                    );
+    /// 選択している文字列を `buf` へ書き込み、書いたバイト数を返す。入り切らない分は捨てる。
+    ///
+    /// 行ごとに末尾の空白を取り除き、`\n` で区切る。コンソールは行を折り返さないので、
+    /// 画面上の 1 行がそのまま 1 行になる。履歴から捨てられた行は飛ばす。
+    pub(crate) fn copy_selection(&self, buf: &mut [u8]) -> usize {
+        let Some(selection) = self.selection.filter(|selection| !selection.is_empty()) else {
+            return 0;
+        };
+        let (start, end) = selection.range();
+        let mut len = 0;
+        let mut first_line = true;
+        for line in start.0..=end.0 {
+            let Some(cells) = self.line(line) else {
+                continue;
+            };
+            let first = if line == start.0 { start.1 } else { 0 };
+            let last = if line == end.0 { end.1 } else { COLUMN_NUM - 1 };
+            let cells = &cells[first..=last];
+            let used = cells
+                .iter()
+                .rposition(|cell| cell.c != 0 && cell.c != b' ')
+                .map_or(0, |i| i + 1);
+            if !first_line {
+                if len == buf.len() {
+                    break;
+                }
+                buf[len] = b'\n';
+                len += 1;
+            }
+            first_line = false;
+            for cell in &cells[..used] {
+                if len == buf.len() {
+                    return len;
                 }
-                write_string(
-                    self.writer,
-                    Vector2D::new(0, 16 * row as u32),
-                    &self.buffer[row],
-                    &self.fg_color,
-                );
+                // 一度も書いていないセルは空白として扱う
+                buf[len] = if cell.c == 0 { b' ' } else { cell.c };
+                len += 1;
+            }
+        }
+        len
+    }
+
+    /// 履歴を表示中であることを、前景色と背景色を入れ替えて右上に表示する。
+    fn draw_scroll_indicator(&self) {
+        let mut buf = [0u8; 32];
+        let mut s = crate::string::StringU8::new(&mut buf);
+        let _ = write!(s, " SCROLLED -{} ", self.scroll_offset);
+        let len = s.len();
+        let size = font::string_size(len, TextOrientation::Normal);
+        let pos = Vector2D::new(8 * COLUMN_NUM as u32 - size.x(), 0);
+        self.writer.fill_rectangle(pos, size, self.fg_color);
+        font::write_string(
+            self.writer,
+            pos,
+            &buf[..len],
+            self.bg_color,
+            TextOrientation::Normal,
+        );
+    }
+
+    /// 現在のカーソル位置を (行, 列) で返す。
+    pub(crate) fn cursor(&self) -> (usize, usize) {
+        (self.cursor_row, self.cursor_column)
+    }
+
+    /// カーソル位置を (行, 列) で設定する。
+    /// 画面外を指定された場合は、画面内に収まるように切り詰める。
+    pub(crate) fn set_cursor(&mut self, row: usize, column: usize) {
+        self.cursor_row = row.min(ROW_NUM - 1);
+        self.cursor_column = column.min(COLUMN_NUM - 1);
+    }
+
+    pub(crate) fn new_line(&mut self) {
+        self.cursor_column = 0;
+
+        if self.cursor_row < ROW_NUM - 1 {
+            self.cursor_row += 1;
+        } else {
+            // 流れ出る行は履歴へ残す
+            scrollback().push(&self.buffer[0]);
+            self.buffer.copy_within(1.., 0);
+            self.buffer[ROW_NUM - 1] = [Self::blank_cell(self.fg_color, self.bg_color); COLUMN_NUM];
+            if self.scroll_offset == 0 {
+                self.redraw();
+            } else {
+                // 履歴を表示中は、見ている位置がずれないようにさかのぼる量を増やす
+                self.scroll_offset = (self.scroll_offset + 1).min(scrollback().len);
+                self.draw_scroll_indicator();
             }
-            self.buffer[ROW_NUM - 1] = [0u8; COLUMN_NUM];
         }
     }
 }
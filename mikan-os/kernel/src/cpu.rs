@@ -0,0 +1,128 @@
+#![allow(unused)]
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    asmfunc::{cpuid, get_cr0, monitor, mwait, set_cr0},
+    log,
+    logger::LogLevel,
+    msr, printk, printkln,
+    sync::OnceLock,
+};
+
+/// CR0 の WP（書き込み保護）ビット
+const CR0_WP: u64 = 1 << 16;
+
+/// 基本機能情報のリーフ
+const LEAF_FEATURES: u32 = 0x01;
+/// 拡張リーフの最大値を得るリーフ
+const LEAF_EXTENDED_MAX: u32 = 0x8000_0000;
+/// 拡張機能情報のリーフ
+const LEAF_EXTENDED_FEATURES: u32 = 0x8000_0001;
+
+/// CPU のベンダ文字列（"GenuineIntel" など）を返す。
+pub(crate) fn vendor() -> [u8; 12] {
+    let (_, ebx, ecx, edx) = cpuid(0, 0);
+    let mut ret = [0u8; 12];
+    ret[0..4].copy_from_slice(&ebx.to_le_bytes());
+    ret[4..8].copy_from_slice(&edx.to_le_bytes());
+    ret[8..12].copy_from_slice(&ecx.to_le_bytes());
+    ret
+}
+
+/// 拡張リーフ 0x80000001 が使えるかどうか。
+fn has_extended_features() -> bool {
+    cpuid(LEAF_EXTENDED_MAX, 0).0 >= LEAF_EXTENDED_FEATURES
+}
+
+/// Local APIC を持つかどうか。
+pub(crate) fn has_apic() -> bool {
+    cpuid(LEAF_FEATURES, 0).3 & (1 << 9) != 0
+}
+
+/// x2APIC モードに対応しているかどうか。
+pub(crate) fn has_x2apic() -> bool {
+    cpuid(LEAF_FEATURES, 0).2 & (1 << 21) != 0
+}
+
+/// MONITOR/MWAIT 命令に対応しているかどうか。
+pub(crate) fn has_monitor_mwait() -> bool {
+    cpuid(LEAF_FEATURES, 0).2 & (1 << 3) != 0
+}
+
+/// 64 ビットモードの SYSCALL/SYSRET 命令に対応しているかどうか。
+pub(crate) fn has_syscall() -> bool {
+    has_extended_features() && cpuid(LEAF_EXTENDED_FEATURES, 0).3 & (1 << 11) != 0
+}
+
+/// NX（実行禁止）ビットに対応しているかどうか。
+pub(crate) fn has_nx() -> bool {
+    has_extended_features() && cpuid(LEAF_EXTENDED_FEATURES, 0).3 & (1 << 20) != 0
+}
+
+/// 1 GiB ページに対応しているかどうか。
+pub(crate) fn has_1gb_pages() -> bool {
+    has_extended_features() && cpuid(LEAF_EXTENDED_FEATURES, 0).3 & (1 << 26) != 0
+}
+
+/// 検出した CPU の機能をログに出す。
+///
+/// 既定のログレベル（Warn）でも残るよう、Warn で出す。
+pub(crate) fn log_features() {
+    let vendor = vendor();
+    log!(
+        LogLevel::Warn,
+        "CPU: {}, APIC={}, x2APIC={}, MWAIT={}, NX={}, 1GiB pages={}",
+        core::str::from_utf8(&vendor).unwrap_or("unknown"),
+        has_apic(),
+        has_x2apic(),
+        has_monitor_mwait(),
+        has_nx(),
+        has_1gb_pages()
+    );
+}
+
+/// [has_monitor_mwait] の結果。待機のたびに CPUID を実行しないよう、最初に調べた値を使う。
+static MONITOR_MWAIT: OnceLock<bool> = OnceLock::new();
+
+/// `word` が `seen` から変わるまで CPU を休ませる。
+///
+/// MONITOR/MWAIT に対応していれば `word` への書き込みで起きる。対応していなければ HLT で
+/// 割り込みを待つ。どちらも割り込みなどで早めに戻ることがあるので、呼び出し側で条件を確かめ直す。
+/// 割り込みの許可は変えない。ポーリングで読む入力は書き込みも割り込みも起こさないので、それを待つ間は
+/// 周期的なタイマ割り込み（[crate::timer::start_tick]）が動いていないと起きられなくなる。
+pub(crate) fn idle_until_changed(word: &AtomicUsize, seen: usize) {
+    if *MONITOR_MWAIT.get_or_init(has_monitor_mwait) {
+        unsafe { monitor(word.as_ptr() as *const u8) };
+        // MONITOR の後に書き込まれていたら MWAIT では起きられないので、ここで確かめる
+        if word.load(Ordering::Acquire) == seen {
+            unsafe { mwait() };
+        }
+    } else if word.load(Ordering::Acquire) == seen {
+        unsafe { asm!("hlt") };
+    }
+}
+
+/// ページテーブルによる保護を CPU 側で有効にする。
+///
+/// CR0.WP を立てて、カーネルモードでも書き込み禁止ページへの書き込みを禁止する。
+/// NX に対応していれば EFER.NXE も立てて、ページテーブルの実行禁止ビットを有効にする。
+/// 各ページの属性は [crate::paging::setup_page_tables] が付ける。実行禁止ビットを使うページテーブルへ
+/// 切り替えるときに EFER.NXE は既に立てているので、ここで立て直しても変わらない。
+pub(crate) fn enable_memory_protection() {
+    unsafe {
+        set_cr0(get_cr0() | CR0_WP);
+        if has_nx() {
+            msr::update(msr::IA32_EFER, |efer| efer | msr::EFER_NXE);
+        }
+    }
+    // 保護が効いているかは起動のたびに確かめたいので、log_features と同じく Warn で出す
+    log!(
+        LogLevel::Warn,
+        "memory protection: WP=1, NXE={}",
+        (unsafe { msr::read(msr::IA32_EFER) } & msr::EFER_NXE != 0) as u8
+    );
+}
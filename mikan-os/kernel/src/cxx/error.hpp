@@ -26,6 +26,19 @@ class Error {
     kInvalidPhase,
     kUnknownXHCISpeedID,
     kNoWaiter,
+    kNoPCIMSI,
+    kUnknownPixelFormat,
+    kNoSuchTask,
+    kInvalidFormat,
+    kFrameTooSmall,
+    kInvalidFile,
+    kIsDirectory,
+    kNoSuchEntry,
+    kFreeTypeError,
+    kEndpointNotInCharge,
+    kTimeout,
+    kHostControllerError,
+    kFirmwareError,
     kLastOfCode,  // この列挙子は常に最後に配置する
   };
 
@@ -51,6 +64,19 @@ class Error {
     "kInvalidPhase",
     "kUnknownXHCISpeedID",
     "kNoWaiter",
+    "kNoPCIMSI",
+    "kUnknownPixelFormat",
+    "kNoSuchTask",
+    "kInvalidFormat",
+    "kFrameTooSmall",
+    "kInvalidFile",
+    "kIsDirectory",
+    "kNoSuchEntry",
+    "kFreeTypeError",
+    "kEndpointNotInCharge",
+    "kTimeout",
+    "kHostControllerError",
+    "kFirmwareError",
   };
   static_assert(Error::Code::kLastOfCode == code_names_.size());
 
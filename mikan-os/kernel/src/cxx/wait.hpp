@@ -0,0 +1,30 @@
+/**
+ * @file wait.hpp
+ *
+ * ハードウェアの状態が変わるのを待つポーリングループを提供する．
+ */
+
+#pragma once
+
+#include "error.hpp"
+
+/** @brief WaitUntil がデフォルトで条件を確認する最大回数 */
+static const unsigned long kDefaultPollLimit = 1000000;
+
+/** @brief pred が true を返すまで繰り返し呼び出す．
+ *
+ * タイマが無いので，時間ではなく確認の回数で打ち切る．
+ *
+ * @param pred        待っている状態になったら true を返す関数
+ * @param max_polls   pred を呼び出す最大回数
+ * @return max_polls 回呼んでも pred が true にならなければ kTimeout
+ */
+template <class Pred>
+Error WaitUntil(Pred pred, unsigned long max_polls = kDefaultPollLimit) {
+  for (unsigned long i = 0; i < max_polls; ++i) {
+    if (pred()) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+  }
+  return MAKE_ERROR(Error::kTimeout);
+}
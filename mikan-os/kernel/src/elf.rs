@@ -0,0 +1,100 @@
+#![allow(unused)]
+
+//! カーネル自身の ELF ヘッダを読むための定義。並びはローダ側の `elf.rs` と同じ。
+
+use core::{
+    mem::{align_of, size_of},
+    ptr::{self, addr_of},
+    slice,
+};
+
+/// ELF ヘッダ
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Elf64Ehdr {
+    pub(crate) ident: [u8; 16],
+    pub(crate) r#type: u16,
+    pub(crate) machine: u16,
+    pub(crate) version: u32,
+    pub(crate) entry: usize,
+    pub(crate) phoff: u64,
+    pub(crate) shoff: u64,
+    pub(crate) flags: u32,
+    pub(crate) ehsize: u16,
+    pub(crate) phentsize: u16,
+    pub(crate) phnum: u16,
+    pub(crate) shentsize: u16,
+    pub(crate) shnum: u16,
+    pub(crate) shstrndx: u16,
+}
+
+/// プログラムヘッダ
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct Elf64Phdr {
+    pub(crate) r#type: u32,
+    pub(crate) flags: u32,
+    pub(crate) offset: u64,
+    pub(crate) vaddr: usize,
+    pub(crate) paddr: usize,
+    pub(crate) filesz: u64,
+    pub(crate) memsz: u64,
+    pub(crate) align: u64,
+}
+
+/// メモリへ読み込むセグメントを表す [Elf64Phdr::type]
+pub(crate) const PT_LOAD: u32 = 1;
+/// [Elf64Phdr::flags] の実行可能ビット
+pub(crate) const PF_X: u32 = 1 << 0;
+/// [Elf64Phdr::flags] の書き込み可能ビット
+pub(crate) const PF_W: u32 = 1 << 1;
+/// [Elf64Phdr::flags] の読み込み可能ビット
+pub(crate) const PF_R: u32 = 1 << 2;
+
+const _: () = assert!(size_of::<Elf64Ehdr>() == 64);
+const _: () = assert!(size_of::<Elf64Phdr>() == 56);
+
+extern "C" {
+    /// リンカが定義する、読み込まれた ELF ヘッダの先頭。
+    ///
+    /// ローダは最初の LOAD セグメントをファイルの先頭から写すので、ヘッダもメモリ上にある。
+    static __ehdr_start: Elf64Ehdr;
+}
+
+/// [Elf64Ehdr::ident] の先頭のマジックナンバー
+pub(crate) const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+
+/// メモリ上の ELF イメージ `image` から、プログラムヘッダの並びを返す。
+///
+/// マジックナンバーが違う、エントリの大きさが [Elf64Phdr] と違う、並びが `image` に収まらない、
+/// 8 バイト境界に揃っていない、のいずれかなら None を返す。
+pub(crate) fn program_headers(image: &[u8]) -> Option<&[Elf64Phdr]> {
+    if image.len() < size_of::<Elf64Ehdr>() {
+        return None;
+    }
+    let ehdr = unsafe { ptr::read_unaligned(image.as_ptr() as *const Elf64Ehdr) };
+    if ehdr.ident[..4] != ELF_MAGIC || ehdr.phentsize as usize != size_of::<Elf64Phdr>() {
+        return None;
+    }
+    let offset = usize::try_from(ehdr.phoff).ok()?;
+    let end = offset.checked_add(ehdr.phnum as usize * size_of::<Elf64Phdr>())?;
+    if end > image.len() {
+        return None;
+    }
+    let phdrs = image[offset..].as_ptr();
+    if !(phdrs as usize).is_multiple_of(align_of::<Elf64Phdr>()) {
+        return None;
+    }
+    Some(unsafe { slice::from_raw_parts(phdrs as *const Elf64Phdr, ehdr.phnum as usize) })
+}
+
+/// カーネル自身のプログラムヘッダを返す。読めなければ空を返す。
+pub(crate) fn kernel_program_headers() -> &'static [Elf64Phdr] {
+    let ehdr = unsafe { &*addr_of!(__ehdr_start) };
+    // ヘッダが示すプログラムヘッダの終わりまでを、イメージとして読ませる
+    let len = (ehdr.phoff as usize)
+        .saturating_add(ehdr.phnum as usize * size_of::<Elf64Phdr>())
+        .max(size_of::<Elf64Ehdr>());
+    let image = unsafe { slice::from_raw_parts(ehdr as *const Elf64Ehdr as *const u8, len) };
+    program_headers(image).unwrap_or(&[])
+}
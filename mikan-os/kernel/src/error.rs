@@ -35,6 +35,9 @@ pub(crate) enum Code {
     NoSuchEntry,
     FreeTypeError,
     EndpointNotInCharge,
+    Timeout,
+    HostControllerError,
+    FirmwareError,
     LastOfCode, // これは常に最後に配置する
 }
 
@@ -71,6 +74,9 @@ impl Display for Code {
             Self::NoSuchEntry => write!(f, "NoSuchEntry"),
             Self::FreeTypeError => write!(f, "FreeTypeError"),
             Self::EndpointNotInCharge => write!(f, "EndpointNotInCharge"),
+            Self::Timeout => write!(f, "Timeout"),
+            Self::HostControllerError => write!(f, "HostControllerError"),
+            Self::FirmwareError => write!(f, "FirmwareError"),
             Self::LastOfCode => write!(f, "LastOfCode"),
         }
     }
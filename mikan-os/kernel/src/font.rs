@@ -1,31 +1,170 @@
 use crate::{
     font_data::get_font,
-    graphics::{PixelColor, PixelWriter, Vector2D},
+    frame_buffer_config::PixelFormat,
+    graphics::{PixelColor, PixelWriter, Vector2D, BYTES_PER_PIXEL},
 };
 
+/// 1 文字の横幅
+const GLYPH_WIDTH: u32 = 8;
+/// 1 文字の高さ
+const GLYPH_HEIGHT: u32 = 16;
+
+/// 文字列を描画する向き（時計回りの回転角）。
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum TextOrientation {
+    /// 回転無し。左から右へ書く。
+    Normal,
+    /// 90 度。上から下へ書く。
+    Rotate90,
+    /// 180 度。右から左へ、上下逆さまに書く。
+    Rotate180,
+    /// 270 度。下から上へ書く。
+    Rotate270,
+}
+
 pub(crate) fn write_ascii(writer: &dyn PixelWriter, pos: Vector2D<u32>, c: u8, color: &PixelColor) {
+    write_ascii_rotated(writer, pos, c, color, TextOrientation::Normal);
+}
+
+/// 1 文字を `orientation` だけ回転させて描画する。`pos` は回転後の文字の左上。
+fn write_ascii_rotated(
+    writer: &dyn PixelWriter,
+    pos: Vector2D<u32>,
+    c: u8,
+    color: &PixelColor,
+    orientation: TextOrientation,
+) {
     let font = get_font(c);
-    for dy in 0..16 {
-        for dx in 0..8 {
-            if ((font[dy] << dx) & 0x80) != 0 {
-                writer.write(pos + Vector2D::new(dx, dy as u32), color);
+    for dy in 0..GLYPH_HEIGHT {
+        for dx in 0..GLYPH_WIDTH {
+            if ((font[dy as usize] << dx) & 0x80) == 0 {
+                continue;
             }
+            let (x, y) = match orientation {
+                TextOrientation::Normal => (dx, dy),
+                TextOrientation::Rotate90 => (GLYPH_HEIGHT - 1 - dy, dx),
+                TextOrientation::Rotate180 => (GLYPH_WIDTH - 1 - dx, GLYPH_HEIGHT - 1 - dy),
+                TextOrientation::Rotate270 => (dy, GLYPH_WIDTH - 1 - dx),
+            };
+            writer.write(pos + Vector2D::new(x, y), color);
         }
     }
 }
 
+/// `len` 文字の文字列を `orientation` の向きで描画したときの、外接矩形の大きさを返す。
+pub(crate) fn string_size(len: usize, orientation: TextOrientation) -> Vector2D<u32> {
+    let len = len as u32;
+    match orientation {
+        TextOrientation::Normal | TextOrientation::Rotate180 => {
+            Vector2D::new(GLYPH_WIDTH * len, GLYPH_HEIGHT)
+        }
+        TextOrientation::Rotate90 | TextOrientation::Rotate270 => {
+            Vector2D::new(GLYPH_HEIGHT, GLYPH_WIDTH * len)
+        }
+    }
+}
+
+/// 文字列を `orientation` の向きで描画する。
+///
+/// `pos` は向きに関わらず外接矩形（[string_size]）の左上を表す。
 pub(crate) fn write_string(
     writer: &dyn PixelWriter,
     pos: Vector2D<u32>,
     s: &[u8],
     color: &PixelColor,
+    orientation: TextOrientation,
 ) {
-    for i in 0..s.len() {
-        write_ascii(
-            writer,
-            Vector2D::new(pos.x() + 8 * i as u32, pos.y()),
-            s[i],
-            color,
-        );
+    let last = s.len().saturating_sub(1) as u32;
+    for (i, &c) in s.iter().enumerate() {
+        let i = i as u32;
+        let offset = match orientation {
+            TextOrientation::Normal => Vector2D::new(GLYPH_WIDTH * i, 0),
+            TextOrientation::Rotate90 => Vector2D::new(0, GLYPH_WIDTH * i),
+            TextOrientation::Rotate180 => Vector2D::new(GLYPH_WIDTH * (last - i), 0),
+            TextOrientation::Rotate270 => Vector2D::new(0, GLYPH_WIDTH * (last - i)),
+        };
+        write_ascii_rotated(writer, pos + offset, c, color, orientation);
+    }
+}
+
+/// キャッシュする文字の数（ASCII）
+const GLYPH_CACHE_NUM: usize = 128;
+/// ラスタライズした 1 行分のバイト数
+const GLYPH_ROW_BYTES: usize = BYTES_PER_PIXEL * GLYPH_WIDTH as usize;
+
+/// 前景色・背景色を塗った状態の文字を、フレームバッファのフォーマットで保持するキャッシュ。
+///
+/// 1 ピクセルずつフォントのビットを調べて書く代わりに、ラスタライズ済みの行をそのまま
+/// フレームバッファへコピーする。保持するのは 1 組の色の分だけで、
+/// 異なる色やフォーマットで描画しようとしたときは作り直す。
+pub(crate) struct GlyphCache {
+    fg: PixelColor,
+    bg: PixelColor,
+    format: PixelFormat,
+    /// 各文字をラスタライズ済みかどうか
+    cached: [bool; GLYPH_CACHE_NUM],
+    glyphs: [[u8; GLYPH_ROW_BYTES * GLYPH_HEIGHT as usize]; GLYPH_CACHE_NUM],
+}
+
+impl GlyphCache {
+    pub(crate) const fn new() -> Self {
+        Self {
+            fg: PixelColor::new(0, 0, 0),
+            bg: PixelColor::new(0, 0, 0),
+            format: PixelFormat::Rgb,
+            cached: [false; GLYPH_CACHE_NUM],
+            glyphs: [[0; GLYPH_ROW_BYTES * GLYPH_HEIGHT as usize]; GLYPH_CACHE_NUM],
+        }
+    }
+
+    /// 背景も含めて 1 文字分の矩形を描画する。
+    ///
+    /// ASCII 以外の文字はキャッシュせず、[write_ascii] と同じ方法で描画する。
+    pub(crate) fn write_ascii(
+        &mut self,
+        writer: &dyn PixelWriter,
+        pos: Vector2D<u32>,
+        c: u8,
+        fg: &PixelColor,
+        bg: &PixelColor,
+    ) {
+        if c as usize >= GLYPH_CACHE_NUM {
+            writer.fill_rectangle(pos, Vector2D::new(GLYPH_WIDTH, GLYPH_HEIGHT), bg);
+            write_ascii(writer, pos, c, fg);
+            return;
+        }
+
+        // キャッシュには明るさを反映した色で描いておく
+        let fg = fg.adjusted();
+        let bg = bg.adjusted();
+        let format = writer.config().pixel_format;
+        if self.fg != fg || self.bg != bg || self.format != format {
+            self.fg = fg;
+            self.bg = bg;
+            self.format = format;
+            self.cached = [false; GLYPH_CACHE_NUM];
+        }
+        if !self.cached[c as usize] {
+            self.rasterize(c);
+        }
+
+        let glyph = &self.glyphs[c as usize];
+        for (dy, row) in glyph.chunks_exact(GLYPH_ROW_BYTES).enumerate() {
+            writer.write_row(pos + Vector2D::new(0, dy as u32), row);
+        }
+    }
+
+    fn rasterize(&mut self, c: u8) {
+        let fg = self.fg.to_bytes(self.format);
+        let bg = self.bg.to_bytes(self.format);
+        let font = get_font(c);
+        let glyph = &mut self.glyphs[c as usize];
+        for (dy, row) in glyph.chunks_exact_mut(GLYPH_ROW_BYTES).enumerate() {
+            for (dx, pixel) in row.chunks_exact_mut(BYTES_PER_PIXEL).enumerate() {
+                let on = ((font[dy] << dx) & 0x80) != 0;
+                pixel.copy_from_slice(if on { &fg } else { &bg });
+            }
+        }
+        self.cached[c as usize] = true;
     }
 }
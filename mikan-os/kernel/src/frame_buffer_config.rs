@@ -1,16 +1,61 @@
+use crate::graphics::BYTES_PER_PIXEL;
+
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub enum PixelFormat {
     Rgb,
     Bgr,
 }
 
+impl TryFrom<u32> for PixelFormat {
+    type Error = u32;
+
+    /// ローダとの取り決めにある値だけを受け付ける。それ以外の値はそのまま返す。
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Rgb),
+            1 => Ok(Self::Bgr),
+            _ => Err(value),
+        }
+    }
+}
+
+/// ローダから渡される、[FrameBufferConfig] と同じ並びの構造体。
+///
+/// 取り決めに無い値の入った enum を作るだけで未定義動作になるので、ピクセル形式は数値のまま受け取り、
+/// [PixelFormat::try_from] で確かめてから [FrameBufferConfig] にする。
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BootFrameBufferConfig {
+    pub frame_buffer: usize,
+    pub pixels_per_scan_line: usize,
+    pub horizontal_resolution: usize,
+    pub vertical_resolution: usize,
+    pub pixel_format: u32,
+}
+
+const _: () = assert!(
+    core::mem::size_of::<BootFrameBufferConfig>() == core::mem::size_of::<FrameBufferConfig>()
+);
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct FrameBufferConfig {
     pub frame_buffer: usize,
+    /// 1 行あたりのピクセル数。GPU によっては行末に見えない余白があり、
+    /// [FrameBufferConfig::horizontal_resolution] より大きくなる。
     pub pixels_per_scan_line: usize,
+    /// 画面に見える横幅（ピクセル）
     pub horizontal_resolution: usize,
     pub vertical_resolution: usize,
     pub pixel_format: PixelFormat,
 }
+
+impl FrameBufferConfig {
+    /// (x, y) のピクセルの、フレームバッファ先頭からのバイト数を返す。
+    ///
+    /// 行の長さには横幅ではなく [FrameBufferConfig::pixels_per_scan_line] を使う。
+    pub(crate) const fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        BYTES_PER_PIXEL * (self.pixels_per_scan_line * y + x)
+    }
+}
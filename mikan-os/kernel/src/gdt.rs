@@ -0,0 +1,176 @@
+#![allow(unused)]
+
+//! カーネルとユーザモードのセグメント記述子を並べた GDT。
+//!
+//! 読み込み直した時点のセグメントレジスタは UEFI の記述子を指したままなので、それを消さないよう、
+//! UEFI の GDT を写した後ろに自前の記述子を足す。IDT（[crate::interrupt]）のゲートは自前の
+//! コードセグメントを使う。最後に TSS の記述子を置き、特権レベル 3 で割り込みや例外が起きたときに
+//! 切り替えるスタックを CPU へ教える。
+
+use core::{
+    arch::asm,
+    mem::size_of,
+    ptr::{addr_of, addr_of_mut},
+    slice,
+    sync::atomic::{AtomicU16, Ordering},
+};
+
+use crate::{bitfield::BitField, log, logger::LogLevel, printk, printkln};
+
+/// 写し取る UEFI の記述子の最大数
+const FIRMWARE_ENTRIES_MAX: usize = 16;
+
+// 自前の記述子。どれも 0 番地からの平坦なセグメント。
+// Accessed ビットを最初から立てておき、セレクタを読み込んだときに CPU が GDT へ書き込まないようにする。
+/// 64 ビットのコードセグメント（DPL 0）
+const KERNEL_CODE: u64 = 0x00af_9b00_0000_ffff;
+/// データセグメント（DPL 0）
+const KERNEL_DATA: u64 = 0x00cf_9300_0000_ffff;
+/// データセグメント（DPL 3）
+const USER_DATA: u64 = 0x00cf_f300_0000_ffff;
+/// 64 ビットのコードセグメント（DPL 3）
+const USER_CODE: u64 = 0x00af_fb00_0000_ffff;
+
+/// SYSCALL と SYSRET は、STAR に書いたセレクタからの決まった位置の記述子を使うので、この順に並べる。
+const OWN_ENTRIES: [u64; 4] = [KERNEL_CODE, KERNEL_DATA, USER_DATA, USER_CODE];
+
+/// TSS の記述子が占めるエントリの数。64 ビットモードでは 16 バイトになる。
+const TSS_ENTRIES: usize = 2;
+/// 使われていない 64 ビットの TSS を表す記述子の種類
+const TSS_TYPE_AVAILABLE: u64 = 0x9;
+
+/// 特権レベル 3 から割り込みや例外で入ったときに使うスタックの大きさ（バイト）
+const INTERRUPT_STACK_SIZE: usize = 16 * 1024;
+
+#[repr(C, align(16))]
+struct Gdt([u64; FIRMWARE_ENTRIES_MAX + OWN_ENTRIES.len() + TSS_ENTRIES]);
+
+static mut GDT: Gdt = Gdt([0; FIRMWARE_ENTRIES_MAX + OWN_ENTRIES.len() + TSS_ENTRIES]);
+
+/// 64 ビットモードの TSS。使うのは特権レベル 0 へ移るときのスタック（RSP0）だけ。
+#[repr(C, packed(4))]
+struct TaskStateSegment {
+    reserved0: u32,
+    /// 特権レベル 0〜2 へ移るときのスタック
+    rsp: [u64; 3],
+    reserved1: u64,
+    /// IDT のゲートで選べる割り込み用のスタック。使っていない。
+    ist: [u64; 7],
+    reserved2: u64,
+    reserved3: u16,
+    /// I/O 許可ビットマップの位置。TSS の大きさ以上にして、ビットマップを持たないことを表す。
+    iomap_base: u16,
+}
+
+const _: () = assert!(size_of::<TaskStateSegment>() == 104);
+
+static mut TSS: TaskStateSegment = TaskStateSegment {
+    reserved0: 0,
+    rsp: [0; 3],
+    reserved1: 0,
+    ist: [0; 7],
+    reserved2: 0,
+    reserved3: 0,
+    iomap_base: size_of::<TaskStateSegment>() as u16,
+};
+
+#[repr(C, align(16))]
+struct InterruptStack([u8; INTERRUPT_STACK_SIZE]);
+
+/// [TSS] の RSP0 が指すスタック
+static mut INTERRUPT_STACK: InterruptStack = InterruptStack([0; INTERRUPT_STACK_SIZE]);
+/// [KERNEL_CODE] のセレクタ。0 なら [initialize] の前。
+static KERNEL_CS: AtomicU16 = AtomicU16::new(0);
+
+/// SGDT、LGDT で読み書きする GDT の位置と大きさ。IDT の LIDT でも同じ形を使う。
+#[repr(C, packed)]
+pub(crate) struct DescriptorTablePointer {
+    pub(crate) limit: u16,
+    pub(crate) base: u64,
+}
+
+/// UEFI の GDT の後ろに自前の記述子と TSS の記述子を足して読み込み、TSS を TR へ読み込む。
+///
+/// 今のセグメントレジスタは UEFI の記述子を指したままなので、ここでは読み込み直さない。
+pub(crate) fn initialize() {
+    let mut current = DescriptorTablePointer { limit: 0, base: 0 };
+    unsafe {
+        asm!("sgdt [{}]", in(reg) &mut current, options(nostack, preserves_flags));
+    }
+    let limit = current.limit as usize;
+    let firmware_entries = ((limit + 1) / 8).min(FIRMWARE_ENTRIES_MAX);
+    if firmware_entries * 8 < limit + 1 {
+        log!(
+            LogLevel::Warn,
+            "GDT: only the first {} of {} firmware descriptors are kept",
+            firmware_entries,
+            (limit + 1) / 8
+        );
+    }
+
+    let gdt = unsafe { &mut (*addr_of_mut!(GDT)).0 };
+    let firmware = unsafe { slice::from_raw_parts(current.base as *const u64, firmware_entries) };
+    gdt[..firmware_entries].copy_from_slice(firmware);
+    gdt[firmware_entries..firmware_entries + OWN_ENTRIES.len()].copy_from_slice(&OWN_ENTRIES);
+
+    let stack_top = unsafe { addr_of!(INTERRUPT_STACK) } as u64 + INTERRUPT_STACK_SIZE as u64;
+    unsafe { (*addr_of_mut!(TSS)).rsp = [stack_top, 0, 0] };
+    let tss_index = firmware_entries + OWN_ENTRIES.len();
+    gdt[tss_index..tss_index + TSS_ENTRIES]
+        .copy_from_slice(&tss_descriptor(unsafe { addr_of!(TSS) } as u64));
+
+    let pointer = DescriptorTablePointer {
+        limit: ((tss_index + TSS_ENTRIES) * 8 - 1) as u16,
+        base: gdt.as_ptr() as u64,
+    };
+    unsafe {
+        asm!("lgdt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+        // LTR は記述子の Busy ビットを立てるので、GDT は書き込めるところに置いておく
+        asm!("ltr {:x}", in(reg) (tss_index * 8) as u16, options(nostack, preserves_flags));
+    }
+    KERNEL_CS.store((firmware_entries * 8) as u16, Ordering::Relaxed);
+    log!(
+        LogLevel::Debug,
+        "GDT: {} firmware descriptors, kernel CS={:#x}, TSS RSP0={:#x}",
+        firmware_entries,
+        kernel_cs(),
+        stack_top
+    );
+}
+
+/// `base` に置いた [TaskStateSegment] を指す TSS の記述子を作る。
+fn tss_descriptor(base: u64) -> [u64; TSS_ENTRIES] {
+    let base = BitField(base);
+    let low = BitField(0u64)
+        .with_bits(0..16, size_of::<TaskStateSegment>() as u64 - 1)
+        .with_bits(16..40, base.get_bits(0..24))
+        .with_bits(40..44, TSS_TYPE_AVAILABLE)
+        .with_bit(47, true)
+        .with_bits(56..64, base.get_bits(24..32));
+    [low.0, base.get_bits(32..64)]
+}
+
+/// [initialize] で GDT を読み込んだかどうか。
+pub(crate) fn is_initialized() -> bool {
+    KERNEL_CS.load(Ordering::Relaxed) != 0
+}
+
+/// カーネルのコードセグメントのセレクタ
+pub(crate) fn kernel_cs() -> u16 {
+    KERNEL_CS.load(Ordering::Relaxed)
+}
+
+/// カーネルのスタックセグメントのセレクタ
+pub(crate) fn kernel_ss() -> u16 {
+    kernel_cs() + 8
+}
+
+/// ユーザモードのスタックセグメントのセレクタ（RPL 3）
+pub(crate) fn user_ss() -> u16 {
+    (kernel_cs() + 16) | 3
+}
+
+/// ユーザモードのコードセグメントのセレクタ（RPL 3）
+pub(crate) fn user_cs() -> u16 {
+    (kernel_cs() + 24) | 3
+}
@@ -1,13 +1,43 @@
 #![allow(unused)]
 
 use core::{
+    hint,
     ops::{Add, AddAssign, Sub, SubAssign},
-    slice,
+    ptr, slice,
+    sync::atomic::{AtomicBool, AtomicU8, Ordering},
 };
 
-use crate::frame_buffer_config::FrameBufferConfig;
+use crate::{
+    error,
+    frame_buffer_config::{FrameBufferConfig, PixelFormat},
+    kassert, make_error, timer,
+};
+
+/// 1 ピクセルあたりのバイト数（8 bit × 3 色 + 予約 8 bit）
+pub(crate) const BYTES_PER_PIXEL: usize = 4;
+
+/// 明るさの初期値（%）。色をそのまま書き込む。
+pub(crate) const DEFAULT_BRIGHTNESS: u8 = 100;
+/// 設定できる明るさの最大値（%）
+pub(crate) const MAX_BRIGHTNESS: u8 = 200;
+
+/// ピクセルを書き込むときに R/G/B の各成分へ掛ける明るさ（%）
+static BRIGHTNESS: AtomicU8 = AtomicU8::new(DEFAULT_BRIGHTNESS);
+
+/// 画面の明るさを `percent` % にする。[MAX_BRIGHTNESS] を超える値は丸める。
+///
+/// これから書き込むピクセルにだけ効くので、画面全体へ反映するには描画し直すこと。
+/// [DEFAULT_BRIGHTNESS] に戻せば、元の色のまま書き込む。
+pub(crate) fn set_brightness(percent: u8) {
+    BRIGHTNESS.store(percent.min(MAX_BRIGHTNESS), Ordering::Relaxed);
+}
 
-#[derive(PartialEq, Eq)]
+/// 現在の画面の明るさ（%）を返す。
+pub(crate) fn brightness() -> u8 {
+    BRIGHTNESS.load(Ordering::Relaxed)
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub struct PixelColor {
     r: u8,
     g: u8,
@@ -29,10 +59,72 @@ impl PixelColor {
             b: c as u8 & 0xff,
         }
     }
+
+    /// `format` の並びで格納された 1 ピクセル分のバイト列から [PixelColor] を作る。
+    pub(crate) fn from_bytes(bytes: &[u8], format: PixelFormat) -> Self {
+        match format {
+            PixelFormat::Rgb => Self::new(bytes[0], bytes[1], bytes[2]),
+            PixelFormat::Bgr => Self::new(bytes[2], bytes[1], bytes[0]),
+        }
+    }
+
+    /// 各成分を `percent` % にした色を返す。255 を超える成分は 255 にする。
+    pub(crate) const fn scaled(self, percent: u8) -> Self {
+        if percent == DEFAULT_BRIGHTNESS {
+            return self;
+        }
+        const fn scale(c: u8, percent: u8) -> u8 {
+            let c = c as u16 * percent as u16 / 100;
+            if c > 0xff {
+                0xff
+            } else {
+                c as u8
+            }
+        }
+        Self::new(
+            scale(self.r, percent),
+            scale(self.g, percent),
+            scale(self.b, percent),
+        )
+    }
+
+    /// 現在の画面の明るさ（[brightness]）を反映した色を返す。
+    pub(crate) fn adjusted(self) -> Self {
+        self.scaled(brightness())
+    }
+
+    /// `format` の並びの 1 ピクセル分のバイト列へ変換する。[PixelColor::from_bytes] の逆。
+    pub(crate) const fn to_bytes(self, format: PixelFormat) -> [u8; BYTES_PER_PIXEL] {
+        match format {
+            PixelFormat::Rgb => [self.r, self.g, self.b, 0],
+            PixelFormat::Bgr => [self.b, self.g, self.r, 0],
+        }
+    }
+
+    /// 相対輝度を 0〜65025 で返す。
+    ///
+    /// sRGB のガンマを 2 で近似して線形に戻し、ITU-R BT.709 の係数で重み付けする。
+    /// 配色どうしのコントラストを比べるためのもので、厳密な値ではない。
+    pub(crate) const fn luminance(self) -> u32 {
+        let (r, g, b) = (self.r as u32, self.g as u32, self.b as u32);
+        (2126 * r * r + 7152 * g * g + 722 * b * b) / 10000
+    }
+
+    /// `self` の上に `other` を不透明度 `alpha`（255 で `other` そのもの）で重ねた色を返す。
+    pub(crate) const fn blend(self, other: Self, alpha: u8) -> Self {
+        const fn mix(a: u8, b: u8, alpha: u8) -> u8 {
+            ((a as u32 * (255 - alpha as u32) + b as u32 * alpha as u32 + 127) / 255) as u8
+        }
+        Self::new(
+            mix(self.r, other.r, alpha),
+            mix(self.g, other.g, alpha),
+            mix(self.b, other.b, alpha),
+        )
+    }
 }
 
 /// ピクセルを塗るための色々を提供する。
-pub(crate) trait PixelWriter {
+pub(crate) trait PixelWriter: Sync {
     /// ピクセルを塗る手段を提供する。
     fn write(&self, pos: Vector2D<u32>, color: &PixelColor);
     /// フレームバッファの情報を提供する。
@@ -41,17 +133,58 @@ pub(crate) trait PixelWriter {
     /// ピクセルの位置から、そのピクセルを塗るための配列を提供する。
     fn pixel_at(&self, pos: Vector2D<u32>) -> &mut [u8] {
         unsafe {
+            let config = self.config();
             slice::from_raw_parts_mut(
-                (self.config().frame_buffer
-                    + 4 * (self.config().pixels_per_scan_line * pos.y as usize + pos.x as usize))
+                (config.frame_buffer + config.pixel_offset(pos.x as usize, pos.y as usize))
                     as *mut u8,
                 3,
             )
         }
     }
 
+    /// `pos` から右へ、フレームバッファのフォーマットに変換済みのピクセル列をそのまま書き込む。
+    ///
+    /// `bytes` は 1 ピクセル [BYTES_PER_PIXEL] バイトで、行をまたいではいけない。
+    /// 明るさは調整しないので、[PixelColor::adjusted] を通した色から作っておくこと。
+    fn write_row(&self, pos: Vector2D<u32>, bytes: &[u8]) {
+        let config = self.config();
+        let offset = config.pixel_offset(pos.x as usize, pos.y as usize);
+        unsafe {
+            slice::from_raw_parts_mut((config.frame_buffer + offset) as *mut u8, bytes.len())
+                .copy_from_slice(bytes);
+        }
+    }
+
+    /// `pos` から右へ `width` ピクセルを `c` で塗る。
+    ///
+    /// [PixelWriter::write] を 1 ピクセルずつ呼ぶ代わりに、2 ピクセルずつまとめて書き込む。
+    fn fill_row(&self, pos: Vector2D<u32>, width: u32, c: &PixelColor) {
+        let config = self.config();
+        let pixel = c.adjusted().to_bytes(config.pixel_format);
+        let offset = config.pixel_offset(pos.x as usize, pos.y as usize);
+        unsafe {
+            fill_pixels(
+                (config.frame_buffer + offset) as *mut u8,
+                width as usize,
+                pixel,
+            )
+        }
+    }
+
+    /// `y` 行目から `rows` 行を、横幅いっぱいに `c` で塗る。
+    ///
+    /// 行末の表示されない部分（[FrameBufferConfig::pixels_per_scan_line] の余り）も一緒に塗り、
+    /// フレームバッファの連続した領域として一度に埋める。
+    fn fill_rows(&self, y: u32, rows: u32, c: &PixelColor) {
+        let config = self.config();
+        let pixel = c.adjusted().to_bytes(config.pixel_format);
+        let offset = config.pixel_offset(0, y as usize);
+        let count = config.pixels_per_scan_line * rows as usize;
+        unsafe { fill_pixels((config.frame_buffer + offset) as *mut u8, count, pixel) }
+    }
+
     /// 長方形の枠を指定された色で塗る。
-    fn draw_rectangle(&mut self, pos: Vector2D<u32>, size: Vector2D<u32>, c: &PixelColor) {
+    fn draw_rectangle(&self, pos: Vector2D<u32>, size: Vector2D<u32>, c: &PixelColor) {
         // 横線
         for dx in 0..size.x {
             self.write(pos + Vector2D::new(dx, 0), c);
@@ -65,14 +198,403 @@ pub(crate) trait PixelWriter {
         }
     }
 
-    // 長方形を指定された色で塗る。
-    fn fill_rectangle(&mut self, pos: Vector2D<u32>, size: Vector2D<u32>, c: &PixelColor) {
-        for dy in 0..size.y {
-            for dx in 0..size.x {
-                self.write(pos + Vector2D::new(dx, dy), c);
+    /// 長方形を指定された色で塗る。
+    ///
+    /// 上の行から順に、各行を [fill_pixels] で左から連続して埋める。
+    /// 横幅いっぱいのときは [PixelWriter::fill_rows] と同じく、まとめて一度に埋める。
+    fn fill_rectangle(&self, pos: Vector2D<u32>, size: Vector2D<u32>, c: &PixelColor) {
+        if size.x == 0 || size.y == 0 {
+            return;
+        }
+        let config = self.config();
+        if pos.x == 0 && size.x as usize == config.horizontal_resolution {
+            self.fill_rows(pos.y, size.y, c);
+            return;
+        }
+        let pixel = c.adjusted().to_bytes(config.pixel_format);
+        let stride = config.pixel_offset(0, 1);
+        let mut row =
+            (config.frame_buffer + config.pixel_offset(pos.x as usize, pos.y as usize)) as *mut u8;
+        for _ in 0..size.y {
+            unsafe {
+                fill_pixels(row, size.x as usize, pixel);
+                row = row.add(stride);
+            }
+        }
+    }
+
+    /// 角を半径 `radius` の円弧にした長方形を指定された色で塗る。
+    fn fill_rounded_rectangle(&self, rect: &Rectangle, radius: u32, c: &PixelColor) {
+        rounded_rectangle_spans(rect, radius, false, |span| {
+            self.fill_rectangle(span.pos, span.size, c)
+        });
+    }
+
+    /// 角を半径 `radius` の円弧にした長方形の枠を指定された色で塗る。
+    fn draw_rounded_rectangle(&self, rect: &Rectangle, radius: u32, c: &PixelColor) {
+        rounded_rectangle_spans(rect, radius, true, |span| {
+            self.fill_rectangle(span.pos, span.size, c)
+        });
+    }
+
+    /// `rect` の中を、`pattern_w` x `pattern_h` の模様 `pattern`（行ごとに並べたもの）を敷き詰めて塗る。
+    ///
+    /// 模様は `rect` ではなく画面の原点を基準に並べるので、領域を分けて塗っても継ぎ目がずれない。
+    fn fill_pattern(
+        &self,
+        rect: &Rectangle,
+        pattern: &[PixelColor],
+        pattern_w: u32,
+        pattern_h: u32,
+    ) {
+        kassert!(
+            pattern_w > 0 && pattern_h > 0 && pattern.len() >= (pattern_w * pattern_h) as usize,
+            "pattern {}x{} needs {} colors but has {}",
+            pattern_w,
+            pattern_h,
+            pattern_w * pattern_h,
+            pattern.len()
+        );
+        let end = rect.end();
+        for y in rect.pos.y..end.y {
+            let row = &pattern[((y % pattern_h) * pattern_w) as usize..];
+            for x in rect.pos.x..end.x {
+                self.write(Vector2D::new(x, y), &row[(x % pattern_w) as usize]);
+            }
+        }
+    }
+
+    /// `rect` の中を、`cell` ピクセル四方の `a` と `b` の市松模様で塗る。
+    ///
+    /// [PixelWriter::fill_pattern] と同じく、画面の原点を基準にする。
+    fn fill_checkerboard(&self, rect: &Rectangle, cell: u32, a: &PixelColor, b: &PixelColor) {
+        let cell = cell.max(1);
+        let end = rect.end();
+        for y in rect.pos.y..end.y {
+            for x in rect.pos.x..end.x {
+                let c = if (x / cell + y / cell).is_multiple_of(2) {
+                    a
+                } else {
+                    b
+                };
+                self.write(Vector2D::new(x, y), c);
+            }
+        }
+    }
+
+    /// 今の色の上に `c` を不透明度 `alpha` で重ねる。
+    ///
+    /// フレームバッファには明るさを調整した後の色が入っているので、`c` の方を調整してから
+    /// 混ぜ、調整せずに書き戻す。
+    fn write_blended(&self, pos: Vector2D<u32>, c: &PixelColor, alpha: u8) {
+        let format = self.config().pixel_format;
+        let current = PixelColor::from_bytes(self.pixel_at(pos), format);
+        let color = current.blend(c.adjusted(), alpha);
+        self.write_row(pos, &color.to_bytes(format));
+    }
+
+    /// `from` から `to` まで（両端を含む）の線分を描く。画面の外にはみ出た部分は描かない。
+    fn draw_line(&self, from: Vector2D<u32>, to: Vector2D<u32>, c: &PixelColor) {
+        // Bresenham のアルゴリズム
+        let (mut x, mut y) = (from.x as i64, from.y as i64);
+        let (x1, y1) = (to.x as i64, to.y as i64);
+        let (dx, dy) = ((x1 - x).abs(), -(y1 - y).abs());
+        let (sx, sy) = ((x1 - x).signum(), (y1 - y).signum());
+        let mut err = dx + dy;
+        loop {
+            plot(self, x, y, c, 255);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * err;
+            if e2 >= dy {
+                err += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                err += dx;
+                y += sy;
+            }
+        }
+    }
+
+    /// 中心 `center`、半径 `radius` の円周を描く。画面の外にはみ出た部分は描かない。
+    fn draw_circle(&self, center: Vector2D<u32>, radius: u32, c: &PixelColor) {
+        // 中点アルゴリズムで 1/8 の円弧を求め、対称に 8 か所へ写す
+        let (cx, cy) = (center.x as i64, center.y as i64);
+        let (mut x, mut y) = (radius as i64, 0i64);
+        let mut err = 1 - x;
+        while x >= y {
+            for (px, py) in [
+                (x, y),
+                (y, x),
+                (-y, x),
+                (-x, y),
+                (-x, -y),
+                (-y, -x),
+                (y, -x),
+                (x, -y),
+            ] {
+                plot(self, cx + px, cy + py, c, 255);
+            }
+            y += 1;
+            if err < 0 {
+                err += 2 * y + 1;
+            } else {
+                x -= 1;
+                err += 2 * (y - x) + 1;
+            }
+        }
+    }
+
+    /// [PixelWriter::draw_line] のアンチエイリアス版。
+    ///
+    /// 線が各ピクセルを覆う割合を不透明度にして、背景と混ぜる（Wu のアルゴリズム）。
+    /// 1 ピクセルごとに背景を読むので、[PixelWriter::draw_line] より遅い。
+    fn draw_line_aa(&self, from: Vector2D<u32>, to: Vector2D<u32>, c: &PixelColor) {
+        let (mut x0, mut y0) = (from.x as i64, from.y as i64);
+        let (mut x1, mut y1) = (to.x as i64, to.y as i64);
+        // 傾きが 1 を超えるなら x と y を入れ替え、x 方向に 1 ピクセルずつ進める
+        let steep = (y1 - y0).abs() > (x1 - x0).abs();
+        if steep {
+            core::mem::swap(&mut x0, &mut y0);
+            core::mem::swap(&mut x1, &mut y1);
+        }
+        if x0 > x1 {
+            core::mem::swap(&mut x0, &mut x1);
+            core::mem::swap(&mut y0, &mut y1);
+        }
+        let mut plot_aa = |x: i64, y: i64, alpha: u8| {
+            if steep {
+                plot(self, y, x, c, alpha);
+            } else {
+                plot(self, x, y, c, alpha);
+            }
+        };
+
+        // y は 16.16 の固定小数点で持つ
+        let gradient = if x1 == x0 {
+            0
+        } else {
+            ((y1 - y0) << 16) / (x1 - x0)
+        };
+        let mut intery = y0 << 16;
+        for x in x0..=x1 {
+            let y = intery >> 16;
+            let frac = ((intery & 0xffff) >> 8) as u8;
+            plot_aa(x, y, 255 - frac);
+            if frac != 0 {
+                plot_aa(x, y + 1, frac);
+            }
+            intery += gradient;
+        }
+    }
+
+    /// [PixelWriter::draw_circle] のアンチエイリアス版。
+    ///
+    /// 各ピクセルの中心から円周までの距離に応じて、幅 1 ピクセルの円周が覆う割合を
+    /// 不透明度にして背景と混ぜる。
+    fn draw_circle_aa(&self, center: Vector2D<u32>, radius: u32, c: &PixelColor) {
+        let (cx, cy, r) = (center.x as i64, center.y as i64, radius as i64);
+        // 円周から 1 ピクセル以内のピクセルだけが覆われる
+        let inner = (r - 1).max(0).pow(2);
+        let outer = (r + 1).pow(2);
+        for dy in -(r + 1)..=(r + 1) {
+            for dx in -(r + 1)..=(r + 1) {
+                let d2 = dx * dx + dy * dy;
+                if d2 <= inner || d2 >= outer {
+                    continue;
+                }
+                // 距離は 1/256 ピクセル単位で求める
+                let distance = ((d2 as u64) << 16).isqrt() as i64;
+                let coverage = 256 - (distance - (r << 8)).abs();
+                if coverage > 0 {
+                    plot(self, cx + dx, cy + dy, c, coverage.min(255) as u8);
+                }
+            }
+        }
+    }
+}
+
+/// 画面の中なら (x, y) に `c` を不透明度 `alpha` で塗る。
+fn plot<W: PixelWriter + ?Sized>(writer: &W, x: i64, y: i64, c: &PixelColor, alpha: u8) {
+    let config = writer.config();
+    if x < 0
+        || y < 0
+        || x >= config.horizontal_resolution as i64
+        || y >= config.vertical_resolution as i64
+    {
+        return;
+    }
+    let pos = Vector2D::new(x as u32, y as u32);
+    if alpha == 255 {
+        writer.write(pos, c);
+    } else if alpha != 0 {
+        writer.write_blended(pos, c, alpha);
+    }
+}
+
+/// 角の円弧の上から `dy` 行目（0 が一番外側）で、角から何ピクセル内側から塗るかを返す。
+///
+/// ピクセルの中心が円に入っていれば塗る。計算を整数で済ませるため、座標を 2 倍して比べる。
+fn corner_inset(radius: u32, dy: u32) -> u32 {
+    let r2 = 2 * radius as i64;
+    let vy = r2 - 2 * dy as i64 - 1;
+    (0..radius)
+        .find(|&dx| {
+            let vx = r2 - 2 * dx as i64 - 1;
+            vx * vx + vy * vy <= r2 * r2
+        })
+        .unwrap_or(radius)
+}
+
+/// 角丸長方形（`outline` なら枠だけ）を、高さ 1 の長方形に分けて `f` へ渡す。
+///
+/// 呼び出し側で長方形ごとに切り抜けば、角丸長方形の一部だけを描ける。
+/// `radius` が短い方の辺の半分より大きければ、半分に丸める。
+pub(crate) fn rounded_rectangle_spans(
+    rect: &Rectangle,
+    radius: u32,
+    outline: bool,
+    mut f: impl FnMut(Rectangle),
+) {
+    if rect.is_empty() {
+        return;
+    }
+    let (width, height) = (rect.size.x, rect.size.y);
+    let radius = radius.min(width / 2).min(height / 2);
+    // 行ごとの左右の欠け。範囲外の行は全部欠けているものとする。
+    let inset = |dy: i64| -> u32 {
+        if dy < 0 || dy >= height as i64 {
+            return width.div_ceil(2);
+        }
+        let dy = dy as u32;
+        if dy < radius {
+            corner_inset(radius, dy)
+        } else if height - 1 - dy < radius {
+            corner_inset(radius, height - 1 - dy)
+        } else {
+            0
+        }
+    };
+    let mut span = |x0: u32, x1: u32, dy: u32| {
+        if x1 > x0 {
+            f(Rectangle::new(
+                rect.pos + Vector2D::new(x0, dy),
+                Vector2D::new(x1 - x0, 1),
+            ));
+        }
+    };
+
+    for dy in 0..height {
+        let left = inset(dy as i64);
+        let right = width - left;
+        if !outline || dy == 0 || dy == height - 1 {
+            span(left, right, dy);
+            continue;
+        }
+        // 上下の行より外へ張り出している部分と、両端の 1 ピクセルが枠になる
+        let edge = (left + 1)
+            .max(inset(dy as i64 - 1))
+            .max(inset(dy as i64 + 1))
+            .min(right);
+        span(left, edge, dy);
+        span((width - edge).max(edge), right, dy);
+    }
+}
+
+/// `src_format` の並びで格納された画像を、`dst` の `pos` の位置へ描画する。
+///
+/// `src` は 1 ピクセル 4 バイト、横 `size.x` ピクセルの行を `size.y` 行並べたもの。
+/// ピクセルごとに `dst` のフォーマットへ変換しながら書き込むので、画像と画面の
+/// フォーマットが異なっていてもよい。
+/// `src` が `size` に対して小さすぎる場合は [error::Code::BufferTooSmall] を返す。
+pub(crate) fn blit_convert(
+    dst: &dyn PixelWriter,
+    src: &[u8],
+    src_format: PixelFormat,
+    pos: Vector2D<u32>,
+    size: Vector2D<u32>,
+) -> error::Error {
+    let width = size.x as usize;
+    if src.len() < BYTES_PER_PIXEL * width * size.y as usize {
+        return make_error!(error::Code::BufferTooSmall);
+    }
+
+    for dy in 0..size.y {
+        for dx in 0..size.x {
+            let offset = BYTES_PER_PIXEL * (width * dy as usize + dx as usize);
+            let color = PixelColor::from_bytes(&src[offset..offset + BYTES_PER_PIXEL], src_format);
+            dst.write(pos + Vector2D::new(dx, dy), &color);
+        }
+    }
+    make_error!(error::Code::Success)
+}
+
+/// RGBA の並びで格納された画像を、アルファ値で透過させながら `dst` の `pos` の位置へ描画する。
+///
+/// `src` は 1 ピクセル 4 バイト（R, G, B, A）、横 `size.x` ピクセルの行を `size.y` 行並べたもの。
+/// アルファ値が 0 のピクセルは描かず、255 のピクセルは上書きし、それ以外は今の色に重ねる。
+/// `src` が `size` に対して小さすぎる場合は [error::Code::BufferTooSmall] を返す。
+pub(crate) fn blit_rgba(
+    dst: &dyn PixelWriter,
+    src: &[u8],
+    pos: Vector2D<u32>,
+    size: Vector2D<u32>,
+) -> error::Error {
+    let width = size.x as usize;
+    if src.len() < BYTES_PER_PIXEL * width * size.y as usize {
+        return make_error!(error::Code::BufferTooSmall);
+    }
+
+    for dy in 0..size.y {
+        for dx in 0..size.x {
+            let offset = BYTES_PER_PIXEL * (width * dy as usize + dx as usize);
+            let [r, g, b, alpha] = [0, 1, 2, 3].map(|i| src[offset + i]);
+            let p = pos + Vector2D::new(dx, dy);
+            match alpha {
+                0 => {}
+                255 => dst.write(p, &PixelColor::new(r, g, b)),
+                _ => dst.write_blended(p, &PixelColor::new(r, g, b), alpha),
             }
         }
     }
+    make_error!(error::Code::Success)
+}
+
+/// `dst` から `count` ピクセルを、フレームバッファのフォーマットに変換済みの `pixel` で埋める。
+///
+/// 2 ピクセル分を 1 つの u64 に詰めて書き込む。8 バイト境界に揃っていない先頭と、
+/// 奇数個のときの末尾の 1 ピクセルは 4 バイトで書く。3 色が同じ値のときは、
+/// 予約バイトも同じ値にしてバイト単位で埋める。
+///
+/// # Safety
+///
+/// `dst` は 4 バイト境界に揃っていて、`count` ピクセル分書き込めること。
+unsafe fn fill_pixels(dst: *mut u8, count: usize, pixel: [u8; BYTES_PER_PIXEL]) {
+    if pixel[0] == pixel[1] && pixel[1] == pixel[2] {
+        ptr::write_bytes(dst, pixel[0], count * BYTES_PER_PIXEL);
+        return;
+    }
+
+    let single = u32::from_ne_bytes(pixel);
+    let mut dst = dst as *mut u32;
+    let mut count = count;
+    if count > 0 && !(dst as usize).is_multiple_of(8) {
+        dst.write(single);
+        dst = dst.add(1);
+        count -= 1;
+    }
+    let pair = u64::from_ne_bytes([
+        pixel[0], pixel[1], pixel[2], pixel[3], pixel[0], pixel[1], pixel[2], pixel[3],
+    ]);
+    let pairs = dst as *mut u64;
+    for i in 0..count / 2 {
+        pairs.add(i).write(pair);
+    }
+    if count % 2 == 1 {
+        dst.add(count - 1).write(single);
+    }
 }
 
 /// フレームバッファのピクセルの持ち方が RGB のときのクラス。
@@ -88,6 +610,7 @@ impl RgbResv8BitPerColorPixelWriter {
 
 impl PixelWriter for RgbResv8BitPerColorPixelWriter {
     fn write(&self, pos: Vector2D<u32>, color: &PixelColor) {
+        let color = color.adjusted();
         let pixel = self.pixel_at(pos);
         pixel[0] = color.r;
         pixel[1] = color.g;
@@ -113,6 +636,7 @@ impl BgrResv8BitPerColorPixelWriter {
 
 impl PixelWriter for BgrResv8BitPerColorPixelWriter {
     fn write(&self, pos: Vector2D<u32>, color: &PixelColor) {
+        let color = color.adjusted();
         let pixel = self.pixel_at(pos);
         pixel[0] = color.b;
         pixel[1] = color.g;
@@ -124,6 +648,147 @@ impl PixelWriter for BgrResv8BitPerColorPixelWriter {
     }
 }
 
+/// [ShadowBuffer::fade_in] と [ShadowBuffer::fade_out] で、少しずつ色を移すかどうか
+static FADE_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// フェードを有効/無効にする。無効なら、フェードせずに最後の状態をすぐ画面へ写す。
+pub(crate) fn set_fade_enabled(enabled: bool) {
+    FADE_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn fade_enabled() -> bool {
+    FADE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 画面と同じ大きさとフォーマットの、画面に映らないバッファ。
+///
+/// ここへ描いておいてから [ShadowBuffer::fade_in] などで画面へ写す。
+/// 画面のピクセルライタと同じく、書き込む色は明るさを調整する。
+pub(crate) struct ShadowBuffer {
+    config: FrameBufferConfig,
+}
+
+impl ShadowBuffer {
+    /// `screen` と同じ形のシャドウバッファを、`buf` から始まる領域に作る。
+    ///
+    /// # Safety
+    ///
+    /// `buf` は [ShadowBuffer::size] バイト以上、書き込めるメモリを指していること。
+    pub(crate) unsafe fn new(screen: &FrameBufferConfig, buf: usize) -> Self {
+        Self {
+            config: FrameBufferConfig {
+                frame_buffer: buf,
+                ..*screen
+            },
+        }
+    }
+
+    /// `screen` と同じ形のシャドウバッファに要るバイト数。
+    pub(crate) const fn size(screen: &FrameBufferConfig) -> usize {
+        screen.pixels_per_scan_line * screen.vertical_resolution * BYTES_PER_PIXEL
+    }
+
+    /// 画面を黒からシャドウバッファの内容へ、`duration_ticks` ティックかけて移す。
+    pub(crate) fn fade_in(&self, screen: &dyn PixelWriter, duration_ticks: u32) {
+        self.fade(screen, duration_ticks, false);
+    }
+
+    /// 画面の今の内容をシャドウバッファへ写し、それを `duration_ticks` ティックかけて黒へ移す。
+    pub(crate) fn fade_out(&self, screen: &dyn PixelWriter, duration_ticks: u32) {
+        copy_dimmed(self, screen, 255);
+        self.fade(screen, duration_ticks, true);
+    }
+
+    /// 1 ティックに 1 回、シャドウバッファを黒と混ぜて画面全体へ写す。
+    ///
+    /// 描くのが 1 ティックより遅ければ、間の段階を飛ばして時間どおりに終える。
+    /// TSC を測る前は経過時間が分からないので、1 段階ごとに 1 ティック分待つ。
+    fn fade(&self, screen: &dyn PixelWriter, duration_ticks: u32, to_black: bool) {
+        let duration = duration_ticks as u64;
+        if !fade_enabled() || duration == 0 {
+            copy_dimmed(screen, self, if to_black { 0 } else { 255 });
+            return;
+        }
+
+        let tick_us = 1_000_000 / timer::TICK_HZ;
+        let start = timer::uptime_us();
+        let mut step = 0;
+        loop {
+            let alpha = (255 * step / duration) as u8;
+            copy_dimmed(screen, self, if to_black { 255 - alpha } else { alpha });
+            if step == duration {
+                break;
+            }
+            let next = step + 1;
+            step = match start {
+                Some(start) => {
+                    let due = start + next * tick_us;
+                    while timer::uptime_us().is_some_and(|now| now < due) {
+                        hint::spin_loop();
+                    }
+                    let elapsed = (timer::uptime_us().unwrap_or(due) - start) / tick_us;
+                    elapsed.clamp(next, duration)
+                }
+                None => {
+                    timer::sleep_ms(1000 / timer::TICK_HZ);
+                    next
+                }
+            };
+        }
+    }
+}
+
+impl PixelWriter for ShadowBuffer {
+    fn write(&self, pos: Vector2D<u32>, color: &PixelColor) {
+        self.write_row(pos, &color.adjusted().to_bytes(self.config.pixel_format));
+    }
+
+    fn config(&self) -> &FrameBufferConfig {
+        &self.config
+    }
+}
+
+/// 画面全体の大きさの `src` を、黒の上に不透明度 `alpha` で重ねて `dst` へ写す。
+///
+/// `src` と `dst` は同じ大きさとフォーマットであること。`src` の色は明るさを調整した後なので、
+/// 調整せずに書き込む。`alpha` が 255 なら、行ごとにそのまま写す。
+fn copy_dimmed(dst: &dyn PixelWriter, src: &dyn PixelWriter, alpha: u8) {
+    /// 1 回の [PixelWriter::write_row] で書き込むピクセル数
+    const CHUNK: usize = 64;
+
+    let config = src.config();
+    let format = config.pixel_format;
+    let width = config.horizontal_resolution;
+    let black = PixelColor::new(0, 0, 0);
+    for y in 0..config.vertical_resolution {
+        let row = unsafe {
+            slice::from_raw_parts(
+                (config.frame_buffer + config.pixel_offset(0, y)) as *const u8,
+                width * BYTES_PER_PIXEL,
+            )
+        };
+        if alpha == 255 {
+            dst.write_row(Vector2D::new(0, y as u32), row);
+            continue;
+        }
+
+        let mut buf = [0u8; BYTES_PER_PIXEL * CHUNK];
+        for (i, pixels) in row.chunks(BYTES_PER_PIXEL * CHUNK).enumerate() {
+            for (from, to) in pixels
+                .chunks_exact(BYTES_PER_PIXEL)
+                .zip(buf.chunks_exact_mut(BYTES_PER_PIXEL))
+            {
+                let color = black.blend(PixelColor::from_bytes(from, format), alpha);
+                to.copy_from_slice(&color.to_bytes(format));
+            }
+            dst.write_row(
+                Vector2D::new((i * CHUNK) as u32, y as u32),
+                &buf[..pixels.len()],
+            );
+        }
+    }
+}
+
 #[derive(PartialEq, Eq, Clone, Copy)]
 /// 2次元のベクトル情報を保持するクラス。
 pub(crate) struct Vector2D<T> {
@@ -191,3 +856,52 @@ impl<T: SubAssign> SubAssign for Vector2D<T> {
         self.y -= rhs.y;
     }
 }
+
+/// 左上の位置と大きさで表した長方形の領域。
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) struct Rectangle {
+    pub(crate) pos: Vector2D<u32>,
+    pub(crate) size: Vector2D<u32>,
+}
+
+impl Rectangle {
+    pub(crate) const fn new(pos: Vector2D<u32>, size: Vector2D<u32>) -> Self {
+        Self { pos, size }
+    }
+
+    /// 面積が 0 かどうか。
+    pub(crate) const fn is_empty(&self) -> bool {
+        self.size.x == 0 || self.size.y == 0
+    }
+
+    /// 右下の角の、1 ピクセル外側の位置を返す。
+    pub(crate) fn end(&self) -> Vector2D<u32> {
+        self.pos + self.size
+    }
+
+    /// 2 つの長方形の重なる部分を返す。重ならなければ None。
+    pub(crate) fn intersection(&self, other: &Self) -> Option<Self> {
+        let (end, other_end) = (self.end(), other.end());
+        let x0 = self.pos.x.max(other.pos.x);
+        let y0 = self.pos.y.max(other.pos.y);
+        let x1 = end.x.min(other_end.x);
+        let y1 = end.y.min(other_end.y);
+        if x0 >= x1 || y0 >= y1 {
+            return None;
+        }
+        Some(Self::new(
+            Vector2D::new(x0, y0),
+            Vector2D::new(x1 - x0, y1 - y0),
+        ))
+    }
+
+    /// 2 つの長方形を両方含む、最小の長方形を返す。
+    pub(crate) fn union(&self, other: &Self) -> Self {
+        let (end, other_end) = (self.end(), other.end());
+        let x0 = self.pos.x.min(other.pos.x);
+        let y0 = self.pos.y.min(other.pos.y);
+        let x1 = end.x.max(other_end.x);
+        let y1 = end.y.max(other_end.y);
+        Self::new(Vector2D::new(x0, y0), Vector2D::new(x1 - x0, y1 - y0))
+    }
+}
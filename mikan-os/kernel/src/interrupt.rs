@@ -0,0 +1,349 @@
+#![allow(unused)]
+
+use core::{
+    arch::asm,
+    mem,
+    ptr::addr_of_mut,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    asmfunc::{get_cr2, interrupt_entries, INTERRUPT_ENTRY_SIZE},
+    bitfield::BitField,
+    error::{self, WithError},
+    gdt::{self, DescriptorTablePointer},
+    io::io_out_8,
+    kassert, lapic, log, log_irq,
+    logger::LogLevel,
+    make_error, printk, printk_irq, printkln, printkln_irq, syscall,
+};
+
+/// CPU の例外用に予約されているベクタの数。これより下のベクタは割り当てない。
+pub(crate) const EXCEPTION_VECTORS: u8 = 32;
+
+/// 用途の決まっている割り込みベクタ。
+///
+/// ここに無いものは [allocate_vector] で空いているベクタを受け取ること。
+pub(crate) mod vector {
+    /// xHC の MSI
+    pub(crate) const XHCI: u8 = 0x40;
+    /// Local APIC タイマ
+    pub(crate) const LAPIC_TIMER: u8 = 0x41;
+    /// Local APIC のスプリアス割り込み。下位 4 ビットが 1 のものを使う慣習に従う。
+    pub(crate) const SPURIOUS: u8 = 0xff;
+}
+
+/// [allocate_vector] が割り当てるベクタの範囲（両端を含む）
+const DYNAMIC_FIRST: u8 = 0x50;
+const DYNAMIC_LAST: u8 = 0xef;
+
+/// ベクタごとの使用者の名前。None なら空いている。
+static OWNERS: Mutex<[Option<&'static str>; 256]> = Mutex::new([None; 256]);
+
+/// 用途の決まっているベクタ（[vector]）を `owner` が使うことを登録する。
+///
+/// 既に別の誰かが登録していれば、両者の名前をログに出して [error::Code::AlreadyAllocated] を返す。
+pub(crate) fn register_vector(vector: u8, owner: &'static str) -> error::Error {
+    if vector < EXCEPTION_VECTORS {
+        log!(
+            LogLevel::Error,
+            "vector {:#04x} is reserved for exceptions ({})",
+            vector,
+            owner
+        );
+        return make_error!(error::Code::IndexOutOfRange);
+    }
+    let mut owners = OWNERS.lock();
+    if let Some(current) = owners[vector as usize] {
+        log!(
+            LogLevel::Error,
+            "vector {:#04x} is already used by {} ({})",
+            vector,
+            current,
+            owner
+        );
+        return make_error!(error::Code::AlreadyAllocated);
+    }
+    owners[vector as usize] = Some(owner);
+    make_error!(error::Code::Success)
+}
+
+/// 空いているベクタを 1 つ割り当てる。
+pub(crate) fn allocate_vector(owner: &'static str) -> WithError<u8> {
+    allocate_vectors(1, owner)
+}
+
+/// 連続した `count` 個の空いているベクタを割り当て、先頭を返す。
+///
+/// 複数メッセージの MSI に使えるよう、`count` は 2 のべき乗とし、先頭はその倍数に揃える。
+/// `count` が 0 や 2 のべき乗でないとき、動的に割り当てる範囲より大きいときは
+/// [error::Code::IndexOutOfRange] を、空きが無ければ [error::Code::Full] を返す。
+pub(crate) fn allocate_vectors(count: usize, owner: &'static str) -> WithError<u8> {
+    const DYNAMIC_COUNT: usize = (DYNAMIC_LAST - DYNAMIC_FIRST) as usize + 1;
+    if count == 0 || !count.is_power_of_two() || count > DYNAMIC_COUNT {
+        return WithError::new(0, make_error!(error::Code::IndexOutOfRange));
+    }
+    let mut owners = OWNERS.lock();
+    let first = (DYNAMIC_FIRST as usize).next_multiple_of(count);
+    for start in (first..=DYNAMIC_LAST as usize + 1 - count).step_by(count) {
+        let range = &mut owners[start..start + count];
+        if range.iter().all(Option::is_none) {
+            range.fill(Some(owner));
+            return WithError::new(start as u8, make_error!(error::Code::Success));
+        }
+    }
+    log!(
+        LogLevel::Error,
+        "no free vectors: {} requested by {}",
+        count,
+        owner
+    );
+    WithError::new(0, make_error!(error::Code::Full))
+}
+
+/// [allocate_vectors] や [register_vector] で得たベクタを返す。
+pub(crate) fn free_vectors(vector: u8, count: usize) {
+    let mut owners = OWNERS.lock();
+    let end = (vector as usize + count).min(owners.len());
+    owners[vector as usize..end].fill(None);
+}
+
+/// ベクタを使っている者の名前を返す。空いていれば None。
+pub(crate) fn vector_owner(vector: u8) -> Option<&'static str> {
+    OWNERS.lock()[vector as usize]
+}
+
+/// 割り込みが起きたときに CPU がスタックへ積む値。割り込みハンドラへの引数になる。
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub(crate) struct InterruptFrame {
+    /// 割り込まれた命令の番地
+    pub(crate) rip: u64,
+    pub(crate) cs: u64,
+    pub(crate) rflags: u64,
+    pub(crate) rsp: u64,
+    pub(crate) ss: u64,
+}
+
+/// 割り込みの入口（[interrupt_entries]）がスタックに積んだもの。積んだのと逆の順に並ぶ。
+#[repr(C)]
+pub(crate) struct InterruptContext {
+    pub(crate) r11: u64,
+    pub(crate) r10: u64,
+    pub(crate) r9: u64,
+    pub(crate) r8: u64,
+    pub(crate) rdi: u64,
+    pub(crate) rsi: u64,
+    pub(crate) rdx: u64,
+    pub(crate) rcx: u64,
+    pub(crate) rax: u64,
+    pub(crate) vector: u64,
+    /// CPU が積んだエラーコード。積まない例外と割り込みでは 0。
+    pub(crate) error_code: u64,
+    pub(crate) frame: InterruptFrame,
+}
+
+/// 割り込みハンドラ。割り込まれたときの状態を受け取る。
+///
+/// ロックを取らずに済む処理だけを行い、出力には [crate::log_irq!] を使うこと。
+/// Local APIC への EOI は呼び出し側で送る。
+pub(crate) type InterruptHandler = fn(frame: &InterruptFrame);
+
+/// ベクタごとの [InterruptHandler] の番地。0 なら未登録。割り込みハンドラから読むので、ロックを使わない。
+static HANDLERS: [AtomicUsize; 256] = [const { AtomicUsize::new(0) }; 256];
+
+/// `vector` の割り込みで `handler` を呼ぶようにする。
+///
+/// 例外のベクタには登録できない。ベクタ自体は [register_vector] か [allocate_vector] で
+/// 先に押さえておくこと。
+pub(crate) fn set_handler(vector: u8, handler: InterruptHandler) {
+    kassert!(
+        vector >= EXCEPTION_VECTORS,
+        "vector {:#04x} is reserved for exceptions",
+        vector
+    );
+    HANDLERS[vector as usize].store(handler as usize, Ordering::Release);
+}
+
+fn handler(vector: u8) -> Option<InterruptHandler> {
+    match HANDLERS[vector as usize].load(Ordering::Acquire) {
+        0 => None,
+        // set_handler が InterruptHandler から作った値しか入らない
+        addr => Some(unsafe { mem::transmute::<usize, InterruptHandler>(addr) }),
+    }
+}
+
+/// ページフォルト（#PF）のベクタ
+const PAGE_FAULT: u8 = 14;
+
+/// 例外の名前を返す。
+const fn exception_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "#DE (divide error)",
+        1 => "#DB (debug)",
+        2 => "NMI",
+        3 => "#BP (breakpoint)",
+        4 => "#OF (overflow)",
+        5 => "#BR (bound range exceeded)",
+        6 => "#UD (invalid opcode)",
+        7 => "#NM (device not available)",
+        8 => "#DF (double fault)",
+        10 => "#TS (invalid TSS)",
+        11 => "#NP (segment not present)",
+        12 => "#SS (stack-segment fault)",
+        13 => "#GP (general protection)",
+        14 => "#PF (page fault)",
+        16 => "#MF (x87 floating-point error)",
+        17 => "#AC (alignment check)",
+        18 => "#MC (machine check)",
+        19 => "#XM (SIMD floating-point exception)",
+        20 => "#VE (virtualization exception)",
+        21 => "#CP (control protection)",
+        _ => "reserved exception",
+    }
+}
+
+/// IDT の 1 エントリ（64 ビットの割り込みゲート）。
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct GateDescriptor {
+    offset_low: u16,
+    segment: u16,
+    /// bit 0〜2 が IST、bit 8〜11 が種類、bit 13〜14 が DPL、bit 15 が Present
+    attributes: u16,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32,
+}
+
+/// 割り込みゲートの種類。入るときに IF を下ろすので、ハンドラの中では次の割り込みが入らない。
+const GATE_TYPE_INTERRUPT: u16 = 0xe;
+
+impl GateDescriptor {
+    const EMPTY: Self = Self {
+        offset_low: 0,
+        segment: 0,
+        attributes: 0,
+        offset_middle: 0,
+        offset_high: 0,
+        reserved: 0,
+    };
+
+    /// `segment` の `offset` へ飛ぶ、特権レベル 0 からだけ INT 命令で呼べる割り込みゲート。
+    const fn interrupt_gate(offset: u64, segment: u16) -> Self {
+        let offset = BitField(offset);
+        Self {
+            offset_low: offset.get_bits(0..16) as u16,
+            segment,
+            attributes: BitField(0u16)
+                .with_bits(8..12, GATE_TYPE_INTERRUPT)
+                .with_bit(15, true)
+                .0,
+            offset_middle: offset.get_bits(16..32) as u16,
+            offset_high: offset.get_bits(32..64) as u32,
+            reserved: 0,
+        }
+    }
+}
+
+#[repr(C, align(16))]
+struct Idt([GateDescriptor; 256]);
+
+static mut IDT: Idt = Idt([GateDescriptor::EMPTY; 256]);
+
+/// 8259 PIC のマスクレジスタの IO ポート（マスタ、スレーブ）
+const PIC_MASTER_DATA: u16 = 0x21;
+const PIC_SLAVE_DATA: u16 = 0xa1;
+
+/// IDT を作って読み込む。
+///
+/// すべてのベクタに入口を置き、例外はカーネルのものならパニックにする。それ以外は [set_handler] で
+/// 登録したハンドラを呼ぶ。Local APIC だけを使うので、ファームウェアが設定したままの 8259 PIC の
+/// IRQ はすべて止める。割り込みはまだ許可しないので、準備ができたら [enable] を呼ぶこと。
+/// [gdt::initialize] の後に呼ぶこと。
+pub(crate) fn initialize() {
+    disable();
+    unsafe {
+        io_out_8(PIC_MASTER_DATA, 0xff);
+        io_out_8(PIC_SLAVE_DATA, 0xff);
+    }
+
+    let idt = unsafe { &mut (*addr_of_mut!(IDT)).0 };
+    let entries = interrupt_entries as *const () as u64;
+    for (vector, gate) in idt.iter_mut().enumerate() {
+        *gate = GateDescriptor::interrupt_gate(
+            entries + (vector * INTERRUPT_ENTRY_SIZE) as u64,
+            gdt::kernel_cs(),
+        );
+    }
+    let pointer = DescriptorTablePointer {
+        limit: (mem::size_of::<Idt>() - 1) as u16,
+        base: idt.as_ptr() as u64,
+    };
+    unsafe {
+        asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack, preserves_flags));
+    }
+    log!(
+        LogLevel::Debug,
+        "IDT: {} entries at {:#x}",
+        idt.len(),
+        pointer.base as u64
+    );
+}
+
+/// 割り込みを許可する（STI）。
+pub(crate) fn enable() {
+    unsafe { asm!("sti", options(nostack)) };
+}
+
+/// 割り込みを禁止する（CLI）。
+pub(crate) fn disable() {
+    unsafe { asm!("cli", options(nostack)) };
+}
+
+/// 割り込みの入口から呼ばれ、ベクタに応じて処理する。
+pub(crate) extern "sysv64" fn interrupt_dispatch(context: &mut InterruptContext) {
+    let vector = context.vector as u8;
+    if vector < EXCEPTION_VECTORS {
+        handle_exception(context);
+        return;
+    }
+    // スプリアス割り込みは ISR に残らないので、EOI を送ってはいけない
+    if vector == vector::SPURIOUS {
+        return;
+    }
+    match handler(vector) {
+        Some(handler) => handler(&context.frame),
+        None => log_irq!(LogLevel::Warn, "unexpected interrupt {:#04x}", vector),
+    }
+    lapic::send_eoi();
+}
+
+/// 例外を処理する。カーネルで起きた例外からは戻れないので、パニックにする。
+///
+/// ユーザモードで起きた例外は、そのプログラムを終わらせて [syscall::run_user] の呼び出し元へ戻る。
+fn handle_exception(context: &InterruptContext) {
+    let vector = context.vector as u8;
+    let frame = &context.frame;
+    if frame.cs & 3 == 3 {
+        syscall::exit_on_exception(vector, frame.rip);
+    }
+    if vector == PAGE_FAULT {
+        panic!(
+            "{} at {:#x}: address {:#x}, error code {:#x}",
+            exception_name(vector),
+            frame.rip,
+            unsafe { get_cr2() },
+            context.error_code
+        );
+    }
+    panic!(
+        "{} at {:#x}: error code {:#x}, rsp {:#x}",
+        exception_name(vector),
+        frame.rip,
+        context.error_code,
+        frame.rsp
+    );
+}
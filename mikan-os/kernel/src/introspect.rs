@@ -0,0 +1,200 @@
+#![allow(unused)]
+
+use crate::{
+    logger::{self, LogLevel},
+    pci::{self, Device},
+    pool, printk, printkln, timer, usb, XHC,
+};
+
+/// 一度に写し取る PCI デバイスの最大数
+const MAX_PCI_DEVICES: usize = 32;
+/// 一度に写し取る USB ポートの最大数
+const MAX_USB_PORTS: usize = 64;
+
+/// ある時点の状態を写し取った、固定長の一覧。
+///
+/// 写し取った後はロックを持たないので、表示している間に元の状態が変わってもよい。
+pub(crate) struct Snapshot<T: Copy, const N: usize> {
+    items: [Option<T>; N],
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> Snapshot<T, N> {
+    const fn new() -> Self {
+        Self {
+            items: [None; N],
+            len: 0,
+        }
+    }
+
+    /// 満杯なら何もしない。
+    fn push(&mut self, item: T) {
+        if self.len < N {
+            self.items[self.len] = Some(item);
+            self.len += 1;
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.items[..self.len].iter().flatten()
+    }
+}
+
+/// [pci::scan_all_bus] で見つかった PCI デバイスの一覧を返す。
+pub(crate) fn pci_devices() -> Snapshot<Device, MAX_PCI_DEVICES> {
+    let mut snapshot = Snapshot::new();
+    let devices = pci::DEVICES.lock();
+    let devices = devices.borrow();
+    let num_devices = *pci::NUM_DEVICES.lock().borrow();
+    for dev in devices.iter().take(num_devices).flatten() {
+        snapshot.push(*dev);
+    }
+    snapshot
+}
+
+/// USB ポートの状態。
+#[derive(Clone, Copy)]
+pub(crate) struct UsbPortInfo {
+    /// ポート番号（1 始まり）
+    pub(crate) number: u8,
+    /// デバイスがつながっているか
+    pub(crate) connected: bool,
+}
+
+/// xHC の各ポートの状態を返す。
+///
+/// xHC が初期化されていないか、他の処理が使っている最中なら空の一覧を返す。
+pub(crate) fn usb_ports() -> Snapshot<UsbPortInfo, MAX_USB_PORTS> {
+    let mut snapshot = Snapshot::new();
+    let Some(mut xhc) = XHC.try_lock() else {
+        return snapshot;
+    };
+    for number in 1..=xhc.max_ports() {
+        let connected = xhc.port_at(number).is_connected();
+        snapshot.push(UsbPortInfo { number, connected });
+    }
+    snapshot
+}
+
+/// メモリプールの使用状況。
+#[derive(Clone, Copy)]
+pub(crate) struct MemoryInfo {
+    /// 空いているバイト数
+    pub(crate) free: usize,
+    /// 全体のバイト数
+    pub(crate) total: usize,
+}
+
+/// メモリプールの使用状況を返す。
+pub(crate) fn memory() -> MemoryInfo {
+    MemoryInfo {
+        free: pool::remaining(),
+        total: pool::POOL_SIZE,
+    }
+}
+
+/// CPU のリセットからの経過時間（ミリ秒）を返す。TSC を測定する前は 0。
+pub(crate) fn uptime_ms() -> u64 {
+    timer::uptime_ms()
+}
+
+/// 現在のログレベルを返す。
+pub(crate) fn log_level() -> LogLevel {
+    logger::get_log_level()
+}
+
+/// PCI デバイスの BAR を表示する。使われていない（0 の）BAR は飛ばす。
+///
+/// 64 ビットの BAR は 2 つ分を使うので、上位の分は表示しない。
+fn print_bars(dev: &Device, prefix: &str) {
+    // ブリッジ（ヘッダタイプ 1）は BAR を 2 つしか持たない
+    let num_bars = if dev.header_type() & 0x7f == 0x01 {
+        2
+    } else {
+        6
+    };
+    let mut index = 0;
+    while index < num_bars {
+        let raw = dev.read_conf_reg(0x10 + 4 * index as u8);
+        let is_64bit = raw & 0b111 == 0b100;
+        if raw != 0 {
+            if raw & 1 != 0 {
+                printkln!("{}BAR{}: I/O {:#06x}", prefix, index, raw & !0b11);
+            } else {
+                let bar = *dev.read_bar(index).value();
+                printkln!(
+                    "{}BAR{}: memory {:#x}{}",
+                    prefix,
+                    index,
+                    bar & !0xf,
+                    if is_64bit { " (64-bit)" } else { "" }
+                );
+            }
+        }
+        index += if is_64bit { 2 } else { 1 };
+    }
+}
+
+/// PCI のバスとデバイス、USB のポート、メモリの状態を、木の形にまとめて表示する。
+///
+/// 見つかったもの全部を並べるので長くなる。ログレベルが [LogLevel::Debug] のときだけ表示する。
+pub(crate) fn print_device_tree() {
+    if log_level() < LogLevel::Debug {
+        return;
+    }
+
+    printkln!("devices");
+    printkln!("+- PCI");
+    let devices = pci_devices();
+    let mut current_bus = None;
+    let mut devices_iter = devices.iter().peekable();
+    while let Some(dev) = devices_iter.next() {
+        if current_bus != Some(dev.bus()) {
+            current_bus = Some(dev.bus());
+            printkln!("|  +- bus {:02x}", dev.bus());
+        }
+        let last_on_bus = devices_iter
+            .peek()
+            .is_none_or(|next| next.bus() != dev.bus());
+        let vendor_id = dev.read_vendor_id();
+        let device_id = pci::read_device_id(dev.bus(), dev.device(), dev.function());
+        printkln!(
+            "|  |  +- {:02x}.{} [{:04x}:{:04x}] {}",
+            dev.device(),
+            dev.function(),
+            vendor_id,
+            device_id,
+            dev.class_code().name()
+        );
+        print_bars(
+            dev,
+            if last_on_bus {
+                "|  |     "
+            } else {
+                "|  |  |  "
+            },
+        );
+    }
+
+    printkln!("+- USB");
+    let ports = usb_ports();
+    if ports.is_empty() {
+        printkln!("|  +- (xHC not available)");
+    }
+    for port in ports.iter().filter(|port| port.connected) {
+        printkln!("|  +- port {}: connected", port.number);
+    }
+    usb::for_each_class_driver(|_, name| printkln!("|  +- driver: {}", name));
+
+    let memory = memory();
+    printkln!("+- memory");
+    printkln!("   +- pool: {} / {} bytes free", memory.free, memory.total);
+}
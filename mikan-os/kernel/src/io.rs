@@ -1,11 +1,27 @@
 use core::arch::global_asm;
 
 extern "C" {
+    pub(crate) fn io_out_8(addr: u16, data: u8);
+    pub(crate) fn io_in_8(addr: u16) -> u8;
     pub(crate) fn io_out_32(addr: u16, data: u32);
     pub(crate) fn io_in_32(addr: u16) -> u32;
 }
 
 global_asm! { r#"
+.global io_out_8
+io_out_8:
+    mov dx, di
+    mov al, sil
+    out dx, al
+    ret
+
+.global io_in_8
+io_in_8:
+    mov dx, di
+    xor eax, eax
+    in al, dx
+    ret
+
 .global io_out_32
 io_out_32:
     mov dx, di
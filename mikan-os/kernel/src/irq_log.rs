@@ -0,0 +1,168 @@
+#![allow(unused)]
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+};
+
+use crate::{printk, printkln, string::StringU8};
+
+/// リングバッファのスロット数（2 のべき乗）
+const SLOT_NUM: usize = 32;
+/// 1 メッセージの最大長。これより長いものは切り詰める。
+const SLOT_SIZE: usize = 128;
+
+const _: () = assert!(SLOT_NUM.is_power_of_two());
+
+/// スロットは空いている
+const SLOT_EMPTY: u8 = 0;
+/// スロットへ書き込み中
+const SLOT_WRITING: u8 = 1;
+/// スロットへの書き込みが終わり、出力を待っている
+const SLOT_READY: u8 = 2;
+
+struct Slot {
+    state: AtomicU8,
+    len: UnsafeCell<usize>,
+    buf: UnsafeCell<[u8; SLOT_SIZE]>,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(SLOT_EMPTY),
+            len: UnsafeCell::new(0),
+            buf: UnsafeCell::new([0; SLOT_SIZE]),
+        }
+    }
+}
+
+struct Ring {
+    slots: [Slot; SLOT_NUM],
+    /// 次に書き込むスロットの通し番号
+    write_index: AtomicUsize,
+    /// 次に出力するスロットの通し番号
+    read_index: AtomicUsize,
+    /// 空きスロットが無くて捨てたメッセージの数
+    dropped: AtomicUsize,
+}
+
+// スロットの中身は、状態を SLOT_WRITING にしたものだけが書き込み、
+// SLOT_READY になったものだけを読み出すので、同時にアクセスされることはない。
+unsafe impl Sync for Ring {}
+
+static RING: Ring = Ring {
+    slots: [const { Slot::new() }; SLOT_NUM],
+    write_index: AtomicUsize::new(0),
+    read_index: AtomicUsize::new(0),
+    dropped: AtomicUsize::new(0),
+};
+
+/// メッセージを 1 つリングバッファへ書き込む。
+///
+/// ロックを取らず、待つこともないので、割り込みハンドラの中や、割り込みハンドラ同士が
+/// ネストした状態からでも呼べる。空きスロットが無い場合はメッセージを捨てる。
+pub(crate) fn write(args: fmt::Arguments) {
+    let index = RING.write_index.fetch_add(1, Ordering::Relaxed);
+    let slot = &RING.slots[index % SLOT_NUM];
+    if slot
+        .state
+        .compare_exchange(
+            SLOT_EMPTY,
+            SLOT_WRITING,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_err()
+    {
+        RING.dropped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+
+    let buf = unsafe { &mut *slot.buf.get() };
+    let mut s = StringU8::new(buf);
+    let _ = s.write_fmt(args);
+    unsafe {
+        *slot.len.get() = s.len();
+    }
+    slot.state.store(SLOT_READY, Ordering::Release);
+}
+
+/// 出力を待っているメッセージがあるかどうか。
+pub(crate) fn has_pending() -> bool {
+    RING.read_index.load(Ordering::Relaxed) != RING.write_index.load(Ordering::Acquire)
+        || RING.dropped.load(Ordering::Relaxed) > 0
+}
+
+/// リングバッファに溜まったメッセージを、溜まった順に printk! で出力する。
+///
+/// メインループから呼ぶこと。割り込みハンドラから呼んではいけない。
+/// 書き込み途中のスロットに当たったらそこで止め、残りは次回に出力する。
+///
+/// メインループの毎周回と、メッセージを待って休む直前に呼ばれるほか、[crate::log!] も
+/// 自分の行を出す前に呼ぶ。割り込みハンドラで先に溜めたログが、後から出した通常のログに
+/// 追い越されることはない。
+pub(crate) fn flush_deferred_logs() {
+    if !has_pending() {
+        return;
+    }
+
+    let end = RING.write_index.load(Ordering::Acquire);
+    let mut i = RING.read_index.load(Ordering::Relaxed);
+    // 追い越されたスロットの分は、もう書き換えられているので読み飛ばす
+    if end.wrapping_sub(i) > SLOT_NUM {
+        i = end.wrapping_sub(SLOT_NUM);
+    }
+
+    while i != end {
+        let slot = &RING.slots[i % SLOT_NUM];
+        match slot.state.load(Ordering::Acquire) {
+            SLOT_READY => {
+                let len = unsafe { *slot.len.get() };
+                let buf = unsafe { &*slot.buf.get() };
+                // StringU8 は ASCII 文字しか書き込まない
+                printk!("{}", unsafe { core::str::from_utf8_unchecked(&buf[..len]) });
+                slot.state.store(SLOT_EMPTY, Ordering::Release);
+            }
+            SLOT_WRITING => break,
+            // 空きスロットが無くて書き込まれなかった番号
+            _ => {}
+        }
+        i = i.wrapping_add(1);
+    }
+    RING.read_index.store(i, Ordering::Relaxed);
+
+    let dropped = RING.dropped.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+        printkln!("irq_log: {} messages dropped", dropped);
+    }
+}
+
+/// 割り込みハンドラから使う printk!。
+///
+/// printk! はコンソールやシリアルポートへ直接書き込むので、メインループが出力している最中に
+/// 割り込みハンドラから呼ぶと、出力が混ざったりデッドロックしたりする。
+/// こちらはメッセージをリングバッファへ溜めるだけで、メインループが [flush_deferred_logs] で出力する。
+#[macro_export]
+macro_rules! printk_irq {
+    ($($arg:tt)*) => {
+        $crate::irq_log::write(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! printkln_irq {
+    () => (printk_irq!("\n"));
+    ($($arg:tt)*) => (printk_irq!("{}\n", format_args!($($arg)*)));
+}
+
+/// 割り込みハンドラから使う log!。
+#[macro_export]
+macro_rules! log_irq {
+    ($level:expr, $($arg:tt)*) => {
+        if $level <= $crate::logger::get_log_level() {
+            printkln_irq!("{}", format_args!($($arg)*));
+        }
+    }
+}
@@ -0,0 +1,392 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+
+use crate::{
+    log,
+    logger::LogLevel,
+    message::{self, InputSource, Message},
+    printk, printkln, ps2, timer,
+    usb::HIDKeyboardDriver,
+};
+
+/// HID キーボードの修飾キーのビット（入力レポートの 0 バイト目）
+pub(crate) const L_CONTROL_BIT: u8 = 0b0000_0001;
+pub(crate) const L_SHIFT_BIT: u8 = 0b0000_0010;
+pub(crate) const L_ALT_BIT: u8 = 0b0000_0100;
+pub(crate) const L_GUI_BIT: u8 = 0b0000_1000;
+pub(crate) const R_CONTROL_BIT: u8 = 0b0001_0000;
+pub(crate) const R_SHIFT_BIT: u8 = 0b0010_0000;
+pub(crate) const R_ALT_BIT: u8 = 0b0100_0000;
+pub(crate) const R_GUI_BIT: u8 = 0b1000_0000;
+
+/// 左右どちらかの修飾キー
+pub(crate) const CONTROL_BITS: u8 = L_CONTROL_BIT | R_CONTROL_BIT;
+pub(crate) const SHIFT_BITS: u8 = L_SHIFT_BIT | R_SHIFT_BIT;
+pub(crate) const ALT_BITS: u8 = L_ALT_BIT | R_ALT_BIT;
+pub(crate) const GUI_BITS: u8 = L_GUI_BIT | R_GUI_BIT;
+
+/// 英字キーの HID キーコード（ショートカットに使うものだけ）
+pub(crate) const KEY_C: u8 = 0x06;
+pub(crate) const KEY_L: u8 = 0x0f;
+pub(crate) const KEY_V: u8 = 0x19;
+
+/// 文字を持たないキーの HID キーコード
+pub(crate) const KEY_CAPS_LOCK: u8 = 0x39;
+pub(crate) const KEY_F1: u8 = 0x3a;
+pub(crate) const KEY_F2: u8 = 0x3b;
+pub(crate) const KEY_PAGE_UP: u8 = 0x4b;
+pub(crate) const KEY_PAGE_DOWN: u8 = 0x4e;
+pub(crate) const KEY_RIGHT_ARROW: u8 = 0x4f;
+pub(crate) const KEY_LEFT_ARROW: u8 = 0x50;
+pub(crate) const KEY_DOWN_ARROW: u8 = 0x51;
+pub(crate) const KEY_UP_ARROW: u8 = 0x52;
+/// 修飾キーのキーコードの始まり。修飾キーのビット i は、このキーコード + i に対応する。
+pub(crate) const KEY_MODIFIER_BASE: u8 = 0xe0;
+
+/// キーが押されたか離されたときのイベント。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct KeyEvent {
+    /// イベントの後の修飾キーの状態（`*_BIT` の組み合わせ）
+    pub(crate) modifiers: u8,
+    /// HID のキーコード。修飾キー自身の変化では [KEY_MODIFIER_BASE] 以降になる。
+    pub(crate) key: u8,
+    /// 押されたなら true、離されたなら false
+    pub(crate) pressed: bool,
+}
+
+impl KeyEvent {
+    pub(crate) const fn new(modifiers: u8, key: u8, pressed: bool) -> Self {
+        Self {
+            modifiers,
+            key,
+            pressed,
+        }
+    }
+
+    pub(crate) const fn ctrl(&self) -> bool {
+        self.modifiers & CONTROL_BITS != 0
+    }
+
+    pub(crate) const fn shift(&self) -> bool {
+        self.modifiers & SHIFT_BITS != 0
+    }
+
+    pub(crate) const fn alt(&self) -> bool {
+        self.modifiers & ALT_BITS != 0
+    }
+
+    pub(crate) const fn gui(&self) -> bool {
+        self.modifiers & GUI_BITS != 0
+    }
+
+    /// 修飾キー自身が押されたか離されたイベントかどうか。
+    pub(crate) const fn is_modifier(&self) -> bool {
+        self.key >= KEY_MODIFIER_BASE && self.key < KEY_MODIFIER_BASE + 8
+    }
+
+    /// Ctrl を押しながら `key` が押されたイベントかどうか。
+    pub(crate) const fn is_ctrl_chord(&self, key: u8) -> bool {
+        self.pressed && self.ctrl() && self.key == key
+    }
+}
+
+/// 最後に届いたイベントでの修飾キーの状態
+static MODIFIERS: AtomicU8 = AtomicU8::new(0);
+
+/// キーボードから届いたイベントを受け取り、修飾キーの状態を更新する。
+///
+/// 文字を入力するキーが押されたときだけ、対応する ASCII 文字を返す。修飾キーの変化や
+/// キーを離したとき、Ctrl や Alt と一緒に押されたときは 0 を返す。
+pub(crate) fn process_key_event(event: &KeyEvent) -> u8 {
+    MODIFIERS.store(event.modifiers, Ordering::Relaxed);
+    event_to_ascii(event)
+}
+
+/// 修飾キーの状態を変えずに、イベントに対応する ASCII 文字を求める。規則は [process_key_event] と同じ。
+fn event_to_ascii(event: &KeyEvent) -> u8 {
+    if !event.pressed || event.is_modifier() || event.ctrl() || event.alt() {
+        return 0;
+    }
+    keycode_to_ascii(event.modifiers, event.key)
+}
+
+/// キーを押し続けてからリピートが始まるまでの既定値（ミリ秒）
+pub(crate) const DEFAULT_REPEAT_DELAY_MS: u32 = 500;
+/// リピートの間隔の既定値（ミリ秒）
+pub(crate) const DEFAULT_REPEAT_INTERVAL_MS: u32 = 30;
+
+/// リピートが始まるまでのティック数。0 ならリピートしない。
+static REPEAT_DELAY: AtomicU32 = AtomicU32::new(ms_to_ticks(DEFAULT_REPEAT_DELAY_MS));
+/// リピートの間隔のティック数
+static REPEAT_INTERVAL: AtomicU32 = AtomicU32::new(ms_to_ticks(DEFAULT_REPEAT_INTERVAL_MS));
+/// リピートしているキーの HID キーコード。0 ならリピートしていない。
+static REPEAT_KEY: AtomicU8 = AtomicU8::new(0);
+/// 次にリピートするまでのティック数
+static REPEAT_COUNTDOWN: AtomicU32 = AtomicU32::new(0);
+
+/// ミリ秒をティック数へ切り上げて変換する。
+const fn ms_to_ticks(ms: u32) -> u32 {
+    (ms as u64 * timer::TICK_HZ).div_ceil(1000) as u32
+}
+
+/// リピートが始まるまでの時間と、その後の間隔（ミリ秒）を設定する。
+///
+/// `delay_ms` が 0 ならリピートしない。間隔は 1 ティックより短くならない。
+pub(crate) fn set_key_repeat(delay_ms: u32, interval_ms: u32) {
+    REPEAT_KEY.store(0, Ordering::Release);
+    REPEAT_DELAY.store(ms_to_ticks(delay_ms), Ordering::Relaxed);
+    REPEAT_INTERVAL.store(ms_to_ticks(interval_ms).max(1), Ordering::Relaxed);
+}
+
+/// 押されたキーと離されたキーから、リピートするキーを決める。
+///
+/// 最後に押されたキーだけをリピートし、そのキーが離されたら止める。修飾キーはリピートせず、
+/// 押し続けているキーのリピートも止めない。離したときのイベントが届くキーボードでだけ呼ぶこと。
+pub(crate) fn track_key_repeat(event: &KeyEvent) {
+    if event.is_modifier() || event.key == 0 {
+        return;
+    }
+    if event.pressed {
+        let delay = REPEAT_DELAY.load(Ordering::Relaxed);
+        if delay == 0 {
+            return;
+        }
+        // タイマ割り込みが新しいキーと古い残り時間を組み合わせないよう、残り時間を先に書く
+        REPEAT_COUNTDOWN.store(delay, Ordering::Relaxed);
+        REPEAT_KEY.store(event.key, Ordering::Release);
+    } else {
+        let _ = REPEAT_KEY.compare_exchange(event.key, 0, Ordering::Release, Ordering::Relaxed);
+    }
+}
+
+/// タイマ割り込み（[crate::timer::start_tick]）から 1 ティックごとに呼ばれる。
+///
+/// リピートする時刻になったら、押したときと同じキーのイベントをメインループへ送る。
+/// 修飾キーは送る時点の状態を使う。キューのロックを取れなければ、次のティックで送り直す。
+pub(crate) fn key_repeat_tick() {
+    let key = REPEAT_KEY.load(Ordering::Acquire);
+    if key == 0 {
+        return;
+    }
+    let countdown = REPEAT_COUNTDOWN.load(Ordering::Relaxed);
+    if countdown > 1 {
+        REPEAT_COUNTDOWN.store(countdown - 1, Ordering::Relaxed);
+        return;
+    }
+
+    let event = KeyEvent::new(modifiers(), key, true);
+    let msg = Message::Key {
+        event,
+        ascii: event_to_ascii(&event),
+        source: InputSource::Local,
+        timestamp: message::timestamp_now(),
+    };
+    if message::try_push_message(msg) {
+        REPEAT_COUNTDOWN.store(REPEAT_INTERVAL.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+}
+
+/// 現在押されている修飾キー（`*_BIT` の組み合わせ）
+pub(crate) fn modifiers() -> u8 {
+    MODIFIERS.load(Ordering::Relaxed)
+}
+
+/// HID キーボードの出力レポートの LED のビット
+pub(crate) const LED_NUM_LOCK_BIT: u8 = 0b0000_0001;
+pub(crate) const LED_CAPS_LOCK_BIT: u8 = 0b0000_0010;
+pub(crate) const LED_SCROLL_LOCK_BIT: u8 = 0b0000_0100;
+
+/// 最後に [set_leds] で設定した LED（`LED_*_BIT` の組み合わせ）
+static LEDS: AtomicU8 = AtomicU8::new(0);
+
+/// キーボードの LED を点灯/消灯する。
+///
+/// 接続されている USB キーボードすべてと、PS/2 キーボードに反映する。
+/// PS/2 キーボードが無い場合の失敗は無視する。
+pub(crate) fn set_leds(caps: bool, num: bool, scroll: bool) {
+    let mut leds = 0;
+    if caps {
+        leds |= LED_CAPS_LOCK_BIT;
+    }
+    if num {
+        leds |= LED_NUM_LOCK_BIT;
+    }
+    if scroll {
+        leds |= LED_SCROLL_LOCK_BIT;
+    }
+    LEDS.store(leds, Ordering::Relaxed);
+    HIDKeyboardDriver::set_all_leds(leds);
+
+    // PS/2 ではビットの並びが HID と異なる
+    let ps2_leds = (caps as u8) << 2 | (num as u8) << 1 | scroll as u8;
+    let err = ps2::set_leds(ps2_leds);
+    if (&err).into() {
+        log!(LogLevel::Debug, "ps2::set_leds: {}", err);
+    }
+}
+
+static CAPS_LOCK: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn caps_lock() -> bool {
+    CAPS_LOCK.load(Ordering::Relaxed)
+}
+
+/// Caps Lock を切り替え、LED に反映する。切り替えた後の状態を返す。
+///
+/// Num Lock と Scroll Lock の LED は、最後に設定した状態のままにする。
+pub(crate) fn toggle_caps_lock() -> bool {
+    let caps = !CAPS_LOCK.fetch_xor(true, Ordering::Relaxed);
+    let leds = LEDS.load(Ordering::Relaxed);
+    set_leds(
+        caps,
+        leds & LED_NUM_LOCK_BIT != 0,
+        leds & LED_SCROLL_LOCK_BIT != 0,
+    );
+    caps
+}
+
+/// キー配列。
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum KeyboardLayout {
+    /// US 配列（101/104 キー）
+    UsEnglish,
+    /// JIS 配列（106/109 キー）
+    JapaneseJis,
+}
+
+static mut KEYBOARD_LAYOUT: KeyboardLayout = KeyboardLayout::UsEnglish;
+
+pub(crate) fn set_keyboard_layout(layout: KeyboardLayout) {
+    unsafe {
+        KEYBOARD_LAYOUT = layout;
+    }
+}
+
+pub(crate) fn get_keyboard_layout() -> KeyboardLayout {
+    unsafe { KEYBOARD_LAYOUT }
+}
+
+/// キーコード表の大きさ。JIS 配列の無変換キー（0x8b）までを含む。
+const KEYCODE_MAP_SIZE: usize = 0x90;
+
+/// US 配列で、シフト無しのときの HID キーコードと文字の対応
+const US_KEYCODE_MAP: [u8; KEYCODE_MAP_SIZE] = [
+    0, 0, 0, 0, b'a', b'b', b'c', b'd', // 0x00
+    b'e', b'f', b'g', b'h', b'i', b'j', b'k', b'l', // 0x08
+    b'm', b'n', b'o', b'p', b'q', b'r', b's', b't', // 0x10
+    b'u', b'v', b'w', b'x', b'y', b'z', b'1', b'2', // 0x18
+    b'3', b'4', b'5', b'6', b'7', b'8', b'9', b'0', // 0x20
+    b'\n', 0x1b, 0x08, b'\t', b' ', b'-', b'=', b'[', // 0x28
+    b']', b'\\', b'#', b';', b'\'', b'`', b',', b'.', // 0x30
+    b'/', 0, 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x40
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x48
+    0, 0, 0, 0, b'/', b'*', b'-', b'+', // 0x50
+    b'\n', b'1', b'2', b'3', b'4', b'5', b'6', b'7', // 0x58
+    b'8', b'9', b'0', b'.', b'\\', 0, 0, b'=', // 0x60
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x68
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x70
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x78
+    0, 0, 0, 0, 0, b',', 0, 0, // 0x80
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x88
+];
+
+/// US 配列で、シフトを押しているときの HID キーコードと文字の対応
+const US_KEYCODE_MAP_SHIFTED: [u8; KEYCODE_MAP_SIZE] = [
+    0, 0, 0, 0, b'A', b'B', b'C', b'D', // 0x00
+    b'E', b'F', b'G', b'H', b'I', b'J', b'K', b'L', // 0x08
+    b'M', b'N', b'O', b'P', b'Q', b'R', b'S', b'T', // 0x10
+    b'U', b'V', b'W', b'X', b'Y', b'Z', b'!', b'@', // 0x18
+    b'#', b'$', b'%', b'^', b'&', b'*', b'(', b')', // 0x20
+    b'\n', 0x1b, 0x08, b'\t', b' ', b'_', b'+', b'{', // 0x28
+    b'}', b'|', b'~', b':', b'"', b'~', b'<', b'>', // 0x30
+    b'?', 0, 0, 0, 0, 0, 0, 0, // 0x38
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x40
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x48
+    0, 0, 0, 0, b'/', b'*', b'-', b'+', // 0x50
+    b'\n', b'1', b'2', b'3', b'4', b'5', b'6', b'7', // 0x58
+    b'8', b'9', b'0', b'.', b'|', 0, 0, b'=', // 0x60
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x68
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x70
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x78
+    0, 0, 0, 0, 0, b',', 0, 0, // 0x80
+    0, 0, 0, 0, 0, 0, 0, 0, // 0x88
+];
+
+/// `map` の一部のキーコードを `overrides` の (キーコード, 文字) で置き換えた表を作る。
+const fn override_keycode_map(
+    mut map: [u8; KEYCODE_MAP_SIZE],
+    overrides: &[(u8, u8)],
+) -> [u8; KEYCODE_MAP_SIZE] {
+    let mut i = 0;
+    while i < overrides.len() {
+        map[overrides[i].0 as usize] = overrides[i].1;
+        i += 1;
+    }
+    map
+}
+
+/// JIS 配列で、シフト無しのときの HID キーコードと文字の対応。
+/// 記号キーの刻印が US 配列と異なる位置と、JIS 配列にだけあるキー（ろ、￥）を上書きする。
+const JIS_KEYCODE_MAP: [u8; KEYCODE_MAP_SIZE] = override_keycode_map(
+    US_KEYCODE_MAP,
+    &[
+        (0x2e, b'^'),
+        (0x2f, b'@'),
+        (0x30, b'['),
+        (0x31, b']'),
+        (0x32, b']'),
+        (0x33, b';'),
+        (0x34, b':'),
+        // 半角/全角
+        (0x35, 0),
+        // International1（ろ）
+        (0x87, b'\\'),
+        // International3（￥）
+        (0x89, b'\\'),
+    ],
+);
+
+/// JIS 配列で、シフトを押しているときの HID キーコードと文字の対応
+const JIS_KEYCODE_MAP_SHIFTED: [u8; KEYCODE_MAP_SIZE] = override_keycode_map(
+    US_KEYCODE_MAP_SHIFTED,
+    &[
+        (0x1f, b'"'),
+        (0x23, b'&'),
+        (0x24, b'\''),
+        (0x25, b'('),
+        (0x26, b')'),
+        // Shift + 0 には文字が割り当てられていない
+        (0x27, 0),
+        (0x2d, b'='),
+        (0x2e, b'~'),
+        (0x2f, b'`'),
+        (0x30, b'{'),
+        (0x31, b'}'),
+        (0x32, b'}'),
+        (0x33, b'+'),
+        (0x34, b'*'),
+        (0x35, 0),
+        (0x87, b'_'),
+        (0x89, b'|'),
+    ],
+);
+
+/// 修飾キーの状態と HID キーコードから、現在のキー配列での ASCII 文字を求める。
+///
+/// Caps Lock が有効なら、英字に限ってシフトの有無を反転する。
+/// 文字が割り当てられていないキーの場合は 0 を返す。
+pub(crate) fn keycode_to_ascii(modifier: u8, keycode: u8) -> u8 {
+    let is_letter = US_KEYCODE_MAP
+        .get(keycode as usize)
+        .is_some_and(|c| c.is_ascii_lowercase());
+    let shift = (modifier & SHIFT_BITS != 0) ^ (is_letter && caps_lock());
+    let map = match (get_keyboard_layout(), shift) {
+        (KeyboardLayout::UsEnglish, false) => &US_KEYCODE_MAP,
+        (KeyboardLayout::UsEnglish, true) => &US_KEYCODE_MAP_SHIFTED,
+        (KeyboardLayout::JapaneseJis, false) => &JIS_KEYCODE_MAP,
+        (KeyboardLayout::JapaneseJis, true) => &JIS_KEYCODE_MAP_SHIFTED,
+    };
+    map.get(keycode as usize).copied().unwrap_or(0)
+}
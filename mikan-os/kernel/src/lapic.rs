@@ -0,0 +1,173 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{
+    cpu,
+    mmio::Mmio,
+    msr::{self, APIC_BASE_ENABLE, APIC_BASE_EXTD, IA32_APIC_BASE, X2APIC_MSR_BASE},
+    paging,
+};
+
+/// Local APIC のレジスタが配置されている物理アドレス（リセット時の既定値）
+pub(crate) const LAPIC_BASE: usize = 0xfee0_0000;
+
+/// Local APIC ID レジスタ（bit 24〜31 が ID）
+const ID: usize = 0x020;
+/// バージョンレジスタ
+const VERSION: usize = 0x030;
+/// End of Interrupt レジスタ
+const EOI: usize = 0x0b0;
+/// Spurious Interrupt Vector レジスタ
+const SPURIOUS_INTERRUPT_VECTOR: usize = 0x0f0;
+/// Interrupt Command レジスタの下位 32 bit
+const ICR_LOW: usize = 0x300;
+/// Interrupt Command レジスタの上位 32 bit（bit 24〜31 が宛先）
+const ICR_HIGH: usize = 0x310;
+/// LVT Timer レジスタ
+const LVT_TIMER: usize = 0x320;
+/// タイマの初期カウントレジスタ
+const TIMER_INITIAL_COUNT: usize = 0x380;
+/// タイマの現在カウントレジスタ
+const TIMER_CURRENT_COUNT: usize = 0x390;
+/// タイマの分周比設定レジスタ
+const TIMER_DIVIDE_CONFIG: usize = 0x3e0;
+
+/// Spurious Interrupt Vector レジスタの APIC Software Enable ビット
+const SVR_APIC_ENABLE: u32 = 1 << 8;
+/// ICR の Delivery Status ビット（1 の間は送信中）
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// LVT の Mask ビット
+pub(crate) const LVT_MASKED: u32 = 1 << 16;
+/// LVT Timer の周期モード
+pub(crate) const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+
+/// x2APIC モードで動いているかどうか
+static X2APIC_MODE: AtomicBool = AtomicBool::new(false);
+
+/// x2APIC モードで動いているかどうか。
+pub(crate) fn is_x2apic_mode() -> bool {
+    X2APIC_MODE.load(Ordering::Relaxed)
+}
+
+/// CPU が対応していれば、Local APIC を x2APIC モードへ切り替える。
+///
+/// 切り替えた後は、レジスタへ MMIO ではなく MSR でアクセスする。
+/// 対応していなければ何もせず、xAPIC モードのまま false を返す。
+/// 一度 x2APIC モードにすると、リセットするまで xAPIC モードへは戻せない。
+pub(crate) fn enable_x2apic() -> bool {
+    if !cpu::has_x2apic() {
+        return false;
+    }
+    unsafe {
+        msr::update(IA32_APIC_BASE, |base| {
+            base | APIC_BASE_ENABLE | APIC_BASE_EXTD
+        });
+    }
+    X2APIC_MODE.store(true, Ordering::Relaxed);
+    true
+}
+
+/// xAPIC モードでのレジスタのオフセットに対応する、x2APIC モードの MSR 番号を返す。
+const fn x2apic_msr(offset: usize) -> u32 {
+    X2APIC_MSR_BASE + (offset >> 4) as u32
+}
+
+/// xAPIC モードでの、Local APIC のレジスタを返す。
+fn mmio_register(offset: usize) -> Mmio<u32> {
+    // Local APIC のレジスタは LAPIC_BASE から 4 KiB の範囲に 16 バイト間隔で並んでいる
+    unsafe { Mmio::new(paging::phys_to_virt(LAPIC_BASE as u64) as usize + offset) }
+}
+
+/// Local APIC のレジスタを読む。`offset` は xAPIC モードでのオフセット。
+fn read_register(offset: usize) -> u32 {
+    if is_x2apic_mode() {
+        unsafe { msr::read(x2apic_msr(offset)) as u32 }
+    } else {
+        mmio_register(offset).read()
+    }
+}
+
+/// Local APIC のレジスタへ書き込む。`offset` は xAPIC モードでのオフセット。
+fn write_register(offset: usize, value: u32) {
+    if is_x2apic_mode() {
+        unsafe { msr::write(x2apic_msr(offset), value as u64) }
+    } else {
+        mmio_register(offset).write(value);
+    }
+}
+
+/// このコアの Local APIC ID を返す。
+///
+/// xAPIC モードでは 8 bit、x2APIC モードでは 32 bit の ID になる。
+pub(crate) fn lapic_id() -> u32 {
+    let id = read_register(ID);
+    if is_x2apic_mode() {
+        id
+    } else {
+        id >> 24
+    }
+}
+
+/// Local APIC のバージョンを返す。
+pub(crate) fn lapic_version() -> u8 {
+    read_register(VERSION) as u8
+}
+
+/// 割り込み処理の終了を Local APIC へ通知する。割り込みハンドラの最後に呼ぶ。
+pub(crate) fn send_eoi() {
+    write_register(EOI, 0);
+}
+
+/// Local APIC を有効にし、スプリアス割り込みのベクタ番号を設定する。
+pub(crate) fn enable(spurious_vector: u8) {
+    write_register(
+        SPURIOUS_INTERRUPT_VECTOR,
+        SVR_APIC_ENABLE | spurious_vector as u32,
+    );
+}
+
+/// `destination` の Local APIC ID を持つコアへ、プロセッサ間割り込みを送る。
+///
+/// `command` は ICR の下位 32 bit（ベクタ番号や配送モード）。
+/// xAPIC モードでは上位を先に書き、下位を書いた時点で送信が始まるので、送信が終わるまで待つ。
+/// x2APIC モードでは ICR は 1 つの 64 bit MSR で、宛先は 32 bit の ID になる。
+pub(crate) fn write_icr(destination: u32, command: u32) {
+    if is_x2apic_mode() {
+        unsafe {
+            msr::write(
+                x2apic_msr(ICR_LOW),
+                ((destination as u64) << 32) | command as u64,
+            );
+        }
+        return;
+    }
+
+    write_register(ICR_HIGH, destination << 24);
+    write_register(ICR_LOW, command);
+    while read_register(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// LVT Timer レジスタを設定する。
+///
+/// `mode` には [LVT_MASKED] や [LVT_TIMER_PERIODIC] を組み合わせて渡す。
+pub(crate) fn set_lvt_timer(vector: u8, mode: u32) {
+    write_register(LVT_TIMER, mode | vector as u32);
+}
+
+/// タイマの分周比を設定する。`config` は分周比設定レジスタへそのまま書き込む値。
+pub(crate) fn set_timer_divide_config(config: u32) {
+    write_register(TIMER_DIVIDE_CONFIG, config);
+}
+
+/// タイマの初期カウントを設定する。書き込んだ時点でカウントダウンが始まる。
+pub(crate) fn set_timer_initial_count(count: u32) {
+    write_register(TIMER_INITIAL_COUNT, count);
+}
+
+/// タイマの現在のカウントを返す。
+pub(crate) fn timer_current_count() -> u32 {
+    read_register(TIMER_CURRENT_COUNT)
+}
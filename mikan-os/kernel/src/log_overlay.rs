@@ -0,0 +1,159 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    error,
+    font::{self, TextOrientation},
+    graphics::{PixelColor, PixelWriter, Rectangle, Vector2D},
+    make_error, render, theme,
+};
+
+/// 表示するログの行数
+const OVERLAY_LINES: usize = 8;
+/// 1 行に表示する最大の文字数。はみ出した分は表示しない。
+const OVERLAY_COLUMNS: usize = 72;
+/// 背景を暗くする度合い（255 で真っ黒）
+const OVERLAY_ALPHA: u8 = 160;
+/// 文字と枠の間の余白
+const PADDING: u32 = 4;
+/// 画面の右端とタスクバーからの余白
+const MARGIN: u32 = 8;
+/// タスクバーの高さ。[crate::draw_desktop] と合わせること。
+const TASKBAR_HEIGHT: u32 = 50;
+
+/// 最近のログを、表示できる長さに切り詰めて覚えておくリングバッファ。
+struct LineRing {
+    lines: [[u8; OVERLAY_COLUMNS]; OVERLAY_LINES],
+    lens: [usize; OVERLAY_LINES],
+    /// 次に書き込む位置
+    next: usize,
+    count: usize,
+}
+
+impl LineRing {
+    const fn new() -> Self {
+        Self {
+            lines: [[0; OVERLAY_COLUMNS]; OVERLAY_LINES],
+            lens: [0; OVERLAY_LINES],
+            next: 0,
+            count: 0,
+        }
+    }
+
+    /// 一杯なら一番古い行を捨てる。表示できない文字は '?' にする。
+    fn push(&mut self, line: &str) {
+        let buf = &mut self.lines[self.next];
+        let mut len = 0;
+        for (dst, &c) in buf.iter_mut().zip(line.as_bytes()) {
+            *dst = if c.is_ascii_graphic() || c == b' ' {
+                c
+            } else {
+                b'?'
+            };
+            len += 1;
+        }
+        self.lens[self.next] = len;
+        self.next = (self.next + 1) % OVERLAY_LINES;
+        self.count = (self.count + 1).min(OVERLAY_LINES);
+    }
+
+    /// 古い順に行を返す。
+    fn iter(&self) -> impl Iterator<Item = &[u8]> {
+        let first = (self.next + OVERLAY_LINES - self.count) % OVERLAY_LINES;
+        (0..self.count).map(move |i| {
+            let i = (first + i) % OVERLAY_LINES;
+            &self.lines[i][..self.lens[i]]
+        })
+    }
+}
+
+static LINES: Mutex<LineRing> = Mutex::new(LineRing::new());
+static VISIBLE: AtomicBool = AtomicBool::new(false);
+/// オーバーレイを表示する領域。[initialize] で決める。
+static BOUNDS: Mutex<Rectangle> =
+    Mutex::new(Rectangle::new(Vector2D::new(0, 0), Vector2D::new(0, 0)));
+
+/// 画面の大きさからオーバーレイの領域を決め、一番上のレイヤとして登録する。
+///
+/// コンソールはレイヤではなく、隠したときに描き直せないので、重ならないよう画面の右下
+/// （タスクバーのすぐ上）に置く。画面が小さくて収まらなければ [error::Code::FrameTooSmall] を返す。
+pub(crate) fn initialize(screen: Vector2D<u32>) -> error::Error {
+    let text = font::string_size(OVERLAY_COLUMNS, TextOrientation::Normal);
+    let size = Vector2D::new(
+        text.x() + 2 * PADDING,
+        text.y() * OVERLAY_LINES as u32 + 2 * PADDING,
+    );
+    if screen.x() < size.x() + MARGIN || screen.y() < size.y() + MARGIN + TASKBAR_HEIGHT {
+        return make_error!(error::Code::FrameTooSmall);
+    }
+    *BOUNDS.lock() = Rectangle::new(
+        Vector2D::new(
+            screen.x() - size.x() - MARGIN,
+            screen.y() - size.y() - MARGIN - TASKBAR_HEIGHT,
+        ),
+        size,
+    );
+    render::add_layer(draw)
+}
+
+/// オーバーレイの表示・非表示を切り替える。切り替えた後の状態を返す。
+///
+/// 隠すときは領域の再描画を依頼するだけで、下のレイヤが元の内容を描き直す。
+pub(crate) fn toggle() -> bool {
+    let visible = !VISIBLE.fetch_xor(true, Ordering::Relaxed);
+    render::request_redraw(*BOUNDS.lock());
+    visible
+}
+
+pub(crate) fn is_visible() -> bool {
+    VISIBLE.load(Ordering::Relaxed)
+}
+
+/// ログを 1 行覚えておく。[crate::log] から呼ばれる。
+///
+/// 表示中なら再描画を依頼する。描画中など、使えないときは捨てる。
+pub(crate) fn push(line: &str) {
+    let Some(mut lines) = LINES.try_lock() else {
+        return;
+    };
+    lines.push(line);
+    drop(lines);
+    if is_visible() {
+        if let Some(bounds) = BOUNDS.try_lock() {
+            render::request_redraw(*bounds);
+        }
+    }
+}
+
+/// オーバーレイのレイヤ。下のレイヤが描いた上に、暗くした背景とログを重ねる。
+pub(crate) fn draw(writer: &dyn PixelWriter, area: &Rectangle) {
+    if !is_visible() {
+        return;
+    }
+    let bounds = *BOUNDS.lock();
+    let Some(clip) = bounds.intersection(area) else {
+        return;
+    };
+
+    let shade = PixelColor::new(0, 0, 0);
+    let end = clip.end();
+    for y in clip.pos.y()..end.y() {
+        for x in clip.pos.x()..end.x() {
+            writer.write_blended(Vector2D::new(x, y), &shade, OVERLAY_ALPHA);
+        }
+    }
+
+    let lines = LINES.lock();
+    let color = theme::current().foreground;
+    let line_height = font::string_size(0, TextOrientation::Normal).y();
+    for (i, line) in lines.iter().enumerate() {
+        let pos = bounds.pos + Vector2D::new(PADDING, PADDING + line_height * i as u32);
+        let size = font::string_size(line.len(), TextOrientation::Normal);
+        if Rectangle::new(pos, size).intersection(area).is_some() {
+            font::write_string(writer, pos, line, &color, TextOrientation::Normal);
+        }
+    }
+}
@@ -1,6 +1,9 @@
 #![allow(unused)]
 
-use core::ffi::{c_char, CStr};
+use core::{
+    ffi::{c_char, CStr},
+    fmt::{self, Write},
+};
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 #[repr(C)]
@@ -23,13 +26,135 @@ pub(crate) fn get_log_level() -> LogLevel {
     unsafe { LOG_LEVEL }
 }
 
+impl LogLevel {
+    /// コンソールでこのレベルのログに色を付ける SGR。色を付けないレベルでは空文字列。
+    ///
+    /// 実際の色は [crate::theme::Theme] の `log_warn`、`log_error` で決まる。
+    pub(crate) const fn color(self) -> &'static str {
+        match self {
+            Self::Error => "\x1b[31m",
+            Self::Warn => "\x1b[33m",
+            Self::Info | Self::Debug => "",
+        }
+    }
+
+    /// [LogLevel::color] で付けた色を戻す SGR。
+    pub(crate) const fn color_reset(self) -> &'static str {
+        match self {
+            Self::Error | Self::Warn => "\x1b[0m",
+            Self::Info | Self::Debug => "",
+        }
+    }
+}
+
+/// 1 行のログの最大長（バイト）。コンソールの幅とは関係なく、これを超えた分は捨てる。
+pub(crate) const MAX_LOG_LINE: usize = 256;
+/// 切り詰めたことを表す印。コンソールは ASCII しか表示できないので "…" の代わりに使う。
+const TRUNCATION_MARKER: &str = "...";
+
+/// [MAX_LOG_LINE] バイトまでに切り詰めて書式化した 1 行のログ。
+///
+/// 巨大なスライスの Debug 出力のようなものでコンソールが埋まったり、出力に何秒もかかったり
+/// しないよう、あふれた時点で書式化そのものを打ち切る。
+pub(crate) struct LogLine {
+    buf: [u8; MAX_LOG_LINE],
+    len: usize,
+    truncated: bool,
+}
+
+impl LogLine {
+    pub(crate) fn format(args: fmt::Arguments) -> Self {
+        let mut line = Self {
+            buf: [0; MAX_LOG_LINE],
+            len: 0,
+            truncated: false,
+        };
+        // あふれたときは write_str がエラーを返して書式化が止まるので、結果は見ない
+        let _ = line.write_fmt(args);
+        line
+    }
+
+    pub(crate) fn as_str(&self) -> &str {
+        // write_str は文字の境界でしか切らない
+        unsafe { core::str::from_utf8_unchecked(&self.buf[..self.len]) }
+    }
+
+    /// 切り詰めていれば [TRUNCATION_MARKER] を、そうでなければ空文字列を返す。
+    pub(crate) fn marker(&self) -> &'static str {
+        if self.truncated {
+            TRUNCATION_MARKER
+        } else {
+            ""
+        }
+    }
+}
+
+impl Write for LogLine {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let room = MAX_LOG_LINE - self.len;
+        if s.len() <= room {
+            self.buf[self.len..self.len + s.len()].copy_from_slice(s.as_bytes());
+            self.len += s.len();
+            return Ok(());
+        }
+
+        let mut end = room;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.buf[self.len..self.len + end].copy_from_slice(&s.as_bytes()[..end]);
+        self.len += end;
+        self.truncated = true;
+        Err(fmt::Error)
+    }
+}
+
+/// ログレベルが `$level` 以上なら、1 行を出力する。[MAX_LOG_LINE] を超えた分は切り詰める。
+///
+/// 出力の順番が入れ替わらないよう、割り込みハンドラが溜めたログ（[crate::log_irq!]）を先に出す。
 #[macro_export]
 macro_rules! log {
-    ($level:expr, $($arg:tt)*) => {
-        if $level <= $crate::logger::get_log_level() {
-            printkln!("{}", format_args!($($arg)*));
+    ($level:expr, $($arg:tt)*) => {{
+        let level: $crate::logger::LogLevel = $level;
+        if level <= $crate::logger::get_log_level() {
+            $crate::irq_log::flush_deferred_logs();
+            let line = $crate::logger::LogLine::format(format_args!($($arg)*));
+            $crate::log_overlay::push(line.as_str());
+            printkln!(
+                "{}{}{}{}",
+                level.color(),
+                line.as_str(),
+                line.marker(),
+                level.color_reset()
+            );
         }
-    }
+    }}
+}
+
+/// 条件が成り立たなければ、条件式とメッセージを添えてパニックする。
+///
+/// パニックハンドラが発生した位置（ファイルと行）と共に使える出力先すべてへ書き出し、
+/// xHC を止めてから停止する。ログレベルに関わらず出力される。
+///
+/// ```ignore
+/// kassert!(index < len, "index {} out of {}", index, len);
+/// ```
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        if !$cond {
+            panic!("assertion failed: {}", stringify!($cond));
+        }
+    };
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            panic!(
+                "assertion failed: {}: {}",
+                stringify!($cond),
+                format_args!($($arg)+)
+            );
+        }
+    };
 }
 
 // #[export_name = "_Z3LogLogLevelPKcz"]
@@ -1,53 +1,138 @@
 #![no_std]
 #![no_main]
 
+mod acpi;
+mod app;
+mod asmfunc;
+mod bitfield;
+mod boot_args;
+mod boot_phase;
+mod clipboard;
+mod collections;
 mod console;
+mod cpu;
+mod elf;
 mod error;
 mod font;
 mod font_data;
 mod frame_buffer_config;
+mod gdt;
 mod graphics;
+mod interrupt;
+mod introspect;
 mod io;
+mod irq_log;
+mod keyboard;
+mod lapic;
+mod log_overlay;
 mod logger;
+mod memory_map;
+mod message;
+mod mmio;
 mod mouse;
+mod msr;
+mod paging;
+mod panic_action;
 mod pci;
 mod placement;
+mod pool;
+mod profiler;
+mod ps2;
+mod render;
+mod rtc;
+mod runtime_services;
+mod safe_mode;
+mod selftest;
+mod serial;
+mod splash;
 mod string;
+mod sync;
+mod syscall;
+mod theme;
+mod timer;
+mod trace;
+mod tui;
 mod usb;
+mod wait;
+mod watchdog;
+mod widget;
 
-use console::Console;
-use core::{arch::asm, cell::OnceCell, fmt::Write, mem::size_of, panic::PanicInfo};
-use frame_buffer_config::{FrameBufferConfig, PixelFormat};
+use boot_args::{BootArgs, BootOptions};
+use boot_phase::BootPhase;
+use console::{set_console_backend, Console, ConsoleBackend};
+use core::{
+    arch::{asm, x86_64::_rdtsc},
+    fmt::{self, Write},
+    mem::size_of,
+    panic::PanicInfo,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+};
+use font::TextOrientation;
+use frame_buffer_config::{BootFrameBufferConfig, FrameBufferConfig, PixelFormat};
 use graphics::{
-    BgrResv8BitPerColorPixelWriter, PixelColor, PixelWriter, RgbResv8BitPerColorPixelWriter,
-    Vector2D,
+    BgrResv8BitPerColorPixelWriter, PixelColor, PixelWriter, Rectangle,
+    RgbResv8BitPerColorPixelWriter, ShadowBuffer, Vector2D,
 };
-use mouse::MouseCursor;
+use keyboard::{set_keyboard_layout, KeyEvent, KeyboardLayout};
+use memory_map::BootMemoryMap;
+use message::{InputSource, Message};
+use mmio::Mmio;
+use mouse::{DoubleClickDetector, MouseCursor};
+use panic_action::PanicAction;
 use pci::Device;
 use placement::new_mut_with_buf;
+use ps2::Ps2Keyboard;
+use serial::SerialPort;
+use sync::{OnceLock, SpinLock};
+use trace::TraceEvent;
+use widget::ProgressBar;
 
 use crate::{
-    logger::{set_log_level, LogLevel},
-    usb::{Controller, HIDMouseDriver},
+    logger::{get_log_level, set_log_level, LogLevel},
+    string::hexdump,
+    usb::{Controller, HIDKeyboardDriver, HIDMouseDriver},
 };
 
-/// デスクトップ背景の色
-const DESKTOP_BG_COLOR: PixelColor = PixelColor::new(45, 118, 237);
-/// デスクトップ前景の色
-const DESKTOP_FG_COLOR: PixelColor = PixelColor::new(255, 255, 255);
-
 const PIXEL_WRITER_SIZE: usize = size_of::<RgbResv8BitPerColorPixelWriter>();
 static mut PIXEL_WRITER_BUF: [u8; PIXEL_WRITER_SIZE] = [0u8; PIXEL_WRITER_SIZE];
-static mut CONSOLE: OnceCell<Console> = OnceCell::new();
+static CONSOLE: OnceLock<SpinLock<Console>> = OnceLock::new();
+/// PageUp / PageDown でコンソールをスクロールする行数
+const CONSOLE_SCROLL_PAGE: usize = 12;
+/// 起動時にデスクトップをフェードインさせるティック数
+const BOOT_FADE_TICKS: u32 = 30;
+static SERIAL: OnceLock<SpinLock<SerialPort>> = OnceLock::new();
+
+/// 指定された出力先のうち、初期化済みのものすべてへ書き込む。
+/// 書き込めた出力先が一つも無かった場合は偽を返す。
+/// 出力中の出力先（出力処理の中でのパニックなど）へは書き込まない。
+fn write_to_sinks(backend: ConsoleBackend, args: fmt::Arguments) -> bool {
+    let mut written = false;
+    if backend.graphics() {
+        if let Some(mut console) = CONSOLE.try_lock() {
+            let _ = console.write_fmt(args);
+            written = true;
+        }
+    }
+    if backend.serial() {
+        if let Some(mut serial) = SERIAL.try_lock() {
+            let _ = serial.write_fmt(args);
+            written = true;
+        }
+    }
+    written
+}
 
+/// 現在の出力先へ書式付きで出力する。
+///
+/// 割り込みハンドラからは使わないこと。代わりに [printk_irq!] を使う。
 #[macro_export]
 macro_rules! printk {
     ($($arg:tt)*) => {
-        unsafe {
-            match CONSOLE.get_mut() {
-                Some(console) => write!(console, $($arg)*).unwrap(),
-                None => $crate::halt(),
-            }
+        if !$crate::write_to_sinks(
+            $crate::console::get_console_backend(),
+            format_args!($($arg)*),
+        ) {
+            $crate::halt()
         }
     };
 }
@@ -58,159 +143,1122 @@ macro_rules! printkln {
     ($($arg:tt)*) => (printk!("{}\n", format_args!($($arg)*)));
 }
 
-static mut MOUSE_CURSOR: OnceCell<MouseCursor> = OnceCell::new();
+/// コンソールのカーソルを指定の (行, 列) へ移動する。
+/// コンソールが未初期化の場合は何もしない。
+#[macro_export]
+macro_rules! goto {
+    ($row:expr, $column:expr) => {
+        if let Some(mut console) = $crate::CONSOLE.lock() {
+            console.set_cursor($row, $column);
+        }
+    };
+}
+
+static MOUSE_CURSOR: OnceLock<SpinLock<MouseCursor>> = OnceLock::new();
+static XHC: OnceLock<SpinLock<Controller>> = OnceLock::new();
+/// [Message::InterruptXHCI] 1 つで処理するイベントの上限。キーボードなどの処理を待たせすぎない。
+const MAX_XHCI_EVENTS_PER_BATCH: usize = 64;
+/// xHC が致命的なエラーで止まったときに、初期化し直す回数の上限
+const MAX_XHC_REINIT: usize = 3;
+/// xHC を初期化し直した回数
+static XHC_REINIT_COUNT: AtomicUsize = AtomicUsize::new(0);
+/// xHC を諦めて使わなくなったら true
+static XHC_DISABLED: AtomicBool = AtomicBool::new(false);
+/// まとめて捨てた [Message::InterruptXHCI] の数
+static COALESCED_XHCI_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+/// 矢印キーでマウスカーソルを動かすか。本物のマウスから入力があれば false にする。
+static KEYBOARD_CURSOR: AtomicBool = AtomicBool::new(true);
+/// 矢印キー 1 回でカーソルを動かすピクセル数。Ctrl を押していれば 1 ピクセルずつ。
+const KEYBOARD_CURSOR_STEP: i32 = 8;
 
-fn mouse_observer(displacement_x: i8, displacement_y: i8) {
-    let cursor = match unsafe { MOUSE_CURSOR.get_mut() } {
-        None => halt(),
-        Some(cursor) => cursor,
+/// 前回のマウスの報告でのボタンの状態
+static MOUSE_BUTTONS: AtomicU8 = AtomicU8::new(0);
+/// 左クリックの時刻と位置から、ダブルクリックを見分ける
+static DOUBLE_CLICK: SpinLock<DoubleClickDetector> = SpinLock::new(DoubleClickDetector::new());
+/// F2 でプロファイラを動かし始めた時刻（ミリ秒）
+static PROFILER_START_MS: AtomicU64 = AtomicU64::new(0);
+
+fn mouse_observer(buttons: u8, displacement_x: i8, displacement_y: i8) {
+    let Some(mut cursor) = MOUSE_CURSOR.lock() else {
+        halt()
     };
+    if KEYBOARD_CURSOR.swap(false, Ordering::Relaxed) {
+        log!(LogLevel::Info, "mouse detected: arrow-key cursor disabled");
+    }
     cursor.move_relative(Vector2D::new(displacement_x as u32, displacement_y as u32));
+    let pos = cursor.position();
+    tui::handle_mouse(pos);
+
+    // 押した瞬間、押したまま動かしたとき、離した瞬間を知らせる。
+    // クリックの処理は描画などを伴うのでメインループに任せる。
+    let previous = MOUSE_BUTTONS.swap(buttons, Ordering::Relaxed);
+    let held = buttons & previous & usb::MOUSE_BUTTON_LEFT != 0;
+    if held && (displacement_x != 0 || displacement_y != 0) {
+        // キューが満杯で落ちても、次のドラッグか離したときの位置で追いつく
+        let _ = message::push_message(Message::MouseDrag {
+            x: pos.x(),
+            y: pos.y(),
+            timestamp: message::timestamp_now(),
+        });
+    }
+    if !buttons & previous & usb::MOUSE_BUTTON_LEFT != 0 {
+        let err = message::push_message(Message::MouseRelease {
+            x: pos.x(),
+            y: pos.y(),
+            timestamp: message::timestamp_now(),
+        });
+        if (&err).into() {
+            log!(LogLevel::Warn, "mouse release dropped: {}", err);
+        }
+    }
+    if buttons & !previous & usb::MOUSE_BUTTON_LEFT != 0 {
+        let timestamp = message::timestamp_now();
+        let err = message::push_message(Message::MouseClick {
+            x: pos.x(),
+            y: pos.y(),
+            timestamp,
+        });
+        if (&err).into() {
+            log!(LogLevel::Warn, "mouse click dropped: {}", err);
+        }
+        if DOUBLE_CLICK.lock().on_click(timestamp, pos) {
+            let err = message::push_message(Message::DoubleClick {
+                x: pos.x(),
+                y: pos.y(),
+                timestamp,
+            });
+            if (&err).into() {
+                log!(LogLevel::Warn, "double click dropped: {}", err);
+            }
+        }
+    }
+}
+
+fn keyboard_observer(modifier: u8, keycode: u8, pressed: bool) {
+    let event = KeyEvent::new(modifier, keycode, pressed);
+    keyboard::track_key_repeat(&event);
+    let err = message::push_message(Message::Key {
+        event,
+        ascii: keyboard::process_key_event(&event),
+        source: InputSource::Local,
+        timestamp: message::timestamp_now(),
+    });
+    if (&err).into() {
+        log!(LogLevel::Warn, "key event dropped: {}", err);
+    }
+}
+
+/// シリアルポートから受信したバイトがあれば、入力として扱える形にして返す。
+///
+/// 初期化されていないか、出力中なら None を返す。
+pub(crate) fn read_serial_input() -> Option<u8> {
+    SERIAL
+        .try_lock()
+        .and_then(|serial| serial.read_byte())
+        .map(serial::normalize_input)
+}
+
+/// シリアルポートから入力された文字を、端末へ表示させるために送り返す。
+fn echo_serial(ascii: u8) {
+    match ascii {
+        // 端末側のカーソルを戻すだけでは文字が消えない
+        0x08 => write_to_sinks(ConsoleBackend::Serial, format_args!("\x08 \x08")),
+        c => write_to_sinks(ConsoleBackend::Serial, format_args!("{}", c as char)),
+    };
+}
+
+/// シリアルポートに届いた文字を、キーボードと同じくメインループへ送る。
+fn poll_serial_input() {
+    while let Some(ascii) = read_serial_input() {
+        if ascii == 0 {
+            continue;
+        }
+        let err = message::push_message(Message::Key {
+            event: KeyEvent::new(0, 0, true),
+            ascii,
+            source: InputSource::Serial,
+            timestamp: message::timestamp_now(),
+        });
+        if (&err).into() {
+            log!(LogLevel::Warn, "serial input dropped: {}", err);
+            return;
+        }
+    }
+}
+
+/// PS/2 キーボードで押されたキーを、USB キーボードと同じくメインループへ送る。
+///
+/// [Ps2Keyboard] は押したときしか報告しないので、離したときのイベントは送らない。
+fn poll_ps2_keyboard(keyboard: &mut Ps2Keyboard) {
+    while let Some((modifier, keycode)) = keyboard.poll() {
+        keyboard_observer(modifier, keycode, true);
+    }
+}
+
+/// Intel の xHC の PCI コンフィギュレーション空間にある、ポートの切り替え用レジスタ
+/// USB 2.0 ポートを xHC へつなぐかどうか（XUSB2PR）
+const XUSB2PR: u8 = 0xd0;
+/// XUSB2PR で切り替えられるポート（XUSB2PRM）
+const XUSB2PRM: u8 = 0xd4;
+/// USB 3.0 の SuperSpeed を有効にするポート（USB3_PSSEN）
+const USB3_PSSEN: u8 = 0xd8;
+/// USB3_PSSEN で有効にできるポート（USB3PRM）
+const USB3PRM: u8 = 0xdc;
+
+/// EHCI の HCCPARAMS のケーパビリティレジスタ先頭からのオフセット
+const EHCI_HCCPARAMS: usize = 0x08;
+/// EHCI の USB Legacy Support 拡張ケーパビリティの ID
+const EHCI_USBLEGSUP_ID: u32 = 1;
+/// USBLEGSUP の HC BIOS Owned Semaphore ビット
+const USBLEGSUP_BIOS_OWNED: u32 = 1 << 16;
+/// USBLEGSUP の HC OS Owned Semaphore ビット
+const USBLEGSUP_OS_OWNED: u32 = 1 << 24;
+
+/// EHC の所有権を BIOS から OS へ移す。
+///
+/// EHCI の USB Legacy Support ケーパビリティがあれば OS Owned Semaphore を立て、
+/// BIOS が BIOS Owned Semaphore を下ろすまで待つ。BIOS が EHC を使い続けていると、
+/// ポートを xHC へ切り替えても SMI で元に戻されることがある。
+fn ehci_bios_handoff(ehc_dev: &Device) {
+    let bar = ehc_dev.read_bar(0);
+    if (&bar.error()).into() {
+        log!(LogLevel::Warn, "EHC BAR0: {}", bar.error());
+        return;
+    }
+    let mmio_base = paging::phys_to_virt(*bar.value() & !0xf);
+    let hccparams = unsafe { Mmio::<u32>::new(mmio_base as usize + EHCI_HCCPARAMS) }.read();
+    // EECP はコンフィギュレーション空間上の拡張ケーパビリティの位置。0x40 未満なら無い。
+    let eecp = ((hccparams >> 8) & 0xff) as u8;
+    if eecp < 0x40 {
+        return;
+    }
+    let usblegsup = ehc_dev.read_conf_reg(eecp);
+    if usblegsup & 0xff != EHCI_USBLEGSUP_ID || usblegsup & USBLEGSUP_BIOS_OWNED == 0 {
+        return;
+    }
+
+    ehc_dev.write_conf_reg(eecp, usblegsup | USBLEGSUP_OS_OWNED);
+    let err = wait::wait_until(
+        || ehc_dev.read_conf_reg(eecp) & USBLEGSUP_BIOS_OWNED == 0,
+        wait::DEFAULT_POLL_LIMIT,
+    );
+    if (&err).into() {
+        log!(
+            LogLevel::Warn,
+            "EHC {}.{}.{}: BIOS did not release the controller: {}",
+            ehc_dev.bus(),
+            ehc_dev.device(),
+            ehc_dev.function(),
+            err
+        );
+    } else {
+        log!(
+            LogLevel::Debug,
+            "EHC {}.{}.{}: OS owns the controller",
+            ehc_dev.bus(),
+            ehc_dev.device(),
+            ehc_dev.function()
+        );
+    }
 }
 
+/// Intel のチップセットで、EHC につながっている USB ポートを xHC へ切り替える。
+///
+/// 切り替えた後にレジスタを読み直し、実際に切り替わったかを確認する。
 fn switch_ehci2xhci(xhc_dev: &Device) {
     let mut intel_ehc_exist = false;
     let num_device = *pci::NUM_DEVICES.lock().borrow();
     let devices = pci::DEVICES.lock();
     let devices = devices.borrow();
-    for i in 0..num_device {
-        if devices[i].unwrap().class_code().r#match(0x0c, 0x03, 0x20)
-            && devices[i].unwrap().read_vendor_id() == 0x8086
-        {
+    for dev in devices.iter().take(num_device).flatten() {
+        if dev.class_code().r#match(0x0c, 0x03, 0x20) && dev.read_vendor_id() == 0x8086 {
             intel_ehc_exist = true;
-            break;
+            ehci_bios_handoff(dev);
         }
     }
     if !intel_ehc_exist {
         return;
     }
 
-    let superspeed_ports = xhc_dev.read_conf_reg(0xdc);
-    xhc_dev.write_conf_reg(0xd8, superspeed_ports);
-    let ehci2xhci_ports = xhc_dev.read_conf_reg(0xd4);
-    xhc_dev.write_conf_reg(0xd0, ehci2xhci_ports);
+    let superspeed_ports = xhc_dev.read_conf_reg(USB3PRM);
+    xhc_dev.write_conf_reg(USB3_PSSEN, superspeed_ports);
+    let ehci2xhci_ports = xhc_dev.read_conf_reg(XUSB2PRM);
+    xhc_dev.write_conf_reg(XUSB2PR, ehci2xhci_ports);
     log!(
         LogLevel::Debug,
         "switch_ehci2xhci: SS = {:02x}, xHCI = {:02x}",
         superspeed_ports,
         ehci2xhci_ports
     );
+
+    // 書き込めないビットもあるので、切り替えられるはずのビットだけを比べる
+    let superspeed_enabled = xhc_dev.read_conf_reg(USB3_PSSEN) & superspeed_ports;
+    let xhci_routed = xhc_dev.read_conf_reg(XUSB2PR) & ehci2xhci_ports;
+    if superspeed_enabled == superspeed_ports && xhci_routed == ehci2xhci_ports {
+        log!(
+            LogLevel::Info,
+            "switch_ehci2xhci: all ports are routed to xHC"
+        );
+    } else {
+        log!(
+            LogLevel::Warn,
+            "switch_ehci2xhci: handoff incomplete: SS = {:02x}/{:02x}, xHCI = {:02x}/{:02x}",
+            superspeed_enabled,
+            superspeed_ports,
+            xhci_routed,
+            ehci2xhci_ports
+        );
+    }
 }
 
-#[no_mangle]
-pub extern "sysv64" fn kernel_entry(frame_buffer_config: FrameBufferConfig) {
-    let pixel_writer: &mut dyn PixelWriter = match frame_buffer_config.pixel_format {
-        PixelFormat::Rgb => {
-            match unsafe {
-                new_mut_with_buf(
-                    RgbResv8BitPerColorPixelWriter::new(frame_buffer_config),
-                    &mut PIXEL_WRITER_BUF,
-                )
-            } {
-                Err(_size) => halt(),
-                Ok(writer) => writer,
+/// デバッグ用に表示する xHC のケーパビリティレジスタのバイト数
+const XHC_CAP_DUMP_SIZE: usize = 0x20;
+
+/// xHC のケーパビリティレジスタを 16 進ダンプする。
+///
+/// MMIO はバイト単位で読むと正しく読めないことがあるので、4 バイトずつ読み出してから表示する。
+fn dump_xhc_capability_registers(mmio_base: u64) {
+    let mut cap_regs = [0u8; XHC_CAP_DUMP_SIZE];
+    for (i, dword) in cap_regs.chunks_mut(4).enumerate() {
+        let value = unsafe { Mmio::<u32>::new(mmio_base as usize + 4 * i) }.read();
+        dword.copy_from_slice(&value.to_le_bytes());
+    }
+    log!(LogLevel::Debug, "xHC capability registers:");
+    hexdump(&cap_regs, mmio_base);
+}
+
+/// コンソールの履歴をスクロールするキーなら、スクロールして true を返す。
+///
+/// PageUp / PageDown で 1 画面の半分ずつ、Shift + ↑ / ↓ で 1 行ずつ動かす。
+/// それ以外のキーが押されたら、最新の画面の表示へ戻して false を返す。
+fn scroll_console(modifier: u8, keycode: u8) -> bool {
+    let Some(mut console) = CONSOLE.lock() else {
+        return false;
+    };
+    let shift = modifier & (keyboard::L_SHIFT_BIT | keyboard::R_SHIFT_BIT) != 0;
+    match (keycode, shift) {
+        (keyboard::KEY_PAGE_UP, _) => console.scroll_up(CONSOLE_SCROLL_PAGE),
+        (keyboard::KEY_PAGE_DOWN, _) => console.scroll_down(CONSOLE_SCROLL_PAGE),
+        (keyboard::KEY_UP_ARROW, true) => console.scroll_up(1),
+        (keyboard::KEY_DOWN_ARROW, true) => console.scroll_down(1),
+        _ => {
+            console.scroll_to_bottom();
+            return false;
+        }
+    }
+    true
+}
+
+/// シェルの操作に割り当てたキーの組み合わせなら、その操作をして true を返す。
+///
+/// Ctrl + L で画面を消去し、Ctrl + C で入力中の行を捨てて新しいプロンプトを出す。
+/// シリアルの端末からは、同じ操作が制御文字（0x0c、0x03）として届く。
+fn handle_shell_chord(event: &KeyEvent, ascii: u8) -> bool {
+    if event.is_ctrl_chord(keyboard::KEY_L) || ascii == 0x0c {
+        run_menu_action(tui::MenuAction::Clear);
+        printk!("> ");
+        return true;
+    }
+    if event.is_ctrl_chord(keyboard::KEY_C) || ascii == 0x03 {
+        printk!("^C\n> ");
+        return true;
+    }
+    false
+}
+
+/// 左ボタンが離されたところで選択を確定し、選択していればクリップボードへ写す。
+fn copy_selection(pos: Vector2D<u32>) {
+    let copied = {
+        let Some(mut console) = CONSOLE.lock() else {
+            return;
+        };
+        console.extend_selection(pos);
+        if !console.has_selection() {
+            return;
+        }
+        clipboard::store(|buf| console.copy_selection(buf))
+    };
+    // コンソールのロックを放してからログに出す
+    log!(LogLevel::Debug, "copied {} bytes to the clipboard", copied);
+}
+
+/// クリップボードの内容を、キーボードから打ち込んだのと同じように入力する。
+fn paste_clipboard(source: InputSource) {
+    clipboard::with(|bytes| {
+        for &ascii in bytes {
+            printk!("{}", ascii as char);
+            if source == InputSource::Serial && !console::get_console_backend().serial() {
+                echo_serial(ascii);
+            }
+        }
+    });
+}
+
+/// プロファイラを止めていれば結果を消して始め、動かしていれば止めて結果を出す。
+///
+/// 標本はタイマ割り込みで取るので、Local APIC タイマが使えず [timer::start_tick] できなかったときは
+/// 0 件のまま。
+fn toggle_profiler() {
+    if profiler::is_enabled() {
+        profiler::set_enabled(false);
+        profiler::report();
+        report_render_stats();
+    } else {
+        profiler::reset();
+        render::reset_stats();
+        message::reset_input_latency();
+        PROFILER_START_MS.store(timer::uptime_ms(), Ordering::Relaxed);
+        profiler::set_enabled(true);
+        log!(LogLevel::Info, "profiler: started, press F2 again to stop");
+    }
+}
+
+/// プロファイラを動かしていた間の描画の回数と描画に使った時間の割合、入力の遅れをログに出す。
+fn report_render_stats() {
+    let stats = render::stats();
+    let elapsed_ms = timer::uptime_ms().saturating_sub(PROFILER_START_MS.load(Ordering::Relaxed));
+    // 描画の時間はマイクロ秒、経過時間はミリ秒なので、割れば千分率になる
+    let busy_permille = stats.busy_us.checked_div(elapsed_ms).unwrap_or(0);
+    log!(
+        LogLevel::Info,
+        "render: {} frames, {} deferred in {} ms (max {} fps), {}.{}% busy drawing",
+        stats.frames,
+        stats.deferred,
+        elapsed_ms,
+        render::max_fps(),
+        busy_permille / 10,
+        busy_permille % 10
+    );
+    let latency = message::input_latency();
+    log!(
+        LogLevel::Info,
+        "input: {} events, latency avg {} ms, max {} ms",
+        latency.events,
+        latency.total_ms.checked_div(latency.events).unwrap_or(0),
+        latency.max_ms
+    );
+}
+
+/// ローダから渡されたアプリを呼び出し、終了コードをログに出す。
+fn run_app() {
+    let result = app::run();
+    if (&result.error()).into() {
+        log!(LogLevel::Warn, "app: {}", result.error());
+    } else {
+        log!(LogLevel::Info, "app: exited with {}", result.value());
+    }
+}
+
+/// スタートメニューで選ばれた操作を実行する。
+fn run_menu_action(action: tui::MenuAction) {
+    match action {
+        tui::MenuAction::Shell => {
+            if let Some(mut console) = CONSOLE.lock() {
+                console.scroll_to_bottom();
             }
+            printk!("\n> ");
         }
-        PixelFormat::Bgr => {
-            match unsafe {
-                new_mut_with_buf(
-                    BgrResv8BitPerColorPixelWriter::new(frame_buffer_config),
-                    &mut PIXEL_WRITER_BUF,
-                )
-            } {
-                Err(_size) => halt(),
-                Ok(writer) => writer,
+        tui::MenuAction::Clear => {
+            if let Some(mut console) = CONSOLE.lock() {
+                console.clear();
+                console.set_cursor(0, 0);
             }
         }
+        tui::MenuAction::Reboot => {
+            log!(LogLevel::Info, "rebooting from the start menu");
+            panic_action::reboot();
+        }
+    }
+}
+
+/// マウスが見つかっていない間、矢印キーならカーソルを動かして true を返す。
+///
+/// Shift + ↑ / ↓ はコンソールのスクロールに使うので、Shift を押していれば何もしない。
+fn move_cursor_by_key(modifier: u8, keycode: u8) -> bool {
+    if !KEYBOARD_CURSOR.load(Ordering::Relaxed)
+        || modifier & (keyboard::L_SHIFT_BIT | keyboard::R_SHIFT_BIT) != 0
+    {
+        return false;
+    }
+    let step = if modifier & (keyboard::L_CONTROL_BIT | keyboard::R_CONTROL_BIT) != 0 {
+        1
+    } else {
+        KEYBOARD_CURSOR_STEP
+    };
+    let (dx, dy) = match keycode {
+        keyboard::KEY_RIGHT_ARROW => (step, 0),
+        keyboard::KEY_LEFT_ARROW => (-step, 0),
+        keyboard::KEY_DOWN_ARROW => (0, step),
+        keyboard::KEY_UP_ARROW => (0, -step),
+        _ => return false,
     };
+    let Some(mut cursor) = MOUSE_CURSOR.lock() else {
+        return false;
+    };
+    cursor.move_clamped(dx, dy);
+    tui::handle_mouse(cursor.position());
+    true
+}
 
-    let frame_width = pixel_writer.config().horizontal_resolution as u32;
-    let frame_height = pixel_writer.config().vertical_resolution as u32;
+/// タスクバーのスタートボタンの領域。押すとスタートメニューを開く。
+fn start_button(frame_height: u32) -> Rectangle {
+    Rectangle::new(Vector2D::new(10, frame_height - 40), Vector2D::new(30, 30))
+}
 
-    // デスクトップ背景の描画
-    pixel_writer.fill_rectangle(
+/// デスクトップの背景とタスクバーを描くレイヤ。
+fn draw_desktop(writer: &dyn PixelWriter, area: &Rectangle) {
+    let theme = theme::current();
+    let frame_width = writer.config().horizontal_resolution as u32;
+    let frame_height = writer.config().vertical_resolution as u32;
+    let fill = |pos: Vector2D<u32>, size: Vector2D<u32>, color: &PixelColor| {
+        if let Some(rect) = Rectangle::new(pos, size).intersection(area) {
+            writer.fill_rectangle(rect.pos, rect.size, color);
+        }
+    };
+
+    // デスクトップ背景。斜めの細い縞で、わずかに模様を付ける。
+    // マウスカーソルやコンソールは背景の色で消すので、模様は目立たない程度にしておく。
+    // 画面全体を塗るので、まず地の色を行単位でまとめて塗り、縞のピクセルだけを後から置く。
+    const PATTERN_SIZE: u32 = 4;
+    if let Some(rect) = Rectangle::new(
         Vector2D::new(0, 0),
         Vector2D::new(frame_width, frame_height - 50),
-        &DESKTOP_BG_COLOR,
-    );
-    // タスクバーの表示
-    pixel_writer.fill_rectangle(
+    )
+    .intersection(area)
+    {
+        let end = rect.end();
+        if rect.pos.x() == 0 && rect.size.x() == frame_width {
+            writer.fill_rows(rect.pos.y(), rect.size.y(), &theme.background);
+        } else {
+            for y in rect.pos.y()..end.y() {
+                writer.fill_row(
+                    Vector2D::new(rect.pos.x(), y),
+                    rect.size.x(),
+                    &theme.background,
+                );
+            }
+        }
+        for y in rect.pos.y()..end.y() {
+            // 縞は右上がりの斜線で、x % 4 == 3 - y % 4 の位置に置く
+            let phase = PATTERN_SIZE - 1 - y % PATTERN_SIZE;
+            let first =
+                rect.pos.x() + (phase + PATTERN_SIZE - rect.pos.x() % PATTERN_SIZE) % PATTERN_SIZE;
+            for x in (first..end.x()).step_by(PATTERN_SIZE as usize) {
+                writer.write(Vector2D::new(x, y), &theme.background_texture);
+            }
+        }
+    }
+    // タスクバー
+    fill(
         Vector2D::new(0, frame_height - 50),
         Vector2D::new(frame_width, 50),
-        &PixelColor::new(1, 8, 17),
+        &theme.taskbar,
     );
+    // 角丸長方形は行ごとに切り抜きながら塗る
+    let fill_rounded = |rect: Rectangle, radius: u32, outline: bool, color: &PixelColor| {
+        graphics::rounded_rectangle_spans(&rect, radius, outline, |span| {
+            if let Some(span) = span.intersection(area) {
+                writer.fill_rectangle(span.pos, span.size, color);
+            }
+        });
+    };
     // （多分）Windows の検索窓
-    pixel_writer.fill_rectangle(
+    let search_box = Rectangle::new(
         Vector2D::new(0, frame_height - 50),
         Vector2D::new(frame_width / 5, 50),
-        &PixelColor::new(80, 80, 80),
     );
+    fill_rounded(search_box, 10, false, &theme.search_box);
+    fill_rounded(search_box, 10, true, &theme.accent);
     // （多分）Windows のスタートボタン
-    pixel_writer.fill_rectangle(
-        Vector2D::new(10, frame_height - 40),
-        Vector2D::new(30, 30),
-        &PixelColor::new(160, 160, 160),
-    );
+    fill_rounded(start_button(frame_height), 6, false, &theme.accent);
+    // デスクトップ右端の縦書きラベル。文字単位では切り抜かず、重なるときだけ描く。
+    let label = b"MikanOS";
+    let label_size = font::string_size(label.len(), TextOrientation::Rotate90);
+    let label_pos = Vector2D::new(frame_width - label_size.x() - 8, 8);
+    if Rectangle::new(label_pos, label_size)
+        .intersection(area)
+        .is_some()
+    {
+        font::write_string(
+            writer,
+            label_pos,
+            label,
+            &theme.foreground,
+            TextOrientation::Rotate90,
+        );
+    }
+}
 
-    // コンソールの生成
-    unsafe {
-        CONSOLE.get_or_init(|| Console::new(pixel_writer, &DESKTOP_FG_COLOR, &DESKTOP_BG_COLOR));
+#[no_mangle]
+pub extern "sysv64" fn kernel_entry(
+    frame_buffer_config: BootFrameBufferConfig,
+    boot_args: Option<&BootArgs>,
+    memory_map: Option<&'static BootMemoryMap>,
+    acpi_rsdp: Option<&'static acpi::Rsdp>,
+    runtime_services: Option<&'static runtime_services::RuntimeServices>,
+    app_image: Option<&app::AppImage>,
+) {
+    // ローダから渡されたものはすべて恒等写像の範囲にあるので、切り替えた後もそのまま読める
+    paging::setup_page_tables();
+    let mut frame_buffer_config = frame_buffer_config;
+    frame_buffer_config.frame_buffer =
+        paging::phys_to_virt(frame_buffer_config.frame_buffer as u64) as usize;
+
+    // 起動引数の解釈
+    // null が渡された場合は、起動引数無しとして扱う
+    let boot_args = boot_args.map_or(&[][..], BootArgs::as_bytes);
+    let boot_options = BootOptions::parse(boot_args);
+    panic_action::set_panic_action(boot_options.panic_action);
+    theme::set_current(boot_options.theme);
+    let theme = theme::current();
+
+    // シリアルポートの初期化
+    // フレームバッファより先に用意しておけば、ここから先はシリアル出力でデバッグできる
+    if boot_options.serial != Some(false) {
+        let serial = SerialPort::new(serial::COM1);
+        if !bool::from(serial.initialize()) {
+            let _ = SERIAL.set(SpinLock::new(serial));
+        }
     }
+    if boot_options.serial == Some(true) {
+        set_console_backend(ConsoleBackend::Both);
+    } else {
+        set_console_backend(ConsoleBackend::Graphics);
+    }
+    set_keyboard_layout(KeyboardLayout::UsEnglish);
+    // 何かを描く前に設定しておく
+    graphics::set_brightness(boot_options.brightness);
+
+    boot_phase::enter(BootPhase::Graphics);
+    // 画面が使えないときは、黙って止まらずにシリアルポートへ理由を出す
+    let Ok(pixel_format) = PixelFormat::try_from(frame_buffer_config.pixel_format) else {
+        write_to_sinks(
+            ConsoleBackend::Serial,
+            format_args!(
+                "unsupported pixel format {} ({}x{}), halting\n",
+                frame_buffer_config.pixel_format,
+                frame_buffer_config.horizontal_resolution,
+                frame_buffer_config.vertical_resolution
+            ),
+        );
+        halt();
+    };
+    let frame_buffer_config = FrameBufferConfig {
+        frame_buffer: frame_buffer_config.frame_buffer,
+        pixels_per_scan_line: frame_buffer_config.pixels_per_scan_line,
+        horizontal_resolution: frame_buffer_config.horizontal_resolution,
+        vertical_resolution: frame_buffer_config.vertical_resolution,
+        pixel_format,
+    };
+    // 行の長さが横幅より短いと、行どうしが重なって正しく描けない
+    if frame_buffer_config.pixels_per_scan_line < frame_buffer_config.horizontal_resolution {
+        write_to_sinks(
+            ConsoleBackend::Serial,
+            format_args!(
+                "pixels per scan line {} is less than the width {}, halting\n",
+                frame_buffer_config.pixels_per_scan_line, frame_buffer_config.horizontal_resolution
+            ),
+        );
+        halt();
+    }
+    let pixel_writer: Result<&mut dyn PixelWriter, usize> = match frame_buffer_config.pixel_format {
+        PixelFormat::Rgb => unsafe {
+            new_mut_with_buf(
+                RgbResv8BitPerColorPixelWriter::new(frame_buffer_config),
+                &mut PIXEL_WRITER_BUF,
+            )
+            .map(|writer| writer as &mut dyn PixelWriter)
+        },
+        PixelFormat::Bgr => unsafe {
+            new_mut_with_buf(
+                BgrResv8BitPerColorPixelWriter::new(frame_buffer_config),
+                &mut PIXEL_WRITER_BUF,
+            )
+            .map(|writer| writer as &mut dyn PixelWriter)
+        },
+    };
+    let pixel_writer = match pixel_writer {
+        Ok(writer) => writer,
+        Err(size) => {
+            write_to_sinks(
+                ConsoleBackend::Serial,
+                format_args!(
+                    "pixel writer needs {} bytes but PIXEL_WRITER_BUF has {}, halting\n",
+                    size, PIXEL_WRITER_SIZE
+                ),
+            );
+            halt();
+        }
+    };
+
+    let frame_width = pixel_writer.config().horizontal_resolution as u32;
+    let frame_height = pixel_writer.config().vertical_resolution as u32;
+
+    render::set_max_fps(boot_options.max_fps);
+    // デスクトップの描画
+    // 最初の 1 回はコンソールより先に描く必要があるので、間隔に関わらずすぐ描画する
+    // F1 で開くデモのダイアログと、スタートボタンで開くメニューは、デスクトップの上に重ねる
+    for layer in [
+        draw_desktop as render::RenderFn,
+        tui::draw_start_menu,
+        tui::draw_dialog,
+    ] {
+        let err = render::add_layer(layer);
+        if (&err).into() {
+            halt();
+        }
+    }
+    // Ctrl + Alt + L で表示するログのオーバーレイは、すべての上に重ねる
+    // 画面が小さくて置けなければ使えないだけなので、起動は続ける
+    let _ = log_overlay::initialize(Vector2D::new(frame_width, frame_height));
+    render::request_redraw(Rectangle::new(
+        Vector2D::new(0, 0),
+        Vector2D::new(frame_width, frame_height),
+    ));
+    // 起動時はデスクトップをシャドウバッファへ描き、黒からフェードインさせる
+    // シャドウバッファを置くメモリが無ければ、フェードせずに画面へ直接描く
+    graphics::set_fade_enabled(boot_options.fade);
+    let shadow = memory_map
+        .filter(|_| boot_options.fade)
+        .and_then(|map| {
+            let size = ShadowBuffer::size(pixel_writer.config()) as u64;
+            memory_map::reserve_conventional(map, size)
+        })
+        .map(|phys| unsafe {
+            ShadowBuffer::new(pixel_writer.config(), paging::phys_to_virt(phys) as usize)
+        });
+    // 画面全体を塗るのにかかった時間は、TSC を測定した後でログに出す
+    let desktop_start = unsafe { _rdtsc() };
+    match &shadow {
+        Some(shadow) => render::flush_now(shadow),
+        None => render::flush_now(pixel_writer),
+    }
+    let desktop_cycles = unsafe { _rdtsc() } - desktop_start;
+    if let Some(shadow) = &shadow {
+        shadow.fade_in(pixel_writer, BOOT_FADE_TICKS);
+    }
+    if boot_options.splash {
+        let _ = splash::draw_splash(pixel_writer);
+    }
+
+    // コンソールの生成
+    CONSOLE.get_or_init(|| SpinLock::new(Console::new(pixel_writer, theme)));
 
     // welcome 文
     printk!("Welcome to MikanOS!\n");
-    set_log_level(LogLevel::Warn);
+    set_log_level(boot_options.log_level);
+    if let Ok(args) = core::str::from_utf8(boot_args) {
+        log!(LogLevel::Info, "boot args: {}", args);
+    }
+    // セーフモードのコマンドからも読めるよう、先に覚えておく
+    if let Some(memory_map) = memory_map {
+        memory_map::set_boot_memory_map(memory_map);
+    }
+    // アプリはローダの領域に置かれているので、他の初期化より先にカーネル側へ写しておく
+    let err = app::initialize(app_image);
+    if (&err).into() && err.cause() != error::Code::NoSuchEntry {
+        log!(LogLevel::Warn, "app unavailable: {}", err);
+    }
+    if boot_options.safe_mode {
+        safe_mode::run();
+    }
+
+    // 以降の処理にかかる時間を測れるよう、最初に TSC を測定する
+    let frequency = timer::initialize_tsc();
+    log!(LogLevel::Debug, "TSC: {} Hz", frequency);
+    if let Some(us) = timer::tsc_to_us(desktop_cycles) {
+        log!(
+            LogLevel::Info,
+            "desktop draw: {}.{:03} ms",
+            us / 1000,
+            us % 1000
+        );
+    }
+
+    boot_phase::enter(BootPhase::Memory);
+    // メモリマップの表示
+    time_it!("memory map", {
+        if let Some(memory_map) = memory_map {
+            // 隣り合う同じ種類のエントリはまとめて 1 行にする
+            for range in memory_map::coalesce_memory_map(memory_map)
+                .filter(|range| boot_options.memory_map_filter.accepts(range.ty))
+            {
+                log!(LogLevel::Debug, "{}", range);
+            }
+            // プログレスバーのさらに下に描く
+            let pos = Vector2D::new(8, 16 * 25 + 28);
+            let size = Vector2D::new(frame_width - 16, 36);
+            if pos.y() + size.y() <= frame_height - 50 {
+                memory_map::draw_memory_map(pixel_writer, memory_map, pos, size, &theme.foreground);
+            }
+        }
+    });
+    cpu::log_features();
+    log!(
+        LogLevel::Info,
+        "paging: {} GiB mapped at 0 and {:#x}",
+        paging::mapped_size() >> 30,
+        paging::DIRECT_MAP_BASE
+    );
+    cpu::enable_memory_protection();
+    gdt::initialize();
+    // 以降の例外は UEFI のハンドラではなく、自前の IDT で受ける
+    interrupt::initialize();
+    {
+        let err = syscall::initialize();
+        if (&err).into() {
+            log!(LogLevel::Warn, "syscall unavailable: {}", err);
+        }
+    }
+    {
+        // FADT が無くてもリセットの別の手段があるので、起動は続ける
+        let err = acpi::initialize(acpi_rsdp);
+        if (&err).into() {
+            log!(LogLevel::Warn, "ACPI tables unavailable: {}", err);
+        }
+        let err = runtime_services::initialize(runtime_services);
+        if (&err).into() {
+            log!(LogLevel::Warn, "UEFI runtime services unavailable: {}", err);
+        }
+    }
+    boot_phase::enter(BootPhase::Interrupts);
+    // 用途の決まっているベクタを、他に割り当てられる前に押さえておく
+    for (vector, owner) in [
+        (interrupt::vector::XHCI, "xHC"),
+        (interrupt::vector::LAPIC_TIMER, "Local APIC timer"),
+        (interrupt::vector::SPURIOUS, "spurious"),
+    ] {
+        interrupt::register_vector(vector, owner);
+    }
+    if cpu::has_apic() {
+        if boot_options.x2apic {
+            lapic::enable_x2apic();
+        }
+        log!(
+            LogLevel::Debug,
+            "Local APIC: id={}, version={:#x}, x2APIC={}",
+            lapic::lapic_id(),
+            lapic::lapic_version(),
+            lapic::is_x2apic_mode()
+        );
+        lapic::enable(interrupt::vector::SPURIOUS);
+        let frequency = timer::initialize_lapic_timer();
+        log!(LogLevel::Debug, "Local APIC timer: {} Hz", frequency);
+    }
+    // ファームウェアの時計が使えればそちらを信じ、使えなければ CMOS の RTC を直接読む
+    let mut boot_time = runtime_services::get_time();
+    if (&boot_time.error()).into() {
+        boot_time = rtc::read_datetime();
+    }
+    if (&boot_time.error()).into() {
+        log!(
+            LogLevel::Warn,
+            "boot time unavailable: {}",
+            boot_time.error()
+        );
+    } else {
+        log!(LogLevel::Info, "boot time: {}", boot_time.value());
+    }
 
     // マウスカーソルの生成
-    unsafe {
-        MOUSE_CURSOR.get_or_init(|| {
-            MouseCursor::new(pixel_writer, DESKTOP_BG_COLOR, Vector2D::new(300, 200))
-        });
+    MOUSE_CURSOR.get_or_init(|| {
+        SpinLock::new(MouseCursor::new(
+            pixel_writer,
+            theme.background,
+            Vector2D::new(300, 200),
+        ))
+    });
+    mouse::set_double_click_ms(boot_options.double_click_ms);
+    keyboard::set_key_repeat(
+        boot_options.key_repeat_delay_ms,
+        boot_options.key_repeat_interval_ms,
+    );
+    if boot_options.cursor_scale != 1 {
+        if let Some(mut cursor) = MOUSE_CURSOR.lock() {
+            cursor.set_scale(boot_options.cursor_scale);
+        }
     }
 
+    boot_phase::enter(BootPhase::Pci);
     // デバイス一覧の表示
-    let err = pci::scan_all_bus();
+    let err = time_it!("PCI scan", { pci::scan_all_bus() });
     log!(LogLevel::Debug, "scan_all_bus: {}", err);
 
-    let mut xhc_dev = None;
+    // コンソールの直下に探索の進み具合を出す
+    // 一覧は xHC を初期化した後に、USB のポートと合わせて print_device_tree で表示する
+    let xhc_dev = {
+        let mut progress = ProgressBar::new(
+            pixel_writer,
+            Vector2D::new(8, 16 * 25 + 8),
+            Vector2D::new(200, 12),
+            theme.foreground,
+            theme.background,
+        );
+        let xhc_dev = find_xhc(&mut progress);
+        progress.set_progress(100);
+        xhc_dev
+    };
+
+    boot_phase::enter(BootPhase::Usb);
+    // xHC が無くても、シリアルポートからの入力は受け付けられるようメインループへ進む
+    match xhc_dev {
+        _ if !boot_options.usb => log!(LogLevel::Warn, "USB is disabled by the boot args"),
+        None => log!(
+            LogLevel::Warn,
+            "no xHC found, USB is disabled and the PS/2 keyboard is used instead"
+        ),
+        Some(xhc_dev) => time_it!("xHC initialization", { start_xhc(&xhc_dev) }),
+    }
+    introspect::print_device_tree();
+    if boot_options.run_app {
+        run_app();
+    }
+    if boot_options.user_test {
+        let result = syscall::run_user_stub();
+        if (&result.error()).into() {
+            log!(LogLevel::Warn, "user mode: {}", result.error());
+        } else {
+            log!(LogLevel::Info, "user mode: exited with {}", result.value());
+        }
+    }
+    if let Some(exit) = boot_options.self_test {
+        selftest::run(exit);
+    }
+
+    // F2 のプロファイラの結果で、番地を関数名に読み替えられるようにしておく
+    for (name, addr) in [
+        ("kernel_entry", kernel_entry as *const () as u64),
+        ("draw_desktop", draw_desktop as *const () as u64),
+        ("render::flush", render::flush as *const () as u64),
+        (
+            "irq_log::flush_deferred_logs",
+            irq_log::flush_deferred_logs as *const () as u64,
+        ),
+        ("poll_serial_input", poll_serial_input as *const () as u64),
+        (
+            "message::wait_for_message",
+            message::wait_for_message as *const () as u64,
+        ),
+    ] {
+        profiler::register_symbol(name, addr);
+    }
+
+    // ここまでは割り込みを止めたまま初期化し、メインループに入る直前でタイマ割り込みを始める
     {
-        let devices = pci::DEVICES.lock();
-        let devices = devices.borrow();
-        let num_devices = *pci::NUM_DEVICES.lock().borrow();
-        for i in 0..num_devices {
-            let dev = devices[i].unwrap();
-            let vendor_id = pci::read_vendor_id(dev.bus(), dev.device(), dev.function());
-            let class_code = pci::read_class_code(dev.bus(), dev.device(), dev.function());
-            log!(
-                LogLevel::Debug,
-                "{}.{}.{}: vend {:04x}, class {:08x}, head {:02x}",
-                dev.bus(),
-                dev.device(),
-                dev.function(),
-                vendor_id,
-                class_code,
-                dev.header_type()
-            );
+        let err = timer::start_tick();
+        if (&err).into() {
+            log!(LogLevel::Warn, "timer interrupt unavailable: {}", err);
+        }
+        interrupt::enable();
+    }
+
+    // xHC が使えない間は、PS/2 キーボードを読む
+    let mut ps2_keyboard = Ps2Keyboard::new();
+    boot_phase::enter(BootPhase::Ready);
+    loop {
+        watchdog::watchdog_kick();
+        irq_log::flush_deferred_logs();
+
+        if let Some(mut xhc) = XHC.lock().filter(|_| xhc_enabled()) {
+            // 割り込みハンドラがまだ無いので、イベントが届いていたら割り込みの代わりに知らせる
+            // キューが満杯でもイベントはイベントリングに残るので、次の周回で処理される
+            if xhc.has_pending_event() {
+                trace!(TraceEvent::Interrupt(interrupt::vector::XHCI));
+                let _ = message::push_message(Message::InterruptXHCI);
+            }
+            let err = xhc.process_secondary_event();
+            if err.cause() == error::Code::HostControllerError {
+                recover_xhc(&mut xhc);
+            } else if (&err).into() {
+                log!(
+                    LogLevel::Error,
+                    "Error while process_secondary_event: {}",
+                    err
+                );
+            }
         }
 
-        // Intel 製を優先して xHC を探す
-        for i in 0..num_devices {
-            if devices[i].unwrap().class_code().r#match(0x0c, 0x03, 0x30) {
-                xhc_dev = devices[i];
+        if !xhc_enabled() {
+            poll_ps2_keyboard(&mut ps2_keyboard);
+        }
+        poll_serial_input();
 
-                if 0x8086 == xhc_dev.unwrap().read_vendor_id() {
-                    break;
+        while let Some(msg) = message::pop_message() {
+            match msg {
+                Message::Key {
+                    event,
+                    ascii,
+                    source,
+                    ..
+                } => {
+                    // 離したときと修飾キーだけの変化は、状態を更新するだけで何もしない
+                    if !event.pressed || event.is_modifier() {
+                        continue;
+                    }
+                    let (modifier, keycode) = (event.modifiers, event.key);
+                    if event.is_ctrl_chord(keyboard::KEY_L) && event.alt() {
+                        log_overlay::toggle();
+                        continue;
+                    }
+                    if handle_shell_chord(&event, ascii) {
+                        continue;
+                    }
+                    if event.is_ctrl_chord(keyboard::KEY_V) || ascii == 0x16 {
+                        paste_clipboard(source);
+                        continue;
+                    }
+                    if move_cursor_by_key(modifier, keycode) {
+                        continue;
+                    }
+                    if tui::handle_key(modifier, keycode, ascii) {
+                        continue;
+                    }
+                    if keycode == keyboard::KEY_CAPS_LOCK {
+                        keyboard::toggle_caps_lock();
+                        continue;
+                    }
+                    if keycode == keyboard::KEY_F1 {
+                        tui::open_demo_dialog(Vector2D::new(frame_width, frame_height));
+                        continue;
+                    }
+                    if keycode == keyboard::KEY_F2 {
+                        toggle_profiler();
+                        continue;
+                    }
+                    if scroll_console(modifier, keycode) {
+                        continue;
+                    }
+                    if ascii != 0 {
+                        printk!("{}", ascii as char);
+                        // 端末は自分では表示しないので、画面にしか出していなければ送り返す
+                        if source == InputSource::Serial && !console::get_console_backend().serial()
+                        {
+                            echo_serial(ascii);
+                        }
+                    }
+                }
+                Message::MouseClick { x, y, .. } => {
+                    let pos = Vector2D::new(x, y);
+                    // メニューやダイアログの上のクリックでは、下のコンソールの選択を始めない
+                    let on_popup = tui::covers(pos);
+                    if let Some(action) = tui::handle_click(pos, &start_button(frame_height)) {
+                        run_menu_action(action);
+                    } else if !on_popup {
+                        if let Some(mut console) = CONSOLE.lock() {
+                            console.start_selection(pos);
+                        }
+                    }
+                }
+                Message::MouseDrag { x, y, .. } => {
+                    // 続けて溜まっていた分は、最後の位置だけを使えばよい
+                    let pos = match message::pop_latest_of_kind(&msg) {
+                        Some(Message::MouseDrag { x, y, .. }) => Vector2D::new(x, y),
+                        _ => Vector2D::new(x, y),
+                    };
+                    if let Some(mut console) = CONSOLE.lock() {
+                        console.extend_selection(pos);
+                    }
+                }
+                Message::MouseRelease { x, y, .. } => copy_selection(Vector2D::new(x, y)),
+                Message::DoubleClick { x, y, .. } => {
+                    log!(LogLevel::Debug, "double click at ({}, {})", x, y);
+                }
+                Message::InterruptXHCI => {
+                    // 1 回でイベントリングを空にするので、続けて届いていた分はまとめて捨てる
+                    let coalesced = message::pop_duplicates(&msg);
+                    if coalesced > 0 {
+                        let total = COALESCED_XHCI_INTERRUPTS
+                            .fetch_add(coalesced, Ordering::Relaxed)
+                            + coalesced;
+                        log!(
+                            LogLevel::Debug,
+                            "coalesced {} xHCI interrupts ({} in total)",
+                            coalesced,
+                            total
+                        );
+                    }
+                    if let Some(mut xhc) = XHC.lock().filter(|_| xhc_enabled()) {
+                        let drained = xhc.drain_events(MAX_XHCI_EVENTS_PER_BATCH);
+                        let err = drained.error();
+                        if err.cause() == error::Code::HostControllerError {
+                            recover_xhc(&mut xhc);
+                        } else if (&err).into() {
+                            log!(LogLevel::Error, "Error while process_event: {}", err);
+                        }
+                    }
                 }
             }
         }
+        render::flush(pixel_writer);
 
-        if xhc_dev.is_none() {
-            log!(LogLevel::Error, "There is no xHC devices.");
-            halt();
+        // xHC、シリアルポート、PS/2 キーボードはどれもポーリングで読むので、xHC かシリアルポートが
+        // あるうちは休まずに回る。どちらも無く PS/2 キーボードだけを読むときも、休んだ後にまた読めるよう、
+        // タイマ割り込みで周期的に起きられるときだけ、メッセージが届くまで CPU を休ませる
+        if !xhc_enabled() && SERIAL.get().is_none() && timer::is_ticking() {
+            // 処理の間に割り込みハンドラが溜めたログを、休む前に出し切る
+            irq_log::flush_deferred_logs();
+            message::wait_for_message();
+        }
+    }
+
+    halt();
+}
+
+/// xHC のポートがどの USB のバージョンに対応するかを、同じバージョンの範囲ごとにログに出す。
+fn log_port_protocols(xhc: &Controller) {
+    let max_ports = xhc.max_ports();
+    let mut first = 1;
+    while first <= max_ports {
+        let revision = xhc.port_revision(first);
+        let mut last = first;
+        while last < max_ports && xhc.port_revision(last + 1) == revision {
+            last += 1;
         }
+        match revision {
+            Some((major, minor)) => log!(
+                LogLevel::Info,
+                "xHC ports {}-{}: USB {:x}.{:02x}",
+                first,
+                last,
+                major,
+                minor
+            ),
+            None => log!(
+                LogLevel::Warn,
+                "xHC ports {}-{}: no supported protocol",
+                first,
+                last
+            ),
+        }
+        if last == max_ports {
+            break;
+        }
+        first = last + 1;
     }
+}
 
-    let xhc_dev = xhc_dev.unwrap();
+/// [pci::scan_all_bus] で見つけたデバイスから xHC を探す。Intel 製があればそれを選ぶ。
+///
+/// 探した割合を `progress` に出す。見つからなければ None を返す。
+fn find_xhc(progress: &mut ProgressBar) -> Option<Device> {
+    let devices = pci::DEVICES.lock();
+    let devices = devices.borrow();
+    let num_devices = *pci::NUM_DEVICES.lock().borrow();
+    let mut xhc_dev = None;
+    for (i, dev) in devices[..num_devices].iter().flatten().enumerate() {
+        progress.set_progress((100 * (i + 1) / num_devices) as u8);
+        if dev.class_code().r#match(0x0c, 0x03, 0x30) {
+            xhc_dev = Some(*dev);
+            if 0x8086 == dev.read_vendor_id() {
+                break;
+            }
+        }
+    }
+    xhc_dev
+}
+
+/// xHC を初期化して動かし、接続済みのポートを設定する。
+fn start_xhc(xhc_dev: &Device) {
     log!(
         LogLevel::Info,
         "xHC has been found: {}.{}.{}",
@@ -222,24 +1270,75 @@ pub extern "sysv64" fn kernel_entry(frame_buffer_config: FrameBufferConfig) {
     // xHC の BAR から情報を得る
     let xhc_bar = xhc_dev.read_bar(0);
     log!(LogLevel::Debug, "ReadBar: {}", xhc_bar.error());
-    let xhc_mmio_base = *xhc_bar.value() & !0xf;
+    let xhc_mmio_base = paging::phys_to_virt(*xhc_bar.value() & !0xf);
     log!(LogLevel::Debug, "xHC mmio_base = {:08x}", xhc_mmio_base);
+    if get_log_level() >= LogLevel::Debug {
+        dump_xhc_capability_registers(xhc_mmio_base);
+    }
 
-    let mut xhc = Controller::new(xhc_mmio_base);
+    // 初期化が終わるまではロックを持ち続ける。イベントの処理ではオブザーバから
+    // 他のグローバル変数を触るが、XHC は触らないので、ロックを持ったままでよい。
+    let mut xhc = XHC
+        .get_or_init(|| SpinLock::new(Controller::new(xhc_mmio_base)))
+        .lock();
 
     if xhc_dev.read_vendor_id() == 0x8086 {
-        switch_ehci2xhci(&xhc_dev);
+        switch_ehci2xhci(xhc_dev);
     }
     {
         let err = xhc.initialize();
         log!(LogLevel::Debug, "xhc.initialize: {}", err);
+        if (&err).into() {
+            log!(LogLevel::Error, "failed to initialize xHC: {}", err);
+        }
     }
+    log_port_protocols(&xhc);
 
     log!(LogLevel::Info, "xHC starting");
-    xhc.run();
+    {
+        let err = xhc.run();
+        if (&err).into() {
+            log!(LogLevel::Error, "failed to run xHC: {}", err);
+        }
+    }
+    log!(
+        LogLevel::Debug,
+        "secondary event ring: {}",
+        xhc.secondary_event_ring().is_some()
+    );
+    {
+        // コマンドリングとイベントリングが動いているか確認する
+        let handle = xhc.issue_no_op_command();
+        let code = xhc.wait_command(handle, wait::DEFAULT_POLL_LIMIT);
+        if (&code.error()).into() {
+            log!(LogLevel::Warn, "No Op command: {}", code.error());
+        } else {
+            log!(
+                LogLevel::Debug,
+                "No Op command completed: code {}",
+                code.value()
+            );
+        }
+    }
 
     HIDMouseDriver::set_default_observer(mouse_observer);
+    HIDKeyboardDriver::set_default_observer(keyboard_observer);
+    usb::for_each_class_driver(|class, name| {
+        log!(
+            LogLevel::Debug,
+            "class driver {:02x}.{:02x}.{:02x}: {}",
+            class.class,
+            class.sub_class,
+            class.protocol,
+            name
+        );
+    });
 
+    configure_ports(&mut xhc);
+}
+
+/// 接続されているポートを設定する。
+fn configure_ports(xhc: &mut Controller) {
     for i in 1..=xhc.max_ports() {
         let mut port = xhc.port_at(i);
         log!(
@@ -255,25 +1354,97 @@ pub extern "sysv64" fn kernel_entry(frame_buffer_config: FrameBufferConfig) {
                 log!(LogLevel::Error, "failed to configure port: {}", err);
                 continue;
             }
+            trace!(TraceEvent::PortConfigured(i));
         }
     }
+}
 
-    loop {
-        let err = xhc.process_event();
+/// xHC が初期化済みで、まだ諦めていなければ true を返す。
+fn xhc_enabled() -> bool {
+    XHC.get().is_some() && !XHC_DISABLED.load(Ordering::Relaxed)
+}
+
+/// 致命的なエラーで止まった xHC を初期化し直す。
+///
+/// 失敗したときや、[MAX_XHC_REINIT] 回やり直しても止まるときは、割り込みを止めて xHC を諦める。
+fn recover_xhc(xhc: &mut Controller) {
+    let attempt = XHC_REINIT_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    if attempt <= MAX_XHC_REINIT {
+        log!(
+            LogLevel::Warn,
+            "xHC halted with a fatal error, reinitializing ({}/{})",
+            attempt,
+            MAX_XHC_REINIT
+        );
+        let err = xhc.reinitialize();
         if (&err).into() {
-            log!(LogLevel::Error, "Error while process_event: {}", err);
+            log!(LogLevel::Error, "failed to reinitialize xHC: {}", err);
+        } else {
+            configure_ports(xhc);
+            return;
         }
     }
 
-    halt();
+    xhc.disable_interrupt();
+    let _ = xhc.stop();
+    XHC_DISABLED.store(true, Ordering::Relaxed);
+    log!(
+        LogLevel::Error,
+        "xHC disabled after {} reinitialization attempts; USB devices are unavailable",
+        attempt.min(MAX_XHC_REINIT)
+    );
 }
 
 #[panic_handler]
-fn panic(_: &PanicInfo) -> ! {
+fn panic(info: &PanicInfo) -> ! {
+    // 止まるまでの間に、タイマ割り込みなどが割り込んでこないようにする
+    interrupt::disable();
+    // 出力先の設定に関わらず、使えるものすべてに出す
+    write_to_sinks(ConsoleBackend::Both, format_args!("{}\n", info));
+    match boot_phase::current() {
+        Some(BootPhase::Ready) => {}
+        Some(phase) => {
+            write_to_sinks(
+                ConsoleBackend::Both,
+                format_args!("panicked during {} init\n", phase),
+            );
+        }
+        None => {
+            write_to_sinks(ConsoleBackend::Both, format_args!("panicked before boot\n"));
+        }
+    }
+
+    // xHC が動いたままだと、停止後も DMA でメモリを書き換えられてしまう
+    if let Some(xhc) = XHC.get() {
+        // パニックした処理がロックを持っていても、そこへはもう戻らないので構わず止める
+        if xhc.is_locked() {
+            unsafe { xhc.force_unlock() };
+        }
+        let err = xhc.lock().stop();
+        if (&err).into() {
+            write_to_sinks(
+                ConsoleBackend::Both,
+                format_args!("failed to stop xHC: {}\n", err),
+            );
+        }
+    }
+    match panic_action::panic_action() {
+        PanicAction::Halt => {}
+        PanicAction::Reboot => panic_action::reboot(),
+        PanicAction::Monitor => {
+            if let Some(serial) = SERIAL.get() {
+                if serial.is_locked() {
+                    unsafe { serial.force_unlock() };
+                }
+                panic_action::run_monitor(&mut serial.lock());
+            }
+        }
+    }
     halt()
 }
 
 fn halt() -> ! {
+    interrupt::disable();
     loop {
         unsafe {
             asm!("hlt");
@@ -0,0 +1,333 @@
+#![allow(unused)]
+
+use core::fmt;
+
+use spin::Mutex;
+
+use crate::{
+    font::{self, TextOrientation},
+    graphics::{PixelColor, PixelWriter, Vector2D},
+    paging,
+    sync::OnceLock,
+};
+
+/// メモリマップのエントリの最大数。ローダ側の定義と合わせること。
+pub(crate) const MEMORY_MAP_MAX_ENTRIES: usize = 256;
+
+/// UEFI のページの大きさ（バイト）
+pub(crate) const UEFI_PAGE_SIZE: u64 = 4096;
+
+/// UEFI のメモリタイプ。
+pub(crate) mod memory_type {
+    pub(crate) const RESERVED: u32 = 0;
+    pub(crate) const LOADER_CODE: u32 = 1;
+    pub(crate) const LOADER_DATA: u32 = 2;
+    pub(crate) const BOOT_SERVICES_CODE: u32 = 3;
+    pub(crate) const BOOT_SERVICES_DATA: u32 = 4;
+    pub(crate) const RUNTIME_SERVICES_CODE: u32 = 5;
+    pub(crate) const RUNTIME_SERVICES_DATA: u32 = 6;
+    pub(crate) const CONVENTIONAL: u32 = 7;
+    pub(crate) const UNUSABLE: u32 = 8;
+    pub(crate) const ACPI_RECLAIM: u32 = 9;
+    pub(crate) const ACPI_NON_VOLATILE: u32 = 10;
+    pub(crate) const MMIO: u32 = 11;
+    pub(crate) const MMIO_PORT_SPACE: u32 = 12;
+    pub(crate) const PAL_CODE: u32 = 13;
+    pub(crate) const PERSISTENT_MEMORY: u32 = 14;
+}
+
+/// メモリタイプの名前を返す。
+pub(crate) const fn memory_type_name(ty: u32) -> &'static str {
+    match ty {
+        memory_type::RESERVED => "EfiReservedMemoryType",
+        memory_type::LOADER_CODE => "EfiLoaderCode",
+        memory_type::LOADER_DATA => "EfiLoaderData",
+        memory_type::BOOT_SERVICES_CODE => "EfiBootServicesCode",
+        memory_type::BOOT_SERVICES_DATA => "EfiBootServicesData",
+        memory_type::RUNTIME_SERVICES_CODE => "EfiRuntimeServicesCode",
+        memory_type::RUNTIME_SERVICES_DATA => "EfiRuntimeServicesData",
+        memory_type::CONVENTIONAL => "EfiConventionalMemory",
+        memory_type::UNUSABLE => "EfiUnusableMemory",
+        memory_type::ACPI_RECLAIM => "EfiACPIReclaimMemory",
+        memory_type::ACPI_NON_VOLATILE => "EfiACPIMemoryNVS",
+        memory_type::MMIO => "EfiMemoryMappedIO",
+        memory_type::MMIO_PORT_SPACE => "EfiMemoryMappedIOPortSpace",
+        memory_type::PAL_CODE => "EfiPalCode",
+        memory_type::PERSISTENT_MEMORY => "EfiPersistentMemory",
+        _ => "InvalidMemoryType",
+    }
+}
+
+/// メモリマップの 1 エントリ。
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub(crate) struct MemoryMapEntry {
+    /// UEFI のメモリタイプ（[memory_type]）
+    pub(crate) ty: u32,
+    pub(crate) phys_start: u64,
+    pub(crate) page_count: u64,
+}
+
+/// ローダから渡される、ブートサービス終了時点のメモリマップ。
+#[repr(C)]
+pub struct BootMemoryMap {
+    len: usize,
+    entries: [MemoryMapEntry; MEMORY_MAP_MAX_ENTRIES],
+}
+
+impl BootMemoryMap {
+    pub(crate) fn entries(&self) -> &[MemoryMapEntry] {
+        &self.entries[..self.len.min(MEMORY_MAP_MAX_ENTRIES)]
+    }
+}
+
+/// ローダから渡されたメモリマップ。起動後のコマンドからも読めるよう覚えておく。
+static BOOT_MEMORY_MAP: OnceLock<&'static BootMemoryMap> = OnceLock::new();
+
+/// ローダから渡されたメモリマップを覚えておく。
+pub(crate) fn set_boot_memory_map(map: &'static BootMemoryMap) {
+    let _ = BOOT_MEMORY_MAP.set(map);
+}
+
+/// [set_boot_memory_map] で覚えたメモリマップを返す。
+pub(crate) fn boot_memory_map() -> Option<&'static BootMemoryMap> {
+    BOOT_MEMORY_MAP.get().copied()
+}
+
+/// 物理アドレスの連続した、同じメモリタイプの範囲。[coalesce_memory_map] で作る。
+#[derive(Clone, Copy)]
+pub(crate) struct MemoryRange {
+    /// UEFI のメモリタイプ（[memory_type]）
+    pub(crate) ty: u32,
+    pub(crate) start: u64,
+    /// 範囲の終わり（この番地は含まない）
+    pub(crate) end: u64,
+}
+
+impl MemoryRange {
+    pub(crate) const fn pages(&self) -> u64 {
+        (self.end - self.start) / UEFI_PAGE_SIZE
+    }
+}
+
+impl fmt::Display for MemoryRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:#012x} - {:#012x}: {} ({} pages)",
+            self.start,
+            self.end,
+            memory_type_name(self.ty),
+            self.pages()
+        )
+    }
+}
+
+/// メモリマップの表示で、どの範囲を出すか。
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub(crate) enum MemoryMapFilter {
+    /// すべて
+    All,
+    /// 空いているか、起動後に再利用できるものだけ
+    Usable,
+}
+
+impl MemoryMapFilter {
+    /// `all` か `usable` を解釈する。
+    pub(crate) fn parse(name: &[u8]) -> Option<Self> {
+        match name {
+            b"all" => Some(Self::All),
+            b"usable" => Some(Self::Usable),
+            _ => None,
+        }
+    }
+
+    /// メモリタイプ `ty` の範囲を出すなら true を返す。
+    pub(crate) const fn accepts(self, ty: u32) -> bool {
+        match self {
+            Self::All => true,
+            Self::Usable => matches!(
+                MemoryCategory::from_type(ty),
+                MemoryCategory::Usable | MemoryCategory::BootServices
+            ),
+        }
+    }
+}
+
+/// メモリマップのエントリのうち、隣り合っていて種類の同じものを 1 つの範囲にまとめて返す。
+///
+/// まとめるのはマップ上で並んでいるエントリどうしだけで、並べ替えはしない。
+/// 間に穴があれば、同じ種類でも別の範囲になる。
+pub(crate) fn coalesce_memory_map(map: &BootMemoryMap) -> impl Iterator<Item = MemoryRange> + '_ {
+    let mut entries = map.entries().iter().peekable();
+    core::iter::from_fn(move || {
+        let first = entries.next()?;
+        let mut range = MemoryRange {
+            ty: first.ty,
+            start: first.phys_start,
+            end: first.phys_start + first.page_count * UEFI_PAGE_SIZE,
+        };
+        while let Some(next) =
+            entries.next_if(|next| next.ty == range.ty && next.phys_start == range.end)
+        {
+            range.end += next.page_count * UEFI_PAGE_SIZE;
+        }
+        Some(range)
+    })
+}
+
+/// [reserve_conventional] で切り出した範囲の終わり（物理アドレス）。ここより下は切り出さない。
+///
+/// 1 MiB より下はレガシーな領域が混じるので、最初から避けておく。
+static RESERVED_END: Mutex<u64> = Mutex::new(0x10_0000);
+
+/// 空いているメモリ（[memory_type::CONVENTIONAL]）から、`size` バイトをページ単位で切り出す。
+///
+/// カーネルは空いているメモリを他に使わないので、切り出した範囲は自由に使ってよい。解放はできない。
+/// [paging::mapped_size] までの、直接写像で読み書きできる範囲からだけ切り出す。
+/// 切り出した先頭の物理アドレスを返す。足りなければ None を返す。
+pub(crate) fn reserve_conventional(map: &BootMemoryMap, size: u64) -> Option<u64> {
+    let size = size.next_multiple_of(UEFI_PAGE_SIZE);
+    let limit = paging::mapped_size();
+    let mut reserved_end = RESERVED_END.lock();
+    let start = coalesce_memory_map(map)
+        .filter(|range| range.ty == memory_type::CONVENTIONAL)
+        .find_map(|range| {
+            let start = range.start.max(*reserved_end);
+            (start + size <= range.end.min(limit)).then_some(start)
+        })?;
+    *reserved_end = start + size;
+    Some(start)
+}
+
+/// 可視化のために、メモリタイプをおおまかに分けたもの。
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum MemoryCategory {
+    /// 空いていて自由に使える
+    Usable,
+    /// ローダやブートサービスが使っていた。カーネルの起動後は再利用できる。
+    BootServices,
+    /// ファームウェアのランタイムサービスが使う
+    RuntimeServices,
+    /// ACPI のテーブルや NVS
+    Acpi,
+    /// MMIO 領域
+    Mmio,
+    /// 予約済み、使用不可、不明なもの
+    Reserved,
+}
+
+impl MemoryCategory {
+    const ALL: [Self; 6] = [
+        Self::Usable,
+        Self::BootServices,
+        Self::RuntimeServices,
+        Self::Acpi,
+        Self::Mmio,
+        Self::Reserved,
+    ];
+
+    const fn from_type(ty: u32) -> Self {
+        match ty {
+            memory_type::CONVENTIONAL | memory_type::PERSISTENT_MEMORY => Self::Usable,
+            memory_type::LOADER_CODE
+            | memory_type::LOADER_DATA
+            | memory_type::BOOT_SERVICES_CODE
+            | memory_type::BOOT_SERVICES_DATA => Self::BootServices,
+            memory_type::RUNTIME_SERVICES_CODE | memory_type::RUNTIME_SERVICES_DATA => {
+                Self::RuntimeServices
+            }
+            memory_type::ACPI_RECLAIM | memory_type::ACPI_NON_VOLATILE => Self::Acpi,
+            memory_type::MMIO | memory_type::MMIO_PORT_SPACE => Self::Mmio,
+            _ => Self::Reserved,
+        }
+    }
+
+    const fn color(self) -> PixelColor {
+        match self {
+            Self::Usable => PixelColor::new(0, 170, 0),
+            Self::BootServices => PixelColor::new(170, 170, 0),
+            Self::RuntimeServices => PixelColor::new(170, 0, 170),
+            Self::Acpi => PixelColor::new(0, 0, 170),
+            Self::Mmio => PixelColor::new(128, 128, 128),
+            Self::Reserved => PixelColor::new(170, 0, 0),
+        }
+    }
+
+    const fn label(self) -> &'static [u8] {
+        match self {
+            Self::Usable => b"usable",
+            Self::BootServices => b"boot",
+            Self::RuntimeServices => b"runtime",
+            Self::Acpi => b"acpi",
+            Self::Mmio => b"mmio",
+            Self::Reserved => b"reserved",
+        }
+    }
+}
+
+/// 凡例 1 行分の高さ
+const LEGEND_HEIGHT: u32 = 16;
+/// バーと凡例の間隔
+const LEGEND_MARGIN: u32 = 4;
+/// 凡例の色見本の大きさ
+const LEGEND_SWATCH_SIZE: u32 = 8;
+
+/// メモリマップを横向きの帯グラフとして、`pos` と `size` の矩形へ描画する。
+///
+/// エントリをマップ上の順に並べ、ページ数に比例した幅でメモリの種類ごとの色に塗る。
+/// エントリ間の穴は詰めるので、横軸は物理アドレスではなくページ数の累計になる。
+/// 矩形の下端 [LEGEND_HEIGHT] ピクセルには凡例を `text_color` で描く。
+/// `size` の高さは [LEGEND_HEIGHT] + [LEGEND_MARGIN] より大きいこと。
+pub(crate) fn draw_memory_map(
+    writer: &dyn PixelWriter,
+    map: &BootMemoryMap,
+    pos: Vector2D<u32>,
+    size: Vector2D<u32>,
+    text_color: &PixelColor,
+) {
+    let bar_height = size.y() - LEGEND_HEIGHT - LEGEND_MARGIN;
+    let total_pages: u64 = map.entries().iter().map(|e| e.page_count).sum();
+    let width = size.x() as u64;
+    // 累計ページ数から両端の位置を決めることで、丸め誤差が溜まらないようにする
+    let page_to_x = |pages: u64| (pages * width).checked_div(total_pages).unwrap_or(0) as u32;
+    let mut pages = 0;
+    for entry in map.entries() {
+        let x0 = page_to_x(pages);
+        pages += entry.page_count;
+        let x1 = page_to_x(pages);
+        if x1 > x0 {
+            writer.fill_rectangle(
+                pos + Vector2D::new(x0, 0),
+                Vector2D::new(x1 - x0, bar_height),
+                &MemoryCategory::from_type(entry.ty).color(),
+            );
+        }
+    }
+
+    // 凡例
+    let legend_y = size.y() - LEGEND_HEIGHT;
+    let swatch_offset = (LEGEND_HEIGHT - LEGEND_SWATCH_SIZE) / 2;
+    let mut x = 0;
+    for category in MemoryCategory::ALL {
+        let label = category.label();
+        let label_size = font::string_size(label.len(), TextOrientation::Normal);
+        if x + LEGEND_SWATCH_SIZE + 4 + label_size.x() > size.x() {
+            break;
+        }
+        writer.fill_rectangle(
+            pos + Vector2D::new(x, legend_y + swatch_offset),
+            Vector2D::new(LEGEND_SWATCH_SIZE, LEGEND_SWATCH_SIZE),
+            &category.color(),
+        );
+        x += LEGEND_SWATCH_SIZE + 4;
+        font::write_string(
+            writer,
+            pos + Vector2D::new(x, legend_y),
+            label,
+            text_color,
+            TextOrientation::Normal,
+        );
+        x += label_size.x() + 12;
+    }
+}
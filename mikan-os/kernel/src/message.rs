@@ -0,0 +1,273 @@
+#![allow(unused)]
+
+use core::{
+    mem::size_of,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{cpu, error, keyboard::KeyEvent, make_error, timer, trace, trace::TraceEvent};
+
+/// 入力がどこから来たか。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum InputSource {
+    /// USB や PS/2 のキーボード
+    Local,
+    /// シリアルポートにつないだ端末
+    Serial,
+}
+
+/// メインループへ届けるイベント。種類ごとに必要なデータを持つ。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Message {
+    /// キーが押されたか離された
+    Key {
+        event: KeyEvent,
+        /// 現在のキー配列で対応する ASCII 文字。押されたとき以外と、文字が無ければ 0。
+        ascii: u8,
+        /// 入力元。シリアルからの入力ではキーコードは 0 になる。
+        source: InputSource,
+        /// 入力を受け取った時刻。[timestamp_now] の値。
+        timestamp: u32,
+    },
+    /// xHC のイベントリングにイベントが届いた
+    InterruptXHCI,
+    /// マウスの左ボタンが押された。位置はそのときのマウスカーソルの位置。
+    MouseClick { x: u32, y: u32, timestamp: u32 },
+    /// マウスの左ボタンが続けて 2 回押された。2 回目の [Message::MouseClick] の後に届く。
+    DoubleClick { x: u32, y: u32, timestamp: u32 },
+    /// マウスの左ボタンを押したまま動かした。位置は動いた後のマウスカーソルの位置。
+    MouseDrag { x: u32, y: u32, timestamp: u32 },
+    /// マウスの左ボタンが離された
+    MouseRelease { x: u32, y: u32, timestamp: u32 },
+}
+
+impl Message {
+    /// メッセージの種類を表す番号。トレースに記録するのに使う。
+    pub(crate) const fn kind(&self) -> u8 {
+        match self {
+            Self::Key { .. } => 0,
+            Self::InterruptXHCI => 1,
+            Self::MouseClick { .. } => 2,
+            Self::DoubleClick { .. } => 3,
+            Self::MouseDrag { .. } => 4,
+            Self::MouseRelease { .. } => 5,
+        }
+    }
+
+    /// 入力のイベントなら、それを受け取った時刻を返す。
+    pub(crate) const fn timestamp(&self) -> Option<u32> {
+        match *self {
+            Self::Key { timestamp, .. }
+            | Self::MouseClick { timestamp, .. }
+            | Self::DoubleClick { timestamp, .. }
+            | Self::MouseDrag { timestamp, .. }
+            | Self::MouseRelease { timestamp, .. } => Some(timestamp),
+            Self::InterruptXHCI => None,
+        }
+    }
+}
+
+/// 入力のイベントに付ける、今の時刻（ミリ秒）。
+///
+/// メッセージを小さく保つため [timer::uptime_ms] の下位 32 ビットだけを使う。約 49 日で一周するので、
+/// 2 つの時刻の間隔は [elapsed_ms] で求めること。
+pub(crate) fn timestamp_now() -> u32 {
+    timer::uptime_ms() as u32
+}
+
+/// 時刻 `since` から `now` までの経過時間（ミリ秒）。どちらも [timestamp_now] の値。
+pub(crate) const fn elapsed_ms(since: u32, now: u32) -> u32 {
+    now.wrapping_sub(since)
+}
+
+// キューは固定長の配列なので、メッセージを大きくし過ぎないようにする
+const _: () = assert!(size_of::<Message>() <= 16);
+
+/// メインループのキューに溜めておけるメッセージ数
+pub(crate) const MAIN_QUEUE_CAPACITY: usize = 32;
+
+/// 固定長のリングバッファによるメッセージキュー。
+pub(crate) struct MessageQueue<const N: usize> {
+    buf: [Option<Message>; N],
+    /// 次に取り出す位置
+    read_pos: usize,
+    /// 溜まっているメッセージ数
+    count: usize,
+}
+
+impl<const N: usize> MessageQueue<N> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buf: [None; N],
+            read_pos: 0,
+            count: 0,
+        }
+    }
+
+    /// メッセージを末尾へ追加する。満杯なら [error::Code::Full] を返す。
+    pub(crate) fn push(&mut self, msg: Message) -> error::Error {
+        if self.count == N {
+            return make_error!(error::Code::Full);
+        }
+        self.buf[(self.read_pos + self.count) % N] = Some(msg);
+        self.count += 1;
+        make_error!(error::Code::Success)
+    }
+
+    /// 先頭のメッセージを取り出す。空なら None を返す。
+    pub(crate) fn pop(&mut self) -> Option<Message> {
+        if self.count == 0 {
+            return None;
+        }
+        let msg = self.buf[self.read_pos].take();
+        self.read_pos = (self.read_pos + 1) % N;
+        self.count -= 1;
+        msg
+    }
+
+    /// 先頭のメッセージを取り出さずに返す。空なら None を返す。
+    pub(crate) fn peek(&self) -> Option<&Message> {
+        self.iter().next()
+    }
+
+    /// 溜まっているメッセージを、先頭から順に取り出さずに返す。
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Message> {
+        (0..self.count).filter_map(move |i| self.buf[(self.read_pos + i) % N].as_ref())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.count
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+}
+
+static MAIN_QUEUE: Mutex<MessageQueue<MAIN_QUEUE_CAPACITY>> = Mutex::new(MessageQueue::new());
+/// [MAIN_QUEUE] に溜まっているメッセージ数の写し。
+/// ロックを取らずに読めるので、[wait_for_message] で MONITOR する対象にする。
+static MAIN_QUEUE_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// メインループのキューへメッセージを送る。
+pub(crate) fn push_message(msg: Message) -> error::Error {
+    trace!(TraceEvent::MessagePushed(msg.kind()));
+    let mut queue = MAIN_QUEUE.lock();
+    let err = queue.push(msg);
+    MAIN_QUEUE_LEN.store(queue.len(), Ordering::Release);
+    err
+}
+
+/// メインループのキューへ、ロックを待たずにメッセージを送る。
+///
+/// 割り込みハンドラから使う。ロックを取れないか、キューが一杯なら送らずに false を返す。
+pub(crate) fn try_push_message(msg: Message) -> bool {
+    let Some(mut queue) = MAIN_QUEUE.try_lock() else {
+        return false;
+    };
+    let err = queue.push(msg);
+    MAIN_QUEUE_LEN.store(queue.len(), Ordering::Release);
+    if (&err).into() {
+        return false;
+    }
+    trace!(TraceEvent::MessagePushed(msg.kind()));
+    true
+}
+
+/// メインループのキューからメッセージを 1 つ取り出す。
+///
+/// 入力のイベントなら、受け取ってから取り出されるまでの時間を [input_latency] に数える。
+pub(crate) fn pop_message() -> Option<Message> {
+    let mut queue = MAIN_QUEUE.lock();
+    let msg = queue.pop();
+    MAIN_QUEUE_LEN.store(queue.len(), Ordering::Release);
+    drop(queue);
+    if let Some(msg) = &msg {
+        trace!(TraceEvent::MessagePopped(msg.kind()));
+        if let Some(timestamp) = msg.timestamp() {
+            record_input_latency(elapsed_ms(timestamp, timestamp_now()));
+        }
+    }
+    msg
+}
+
+/// 数えた入力のイベントの数
+static INPUT_EVENTS: AtomicU64 = AtomicU64::new(0);
+/// 入力の遅れの合計（ミリ秒）
+static INPUT_LATENCY_TOTAL_MS: AtomicU64 = AtomicU64::new(0);
+/// 入力の遅れの最大（ミリ秒）
+static INPUT_LATENCY_MAX_MS: AtomicU64 = AtomicU64::new(0);
+
+fn record_input_latency(latency_ms: u32) {
+    INPUT_EVENTS.fetch_add(1, Ordering::Relaxed);
+    INPUT_LATENCY_TOTAL_MS.fetch_add(latency_ms as u64, Ordering::Relaxed);
+    INPUT_LATENCY_MAX_MS.fetch_max(latency_ms as u64, Ordering::Relaxed);
+}
+
+/// 入力のイベントを受け取ってから、メインループが取り出すまでの遅れ。
+#[derive(Clone, Copy)]
+pub(crate) struct InputLatency {
+    /// 数えたイベントの数
+    pub(crate) events: u64,
+    /// 遅れの合計（ミリ秒）
+    pub(crate) total_ms: u64,
+    /// 遅れの最大（ミリ秒）
+    pub(crate) max_ms: u64,
+}
+
+/// [reset_input_latency] してからの入力の遅れを返す。
+pub(crate) fn input_latency() -> InputLatency {
+    InputLatency {
+        events: INPUT_EVENTS.load(Ordering::Relaxed),
+        total_ms: INPUT_LATENCY_TOTAL_MS.load(Ordering::Relaxed),
+        max_ms: INPUT_LATENCY_MAX_MS.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn reset_input_latency() {
+    INPUT_EVENTS.store(0, Ordering::Relaxed);
+    INPUT_LATENCY_TOTAL_MS.store(0, Ordering::Relaxed);
+    INPUT_LATENCY_MAX_MS.store(0, Ordering::Relaxed);
+}
+
+/// メインループのキューにメッセージが届くまで CPU を休ませる。
+///
+/// 既に溜まっていればすぐに戻る。割り込みなどで何も届かずに戻ることもある。
+/// タイマ割り込みが動いていなければ、休むと何にも起こされなくなるので、休まずにすぐ戻る。
+pub(crate) fn wait_for_message() {
+    if !timer::is_ticking() {
+        return;
+    }
+    let len = MAIN_QUEUE_LEN.load(Ordering::Acquire);
+    if len == 0 {
+        cpu::idle_until_changed(&MAIN_QUEUE_LEN, len);
+    }
+}
+
+/// メインループのキューの先頭に続く、`msg` と同じメッセージを取り除き、その数を返す。
+///
+/// 何度届いても 1 回処理すれば済むメッセージ（[Message::InterruptXHCI] など）をまとめるのに使う。
+pub(crate) fn pop_duplicates(msg: &Message) -> usize {
+    let mut queue = MAIN_QUEUE.lock();
+    let count = queue.iter().take_while(|m| *m == msg).count();
+    for _ in 0..count {
+        queue.pop();
+    }
+    MAIN_QUEUE_LEN.store(queue.len(), Ordering::Release);
+    count
+}
+
+/// メインループのキューの先頭に続く、`msg` と同じ種類のメッセージを取り除き、その最後のものを返す。
+///
+/// 最新のものだけを処理すれば済むメッセージ（[Message::MouseDrag] など）をまとめるのに使う。
+pub(crate) fn pop_latest_of_kind(msg: &Message) -> Option<Message> {
+    let mut queue = MAIN_QUEUE.lock();
+    let mut latest = None;
+    while queue.peek().is_some_and(|m| m.kind() == msg.kind()) {
+        latest = queue.pop();
+    }
+    MAIN_QUEUE_LEN.store(queue.len(), Ordering::Release);
+    latest
+}
@@ -0,0 +1,89 @@
+#![allow(unused)]
+
+use core::{
+    marker::PhantomData,
+    ptr::{read_volatile, write_volatile},
+};
+
+/// メモリマップド IO のレジスタ 1 つを表す。
+///
+/// 読み書きは必ず volatile で行うので、コンパイラに省略・並べ替えされることがない。
+/// アドレスは仮想アドレスで、レジスタの物理アドレスからは [crate::paging::phys_to_virt] で求める。
+#[derive(Clone, Copy)]
+pub(crate) struct Mmio<T> {
+    addr: usize,
+    _marker: PhantomData<T>,
+}
+
+impl<T: Copy> Mmio<T> {
+    /// 指定されたアドレスのレジスタを表すオブジェクトを作る。
+    ///
+    /// # Safety
+    ///
+    /// `addr` は `T` に合わせてアラインされた、読み書きして問題無い MMIO 領域を指していること。
+    pub(crate) const unsafe fn new(addr: usize) -> Self {
+        Self {
+            addr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// レジスタのアドレスを返す。
+    pub(crate) const fn addr(&self) -> usize {
+        self.addr
+    }
+
+    /// レジスタの値を読み出す。
+    pub(crate) fn read(&self) -> T {
+        unsafe { read_volatile(self.addr as *const T) }
+    }
+
+    /// レジスタへ値を書き込む。
+    pub(crate) fn write(&self, value: T) {
+        unsafe { write_volatile(self.addr as *mut T, value) }
+    }
+
+    /// このレジスタから `offset` バイト先にあるレジスタを返す。
+    ///
+    /// # Safety
+    ///
+    /// [Mmio::new] と同じく、先のアドレスが有効な MMIO 領域であること。
+    pub(crate) const unsafe fn offset<U>(&self, offset: usize) -> Mmio<U> {
+        Mmio {
+            addr: self.addr + offset,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl Mmio<u32> {
+    /// `lsb` ビット目から `width` ビット分のフィールドを読み出す。
+    pub(crate) fn read_bits(&self, lsb: u32, width: u32) -> u32 {
+        (self.read() >> lsb) & field_mask(width)
+    }
+
+    /// `lsb` ビット目から `width` ビット分のフィールドだけを書き換える（他のビットは保持する）。
+    pub(crate) fn write_bits(&self, lsb: u32, width: u32, value: u32) {
+        let mask = field_mask(width) << lsb;
+        self.write((self.read() & !mask) | ((value << lsb) & mask));
+    }
+
+    /// `mask` で指定されたビットを立てる。
+    pub(crate) fn set_bits(&self, mask: u32) {
+        self.write(self.read() | mask);
+    }
+
+    /// `mask` で指定されたビットを下ろす。
+    pub(crate) fn clear_bits(&self, mask: u32) {
+        self.write(self.read() & !mask);
+    }
+}
+
+/// 下位 `width` ビットが立ったマスクを返す。
+const fn field_mask(width: u32) -> u32 {
+    if width >= 32 {
+        u32::MAX
+    } else {
+        (1 << width) - 1
+    }
+}
@@ -1,4 +1,11 @@
-use crate::graphics::{PixelColor, PixelWriter, Vector2D};
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use crate::{
+    graphics::{PixelColor, PixelWriter, Vector2D},
+    message,
+};
 
 /// マウスカーソルの横幅
 const MOUSE_CURSOR_WIDTH: usize = 15;
@@ -32,10 +39,23 @@ const MOUSE_CURSOR_SHAPE: [&[u8; MOUSE_CURSOR_WIDTH]; MOUSE_CURSOR_HEIGHT] = [
     b"         @@@   ",
 ];
 
+/// カーソルの縁の色の初期値
+pub(crate) const DEFAULT_BORDER_COLOR: PixelColor = PixelColor::new(0, 0, 0);
+/// カーソルの中の色の初期値
+pub(crate) const DEFAULT_FILL_COLOR: PixelColor = PixelColor::new(255, 255, 255);
+/// カーソルを拡大できる最大の倍率
+pub(crate) const MAX_SCALE: u32 = 8;
+
 pub(crate) struct MouseCursor<'a> {
     pixel_writer: &'a dyn PixelWriter,
     erase_color: PixelColor,
     position: Vector2D<u32>,
+    /// 形の `@` の部分の色
+    border_color: PixelColor,
+    /// 形の `.` の部分の色
+    fill_color: PixelColor,
+    /// 形の 1 ピクセルを何ピクセル四方で描くか
+    scale: u32,
 }
 
 impl<'a> MouseCursor<'a> {
@@ -48,6 +68,9 @@ impl<'a> MouseCursor<'a> {
             pixel_writer: writer,
             erase_color,
             position: initial_position,
+            border_color: DEFAULT_BORDER_COLOR,
+            fill_color: DEFAULT_FILL_COLOR,
+            scale: 1,
         };
         ret.draw_mouse_cursor();
         ret
@@ -59,34 +82,128 @@ impl<'a> MouseCursor<'a> {
         self.draw_mouse_cursor();
     }
 
+    /// カーソルを (dx, dy) だけ動かす。カーソルの先端が画面の外へ出ないように止める。
+    pub(crate) fn move_clamped(&mut self, dx: i32, dy: i32) {
+        let config = self.pixel_writer.config();
+        let max_x = config.horizontal_resolution as i64 - 1;
+        let max_y = config.vertical_resolution as i64 - 1;
+        let x = (self.position.x() as i64 + dx as i64).clamp(0, max_x);
+        let y = (self.position.y() as i64 + dy as i64).clamp(0, max_y);
+        self.erase_mouse_cursor();
+        self.position = Vector2D::new(x as u32, y as u32);
+        self.draw_mouse_cursor();
+    }
+
+    /// カーソルの縁と中の色を変えて描き直す。背景に埋もれて見づらいときに使う。
+    pub(crate) fn set_colors(&mut self, border: PixelColor, fill: PixelColor) {
+        self.border_color = border;
+        self.fill_color = fill;
+        self.draw_mouse_cursor();
+    }
+
+    /// カーソルを `scale` 倍の大きさにして描き直す。1 から [MAX_SCALE] の範囲に丸める。
+    ///
+    /// 解像度の高い画面でカーソルが小さくなりすぎないようにするためのもの。
+    pub(crate) fn set_scale(&mut self, scale: u32) {
+        // 消す範囲は今の大きさで決まるので、大きさを変える前に消す
+        self.erase_mouse_cursor();
+        self.scale = scale.clamp(1, MAX_SCALE);
+        self.draw_mouse_cursor();
+    }
+
+    /// カーソルの先端の位置を返す。
+    pub(crate) fn position(&self) -> Vector2D<u32> {
+        self.position
+    }
+
+    /// 画面上でカーソルが占める大きさを返す。
+    pub(crate) fn size(&self) -> Vector2D<u32> {
+        Vector2D::new(
+            MOUSE_CURSOR_WIDTH as u32 * self.scale,
+            MOUSE_CURSOR_HEIGHT as u32 * self.scale,
+        )
+    }
+
+    /// 形の (dx, dy) の 1 ピクセルを、倍率に合わせた大きさで塗る。
+    fn fill_cell(&self, dx: usize, dy: usize, color: &PixelColor) {
+        let scale = self.scale;
+        self.pixel_writer.fill_rectangle(
+            self.position + Vector2D::new(dx as u32 * scale, dy as u32 * scale),
+            Vector2D::new(scale, scale),
+            color,
+        );
+    }
+
     fn draw_mouse_cursor(&mut self) {
-        for dy in 0..MOUSE_CURSOR_HEIGHT {
-            for dx in 0..MOUSE_CURSOR_WIDTH {
-                if MOUSE_CURSOR_SHAPE[dy][dx] == b'@' {
-                    self.pixel_writer.write(
-                        self.position + Vector2D::new(dx as u32, dy as u32),
-                        &PixelColor::new(0, 0, 0),
-                    );
-                } else if MOUSE_CURSOR_SHAPE[dy][dx] == b'.' {
-                    self.pixel_writer.write(
-                        self.position + Vector2D::new(dx as u32, dy as u32),
-                        &PixelColor::new(255, 255, 255),
-                    );
+        for (dy, row) in MOUSE_CURSOR_SHAPE.iter().enumerate() {
+            for (dx, &c) in row.iter().enumerate() {
+                match c {
+                    b'@' => self.fill_cell(dx, dy, &self.border_color),
+                    b'.' => self.fill_cell(dx, dy, &self.fill_color),
+                    _ => {}
                 }
             }
         }
     }
 
     fn erase_mouse_cursor(&mut self) {
-        for dy in 0..MOUSE_CURSOR_HEIGHT {
-            for dx in 0..MOUSE_CURSOR_WIDTH {
-                if MOUSE_CURSOR_SHAPE[dy][dx] != b' ' {
-                    self.pixel_writer.write(
-                        self.position + Vector2D::new(dx as u32, dy as u32),
-                        &self.erase_color,
-                    )
+        for (dy, row) in MOUSE_CURSOR_SHAPE.iter().enumerate() {
+            for (dx, &c) in row.iter().enumerate() {
+                if c != b' ' {
+                    self.fill_cell(dx, dy, &self.erase_color);
                 }
             }
         }
     }
 }
+
+/// ダブルクリックとみなす 2 回のクリックの間隔の既定値（ミリ秒）
+pub(crate) const DEFAULT_DOUBLE_CLICK_MS: u32 = 500;
+/// ダブルクリックとみなす 2 回のクリックの位置のずれ（ピクセル）
+const DOUBLE_CLICK_DISTANCE: u32 = 4;
+
+/// ダブルクリックとみなす 2 回のクリックの間隔（ミリ秒）。0 ならダブルクリックを検出しない。
+static DOUBLE_CLICK_MS: AtomicU32 = AtomicU32::new(DEFAULT_DOUBLE_CLICK_MS);
+
+pub(crate) fn set_double_click_ms(ms: u32) {
+    DOUBLE_CLICK_MS.store(ms, Ordering::Relaxed);
+}
+
+pub(crate) fn double_click_ms() -> u32 {
+    DOUBLE_CLICK_MS.load(Ordering::Relaxed)
+}
+
+/// 左クリックの時刻と位置を覚えておき、ダブルクリックを見分ける。
+pub(crate) struct DoubleClickDetector {
+    /// 前回のクリックの時刻と位置。ダブルクリックになったら忘れる。
+    last: Option<(u32, Vector2D<u32>)>,
+}
+
+impl DoubleClickDetector {
+    pub(crate) const fn new() -> Self {
+        Self { last: None }
+    }
+
+    /// 時刻 `timestamp`（[message::timestamp_now] の値）に `pos` でクリックされたことを伝える。
+    ///
+    /// 前回のクリックから [double_click_ms] 以内で、位置がほぼ同じならダブルクリックとして true を返す。
+    /// 3 回続けてクリックしても、ダブルクリックは 1 回だけになる。
+    pub(crate) fn on_click(&mut self, timestamp: u32, pos: Vector2D<u32>) -> bool {
+        let threshold = double_click_ms();
+        let is_double = match self.last {
+            Some((last_timestamp, last_pos)) => {
+                threshold != 0
+                    && message::elapsed_ms(last_timestamp, timestamp) <= threshold
+                    && last_pos.x().abs_diff(pos.x()) <= DOUBLE_CLICK_DISTANCE
+                    && last_pos.y().abs_diff(pos.y()) <= DOUBLE_CLICK_DISTANCE
+            }
+            None => false,
+        };
+        self.last = if is_double {
+            None
+        } else {
+            Some((timestamp, pos))
+        };
+        is_double
+    }
+}
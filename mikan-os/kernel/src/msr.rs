@@ -0,0 +1,73 @@
+#![allow(unused)]
+
+//! モデル固有レジスタ（MSR）の読み書き。
+//!
+//! RDMSR / WRMSR は特権レベル 0 でしか実行できず、それ以外では #GP になる。存在しない MSR や
+//! 予約ビットへの書き込みも #GP になるので、使う前に CPUID で対応を確かめること。
+
+use core::arch::asm;
+
+/// IA32_APIC_BASE：Local APIC のベースアドレスと動作モード
+pub(crate) const IA32_APIC_BASE: u32 = 0x1b;
+/// IA32_APIC_BASE の x2APIC モード有効化ビット
+pub(crate) const APIC_BASE_EXTD: u64 = 1 << 10;
+/// IA32_APIC_BASE の APIC グローバル有効化ビット
+pub(crate) const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// IA32_EFER：ロングモードや実行禁止ビットなどの拡張機能
+pub(crate) const IA32_EFER: u32 = 0xc000_0080;
+/// IA32_EFER の SCE（SYSCALL/SYSRET 有効化）ビット
+pub(crate) const EFER_SCE: u64 = 1 << 0;
+/// IA32_EFER の NXE（実行禁止ビット有効化）ビット
+pub(crate) const EFER_NXE: u64 = 1 << 11;
+
+/// IA32_STAR：SYSCALL と SYSRET で読み込むセグメントセレクタ
+pub(crate) const IA32_STAR: u32 = 0xc000_0081;
+/// IA32_LSTAR：64 ビットモードで SYSCALL したときの飛び先
+pub(crate) const IA32_LSTAR: u32 = 0xc000_0082;
+/// IA32_FMASK：SYSCALL したときに下ろす RFLAGS のビット
+pub(crate) const IA32_FMASK: u32 = 0xc000_0084;
+
+/// x2APIC モードで、Local APIC のレジスタが割り当てられている MSR の先頭
+pub(crate) const X2APIC_MSR_BASE: u32 = 0x800;
+
+/// `msr` を読む。
+///
+/// # Safety
+///
+/// 特権レベル 0 で、CPU が `msr` に対応していること。
+pub(crate) unsafe fn read(msr: u32) -> u64 {
+    let (low, high): (u32, u32);
+    asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    ((high as u64) << 32) | low as u64
+}
+
+/// `msr` へ `value` を書き込む。
+///
+/// # Safety
+///
+/// 特権レベル 0 で、CPU が `msr` に対応していること。予約ビットを立てないこと。
+/// APIC のモードやページングの設定のように、書き込むとその場で CPU の動作が変わる MSR もある。
+pub(crate) unsafe fn write(msr: u32, value: u64) {
+    asm!(
+        "wrmsr",
+        in("ecx") msr,
+        in("eax") value as u32,
+        in("edx") (value >> 32) as u32,
+        options(nostack, preserves_flags)
+    );
+}
+
+/// `msr` を読み、`f` で書き換えた値を書き戻す。書き戻した値を返す。
+///
+/// 立てたいビットだけを変え、他のビットを壊さないようにするのに使う。間に割り込みが入って
+/// 同じ MSR を書き換えることは想定していない。
+///
+/// # Safety
+///
+/// [read] と [write] の条件を満たすこと。
+pub(crate) unsafe fn update(msr: u32, f: impl FnOnce(u64) -> u64) -> u64 {
+    let value = f(read(msr));
+    write(msr, value);
+    value
+}
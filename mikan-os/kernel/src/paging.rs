@@ -0,0 +1,405 @@
+#![allow(unused)]
+
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    asmfunc::{get_cr3, invlpg, set_cr3},
+    cpu, elf, error, kassert, make_error, msr,
+    sync::SpinLock,
+};
+
+/// 物理メモリ全体を写す仮想アドレスの先頭。PML4 の 256 番目、上位半分の先頭にあたる。
+pub(crate) const DIRECT_MAP_BASE: u64 = 0xffff_8000_0000_0000;
+/// ユーザモードから触れる領域の先頭。PML4 の 1 番目で、カーネルの写しとはどの段のテーブルも共有しない。
+pub(crate) const USER_BASE: u64 = 0x0000_0080_0000_0000;
+/// ユーザモードから触れる領域の大きさ。ページテーブル 1 つ分の 2 MiB。
+pub(crate) const USER_SIZE: u64 = MIB_2;
+
+const PAGE_SIZE: u64 = 4096;
+const MIB_2: u64 = 1 << 21;
+const GIB: u64 = 1 << 30;
+/// PML4 の 1 エントリが受け持つ大きさ
+const GIB_512: u64 = 1 << 39;
+/// 1 つのページテーブルのエントリ数
+const ENTRIES: usize = 512;
+/// 2 MiB ページで写すときの大きさ（GiB）。1 GiB ページが使えれば PDPT 1 つ分の 512 GiB を写す。
+const MAPPED_GIB_2M: usize = 64;
+/// [DIRECT_MAP_BASE] から写せる物理アドレスの上限。PML4 の 256〜383 番を使う。
+const DIRECT_MAP_LIMIT: u64 = 128 * GIB_512;
+/// カーネルのイメージを 4 KiB ページで写すのに使うページテーブルの数。1 つで 2 MiB を受け持つ。
+const KERNEL_PAGE_TABLES: usize = 16;
+/// 起動時に写さなかった物理メモリを、後から写すために取っておくページテーブルの数
+const SPARE_PAGE_TABLES: usize = 16;
+
+/// ページテーブルのエントリの Present ビット
+const PTE_PRESENT: u64 = 1 << 0;
+/// ページテーブルのエントリの Read/Write ビット
+const PTE_WRITABLE: u64 = 1 << 1;
+/// ページテーブルのエントリの User/Supervisor ビット。すべての段で立っていると特権レベル 3 から触れる。
+const PTE_USER: u64 = 1 << 2;
+/// PDPT や PD のエントリで、大きいページを直接指すことを表すビット
+const PTE_HUGE: u64 = 1 << 7;
+/// ページテーブルのエントリの実行禁止ビット。どこかの段で立っていれば、その先は実行できない。
+const PTE_NO_EXECUTE: u64 = 1 << 63;
+/// エントリのうち、次の段のテーブルやページの物理アドレスを表すビット
+const PTE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+#[derive(Clone, Copy)]
+#[repr(C, align(4096))]
+struct PageTable([u64; ENTRIES]);
+
+impl PageTable {
+    const fn new() -> Self {
+        Self([0; ENTRIES])
+    }
+}
+
+static mut PML4: PageTable = PageTable::new();
+static mut PDPT: PageTable = PageTable::new();
+/// 2 MiB ページのページディレクトリ。1 GiB ページが使えるときも、先頭の 1 GiB には 0 番目を使う。
+static mut PAGE_DIRECTORIES: [PageTable; MAPPED_GIB_2M] = [PageTable::new(); MAPPED_GIB_2M];
+/// カーネルのイメージを含む 2 MiB ずつを、4 KiB ページで写すページテーブル
+static mut KERNEL_TABLES: [PageTable; KERNEL_PAGE_TABLES] = [PageTable::new(); KERNEL_PAGE_TABLES];
+/// [USER_BASE] からの領域を写す PDPT、ページディレクトリ、ページテーブル
+static mut USER_PDPT: PageTable = PageTable::new();
+static mut USER_DIRECTORY: PageTable = PageTable::new();
+static mut USER_TABLE: PageTable = PageTable::new();
+/// [map_direct] が使うページテーブル
+static mut SPARE_TABLES: [PageTable; SPARE_PAGE_TABLES] = [PageTable::new(); SPARE_PAGE_TABLES];
+/// [SPARE_TABLES] のうち、次に使うものの番号。ロックは [map_direct] 全体を直列にするのにも使う。
+static NEXT_SPARE: SpinLock<usize> = SpinLock::new(0);
+
+/// [DIRECT_MAP_BASE] からの写しが使えるようになったら true
+static DIRECT_MAP: AtomicBool = AtomicBool::new(false);
+/// 写した物理メモリの大きさ（バイト）
+static MAPPED_SIZE: AtomicU64 = AtomicU64::new(0);
+/// 1 GiB ページで写していれば true
+static GIB_PAGES: AtomicBool = AtomicBool::new(false);
+/// CPU が NX に対応していれば [PTE_NO_EXECUTE]、対応していなければ 0
+static NO_EXECUTE: AtomicU64 = AtomicU64::new(0);
+/// [KERNEL_TABLES] が受け持つ範囲の先頭（2 MiB 境界）
+static KERNEL_TABLES_BASE: AtomicU64 = AtomicU64::new(0);
+/// [KERNEL_TABLES] が受け持つ範囲の終わり。写し分けていなければ 0。
+static KERNEL_TABLES_END: AtomicU64 = AtomicU64::new(0);
+
+/// カーネル自身のページテーブルを作って切り替える。
+///
+/// 物理メモリの先頭から、0 番地からの恒等写像と [DIRECT_MAP_BASE] からの写しの両方を作る。
+/// 2 つは同じ PDPT を共有し、どちらもカーネルからしか触れない。ユーザモードから触れるのは
+/// [map_user] で [USER_BASE] からの領域に写したページだけ。カーネルのコードやデータ、
+/// DMA で xHC に渡すバッファはこれまで通り恒等写像で使い、MMIO は [phys_to_virt] を通して上位半分から使う。
+/// カーネルのイメージはプログラムヘッダに従って 4 KiB ページで写し分け、コードは読み込みと実行だけ、
+/// 読み込み専用データは読み込みだけ、それ以外のデータは読み書きだけを許す。
+/// 恒等写像のうちイメージの外は、UEFI のランタイムサービスのコードを含むので実行を禁じない。
+/// 上位半分の写しは PML4 で実行を禁じる。カーネルのイメージの部分は恒等写像と同じ属性になる。
+/// UEFI のページテーブルに頼らないよう、他のどの初期化よりも先に呼ぶこと。
+pub(crate) fn setup_page_tables() {
+    let no_execute = if cpu::has_nx() {
+        // EFER.NXE が下りていると実行禁止ビットは予約ビット扱いなので、切り替える前に立てる
+        unsafe { msr::update(msr::IA32_EFER, |efer| efer | msr::EFER_NXE) };
+        PTE_NO_EXECUTE
+    } else {
+        0
+    };
+    NO_EXECUTE.store(no_execute, Ordering::Relaxed);
+
+    let pml4 = unsafe { &mut *addr_of_mut!(PML4) };
+    let pdpt = unsafe { &mut *addr_of_mut!(PDPT) };
+    let directories = unsafe { &mut *addr_of_mut!(PAGE_DIRECTORIES) };
+
+    let gib_pages = cpu::has_1gb_pages();
+    let mapped_gib = if gib_pages {
+        for (i, entry) in pdpt.0.iter_mut().enumerate().skip(1) {
+            *entry = (i as u64 * GIB) | PTE_PRESENT | PTE_WRITABLE | PTE_HUGE;
+        }
+        ENTRIES
+    } else {
+        MAPPED_GIB_2M
+    };
+    // 先頭の 1 GiB はカーネルのイメージを写し分けるので、1 GiB ページが使えても分けておく
+    let directory_count = if gib_pages { 1 } else { MAPPED_GIB_2M };
+    for (i, directory) in directories.iter_mut().take(directory_count).enumerate() {
+        fill_directory(directory, i as u64 * GIB);
+        pdpt.0[i] = table_entry(directory);
+    }
+    map_kernel_image(&mut directories[0], no_execute);
+
+    pml4.0[0] = table_entry(pdpt);
+    pml4.0[pml4_index(DIRECT_MAP_BASE)] = table_entry(pdpt) | no_execute;
+    // User ビットは、ユーザモードの領域へ続くエントリにだけ立てる
+    let user_pdpt = unsafe { &mut *addr_of_mut!(USER_PDPT) };
+    let user_directory = unsafe { &mut *addr_of_mut!(USER_DIRECTORY) };
+    let user_table = unsafe { &*addr_of!(USER_TABLE) };
+    user_directory.0[pd_index(USER_BASE)] = table_entry(user_table) | PTE_USER;
+    user_pdpt.0[pdpt_index(USER_BASE)] = table_entry(user_directory) | PTE_USER;
+    pml4.0[pml4_index(USER_BASE)] = table_entry(user_pdpt) | PTE_USER;
+    unsafe { set_cr3(pml4 as *const PageTable as u64) };
+
+    GIB_PAGES.store(gib_pages, Ordering::Relaxed);
+    MAPPED_SIZE.store(mapped_gib as u64 * GIB, Ordering::Relaxed);
+    DIRECT_MAP.store(true, Ordering::Relaxed);
+}
+
+/// 次の段のテーブルを指す、カーネルだけが書き込めるエントリを作る。
+///
+/// ページテーブルは恒等写像の範囲にあるので、そのまま物理アドレスとして使える。
+fn table_entry(table: &PageTable) -> u64 {
+    table as *const PageTable as u64 | PTE_PRESENT | PTE_WRITABLE
+}
+
+/// `directory` に、物理アドレス `base` からの 1 GiB を 2 MiB ページで写す。
+fn fill_directory(directory: &mut PageTable, base: u64) {
+    for (i, entry) in directory.0.iter_mut().enumerate() {
+        *entry = (base + i as u64 * MIB_2) | PTE_PRESENT | PTE_WRITABLE | PTE_HUGE;
+    }
+}
+
+/// カーネルのイメージを含む 2 MiB ずつを [KERNEL_TABLES] で写し直し、セグメントごとに属性を付ける。
+///
+/// `directory` は先頭の 1 GiB を写すページディレクトリ。セグメントに含まれないページは
+/// 周りの恒等写像と同じく読み書きと実行を許す。ローダはセグメントを仮想アドレスと同じ物理アドレスへ
+/// 置き、リンカはセグメントごとにページを分けているので、1 つのページに 2 つの属性が混ざることはない。
+fn map_kernel_image(directory: &mut PageTable, no_execute: u64) {
+    let segments = elf::kernel_program_headers()
+        .iter()
+        .filter(|phdr| phdr.r#type == elf::PT_LOAD && phdr.memsz > 0);
+    let (start, end) = segments.clone().fold((u64::MAX, 0), |(start, end), phdr| {
+        (
+            start.min(phdr.vaddr as u64),
+            end.max(phdr.vaddr as u64 + phdr.memsz),
+        )
+    });
+    if start >= end {
+        return;
+    }
+    let base = start & !(MIB_2 - 1);
+    let end = end.next_multiple_of(MIB_2);
+    kassert!(
+        end <= GIB && end - base <= KERNEL_PAGE_TABLES as u64 * MIB_2,
+        "kernel image {:#x}-{:#x} does not fit in the kernel page tables",
+        start,
+        end
+    );
+
+    let tables = unsafe { &mut *addr_of_mut!(KERNEL_TABLES) };
+    for (i, table) in tables.iter_mut().enumerate() {
+        let region = base + i as u64 * MIB_2;
+        if region >= end {
+            break;
+        }
+        for (j, entry) in table.0.iter_mut().enumerate() {
+            *entry = (region + j as u64 * PAGE_SIZE) | PTE_PRESENT | PTE_WRITABLE;
+        }
+        directory.0[(region / MIB_2) as usize] = table_entry(table);
+    }
+    KERNEL_TABLES_BASE.store(base, Ordering::Relaxed);
+    KERNEL_TABLES_END.store(end, Ordering::Relaxed);
+
+    for phdr in segments {
+        let mut flags = PTE_PRESENT;
+        if phdr.flags & elf::PF_W != 0 {
+            flags |= PTE_WRITABLE;
+        }
+        if phdr.flags & elf::PF_X == 0 {
+            flags |= no_execute;
+        }
+        let first = phdr.vaddr as u64 & !(PAGE_SIZE - 1);
+        let last = (phdr.vaddr as u64 + phdr.memsz).next_multiple_of(PAGE_SIZE);
+        for page in (first..last).step_by(PAGE_SIZE as usize) {
+            let index = ((page - base) / PAGE_SIZE) as usize;
+            tables[index / ENTRIES].0[index % ENTRIES] = page | flags;
+        }
+    }
+}
+
+/// カーネルのイメージ内の `virt` から `len` バイトを含むページを、読み込みと実行だけができるようにする。
+///
+/// [crate::app] が読み込んだアプリを呼べるようにするためのもの。同じページにある他のデータにも
+/// 書き込めなくなるので、ページ単位で確保した領域に使うこと。
+/// カーネルのイメージを写す範囲の外なら [error::Code::IndexOutOfRange] を返す。
+pub(crate) fn make_executable(virt: u64, len: usize) -> error::Error {
+    let base = KERNEL_TABLES_BASE.load(Ordering::Relaxed);
+    let end = KERNEL_TABLES_END.load(Ordering::Relaxed);
+    let first = virt & !(PAGE_SIZE - 1);
+    let last = (virt + len as u64).next_multiple_of(PAGE_SIZE);
+    if first < base || last > end || first >= last {
+        return make_error!(error::Code::IndexOutOfRange);
+    }
+    let tables = unsafe { &mut *addr_of_mut!(KERNEL_TABLES) };
+    for page in (first..last).step_by(PAGE_SIZE as usize) {
+        let index = ((page - base) / PAGE_SIZE) as usize;
+        tables[index / ENTRIES].0[index % ENTRIES] = page | PTE_PRESENT;
+        unsafe { invlpg(page) };
+    }
+    make_error!(error::Code::Success)
+}
+
+const fn pml4_index(virt: u64) -> usize {
+    ((virt >> 39) & (ENTRIES as u64 - 1)) as usize
+}
+
+const fn pdpt_index(virt: u64) -> usize {
+    ((virt >> 30) & (ENTRIES as u64 - 1)) as usize
+}
+
+const fn pd_index(virt: u64) -> usize {
+    ((virt >> 21) & (ENTRIES as u64 - 1)) as usize
+}
+
+/// 物理アドレス `phys` から `len` バイトを、ユーザモードから触れるように `virt` へ写す。
+///
+/// `virt` と `phys` は 4 KiB 境界に揃えること。書き込みは `writable`、実行は `executable` のときだけ許す。
+/// `phys` は恒等写像でカーネルからも触れたままなので、中身はカーネル側で用意してから写せばよい。
+/// [USER_BASE] からの [USER_SIZE] バイトに収まらなければ [error::Code::IndexOutOfRange] を返す。
+pub(crate) fn map_user(
+    virt: u64,
+    phys: u64,
+    len: usize,
+    writable: bool,
+    executable: bool,
+) -> error::Error {
+    debug_assert!(virt.is_multiple_of(PAGE_SIZE) && phys.is_multiple_of(PAGE_SIZE));
+    match virt.checked_add(len as u64) {
+        Some(end) if virt >= USER_BASE && end <= USER_BASE + USER_SIZE => {}
+        _ => return make_error!(error::Code::IndexOutOfRange),
+    }
+    let mut flags = PTE_PRESENT | PTE_USER;
+    if writable {
+        flags |= PTE_WRITABLE;
+    }
+    if !executable {
+        flags |= NO_EXECUTE.load(Ordering::Relaxed);
+    }
+    let table = unsafe { &mut *addr_of_mut!(USER_TABLE) };
+    for offset in (0..len as u64).step_by(PAGE_SIZE as usize) {
+        let page = virt + offset;
+        table.0[((page - USER_BASE) / PAGE_SIZE) as usize] = (phys + offset) | flags;
+        unsafe { invlpg(page) };
+    }
+    make_error!(error::Code::Success)
+}
+
+/// `start` から `end` の手前までが、すべてユーザモードから読めるページに写されていれば true を返す。
+///
+/// システムコールが受け取ったポインタを、カーネルが読む前に確かめるためのもの。
+pub(crate) fn is_user_range(start: u64, end: u64) -> bool {
+    if start >= end || start < USER_BASE || end > USER_BASE + USER_SIZE {
+        return false;
+    }
+    (start & !(PAGE_SIZE - 1)..end)
+        .step_by(PAGE_SIZE as usize)
+        .all(|page| page_attributes(page).is_some_and(|attributes| attributes.user))
+}
+
+/// [setup_page_tables] で写した物理メモリの大きさ（バイト）を返す。まだ写していなければ 0。
+///
+/// これより上も [phys_to_virt] が必要に応じて写す。
+pub(crate) fn mapped_size() -> u64 {
+    MAPPED_SIZE.load(Ordering::Relaxed)
+}
+
+/// 物理アドレスを、それを指す仮想アドレスへ変換する。
+///
+/// [setup_page_tables] の前は UEFI の恒等写像なので、そのまま返す。
+/// [mapped_size] より上のアドレスは、その場で [DIRECT_MAP_BASE] からの写しに加える。
+pub(crate) fn phys_to_virt(phys: u64) -> u64 {
+    if !DIRECT_MAP.load(Ordering::Relaxed) {
+        return phys;
+    }
+    if phys >= mapped_size() {
+        map_direct(phys);
+    }
+    DIRECT_MAP_BASE + phys
+}
+
+/// 起動時に写さなかった `phys` を含む範囲を、[DIRECT_MAP_BASE] からの写しに加える。
+///
+/// 1 GiB ページが使えれば 1 GiB 単位で、使えなければ [SPARE_TABLES] のページディレクトリで
+/// 1 GiB 分を 2 MiB ページで写す。512 GiB より上には PDPT も [SPARE_TABLES] から使う。
+/// [DIRECT_MAP_LIMIT] を超えるアドレスや、[SPARE_TABLES] を使い切ったときは kassert で止まる。
+/// 写していなかったエントリを埋めるだけなので、TLB を消す必要はない。
+fn map_direct(phys: u64) {
+    kassert!(
+        phys < DIRECT_MAP_LIMIT,
+        "physical address {:#x} is beyond the direct map",
+        phys
+    );
+    let mut next_spare = NEXT_SPARE.lock();
+    let mut take_spare = || {
+        kassert!(
+            *next_spare < SPARE_PAGE_TABLES,
+            "no page table left to map physical address {:#x}",
+            phys
+        );
+        let table = unsafe { &mut (*addr_of_mut!(SPARE_TABLES))[*next_spare] };
+        *next_spare += 1;
+        table
+    };
+
+    let pml4 = unsafe { &mut *addr_of_mut!(PML4) };
+    let pml4_entry = &mut pml4.0[pml4_index(DIRECT_MAP_BASE + phys)];
+    if *pml4_entry & PTE_PRESENT == 0 {
+        *pml4_entry = table_entry(take_spare()) | NO_EXECUTE.load(Ordering::Relaxed);
+    }
+    let pdpt = unsafe { &mut *((*pml4_entry & PTE_ADDRESS_MASK) as *mut PageTable) };
+    let pdpt_entry = &mut pdpt.0[pdpt_index(phys)];
+    if *pdpt_entry & PTE_PRESENT != 0 {
+        return;
+    }
+    let gib = phys & !(GIB - 1);
+    if GIB_PAGES.load(Ordering::Relaxed) {
+        *pdpt_entry = gib | PTE_PRESENT | PTE_WRITABLE | PTE_HUGE;
+    } else {
+        let directory = take_spare();
+        fill_directory(directory, gib);
+        *pdpt_entry = table_entry(directory);
+    }
+}
+
+/// 仮想アドレスを物理アドレスへ変換する。恒等写像のアドレスはそのまま返す。
+pub(crate) fn virt_to_phys(virt: u64) -> u64 {
+    if DIRECT_MAP.load(Ordering::Relaxed) && virt >= DIRECT_MAP_BASE {
+        virt - DIRECT_MAP_BASE
+    } else {
+        virt
+    }
+}
+
+/// ページテーブルの各段のエントリを合わせた、あるページの属性。
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) struct PageAttributes {
+    pub(crate) writable: bool,
+    pub(crate) executable: bool,
+    pub(crate) user: bool,
+}
+
+/// 今のページテーブルをたどり、`virt` が写されていればそのページの属性を返す。
+///
+/// ページテーブルは恒等写像の範囲にある前提で読む。CR0.WP や EFER.NXE の設定は考えない。
+pub(crate) fn page_attributes(virt: u64) -> Option<PageAttributes> {
+    let mut table = unsafe { get_cr3() } & PTE_ADDRESS_MASK;
+    let mut attributes = PageAttributes {
+        writable: true,
+        executable: true,
+        user: true,
+    };
+    // PML4、PDPT、PD、PT の順にたどる
+    for level in (0..4).rev() {
+        let index = ((virt >> (12 + 9 * level)) & (ENTRIES as u64 - 1)) as usize;
+        let entry = unsafe { *(table as *const u64).add(index) };
+        if entry & PTE_PRESENT == 0 {
+            return None;
+        }
+        attributes.writable &= entry & PTE_WRITABLE != 0;
+        attributes.executable &= entry & PTE_NO_EXECUTE == 0;
+        attributes.user &= entry & PTE_USER != 0;
+        if level == 0 || (level < 3 && entry & PTE_HUGE != 0) {
+            break;
+        }
+        table = entry & PTE_ADDRESS_MASK;
+    }
+    Some(attributes)
+}
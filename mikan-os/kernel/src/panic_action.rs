@@ -0,0 +1,220 @@
+#![allow(unused)]
+
+use core::{
+    arch::asm,
+    fmt::Write,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use crate::{
+    acpi, boot_phase,
+    io::{io_in_8, io_out_8},
+    paging,
+    runtime_services::{self, ResetType},
+    serial::{self, SerialPort},
+    trace,
+};
+
+/// パニックしたときの動作。起動引数 `panic=halt|reboot|monitor` で選ぶ。
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum PanicAction {
+    /// 止まったままにして、画面やシリアルの出力を調べられるようにする
+    Halt,
+    /// マシンをリセットする。CI などで自動的にやり直すのに使う。
+    Reboot,
+    /// シリアルポートで簡単なモニタを動かす。シリアルポートが無ければ止まる。
+    Monitor,
+}
+
+impl PanicAction {
+    pub(crate) fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            b"halt" => Some(Self::Halt),
+            b"reboot" => Some(Self::Reboot),
+            b"monitor" => Some(Self::Monitor),
+            _ => None,
+        }
+    }
+
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Reboot,
+            2 => Self::Monitor,
+            _ => Self::Halt,
+        }
+    }
+}
+
+static ACTION: AtomicU8 = AtomicU8::new(PanicAction::Halt as u8);
+
+pub(crate) fn set_panic_action(action: PanicAction) {
+    ACTION.store(action as u8, Ordering::Relaxed);
+}
+
+pub(crate) fn panic_action() -> PanicAction {
+    PanicAction::from_u8(ACTION.load(Ordering::Relaxed))
+}
+
+/// キーボードコントローラのステータス/コマンドポート
+const KBC_STATUS_COMMAND: u16 = 0x64;
+/// ステータスの入力バッファフルビット。立っている間はコマンドを受け付けない。
+const KBC_INPUT_BUFFER_FULL: u8 = 1 << 1;
+/// CPU のリセット線をパルスさせるコマンド
+const KBC_PULSE_RESET: u8 = 0xfe;
+
+/// リセットの方法を 1 つ試してから、次を試すまでに待つ回数
+const RESET_WAIT_SPINS: usize = 1_000_000;
+
+/// マシンをリセットする。
+///
+/// ACPI のリセットレジスタ、UEFI の ResetSystem、キーボードコントローラの順に試し、
+/// どれも効かなければ空の IDT を読み込んで例外を起こし、トリプルフォールトでリセットさせる。
+pub(crate) fn reboot() -> ! {
+    if !bool::from(acpi::reset()) {
+        spin_wait(RESET_WAIT_SPINS);
+    }
+    let _ = runtime_services::reset_system(ResetType::Cold);
+
+    for _ in 0..RESET_WAIT_SPINS {
+        if unsafe { io_in_8(KBC_STATUS_COMMAND) } & KBC_INPUT_BUFFER_FULL == 0 {
+            break;
+        }
+    }
+    unsafe { io_out_8(KBC_STATUS_COMMAND, KBC_PULSE_RESET) };
+    spin_wait(RESET_WAIT_SPINS);
+
+    // リミット 0 の IDT では、どの例外も処理できずにトリプルフォールトになる
+    let idtr = [0u16; 5];
+    unsafe { asm!("lidt [{}]", "int3", in(reg) &idtr, options(noreturn)) }
+}
+
+fn spin_wait(spins: usize) {
+    for _ in 0..spins {
+        core::hint::spin_loop();
+    }
+}
+
+/// モニタの 1 行の最大長
+const MONITOR_LINE_SIZE: usize = 64;
+/// `dump` で一度に表示できる最大バイト数
+const MONITOR_MAX_DUMP: usize = 256;
+
+/// シリアルポートで簡単なモニタを動かす。`reboot` か `halt` が入力されるまで戻らない。
+///
+/// `dump` はページごとにページテーブルを確かめ、写されていない番地に来たらそこで止める。
+/// パニックの中でページフォールトを起こすと、モニタへ戻れなくなるため。
+pub(crate) fn run_monitor(serial: &mut SerialPort) -> ! {
+    let _ = writeln!(serial, "entering panic monitor; type 'help' for commands");
+    let mut line = [0u8; MONITOR_LINE_SIZE];
+    loop {
+        let _ = write!(serial, "panic> ");
+        let len = read_line(serial, &mut line);
+        let mut words = line[..len]
+            .split(|b| *b == b' ')
+            .filter(|word| !word.is_empty());
+        match words.next() {
+            None => {}
+            Some(b"help") => {
+                let _ = writeln!(
+                    serial,
+                    "help              show this message\n\
+                     phase             show the boot phase the panic happened in\n\
+                     dump <addr> [len] hexdump memory (hex address, decimal length)\n\
+                     trace             show the recorded trace events\n\
+                     reboot            reset the machine\n\
+                     halt              stop here"
+                );
+            }
+            Some(b"phase") => {
+                let _ = match boot_phase::current() {
+                    Some(phase) => writeln!(serial, "{}", phase),
+                    None => writeln!(serial, "before boot"),
+                };
+            }
+            Some(b"dump") => {
+                let addr = words.next().and_then(|word| parse_number(word, 16));
+                let size = match words.next() {
+                    Some(word) => parse_number(word, 10),
+                    None => Some(64),
+                };
+                match (addr, size) {
+                    (Some(addr), Some(size)) => dump(serial, addr, size.min(MONITOR_MAX_DUMP)),
+                    _ => {
+                        let _ = writeln!(serial, "usage: dump <addr> [len]");
+                    }
+                }
+            }
+            Some(b"trace") => {
+                let _ = trace::dump_trace(serial);
+            }
+            Some(b"reboot") => reboot(),
+            Some(b"halt") => loop {
+                unsafe { asm!("hlt") };
+            },
+            Some(_) => {
+                let _ = writeln!(serial, "unknown command");
+            }
+        }
+    }
+}
+
+/// 改行までの 1 行を読み、その長さを返す。入力した文字は送り返す。
+fn read_line(serial: &mut SerialPort, buf: &mut [u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let Some(b) = serial.read_byte() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        match serial::normalize_input(b) {
+            b'\n' => {
+                let _ = writeln!(serial);
+                return len;
+            }
+            0x08 if len > 0 => {
+                len -= 1;
+                let _ = write!(serial, "\x08 \x08");
+            }
+            c @ 0x20..=0x7e if len < buf.len() => {
+                buf[len] = c;
+                len += 1;
+                serial.write_byte(c);
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_number(word: &[u8], radix: u32) -> Option<usize> {
+    let word = core::str::from_utf8(word).ok()?;
+    let word = if radix == 16 {
+        word.trim_start_matches("0x")
+    } else {
+        word
+    };
+    usize::from_str_radix(word, radix).ok()
+}
+
+/// `addr` を含むページが読めるかどうか。正規形でない番地は、ページテーブルに関わらず読めない。
+fn is_readable(addr: usize) -> bool {
+    let canonical = ((addr as i64) << 16 >> 16) as usize == addr;
+    canonical && paging::page_attributes(addr as u64).is_some()
+}
+
+fn dump(serial: &mut SerialPort, addr: usize, size: usize) {
+    let end = addr.saturating_add(size);
+    for line in (addr..end).step_by(16) {
+        let _ = write!(serial, "{:016x} ", line);
+        for p in line..line.saturating_add(16).min(end) {
+            if (p == addr || p.is_multiple_of(4096)) && !is_readable(p) {
+                let _ = writeln!(serial, " ({:016x} is not mapped)", p);
+                return;
+            }
+            let _ = write!(serial, " {:02x}", unsafe {
+                (p as *const u8).read_volatile()
+            });
+        }
+        let _ = writeln!(serial);
+    }
+}
@@ -1,18 +1,22 @@
 #![allow(unused)]
 
 use core::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
     fmt::{self, Display, LowerHex},
-    ptr::addr_of_mut,
     sync::atomic::AtomicUsize,
 };
 
 use spin::Mutex;
 
 use crate::{
+    bit_flag,
+    bitfield::BitField,
     error::{self, WithError},
+    interrupt,
     io::{io_in_32, io_out_32},
-    make_error,
+    kassert, log,
+    logger::LogLevel,
+    make_error, printk, printkln,
 };
 
 /// CONFIG_ADDRESS レジスタの IO ポートアドレス
@@ -20,6 +24,59 @@ const CONFIG_ADDRESS: u16 = 0x0cf8;
 /// CONFIG_DATA レジスタの IO ポートアドレス
 const CONFIG_DATA: u16 = 0x0cfc;
 
+/// コンフィギュレーション空間を読み書きする手段。
+///
+/// ビット操作の多い解釈処理を、実際の IO ポートから切り離すためのもの。
+/// 普段は [PortIo] を使い、自己診断では [MemoryConfigSpace] を使う。
+pub(crate) trait ConfigSpace {
+    /// `reg_addr` を含む 32 ビットのレジスタを読む。下位 2 ビットは無視する。
+    fn read(&self, bus: u8, device: u8, function: u8, reg_addr: u8) -> u32;
+    /// `reg_addr` を含む 32 ビットのレジスタへ書く。下位 2 ビットは無視する。
+    fn write(&self, bus: u8, device: u8, function: u8, reg_addr: u8, value: u32);
+}
+
+/// CONFIG_ADDRESS と CONFIG_DATA の IO ポートを使ったアクセス。
+pub(crate) struct PortIo;
+
+impl ConfigSpace for PortIo {
+    fn read(&self, bus: u8, device: u8, function: u8, reg_addr: u8) -> u32 {
+        write_address(make_address(bus, device, function, reg_addr));
+        read_data()
+    }
+
+    fn write(&self, bus: u8, device: u8, function: u8, reg_addr: u8, value: u32) {
+        write_address(make_address(bus, device, function, reg_addr));
+        write_data(value);
+    }
+}
+
+/// メモリ上に置いた、1 つのファンクションぶんのコンフィギュレーション空間。
+///
+/// 壊れたケーパビリティのリストなど、実機では作れない内容を解釈処理に読ませるためのもの。
+/// バス、デバイス、ファンクションの番号は無視する。
+pub(crate) struct MemoryConfigSpace {
+    regs: [Cell<u32>; 64],
+}
+
+impl MemoryConfigSpace {
+    /// すべてのレジスタが 0 の空間を作る。
+    pub(crate) fn new() -> Self {
+        Self {
+            regs: core::array::from_fn(|_| Cell::new(0)),
+        }
+    }
+}
+
+impl ConfigSpace for MemoryConfigSpace {
+    fn read(&self, _bus: u8, _device: u8, _function: u8, reg_addr: u8) -> u32 {
+        self.regs[reg_addr as usize / 4].get()
+    }
+
+    fn write(&self, _bus: u8, _device: u8, _function: u8, reg_addr: u8, value: u32) {
+        self.regs[reg_addr as usize / 4].set(value);
+    }
+}
+
 /// PCI デバイスのクラスコード。
 #[derive(Clone, Copy, Debug)]
 pub(crate) struct ClassCode {
@@ -43,6 +100,37 @@ impl ClassCode {
     pub(crate) fn r#match(&self, b: u8, s: u8, i: u8) -> bool {
         self.match_base_sub(b, s) && i == self.interface
     }
+
+    /// 表示用の分かりやすい名前を返す。知らないクラスはベースクラスの名前だけを返す。
+    pub(crate) const fn name(&self) -> &'static str {
+        match (self.base, self.sub, self.interface) {
+            (0x01, 0x01, _) => "IDE controller",
+            (0x01, 0x06, _) => "SATA controller",
+            (0x01, 0x08, _) => "NVMe controller",
+            (0x01, _, _) => "mass storage controller",
+            (0x02, 0x00, _) => "Ethernet controller",
+            (0x02, _, _) => "network controller",
+            (0x03, 0x00, _) => "VGA controller",
+            (0x03, _, _) => "display controller",
+            (0x04, 0x03, _) => "audio device",
+            (0x04, _, _) => "multimedia controller",
+            (0x05, _, _) => "memory controller",
+            (0x06, 0x00, _) => "host bridge",
+            (0x06, 0x01, _) => "ISA bridge",
+            (0x06, 0x04, _) => "PCI-to-PCI bridge",
+            (0x06, _, _) => "bridge",
+            (0x07, _, _) => "communication controller",
+            (0x08, _, _) => "system peripheral",
+            (0x0c, 0x03, 0x00) => "USB UHCI controller",
+            (0x0c, 0x03, 0x10) => "USB OHCI controller",
+            (0x0c, 0x03, 0x20) => "USB EHCI controller",
+            (0x0c, 0x03, 0x30) => "USB xHCI controller",
+            (0x0c, 0x03, _) => "USB controller",
+            (0x0c, 0x05, _) => "SMBus controller",
+            (0x0c, _, _) => "serial bus controller",
+            _ => "unknown device",
+        }
+    }
 }
 
 impl Display for ClassCode {
@@ -117,25 +205,38 @@ impl Device {
     }
 
     pub(crate) fn read_conf_reg(&self, reg_addr: u8) -> u32 {
-        write_address(make_address(self.bus, self.device, self.function, reg_addr));
-        read_data()
+        self.read_conf_reg_in(&PortIo, reg_addr)
     }
 
     pub(crate) fn write_conf_reg(&self, reg_addr: u8, value: u32) {
-        write_address(make_address(self.bus, self.device, self.function, reg_addr));
-        write_data(value);
+        self.write_conf_reg_in(&PortIo, reg_addr, value);
+    }
+
+    /// `space` からこのデバイスのレジスタを読む。
+    pub(crate) fn read_conf_reg_in(&self, space: &impl ConfigSpace, reg_addr: u8) -> u32 {
+        space.read(self.bus, self.device, self.function, reg_addr)
+    }
+
+    /// `space` のこのデバイスのレジスタへ書く。
+    pub(crate) fn write_conf_reg_in(&self, space: &impl ConfigSpace, reg_addr: u8, value: u32) {
+        space.write(self.bus, self.device, self.function, reg_addr, value);
     }
 
     pub(crate) fn read_bar(&self, bar_index: u32) -> WithError<u64> {
+        self.read_bar_in(&PortIo, bar_index)
+    }
+
+    /// `space` からこのデバイスの BAR を読み、64 ビットの BAR なら上位と合わせて返す。
+    pub(crate) fn read_bar_in(&self, space: &impl ConfigSpace, bar_index: u32) -> WithError<u64> {
         if bar_index >= 6 {
             return WithError::new(0, make_error!(error::Code::IndexOutOfRange));
         }
 
         let addr = cals_bar_address(bar_index);
-        let bar = self.read_conf_reg(addr);
+        let bar = self.read_conf_reg_in(space, addr);
 
-        // 32 bit アドレス
-        if bar & 4 == 0 {
+        // I/O 空間の BAR と 32 bit アドレス。I/O 空間ではビット 2 もアドレスの一部なので、先に見分ける
+        if bar & 1 != 0 || bar & 4 == 0 {
             return WithError::new(bar as u64, make_error!(error::Code::Success));
         }
 
@@ -144,7 +245,7 @@ impl Device {
             return WithError::new(0, make_error!(error::Code::IndexOutOfRange));
         }
 
-        let bar_upper = self.read_conf_reg(addr + 4);
+        let bar_upper = self.read_conf_reg_in(space, addr + 4);
         WithError::new(
             bar as u64 | (bar_upper as u64) << 32,
             make_error!(error::Code::Success),
@@ -157,35 +258,58 @@ impl Device {
         }
     }
 
+    /// ケーパビリティのリストを先頭から辿り、それぞれの位置と ID を返す。
+    ///
+    /// リストが壊れていて循環していても止まるよう、辿る数に上限を設ける。
+    pub(crate) fn capabilities_in<'a>(
+        &'a self,
+        space: &'a impl ConfigSpace,
+    ) -> impl Iterator<Item = (u8, u8)> + 'a {
+        // ケーパビリティは 0x40 以降に 4 バイト単位で並ぶので、これより多くはならない
+        const MAX_CAPABILITIES: usize = (0x100 - 0x40) / 4;
+
+        // ステータスレジスタの Capabilities List ビットが下りていれば、0x34 は意味を持たない
+        let (_, status) = split_command_status(self.read_conf_reg_in(space, 0x04));
+        let mut cap_addr = if status.capabilities_list() {
+            (self.read_conf_reg_in(space, 0x34) & 0xfc) as u8
+        } else {
+            0
+        };
+        core::iter::from_fn(move || {
+            if cap_addr == 0 {
+                return None;
+            }
+            let header = CapabilityHeader {
+                data: self.read_conf_reg_in(space, cap_addr),
+            };
+            let item = (cap_addr, header.bits().cap_id() as u8);
+            cap_addr = (header.bits().next_ptr() & 0xfc) as u8;
+            Some(item)
+        })
+        .take(MAX_CAPABILITIES)
+    }
+
+    /// ケーパビリティのリストを辿り、ID が `cap_id` の最初のものの位置を返す。
+    pub(crate) fn find_capability_in(&self, space: &impl ConfigSpace, cap_id: u8) -> Option<u8> {
+        self.capabilities_in(space)
+            .find(|&(_, id)| id == cap_id)
+            .map(|(addr, _)| addr)
+    }
+
+    pub(crate) fn find_capability(&self, cap_id: u8) -> Option<u8> {
+        self.find_capability_in(&PortIo, cap_id)
+    }
+
     fn configure_msi(
         &mut self,
         msg_addr: u32,
         msg_data: u32,
         num_vector_exponent: u32,
     ) -> error::Error {
-        let mut cap_addr = self.read_conf_reg(0x34) & 0xff;
-        let mut msi_cap_addr = 0;
-        let mut msix_cap_addr = 0;
-
-        while cap_addr != 0 {
-            let header = self.read_capability_header(cap_addr as u8);
-            if header.bits().cap_id() == CAPABILITY_MSI as u32 {
-                msi_cap_addr = cap_addr;
-            } else if header.bits().cap_id() == CAPABILITY_MSIX as u32 {
-                msix_cap_addr = cap_addr;
-            }
-            cap_addr = header.bits().next_ptr();
-        }
-
-        if msi_cap_addr != 0 {
-            self.configure_msi_register(msi_cap_addr as u8, msg_addr, msg_data, num_vector_exponent)
-        } else if msix_cap_addr != 0 {
-            self.configure_msix_register(
-                msix_cap_addr as u8,
-                msg_addr,
-                msg_data,
-                num_vector_exponent,
-            )
+        if let Some(msi_cap_addr) = self.find_capability(CAPABILITY_MSI) {
+            self.configure_msi_register(msi_cap_addr, msg_addr, msg_data, num_vector_exponent)
+        } else if let Some(msix_cap_addr) = self.find_capability(CAPABILITY_MSIX) {
+            self.configure_msix_register(msix_cap_addr, msg_addr, msg_data, num_vector_exponent)
         } else {
             make_error!(error::Code::NoPCIMSI)
         }
@@ -259,15 +383,17 @@ impl Device {
         let mut msi_cap = self.read_msi_capability(cap_addr);
 
         // なんか unpacked 構造体の参照は UB（未定義動作）らしい
-        let header = addr_of_mut!(msi_cap.header);
-        let enable = if unsafe { *header }.bits().multi_msg_capable() <= num_vector_exponent {
-            unsafe { *header }.bits().multi_msg_capable()
+        // なので、一度コピーしてから書き換えて書き戻す
+        let mut header = msi_cap.header;
+        let enable = if header.bits().multi_msg_capable() <= num_vector_exponent {
+            header.bits().multi_msg_capable()
         } else {
             num_vector_exponent
         };
-        unsafe { *header }.bits_mut().set_multi_msg_enable(enable);
+        header.bits_mut().set_multi_msg_enable(enable);
 
-        unsafe { *header }.bits_mut().set_msi_enable(1);
+        header.bits_mut().set_msi_enable(1);
+        msi_cap.header = header;
         msi_cap.msg_addr = msg_addr;
         msi_cap.msg_data = msg_data;
 
@@ -275,6 +401,18 @@ impl Device {
         make_error!(error::Code::Success)
     }
 
+    /// MSI で有効になっているメッセージ数の 2 を底とする対数を返す。
+    /// MSI ケーパビリティが無ければ 0 を返す。
+    fn enabled_msi_exponent(&self) -> u32 {
+        match self.find_capability(CAPABILITY_MSI) {
+            Some(cap_addr) => {
+                let header = self.read_msi_capability(cap_addr).header;
+                header.bits().multi_msg_enable()
+            }
+            None => 0,
+        }
+    }
+
     fn configure_msix_register(
         &mut self,
         cap_addr: u8,
@@ -307,8 +445,17 @@ pub(crate) fn read_data() -> u32 {
 
 /// ベンダ ID レジスタを読み取る（全ヘッダタイプ共通）。
 pub(crate) fn read_vendor_id(bus: u8, device: u8, function: u8) -> u16 {
-    write_address(make_address(bus, device, function, 0x00));
-    (read_data() & 0xffff) as u16
+    read_vendor_id_in(&PortIo, bus, device, function)
+}
+
+/// `space` からベンダ ID レジスタを読み取る。
+pub(crate) fn read_vendor_id_in(
+    space: &impl ConfigSpace,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> u16 {
+    (space.read(bus, device, function, 0x00) & 0xffff) as u16
 }
 
 /// デバイス ID レジスタを読み取る（全ヘッダタイプ共通）。
@@ -319,14 +466,32 @@ pub(crate) fn read_device_id(bus: u8, device: u8, function: u8) -> u16 {
 
 /// ヘッダタイプレジスタを読み取る（全ヘッダタイプ共通）。
 pub(crate) fn read_header_type(bus: u8, device: u8, function: u8) -> u8 {
-    write_address(make_address(bus, device, function, 0x0c));
-    ((read_data() >> 16) & 0xff) as u8
+    read_header_type_in(&PortIo, bus, device, function)
+}
+
+/// `space` からヘッダタイプレジスタを読み取る。
+pub(crate) fn read_header_type_in(
+    space: &impl ConfigSpace,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> u8 {
+    ((space.read(bus, device, function, 0x0c) >> 16) & 0xff) as u8
 }
 
 /// クラスコード・レジスタを読み取る（全ヘッダタイプ共通）。
 pub(crate) fn read_class_code(bus: u8, device: u8, function: u8) -> ClassCode {
-    write_address(make_address(bus, device, function, 0x08));
-    let reg = read_data();
+    read_class_code_in(&PortIo, bus, device, function)
+}
+
+/// `space` からクラスコード・レジスタを読み取る。
+pub(crate) fn read_class_code_in(
+    space: &impl ConfigSpace,
+    bus: u8,
+    device: u8,
+    function: u8,
+) -> ClassCode {
+    let reg = space.read(bus, device, function, 0x08);
     ClassCode {
         base: ((reg >> 24) & 0xff) as u8,
         sub: ((reg >> 16) & 0xff) as u8,
@@ -346,7 +511,7 @@ pub(crate) fn read_bus_numbers(bus: u8, device: u8, function: u8) -> u32 {
 }
 
 /// 単一ファンクションの場合に真を返す。
-fn is_single_function_device(header_type: u8) -> bool {
+pub(crate) fn is_single_function_device(header_type: u8) -> bool {
     header_type & 0x80 == 0
 }
 
@@ -364,21 +529,29 @@ const fn cals_bar_address(bar_index: u32) -> u8 {
 
 /// PCI デバイスをすべて探索し、[DEVICES] に格納する。
 ///
-/// バス 0 から再帰的に PCI デバイスを探索し、[DEVICES] の先頭から詰めて書き込む。
-/// 発見したデバイスの数を [NUM_DEVICES] に設定する。
+/// バス 0 から、PCI-PCI ブリッジの先のセカンダリバスを順に辿って PCI デバイスを探索し、
+/// [DEVICES] の先頭から詰めて書き込む。発見したデバイスの数を [NUM_DEVICES] に設定する。
+/// 前回の結果は消してから探索し直す。
 pub(crate) fn scan_all_bus() -> error::Error {
-    let mut num_device = 0;
+    *NUM_DEVICES.lock().get_mut() = 0;
+    *DEVICES.lock().get_mut() = [None; DEVICE_MAX_LEN];
 
+    let mut queue = BusQueue::new();
+    // バス 0 のデバイス 0 がマルチファンクションなら、ファンクション番号ごとに別のホストブリッジがあり、
+    // それぞれがファンクション番号と同じ番号のバスを受け持つ
     let header_type = read_header_type(0, 0, 0);
     if is_single_function_device(header_type) {
-        return scan_bus(0);
+        queue.push(0);
+    } else {
+        for function in 0..8 {
+            if read_vendor_id(0, 0, function) != 0xffff {
+                queue.push(function);
+            }
+        }
     }
 
-    for function in 0..8 {
-        if read_vendor_id(0, 0, function) == 0xffff {
-            continue;
-        }
-        let err = scan_bus(function);
+    while let Some(bus) = queue.pop() {
+        let err = scan_bus(bus, &mut queue);
         if (&err).into() {
             return err;
         }
@@ -386,6 +559,47 @@ pub(crate) fn scan_all_bus() -> error::Error {
     make_error!(error::Code::Success)
 }
 
+/// これから探索するバスの待ち行列。
+///
+/// ブリッジの設定がおかしくてバス番号が循環していても止まるよう、一度積んだバスは二度と積まない。
+struct BusQueue {
+    /// 積んだことのあるバス（ビットごと）
+    visited: [u64; 4],
+    pending: [u8; 256],
+    len: usize,
+}
+
+impl BusQueue {
+    const fn new() -> Self {
+        Self {
+            visited: [0; 4],
+            pending: [0; 256],
+            len: 0,
+        }
+    }
+
+    /// `bus` を積む。既に積んだことがあれば何もせず false を返す。
+    fn push(&mut self, bus: u8) -> bool {
+        let (word, bit) = (bus as usize / 64, bus as usize % 64);
+        if self.visited[word] & (1 << bit) != 0 {
+            return false;
+        }
+        self.visited[word] |= 1 << bit;
+        // 各バスは一度しか積まないので、256 個を超えることはない
+        self.pending[self.len] = bus;
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        self.len -= 1;
+        Some(self.pending[self.len])
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(packed)]
 pub(crate) struct CapabilityHeaderBits {
@@ -434,6 +648,135 @@ impl CapabilityHeader {
 const CAPABILITY_MSI: u8 = 0x05;
 const CAPABILITY_MSIX: u8 = 0x11;
 
+/// コマンドレジスタ（0x04 の下位 16 ビット）
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub(crate) struct Command(u16);
+
+impl Command {
+    pub(crate) const fn value(self) -> u16 {
+        self.0
+    }
+
+    bit_flag!(io_space, with_io_space, 0);
+    bit_flag!(memory_space, with_memory_space, 1);
+    bit_flag!(bus_master, with_bus_master, 2);
+    bit_flag!(
+        /// INTx による割り込みを止める。MSI を使うときに立てる。
+        interrupt_disable,
+        with_interrupt_disable,
+        10
+    );
+}
+
+/// ステータスレジスタ（0x04 の上位 16 ビット）
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub(crate) struct Status(u16);
+
+impl Status {
+    pub(crate) const fn value(self) -> u16 {
+        self.0
+    }
+
+    bit_flag!(interrupt_status, with_interrupt_status, 3);
+    bit_flag!(
+        /// 立っていれば、0x34 がケーパビリティのリストの先頭を指す
+        capabilities_list,
+        with_capabilities_list,
+        4
+    );
+    bit_flag!(capable_66mhz, with_capable_66mhz, 5);
+}
+
+/// コンフィギュレーション空間の 0x04 の値を、コマンドレジスタとステータスレジスタに分ける。
+pub(crate) const fn split_command_status(value: u32) -> (Command, Status) {
+    let value = BitField(value);
+    (
+        Command(value.get_bits(0..16) as u16),
+        Status(value.get_bits(16..32) as u16),
+    )
+}
+
+/// ケーパビリティ ID の表示用の名前を返す。知らないものは "unknown"。
+pub(crate) const fn capability_name(cap_id: u8) -> &'static str {
+    match cap_id {
+        0x01 => "power management",
+        0x02 => "AGP",
+        0x03 => "VPD",
+        0x04 => "slot identification",
+        CAPABILITY_MSI => "MSI",
+        0x06 => "CompactPCI hot swap",
+        0x07 => "PCI-X",
+        0x08 => "HyperTransport",
+        0x09 => "vendor specific",
+        0x0a => "debug port",
+        0x0c => "PCI hot plug",
+        0x0d => "bridge subsystem vendor ID",
+        0x0e => "AGP 8x",
+        0x0f => "secure device",
+        0x10 => "PCI Express",
+        CAPABILITY_MSIX => "MSI-X",
+        0x12 => "SATA",
+        0x13 => "advanced features",
+        0x14 => "enhanced allocation",
+        0x15 => "flattening portal bridge",
+        _ => "unknown",
+    }
+}
+
+/// BAR の値を解釈したもの。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Bar {
+    /// 何も割り当てられていない
+    Unused,
+    /// I/O 空間のポート番号
+    Io(u32),
+    /// メモリ空間の番地
+    Memory {
+        address: u64,
+        is_64bit: bool,
+        prefetchable: bool,
+    },
+}
+
+impl Bar {
+    /// [Device::read_bar] で読んだ値を解釈する。
+    pub(crate) const fn decode(value: u64) -> Self {
+        if value & 1 != 0 {
+            Self::Io((value & !0x3) as u32)
+        } else if value & !0xf == 0 {
+            Self::Unused
+        } else {
+            Self::Memory {
+                address: value & !0xf,
+                is_64bit: value & 0x4 != 0,
+                prefetchable: value & 0x8 != 0,
+            }
+        }
+    }
+}
+
+impl Display for Bar {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Unused => write!(f, "unused"),
+            Self::Io(port) => write!(f, "I/O at {:#x}", port),
+            Self::Memory {
+                address,
+                is_64bit,
+                prefetchable,
+            } => write!(
+                f,
+                "memory at {:#x} ({}-bit{})",
+                address,
+                if is_64bit { 64 } else { 32 },
+                if prefetchable { ", prefetchable" } else { "" }
+            ),
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(packed)]
 pub(crate) struct MSICapabilityHeaderBits {
@@ -453,31 +796,31 @@ impl MSICapabilityHeaderBits {
     }
 
     pub(crate) fn msi_enable(&self) -> u32 {
-        (self.etc_1 & 0x01) as u32
+        BitField(self.etc_1).get_bits(0..1) as u32
     }
 
     pub(crate) fn set_msi_enable(&mut self, value: u32) {
-        self.etc_1 = (self.etc_1 & !0x01) | ((value as u8) & 0x01);
+        self.etc_1 = BitField(self.etc_1).with_bits(0..1, value as u8).0;
     }
 
     pub(crate) fn multi_msg_capable(&self) -> u32 {
-        ((self.etc_1 >> 1) & 0x07) as u32
+        BitField(self.etc_1).get_bits(1..4) as u32
     }
 
     pub(crate) fn multi_msg_enable(&self) -> u32 {
-        ((self.etc_1 >> 4) & 0x07) as u32
+        BitField(self.etc_1).get_bits(4..7) as u32
     }
 
     pub(crate) fn set_multi_msg_enable(&mut self, value: u32) {
-        self.etc_1 = (self.etc_1 & !0x70) | (((value as u8) & 0x07) << 4);
+        self.etc_1 = BitField(self.etc_1).with_bits(4..7, value as u8).0;
     }
 
     pub(crate) fn addr_64_capable(&self) -> u32 {
-        ((self.etc_1 >> 7) & 0x01) as u32
+        BitField(self.etc_1).get_bits(7..8) as u32
     }
 
     pub(crate) fn per_vector_mask_capable(&self) -> u32 {
-        (self.etc_2 & 0x01) as u32
+        BitField(self.etc_2).get_bits(0..1) as u32
     }
 }
 
@@ -513,12 +856,14 @@ pub(crate) struct MSICapability {
     pending_bits: u32,
 }
 
-enum MSITriggerMode {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MSITriggerMode {
     Edge = 0,
     Level = 1,
 }
 
-enum MSIDeliverMode {
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MSIDeliverMode {
     Fixed = 0b000,
     LowestPriority = 0b001,
     SMI = 0b010,
@@ -527,6 +872,133 @@ enum MSIDeliverMode {
     ExtINT = 0b111,
 }
 
+/// MSI のメッセージアドレスの宛先の解釈（Destination Mode）。
+///
+/// 物理モードでは宛先 ID を Local APIC ID としてそのまま比べるので、1 つのコアへ確実に届く。
+/// 論理モードでは各コアの論理 APIC ID（LDR と DFR）と比べるので、複数のコアを宛先にできるが、
+/// 事前に LDR を設定しておかないとどのコアにも届かない。まだ LDR を設定していないので、
+/// 物理モードを使う。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MSIDestinationMode {
+    Physical = 0,
+    Logical = 1,
+}
+
+/// MSI のメッセージアドレスの組み立て方。
+#[derive(Clone, Copy)]
+pub(crate) struct MSIAddress {
+    /// アドレスの上位 12 ビット。x86 では通常 0xfee0_0000。
+    pub(crate) base: u32,
+    /// Redirection Hint。true にすると、論理モードで宛先のうち優先度の低いコアへ届ける。
+    pub(crate) redirection_hint: bool,
+    pub(crate) destination_mode: MSIDestinationMode,
+}
+
+impl MSIAddress {
+    /// 割り込みリマッピングを使わない、標準の物理モードのアドレス。
+    pub(crate) const DEFAULT: Self = Self {
+        base: 0xfee0_0000,
+        redirection_hint: false,
+        destination_mode: MSIDestinationMode::Physical,
+    };
+
+    /// 宛先 ID が `destination` のメッセージアドレスを返す。
+    pub(crate) const fn encode(&self, destination: u8) -> u32 {
+        BitField(self.base & 0xfff0_0000)
+            .with_bits(12..20, destination as u32)
+            .with_bit(3, self.redirection_hint)
+            .with_bits(2..3, self.destination_mode as u32)
+            .0
+    }
+}
+
+/// 一度に割り当てられる MSI メッセージ数の上限
+const MSI_MAX_MESSAGES: usize = 32;
+
+/// 指定された CPU コアへの固定割り込みとして MSI を設定する。
+///
+/// * `apic_id` - 割り込みを受け取る CPU コアの Local APIC ID（論理モードなら論理 APIC ID）
+/// * `address` - メッセージアドレスの組み立て方。普段は [MSIAddress::DEFAULT]
+/// * `vector` - 先頭の割り込みベクタ番号。[interrupt::allocate_vectors] か
+///   [interrupt::register_vector] で得たものを使う。
+/// * `num_vector_exponent` - 要求するメッセージ数の 2 を底とする対数
+pub(crate) fn configure_msi_fixed_destination(
+    dev: &mut Device,
+    apic_id: u8,
+    address: &MSIAddress,
+    trigger_mode: MSITriggerMode,
+    delivery_mode: MSIDeliverMode,
+    vector: u8,
+    num_vector_exponent: u32,
+) -> error::Error {
+    let msg_addr = address.encode(apic_id);
+    // レベルトリガでは Trigger Mode（15 ビット目）を立て、Level（14 ビット目）を Assert にする
+    let level = trigger_mode == MSITriggerMode::Level;
+    let msg_data = BitField(vector as u32)
+        .with_bits(8..11, delivery_mode as u32)
+        .with_bit(14, level)
+        .with_bit(15, level);
+    dev.configure_msi(msg_addr, msg_data.0, num_vector_exponent)
+}
+
+/// `vectors` の各ベクタに MSI メッセージを割り当て、実際に有効になったメッセージ数を返す。
+///
+/// 複数メッセージの MSI では、デバイスがメッセージデータの下位ビットを書き換えてベクタを区別する。
+/// そのため `vectors` は連続していて、個数が 2 のべき乗（32 以下）、かつ先頭が個数の倍数でなければならない。
+/// この条件を満たさない場合や、デバイスがそれだけのメッセージに対応していない場合は、
+/// 先頭のベクタだけを使う（あるいはデバイスが対応する数まで減らす）。
+pub(crate) fn configure_msi(dev: &mut Device, apic_id: u8, vectors: &[u8]) -> WithError<usize> {
+    if vectors.is_empty() {
+        return WithError::new(0, make_error!(error::Code::IndexOutOfRange));
+    }
+    // 手で決めた番号は他の割り込みとぶつかりうる
+    if let Some(&vector) = vectors
+        .iter()
+        .find(|&&v| interrupt::vector_owner(v).is_none())
+    {
+        log!(
+            LogLevel::Warn,
+            "MSI vector {:#04x} of {}.{}.{} is not allocated",
+            vector,
+            dev.bus(),
+            dev.device(),
+            dev.function()
+        );
+    }
+
+    let contiguous = vectors
+        .windows(2)
+        .all(|w| w[0].checked_add(1) == Some(w[1]));
+    let num_vectors = vectors.len();
+    let allocatable = contiguous
+        && num_vectors.is_power_of_two()
+        && num_vectors <= MSI_MAX_MESSAGES
+        && (vectors[0] as usize).is_multiple_of(num_vectors);
+    let num_vector_exponent = if allocatable {
+        num_vectors.trailing_zeros()
+    } else {
+        0
+    };
+
+    let err = configure_msi_fixed_destination(
+        dev,
+        apic_id,
+        &MSIAddress::DEFAULT,
+        MSITriggerMode::Level,
+        MSIDeliverMode::Fixed,
+        vectors[0],
+        num_vector_exponent,
+    );
+    if (&err).into() {
+        return WithError::new(0, err);
+    }
+
+    WithError::new(
+        1 << dev.enabled_msi_exponent(),
+        make_error!(error::Code::Success),
+    )
+}
+
 pub(crate) fn initialize_pci() {}
 
 fn make_address(bus: u8, device: u8, function: u8, reg_addr: u8) -> u32 {
@@ -545,6 +1017,11 @@ fn add_device(device: Device) -> error::Error {
     if *num_devices == devices.len() {
         return make_error!(error::Code::Full);
     }
+    kassert!(
+        devices[*num_devices].is_none(),
+        "DEVICES[{}] is already used",
+        *num_devices
+    );
 
     devices[*num_devices] = Some(device);
     *num_devices += 1;
@@ -552,8 +1029,8 @@ fn add_device(device: Device) -> error::Error {
 }
 
 /// 指定のファンクションを devices に追加する。
-/// もし PCI-PCI ブリッジなら、セカンダリバスに対し [scan_bus] を実行する。
-fn scan_function(bus: u8, device: u8, function: u8) -> error::Error {
+/// もし PCI-PCI ブリッジなら、セカンダリバスを `queue` に積む。
+fn scan_function(bus: u8, device: u8, function: u8, queue: &mut BusQueue) -> error::Error {
     let class_code = read_class_code(bus, device, function);
     let header_type = read_header_type(bus, device, function);
     let dev = Device::new(bus, device, function, header_type, class_code);
@@ -566,8 +1043,17 @@ fn scan_function(bus: u8, device: u8, function: u8) -> error::Error {
     // PCI-PCI ブリッジの場合
     if class_code.match_base_sub(0x06, 0x04) {
         let bus_number = read_bus_numbers(bus, device, function);
-        let secondary_bus = (bus_number >> 8) & 0xff;
-        return scan_bus(secondary_bus as u8);
+        let secondary_bus = ((bus_number >> 8) & 0xff) as u8;
+        if !queue.push(secondary_bus) {
+            log!(
+                LogLevel::Warn,
+                "{}.{}.{}: secondary bus {} is already scanned",
+                bus,
+                device,
+                function,
+                secondary_bus
+            );
+        }
     }
 
     make_error!(error::Code::Success)
@@ -575,8 +1061,10 @@ fn scan_function(bus: u8, device: u8, function: u8) -> error::Error {
 
 /// 指定のデバイス番号の各ファンクションをスキャンする。
 /// 有効なファンクションを見つけたら [scan_function] を実行する。
-fn scan_device(bus: u8, device: u8) -> error::Error {
-    let err = scan_function(bus, device, 0);
+///
+/// ファンクション 0 のヘッダタイプのビット 7 が立っていれば、ファンクション 1〜7 も調べる。
+fn scan_device(bus: u8, device: u8, queue: &mut BusQueue) -> error::Error {
+    let err = scan_function(bus, device, 0, queue);
     if (&err).into() {
         return err;
     }
@@ -588,7 +1076,7 @@ fn scan_device(bus: u8, device: u8) -> error::Error {
         if read_vendor_id(bus, device, function) == 0xffff {
             continue;
         }
-        let err = scan_function(bus, device, function);
+        let err = scan_function(bus, device, function, queue);
         if (&err).into() {
             return err;
         }
@@ -598,12 +1086,12 @@ fn scan_device(bus: u8, device: u8) -> error::Error {
 
 /// 指定のバス番号の各デバイスをスキャンする。
 /// 有効なデバイスを見つけたら [scan_device] を実行する。
-fn scan_bus(bus: u8) -> error::Error {
+fn scan_bus(bus: u8, queue: &mut BusQueue) -> error::Error {
     for device in 0..DEVICE_MAX_LEN as u8 {
         if read_vendor_id(bus, device, 0) == 0xffff {
             continue;
         }
-        let err = scan_device(bus, device);
+        let err = scan_device(bus, device, queue);
         if (&err).into() {
             return err;
         }
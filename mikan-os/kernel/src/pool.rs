@@ -0,0 +1,71 @@
+#![allow(unused)]
+
+use core::ptr::addr_of_mut;
+
+use spin::Mutex;
+
+use crate::{
+    error::{self, WithError},
+    make_error,
+};
+
+/// メモリプールの容量（バイト）
+///
+/// xHC のスクラッチパッドバッファ（1 つ 1 ページで、実機では数十個を求められることがある）も
+/// ここから切り出すので、リングやコンテキストの分に加えて余裕を持たせておく。
+pub(crate) const POOL_SIZE: usize = 4096 * 128;
+
+/// ページ境界に揃えたメモリプールの実体。
+#[repr(C, align(4096))]
+struct Arena([u8; POOL_SIZE]);
+
+static mut ARENA: Arena = Arena([0u8; POOL_SIZE]);
+/// [ARENA] の先頭から、既に切り出したバイト数。
+static ALLOC_OFFSET: Mutex<usize> = Mutex::new(0);
+
+/// メモリプールから `size` バイトの領域を切り出し、その先頭ポインタを返す。
+///
+/// 先頭アドレスは `align` の倍数に揃えられる（`align` は 2 のべき乗。0 なら制約しない）。
+/// ヒープが使えるようになる前に、DMA に使う構造体などを置くためのもので、解放はできない。
+/// 切り出した領域は一度も使われていないので、0 で初期化されている。
+/// 容量が足りない場合は [error::Code::NoEnoughMemory] を返す。
+pub(crate) fn alloc_aligned(size: usize, align: usize) -> WithError<*mut u8> {
+    alloc_bounded(size, align, 0)
+}
+
+/// [alloc_aligned] に加え、`size <= boundary` なら領域が `boundary` の倍数の境界を跨がないようにする。
+///
+/// `boundary` は 2 のべき乗で、0 なら制約しない。典型的にはページ境界を跨がないように 4096 を指定する。
+pub(crate) fn alloc_bounded(size: usize, align: usize, boundary: usize) -> WithError<*mut u8> {
+    debug_assert!(align == 0 || align.is_power_of_two());
+    debug_assert!(boundary == 0 || boundary.is_power_of_two());
+
+    let base = unsafe { addr_of_mut!(ARENA) } as usize;
+    let mut offset = ALLOC_OFFSET.lock();
+
+    let mut addr = base + *offset;
+    if align > 0 {
+        addr = (addr + align - 1) & !(align - 1);
+    }
+    if boundary > 0 {
+        let next_boundary = (addr + boundary - 1) & !(boundary - 1);
+        if next_boundary < addr + size {
+            addr = next_boundary;
+        }
+    }
+
+    if addr + size > base + POOL_SIZE {
+        return WithError::new(
+            core::ptr::null_mut(),
+            make_error!(error::Code::NoEnoughMemory),
+        );
+    }
+
+    *offset = addr + size - base;
+    WithError::new(addr as *mut u8, make_error!(error::Code::Success))
+}
+
+/// メモリプールの残り容量（バイト）を返す。
+pub(crate) fn remaining() -> usize {
+    POOL_SIZE - *ALLOC_OFFSET.lock()
+}
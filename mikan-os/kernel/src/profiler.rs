@@ -0,0 +1,145 @@
+#![allow(unused)]
+
+use core::{
+    cmp::Reverse,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use spin::Mutex;
+
+use crate::{
+    collections::FixedMap, interrupt::InterruptFrame, log, logger::LogLevel, printk, printkln,
+};
+
+/// 番地をまとめて数える単位のビット数。256 バイトごとに 1 つのバケットにする。
+const BUCKET_SHIFT: u32 = 8;
+/// 数えておけるバケットの数。あふれた標本は数だけ覚えて捨てる。
+const MAX_BUCKETS: usize = 128;
+/// [report] で表示するバケットの数
+const REPORT_TOP: usize = 10;
+/// [register_symbol] で登録できる関数の数
+const MAX_SYMBOLS: usize = 32;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// バケットの番号に 1 を足したもの。0 なら空き。
+static KEYS: [AtomicU64; MAX_BUCKETS] = [const { AtomicU64::new(0) }; MAX_BUCKETS];
+/// バケットごとの標本数
+static COUNTS: [AtomicU64; MAX_BUCKETS] = [const { AtomicU64::new(0) }; MAX_BUCKETS];
+/// 記録した標本の総数
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+/// バケットが足りずに捨てた標本の数
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+
+/// 先頭の番地と名前。[report] で番地を関数名に読み替えるのに使う。
+static SYMBOLS: Mutex<FixedMap<u64, &'static str, MAX_SYMBOLS>> = Mutex::new(FixedMap::new());
+
+/// 標本の記録を始めるか止める。止めても、それまでの結果は [reset] するまで残る。
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// タイマ割り込み（[crate::timer::start_tick]）から 1 ティックごとに呼ばれる。有効なら、割り込まれた番地を記録する。
+pub(crate) fn on_timer_interrupt(frame: &InterruptFrame) {
+    if is_enabled() {
+        record(frame.rip);
+    }
+}
+
+/// `rip` を含むバケットの標本数を 1 増やす。
+///
+/// 割り込みハンドラから呼ばれるので、ロックを取らずにアトミック操作だけで数える。
+pub(crate) fn record(rip: u64) {
+    TOTAL.fetch_add(1, Ordering::Relaxed);
+    let key = (rip >> BUCKET_SHIFT) + 1;
+    // 近い番地が同じ場所に固まらないように散らしてから、空きを線形に探す
+    let start = (key.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize % MAX_BUCKETS;
+    for i in 0..MAX_BUCKETS {
+        let slot = (start + i) % MAX_BUCKETS;
+        let current =
+            match KEYS[slot].compare_exchange(0, key, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => key,
+                Err(current) => current,
+            };
+        if current == key {
+            COUNTS[slot].fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    }
+    DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// 記録した標本をすべて消す。記録中に呼ぶと、消している間の標本が残ることがある。
+pub(crate) fn reset() {
+    for (key, count) in KEYS.iter().zip(COUNTS.iter()) {
+        key.store(0, Ordering::Relaxed);
+        count.store(0, Ordering::Relaxed);
+    }
+    TOTAL.store(0, Ordering::Relaxed);
+    DROPPED.store(0, Ordering::Relaxed);
+}
+
+/// [report] で番地を読み替える関数を登録する。満杯なら何もしない。
+pub(crate) fn register_symbol(name: &'static str, addr: u64) {
+    let _ = SYMBOLS.lock().insert(addr, name);
+}
+
+/// `addr` 以下で最も近い、登録された関数の名前と先頭からのずれを返す。
+///
+/// 関数の大きさは分からないので、次に登録された関数までをその関数の範囲とみなす。
+fn symbolize(addr: u64) -> Option<(&'static str, u64)> {
+    SYMBOLS
+        .lock()
+        .iter()
+        .filter(|(start, _)| **start <= addr)
+        .max_by_key(|(start, _)| **start)
+        .map(|(start, name)| (*name, addr - start))
+}
+
+/// 標本の多いバケットから順に、[REPORT_TOP] 個を Info でログに出す。
+pub(crate) fn report() {
+    let mut buckets = [(0u64, 0u64); MAX_BUCKETS];
+    for (bucket, (key, count)) in buckets.iter_mut().zip(KEYS.iter().zip(COUNTS.iter())) {
+        *bucket = (key.load(Ordering::Relaxed), count.load(Ordering::Relaxed));
+    }
+    buckets.sort_unstable_by_key(|&(_, count)| Reverse(count));
+
+    let total = TOTAL.load(Ordering::Relaxed);
+    log!(
+        LogLevel::Info,
+        "profiler: {} samples ({} dropped)",
+        total,
+        DROPPED.load(Ordering::Relaxed)
+    );
+    for &(key, count) in buckets
+        .iter()
+        .take(REPORT_TOP)
+        .filter(|(key, count)| *key != 0 && *count != 0)
+    {
+        let addr = (key - 1) << BUCKET_SHIFT;
+        let permille = count * 1000 / total.max(1);
+        match symbolize(addr) {
+            Some((name, offset)) => log!(
+                LogLevel::Info,
+                "  {:#018x} {:6} {:3}.{}%  {}+{:#x}",
+                addr,
+                count,
+                permille / 10,
+                permille % 10,
+                name,
+                offset
+            ),
+            None => log!(
+                LogLevel::Info,
+                "  {:#018x} {:6} {:3}.{}%",
+                addr,
+                count,
+                permille / 10,
+                permille % 10
+            ),
+        }
+    }
+}
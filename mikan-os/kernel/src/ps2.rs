@@ -0,0 +1,186 @@
+#![allow(unused)]
+
+use crate::{
+    error,
+    io::{io_in_8, io_out_8},
+    keyboard::{L_ALT_BIT, L_CONTROL_BIT, L_SHIFT_BIT, R_SHIFT_BIT},
+    make_error,
+    wait::wait_until,
+};
+
+/// PS/2 コントローラのデータポート
+const DATA_PORT: u16 = 0x60;
+/// PS/2 コントローラのステータスレジスタ（読み出し）とコマンドレジスタ（書き込み）
+const STATUS_PORT: u16 = 0x64;
+/// ステータスレジスタの出力バッファフルビット
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+/// ステータスレジスタの、出力バッファのデータがマウスから来たことを表すビット
+const STATUS_AUX_DATA: u8 = 0x20;
+/// ステータスレジスタの入力バッファフルビット
+const STATUS_INPUT_FULL: u8 = 0x02;
+/// CPU のリセット線を叩くコマンド
+const COMMAND_PULSE_RESET: u8 = 0xfe;
+
+/// キーボードの LED を設定するコマンド。続けて LED のビットを送る。
+const KEYBOARD_COMMAND_SET_LEDS: u8 = 0xed;
+/// キーボードがコマンドを受け付けたときの応答
+const KEYBOARD_ACK: u8 = 0xfa;
+/// キーボードが送り直しを求めるときの応答
+const KEYBOARD_RESEND: u8 = 0xfe;
+/// 送り直しを求められたときに、送り直す回数の上限
+const KEYBOARD_MAX_RETRIES: usize = 3;
+/// キーボードの応答を待つ回数。PS/2 キーボードが無いマシンでも待たされすぎないよう短めにする。
+const KEYBOARD_POLL_LIMIT: usize = 10_000;
+
+/// 拡張キーの前置バイト
+const SCANCODE_EXTENDED: u8 = 0xe0;
+/// キーを離したことを表すビット
+const SCANCODE_BREAK: u8 = 0x80;
+
+const SCANCODE_L_CONTROL: u8 = 0x1d;
+const SCANCODE_L_SHIFT: u8 = 0x2a;
+const SCANCODE_R_SHIFT: u8 = 0x36;
+const SCANCODE_L_ALT: u8 = 0x38;
+
+/// スキャンコードセット 1 から HID キーコードへの対応。0 は対応無し。
+const SCANCODE_TO_KEYCODE: [u8; 0x80] = [
+    0x00, 0x29, 0x1e, 0x1f, 0x20, 0x21, 0x22, 0x23, // 0x00
+    0x24, 0x25, 0x26, 0x27, 0x2d, 0x2e, 0x2a, 0x2b, // 0x08
+    0x14, 0x1a, 0x08, 0x15, 0x17, 0x1c, 0x18, 0x0c, // 0x10
+    0x12, 0x13, 0x2f, 0x30, 0x28, 0x00, 0x04, 0x16, // 0x18
+    0x07, 0x09, 0x0a, 0x0b, 0x0d, 0x0e, 0x0f, 0x33, // 0x20
+    0x34, 0x35, 0x00, 0x31, 0x1d, 0x1b, 0x06, 0x19, // 0x28
+    0x05, 0x11, 0x10, 0x36, 0x37, 0x38, 0x00, 0x55, // 0x30
+    0x00, 0x2c, 0x39, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, // 0x38
+    0x3f, 0x40, 0x41, 0x42, 0x43, 0x53, 0x47, 0x5f, // 0x40
+    0x60, 0x61, 0x56, 0x5c, 0x5d, 0x5e, 0x57, 0x59, // 0x48
+    0x5a, 0x5b, 0x62, 0x63, 0x00, 0x00, 0x64, 0x44, // 0x50
+    0x45, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x58
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x60
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // 0x68
+    // JIS 配列のかな（0x70）、ろ（0x73）、変換（0x79）、無変換（0x7b）、￥（0x7d）
+    0x88, 0x00, 0x00, 0x87, 0x00, 0x00, 0x00, 0x00, // 0x70
+    0x00, 0x8a, 0x00, 0x8b, 0x00, 0x89, 0x00, 0x00, // 0x78
+];
+
+/// 割り込みを使わずに PS/2 キーボードを読む。
+///
+/// xHC を使わない場面（セーフモードなど）のためのもので、ファームウェアが
+/// スキャンコードセット 1 への変換を有効にしたままにしていることを前提とする。
+pub(crate) struct Ps2Keyboard {
+    /// HID と同じ形式の修飾キーの状態
+    modifier: u8,
+    /// 直前に拡張キーの前置バイトを受け取った
+    extended: bool,
+}
+
+impl Ps2Keyboard {
+    pub(crate) const fn new() -> Self {
+        Self {
+            modifier: 0,
+            extended: false,
+        }
+    }
+
+    /// 押されたキーがあれば、(修飾キーの状態, HID キーコード) を返す。
+    ///
+    /// 待たずにすぐ戻る。修飾キーや拡張キー、キーを離したとき、マウスのデータを読み捨てたときは None を返す。
+    pub(crate) fn poll(&mut self) -> Option<(u8, u8)> {
+        let status = unsafe { io_in_8(STATUS_PORT) };
+        if status & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        let scancode = unsafe { io_in_8(DATA_PORT) };
+        // マウスのデータは使わないが、読み出さないと出力バッファが空かず、キーボードのデータも届かなくなる
+        if status & STATUS_AUX_DATA != 0 {
+            return None;
+        }
+
+        if scancode == SCANCODE_EXTENDED {
+            self.extended = true;
+            return None;
+        }
+        // 拡張キー（カーソルキーや右 Ctrl など）は扱わない
+        if self.extended {
+            self.extended = false;
+            return None;
+        }
+
+        let released = scancode & SCANCODE_BREAK != 0;
+        let code = scancode & !SCANCODE_BREAK;
+        let bit = match code {
+            SCANCODE_L_CONTROL => L_CONTROL_BIT,
+            SCANCODE_L_SHIFT => L_SHIFT_BIT,
+            SCANCODE_R_SHIFT => R_SHIFT_BIT,
+            SCANCODE_L_ALT => L_ALT_BIT,
+            _ => 0,
+        };
+        if bit != 0 {
+            if released {
+                self.modifier &= !bit;
+            } else {
+                self.modifier |= bit;
+            }
+            return None;
+        }
+
+        if released {
+            return None;
+        }
+        match SCANCODE_TO_KEYCODE[code as usize] {
+            0 => None,
+            keycode => Some((self.modifier, keycode)),
+        }
+    }
+}
+
+/// `byte` をキーボードへ送り、受け付けられたことを確かめる。
+///
+/// 送り直しを求められたら [KEYBOARD_MAX_RETRIES] 回まで送り直す。応答が無ければ
+/// [error::Code::Timeout] を、ACK 以外が返るか送り直しても受け付けられなければ
+/// [error::Code::TransferFailed] を返す。
+fn send_to_keyboard(byte: u8) -> error::Error {
+    for _ in 0..=KEYBOARD_MAX_RETRIES {
+        let err = wait_until(
+            || unsafe { io_in_8(STATUS_PORT) } & STATUS_INPUT_FULL == 0,
+            KEYBOARD_POLL_LIMIT,
+        );
+        if (&err).into() {
+            return err;
+        }
+        unsafe { io_out_8(DATA_PORT, byte) };
+
+        let err = wait_until(
+            || unsafe { io_in_8(STATUS_PORT) } & STATUS_OUTPUT_FULL != 0,
+            KEYBOARD_POLL_LIMIT,
+        );
+        if (&err).into() {
+            return err;
+        }
+        match unsafe { io_in_8(DATA_PORT) } {
+            KEYBOARD_ACK => return make_error!(error::Code::Success),
+            KEYBOARD_RESEND => continue,
+            _ => return make_error!(error::Code::TransferFailed),
+        }
+    }
+    make_error!(error::Code::TransferFailed)
+}
+
+/// PS/2 キーボードの LED を設定する。
+///
+/// `leds` は bit0 が Scroll Lock、bit1 が Num Lock、bit2 が Caps Lock。
+pub(crate) fn set_leds(leds: u8) -> error::Error {
+    let err = send_to_keyboard(KEYBOARD_COMMAND_SET_LEDS);
+    if (&err).into() {
+        return err;
+    }
+    send_to_keyboard(leds)
+}
+
+/// PS/2 コントローラのリセット線を使って CPU をリセットする。
+pub(crate) fn pulse_reset() {
+    unsafe {
+        while io_in_8(STATUS_PORT) & STATUS_INPUT_FULL != 0 {}
+        io_out_8(STATUS_PORT, COMMAND_PULSE_RESET);
+    }
+}
@@ -0,0 +1,204 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use spin::Mutex;
+
+use crate::{
+    error,
+    graphics::{PixelWriter, Rectangle, Vector2D},
+    make_error, timer,
+};
+
+/// 描画関数。渡された領域の中だけを描画すること。
+pub(crate) type RenderFn = fn(writer: &dyn PixelWriter, area: &Rectangle);
+
+/// 登録できるレイヤの最大数
+const MAX_LAYERS: usize = 8;
+/// 個別に保持する再描画領域の最大数。溢れた分は 1 つにまとめる。
+const MAX_DIRTY_RECTS: usize = 16;
+/// 1 秒あたりの描画回数の上限の既定値
+pub(crate) const DEFAULT_MAX_FPS: u32 = 60;
+
+/// 1 秒あたりの描画回数の上限。0 なら制限しない。
+static MAX_FPS: AtomicU32 = AtomicU32::new(DEFAULT_MAX_FPS);
+
+/// 描画した回数
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+/// 次の描画の時刻まで持ち越した [flush] の回数
+static DEFERRED: AtomicU64 = AtomicU64::new(0);
+/// 描画にかかった時間の合計（マイクロ秒）
+static BUSY_US: AtomicU64 = AtomicU64::new(0);
+
+/// 再描画が必要な領域と、描画を担当するレイヤをまとめて管理する。
+struct RenderScheduler {
+    /// 下から順に並べたレイヤ
+    layers: [Option<RenderFn>; MAX_LAYERS],
+    num_layers: usize,
+    dirty: [Rectangle; MAX_DIRTY_RECTS],
+    num_dirty: usize,
+    /// 次に描画してよい時刻（マイクロ秒）。None ならいつでもよい。
+    next_frame_us: Option<u64>,
+}
+
+impl RenderScheduler {
+    const fn new() -> Self {
+        Self {
+            layers: [None; MAX_LAYERS],
+            num_layers: 0,
+            dirty: [Rectangle::new(Vector2D::new(0, 0), Vector2D::new(0, 0)); MAX_DIRTY_RECTS],
+            num_dirty: 0,
+            next_frame_us: None,
+        }
+    }
+
+    fn add_dirty(&mut self, area: Rectangle) {
+        if area.is_empty() {
+            return;
+        }
+        // 重なる領域があれば併合して、同じ場所を何度も描かないようにする
+        for rect in &mut self.dirty[..self.num_dirty] {
+            if rect.intersection(&area).is_some() {
+                *rect = rect.union(&area);
+                return;
+            }
+        }
+        if self.num_dirty < MAX_DIRTY_RECTS {
+            self.dirty[self.num_dirty] = area;
+            self.num_dirty += 1;
+        } else {
+            // 溢れたら最後の領域へまとめる。余計に描くことはあっても描き漏らしはしない。
+            let last = &mut self.dirty[MAX_DIRTY_RECTS - 1];
+            *last = last.union(&area);
+        }
+    }
+}
+
+static SCHEDULER: Mutex<RenderScheduler> = Mutex::new(RenderScheduler::new());
+
+/// 一番上にレイヤを追加する。満杯なら [error::Code::Full] を返す。
+pub(crate) fn add_layer(render: RenderFn) -> error::Error {
+    let mut scheduler = SCHEDULER.lock();
+    if scheduler.num_layers == MAX_LAYERS {
+        return make_error!(error::Code::Full);
+    }
+    let i = scheduler.num_layers;
+    scheduler.layers[i] = Some(render);
+    scheduler.num_layers += 1;
+    make_error!(error::Code::Success)
+}
+
+/// `area` の再描画を依頼する。
+///
+/// その場では描画せず、次に [flush] が呼ばれたときにまとめて描画する。
+/// 描画の状態を変えた側は、描画処理を知らなくてよい。
+pub(crate) fn request_redraw(area: Rectangle) {
+    SCHEDULER.lock().add_dirty(area);
+}
+
+/// 1 秒あたりの描画回数の上限を `fps` にする。0 なら制限せず、[flush] のたびに描画する。
+///
+/// イベントがいくら届いても、描画はこの回数までにまとめられる。
+pub(crate) fn set_max_fps(fps: u32) {
+    MAX_FPS.store(fps, Ordering::Relaxed);
+    // 上限を緩めたときに、前の設定で決めた次の描画の時刻まで待たないようにする
+    SCHEDULER.lock().next_frame_us = None;
+}
+
+pub(crate) fn max_fps() -> u32 {
+    MAX_FPS.load(Ordering::Relaxed)
+}
+
+/// 描画の間隔（マイクロ秒）。上限が無ければ 0。
+fn frame_interval_us() -> u64 {
+    match max_fps() {
+        0 => 0,
+        fps => 1_000_000 / fps as u64,
+    }
+}
+
+/// 再描画を依頼された領域を、全レイヤを下から順に重ねて描画する。
+///
+/// メインループでイベントを処理し終えたときに呼ぶ。[set_max_fps] で決めた次の描画の時刻に
+/// なっていなければ何もせず、依頼された領域はまとめたまま次回へ持ち越す。
+/// 描画したら true を返す。
+pub(crate) fn flush(writer: &dyn PixelWriter) -> bool {
+    let now = timer::uptime_us();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.num_dirty == 0 {
+            return false;
+        }
+        if let (Some(now), Some(next)) = (now, scheduler.next_frame_us) {
+            if now < next {
+                DEFERRED.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+        }
+        // 一定の間隔で描画するよう、次の時刻は前回の予定から数える。遅れていれば今から数える。
+        let interval = frame_interval_us();
+        let prev = scheduler.next_frame_us;
+        scheduler.next_frame_us = now.filter(|_| interval != 0).map(|now| match prev {
+            Some(next) if next + interval > now => next + interval,
+            _ => now + interval,
+        });
+    }
+    draw(writer);
+    true
+}
+
+/// 間隔に関わらず、再描画を依頼された領域を今すぐ描画する。
+pub(crate) fn flush_now(writer: &dyn PixelWriter) {
+    let interval = frame_interval_us();
+    SCHEDULER.lock().next_frame_us = timer::uptime_us()
+        .filter(|_| interval != 0)
+        .map(|now| now + interval);
+    draw(writer);
+}
+
+fn draw(writer: &dyn PixelWriter) {
+    let start = timer::uptime_us();
+    // 描画中にも再描画を依頼できるよう、ロックを放してから描く
+    let (layers, dirty, num_dirty) = {
+        let mut scheduler = SCHEDULER.lock();
+        let num_dirty = scheduler.num_dirty;
+        scheduler.num_dirty = 0;
+        (scheduler.layers, scheduler.dirty, num_dirty)
+    };
+
+    for area in &dirty[..num_dirty] {
+        for render in layers.iter().flatten() {
+            render(writer, area);
+        }
+    }
+
+    FRAMES.fetch_add(1, Ordering::Relaxed);
+    if let (Some(start), Some(end)) = (start, timer::uptime_us()) {
+        BUSY_US.fetch_add(end - start, Ordering::Relaxed);
+    }
+}
+
+/// [reset_stats] してからの描画の統計。
+#[derive(Clone, Copy)]
+pub(crate) struct RenderStats {
+    /// 描画した回数
+    pub(crate) frames: u64,
+    /// 次の描画の時刻まで持ち越した回数
+    pub(crate) deferred: u64,
+    /// 描画にかかった時間の合計（マイクロ秒）
+    pub(crate) busy_us: u64,
+}
+
+pub(crate) fn stats() -> RenderStats {
+    RenderStats {
+        frames: FRAMES.load(Ordering::Relaxed),
+        deferred: DEFERRED.load(Ordering::Relaxed),
+        busy_us: BUSY_US.load(Ordering::Relaxed),
+    }
+}
+
+pub(crate) fn reset_stats() {
+    FRAMES.store(0, Ordering::Relaxed);
+    DEFERRED.store(0, Ordering::Relaxed);
+    BUSY_US.store(0, Ordering::Relaxed);
+}
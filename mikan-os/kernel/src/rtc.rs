@@ -0,0 +1,178 @@
+#![allow(unused)]
+
+use core::fmt::{self, Display};
+
+use crate::{
+    error::{self, WithError},
+    io::{io_in_8, io_out_8},
+    make_error,
+};
+
+/// CMOS のレジスタ番号を指定する IO ポート
+const CMOS_ADDRESS: u16 = 0x70;
+/// CMOS のレジスタを読み書きする IO ポート
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+/// ステータスレジスタ A（bit 7 が更新中フラグ）
+const REG_STATUS_A: u8 = 0x0a;
+/// ステータスレジスタ B（bit 1 が 24 時間表記、bit 2 がバイナリ表記）
+const REG_STATUS_B: u8 = 0x0b;
+
+/// 更新中フラグが下りるのを待つ回数の上限。更新は 2 ms ほどで終わるので、
+/// IO ポートを 1 回読むのに 1 µs かかるとしても十分に余裕がある。
+const MAX_UPDATE_POLLS: usize = 100_000;
+/// 同じ値が 2 回続けて読めるまで読み直す回数の上限
+const MAX_READ_RETRIES: usize = 10;
+
+/// 世紀レジスタが分からないときに仮定する世紀
+const DEFAULT_CENTURY: u16 = 20;
+
+/// 世紀を保持している CMOS レジスタ番号。ACPI FADT の CENTURY フィールドから設定する。
+static mut CENTURY_REGISTER: Option<u8> = None;
+
+/// 世紀レジスタの番号を設定する。0 は「世紀レジスタ無し」を表す。
+pub(crate) fn set_century_register(reg: u8) {
+    unsafe {
+        CENTURY_REGISTER = if reg == 0 { None } else { Some(reg) };
+    }
+}
+
+fn get_century_register() -> Option<u8> {
+    unsafe { CENTURY_REGISTER }
+}
+
+/// 日付と時刻。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DateTime {
+    pub(crate) year: u16,
+    pub(crate) month: u8,
+    pub(crate) day: u8,
+    pub(crate) hour: u8,
+    pub(crate) minute: u8,
+    pub(crate) second: u8,
+}
+
+impl Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// CMOS から読み出したままの値。
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct RawDateTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_cmos(reg: u8) -> u8 {
+    unsafe {
+        io_out_8(CMOS_ADDRESS, reg);
+        io_in_8(CMOS_DATA)
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_cmos(REG_STATUS_A) & 0x80 != 0
+}
+
+/// 更新が終わるのを待ってから、日付と時刻のレジスタを読む。
+///
+/// 更新中フラグが [MAX_UPDATE_POLLS] 回読んでも下りなければ None を返す。
+/// 壊れた RTC や、フラグを下ろさないエミュレータで止まらないようにするため。
+fn read_raw() -> Option<RawDateTime> {
+    (0..MAX_UPDATE_POLLS).find(|_| !update_in_progress())?;
+    Some(RawDateTime {
+        second: read_cmos(REG_SECOND),
+        minute: read_cmos(REG_MINUTE),
+        hour: read_cmos(REG_HOUR),
+        day: read_cmos(REG_DAY),
+        month: read_cmos(REG_MONTH),
+        year: read_cmos(REG_YEAR),
+        century: get_century_register().map_or(0, read_cmos),
+    })
+}
+
+const fn bcd_to_binary(v: u8) -> u8 {
+    (v >> 4) * 10 + (v & 0x0f)
+}
+
+/// RTC から現在の日付と時刻を読み出す。
+///
+/// 読み出しの途中で RTC が更新されると値が壊れるので、同じ値が 2 回続けて読めるまで繰り返す。
+/// 更新中フラグが下りないときや、何度読んでも値が揃わないときは [error::Code::Timeout] を返す。
+pub(crate) fn read_datetime() -> WithError<DateTime> {
+    let unavailable = DateTime {
+        year: 0,
+        month: 0,
+        day: 0,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    let Some(mut raw) = read_raw() else {
+        return WithError::new(unavailable, make_error!(error::Code::Timeout));
+    };
+    let mut stable = false;
+    for _ in 0..MAX_READ_RETRIES {
+        let Some(next) = read_raw() else {
+            break;
+        };
+        if next == raw {
+            stable = true;
+            break;
+        }
+        raw = next;
+    }
+    if !stable {
+        return WithError::new(unavailable, make_error!(error::Code::Timeout));
+    }
+
+    let status_b = read_cmos(REG_STATUS_B);
+    let binary = status_b & 0x04 != 0;
+    let hour_24 = status_b & 0x02 != 0;
+
+    // 12 時間表記では時の bit 7 が PM を表す
+    let pm = !hour_24 && raw.hour & 0x80 != 0;
+    let convert = |v: u8| if binary { v } else { bcd_to_binary(v) };
+
+    let mut hour = convert(raw.hour & 0x7f);
+    if !hour_24 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = match get_century_register() {
+        Some(_) => convert(raw.century) as u16,
+        None => DEFAULT_CENTURY,
+    };
+
+    WithError::new(
+        DateTime {
+            year: century * 100 + convert(raw.year) as u16,
+            month: convert(raw.month),
+            day: convert(raw.day),
+            hour,
+            minute: convert(raw.minute),
+            second: convert(raw.second),
+        },
+        make_error!(error::Code::Success),
+    )
+}
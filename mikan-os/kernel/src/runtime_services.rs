@@ -0,0 +1,179 @@
+#![allow(unused)]
+
+use core::{ffi::c_void, ptr};
+
+use crate::{
+    error, log,
+    logger::LogLevel,
+    make_error, printk, printkln,
+    rtc::DateTime,
+    sync::{OnceLock, SpinLock},
+};
+
+/// UEFI のステータスコード。最上位ビットが立っていればエラー。
+type Status = usize;
+
+const STATUS_SUCCESS: Status = 0;
+const STATUS_ERROR_BIT: Status = 1 << (usize::BITS - 1);
+const STATUS_UNSUPPORTED: Status = STATUS_ERROR_BIT | 3;
+
+/// 各テーブルの先頭に付くヘッダ。
+#[repr(C)]
+struct TableHeader {
+    signature: u64,
+    revision: u32,
+    header_size: u32,
+    crc32: u32,
+    reserved: u32,
+}
+
+/// EFI_RUNTIME_SERVICES の `signature`（"RUNTSERV"）
+const RUNTIME_SERVICES_SIGNATURE: u64 = u64::from_le_bytes(*b"RUNTSERV");
+
+/// EFI_TIME
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct Time {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    pad1: u8,
+    nanosecond: u32,
+    time_zone: i16,
+    daylight: u8,
+    pad2: u8,
+}
+
+/// ResetSystem の種類
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub(crate) enum ResetType {
+    /// 電源を入れ直したのと同じ状態にする
+    Cold = 0,
+    /// CPU だけをリセットする
+    Warm = 1,
+    /// 電源を切る
+    Shutdown = 2,
+}
+
+/// EFI_RUNTIME_SERVICES。ローダが SetVirtualAddressMap で恒等写像を設定してから渡す。
+///
+/// 使わないサービスは関数ポインタの型を付けずに場所だけ取っておく。
+#[repr(C)]
+pub struct RuntimeServices {
+    header: TableHeader,
+    get_time: unsafe extern "efiapi" fn(time: *mut Time, capabilities: *mut c_void) -> Status,
+    set_time: usize,
+    get_wakeup_time: usize,
+    set_wakeup_time: usize,
+    set_virtual_address_map: usize,
+    convert_pointer: usize,
+    get_variable: usize,
+    get_next_variable_name: usize,
+    set_variable: usize,
+    get_next_high_monotonic_count: usize,
+    reset_system: unsafe extern "efiapi" fn(
+        reset_type: ResetType,
+        status: Status,
+        data_size: usize,
+        data: *const c_void,
+    ),
+}
+
+/// ランタイムサービスは再入できないので、呼び出しはロックを取ってから行う。
+static RUNTIME_SERVICES: OnceLock<SpinLock<&'static RuntimeServices>> = OnceLock::new();
+
+/// ローダから渡されたランタイムサービスのテーブルを確かめて、覚えておく。
+///
+/// 渡されなかったかシグネチャがおかしければ [error::Code::InvalidFormat] を返す。
+/// その場合でも、各ラッパは [error::Code::NotImplemented] を返すだけで動き続ける。
+pub(crate) fn initialize(runtime_services: Option<&'static RuntimeServices>) -> error::Error {
+    let Some(rs) = runtime_services else {
+        return make_error!(error::Code::InvalidFormat);
+    };
+    if rs.header.signature != RUNTIME_SERVICES_SIGNATURE {
+        log!(LogLevel::Debug, "invalid runtime services signature");
+        return make_error!(error::Code::InvalidFormat);
+    }
+    let revision = rs.header.revision;
+    log!(
+        LogLevel::Info,
+        "UEFI runtime services: revision {}.{:02}",
+        revision >> 16,
+        revision & 0xffff
+    );
+    let _ = RUNTIME_SERVICES.set(SpinLock::new(rs));
+    make_error!(error::Code::Success)
+}
+
+/// UEFI のステータスコードを [error::Error] に直す。
+///
+/// 対応していないサービス（EFI_UNSUPPORTED）は [error::Code::NotImplemented]、
+/// それ以外のエラーは [error::Code::FirmwareError] にする。
+fn status_to_error(status: Status) -> error::Error {
+    match status {
+        STATUS_SUCCESS => make_error!(error::Code::Success),
+        STATUS_UNSUPPORTED => make_error!(error::Code::NotImplemented),
+        _ => {
+            log!(
+                LogLevel::Debug,
+                "runtime service failed: status {:#x}",
+                status & !STATUS_ERROR_BIT
+            );
+            make_error!(error::Code::FirmwareError)
+        }
+    }
+}
+
+/// ファームウェアの時計から現在の日付と時刻を読み出す。
+///
+/// ランタイムサービスが無いか GetTime に対応していなければ [error::Code::NotImplemented] を返す。
+/// そのときは [crate::rtc::read_datetime] を使うこと。
+pub(crate) fn get_time() -> error::WithError<DateTime> {
+    let unavailable = DateTime {
+        year: 0,
+        month: 0,
+        day: 0,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    let Some(rs) = RUNTIME_SERVICES.lock() else {
+        return error::WithError::new(unavailable, make_error!(error::Code::NotImplemented));
+    };
+
+    let mut time = Time::default();
+    let status = unsafe { (rs.get_time)(&mut time, ptr::null_mut()) };
+    drop(rs);
+    let err = status_to_error(status);
+    if (&err).into() {
+        return error::WithError::new(unavailable, err);
+    }
+    error::WithError::new(
+        DateTime {
+            year: time.year,
+            month: time.month,
+            day: time.day,
+            hour: time.hour,
+            minute: time.minute,
+            second: time.second,
+        },
+        err,
+    )
+}
+
+/// ファームウェアにマシンのリセットか電源断を頼む。
+///
+/// 成功すれば戻らない。ランタイムサービスが無いときや対応していないとき、他の呼び出しの途中（その中で
+/// パニックした場合など）で再入できないときは [error::Code::NotImplemented] を返す。
+pub(crate) fn reset_system(reset_type: ResetType) -> error::Error {
+    let Some(rs) = RUNTIME_SERVICES.try_lock() else {
+        return make_error!(error::Code::NotImplemented);
+    };
+    unsafe { (rs.reset_system)(reset_type, STATUS_SUCCESS, 0, ptr::null()) };
+    // 仕様上は戻らないが、対応していないファームウェアでは戻ってくることがある
+    make_error!(error::Code::NotImplemented)
+}
@@ -0,0 +1,324 @@
+#![allow(unused)]
+
+use crate::{
+    app, halt, introspect,
+    keyboard::{self, KeyEvent},
+    log,
+    logger::LogLevel,
+    memory_map::{self, MemoryMapFilter},
+    pci, pool, printk, printkln,
+    ps2::{self, Ps2Keyboard},
+    theme::{self, ThemePreset},
+};
+
+/// 1 行に入力できる最大の文字数
+const LINE_MAX: usize = 64;
+
+/// セーフモードで起動する。
+///
+/// 問題のあるハードウェアでも最低限のデバッグができるよう、xHC や割り込みは使わない。
+/// PCI デバイスとメモリの情報を表示した後、PS/2 キーボードをポーリングする簡単なシェルに入る。
+pub(crate) fn run() -> ! {
+    printkln!("Safe mode: USB and interrupts are disabled");
+
+    let err = pci::scan_all_bus();
+    if (&err).into() {
+        log!(LogLevel::Error, "scan_all_bus: {}", err);
+    }
+    list_pci_devices();
+    print_memory_info(MemoryMapFilter::Usable);
+
+    let mut keyboard = Ps2Keyboard::new();
+    let mut line = [0u8; LINE_MAX];
+    let mut len = 0;
+    printk!("> ");
+    loop {
+        // シリアルポートにつないだ端末からも操作できる
+        let ascii = match keyboard.poll() {
+            Some((_, keyboard::KEY_CAPS_LOCK)) => {
+                keyboard::toggle_caps_lock();
+                continue;
+            }
+            Some((modifier, keycode)) => {
+                let event = KeyEvent::new(modifier, keycode, true);
+                if event.is_ctrl_chord(keyboard::KEY_C) {
+                    0x03
+                } else if event.is_ctrl_chord(keyboard::KEY_L) {
+                    0x0c
+                } else {
+                    keyboard::process_key_event(&event)
+                }
+            }
+            None => match crate::read_serial_input() {
+                Some(ascii) => ascii,
+                None => continue,
+            },
+        };
+        match ascii {
+            b'\n' => {
+                printkln!();
+                execute(&line[..len]);
+                len = 0;
+                printk!("> ");
+            }
+            // Ctrl + C：入力中の行を捨てる
+            0x03 => {
+                printk!("^C\n> ");
+                len = 0;
+            }
+            // Ctrl + L：画面を消去し、入力中の行を出し直す
+            0x0c => {
+                printk!("\x1b[2J\x1b[H> ");
+                for &c in &line[..len] {
+                    printk!("{}", c as char);
+                }
+            }
+            // Backspace
+            0x08 => {
+                if len > 0 {
+                    len -= 1;
+                    printk!("\x08 \x08");
+                }
+            }
+            0 => {}
+            c => {
+                if len < LINE_MAX {
+                    line[len] = c;
+                    len += 1;
+                    printk!("{}", c as char);
+                }
+            }
+        }
+    }
+}
+
+fn execute(command: &[u8]) {
+    match command.trim_ascii() {
+        b"" => {}
+        b"help" => {
+            printkln!(
+                "commands: help, lspci, pcidump <bus> <dev> <func>, mem [usable], clear, \
+                 theme [name], app, reboot"
+            )
+        }
+        b"lspci" => list_pci_devices(),
+        b"mem" => print_memory_info(MemoryMapFilter::All),
+        b"mem usable" => print_memory_info(MemoryMapFilter::Usable),
+        b"clear" => printk!("\x1b[2J\x1b[H"),
+        b"app" => {
+            let result = app::run();
+            if (&result.error()).into() {
+                printkln!("app: {}", result.error());
+            } else {
+                printkln!("app: exited with {}", result.value());
+            }
+        }
+        // lock-debug の検出を確かめるため、わざと同じロックを取り直す。戻らない。
+        #[cfg(feature = "lock-debug")]
+        b"deadlock" => {
+            static LOCK: crate::sync::SpinLock<()> = crate::sync::SpinLock::new(());
+            let _held = LOCK.lock();
+            printkln!("acquiring the same lock again");
+            let _again = LOCK.lock();
+        }
+        b"reboot" => {
+            ps2::pulse_reset();
+            halt();
+        }
+        b"theme" => {
+            printk!("themes:");
+            for preset in ThemePreset::ALL {
+                let mark = if preset == theme::current_preset() {
+                    "*"
+                } else {
+                    ""
+                };
+                printk!(" {}{}", preset.name(), mark);
+            }
+            printkln!();
+        }
+        other if other.starts_with(b"pcidump ") => {
+            dump_pci_device(other[b"pcidump ".len()..].trim_ascii())
+        }
+        other if other.starts_with(b"theme ") => set_theme(other[b"theme ".len()..].trim_ascii()),
+        other => match core::str::from_utf8(other) {
+            Ok(s) => printkln!("unknown command: {}", s),
+            Err(_) => printkln!("unknown command"),
+        },
+    }
+}
+
+/// 配色を `name` に切り替える。コンソールは消去される。
+fn set_theme(name: &[u8]) {
+    let Some(preset) = ThemePreset::parse(name) else {
+        printkln!("unknown theme");
+        return;
+    };
+    theme::set_current(preset);
+    if let Some(mut console) = crate::CONSOLE.lock() {
+        console.set_theme(preset.theme());
+        console.set_cursor(0, 0);
+    }
+}
+
+fn list_pci_devices() {
+    for dev in introspect::pci_devices().iter() {
+        printkln!(
+            "{}.{}.{}: vend {:04x}, class {}, head {:02x}",
+            dev.bus(),
+            dev.device(),
+            dev.function(),
+            dev.read_vendor_id(),
+            dev.class_code(),
+            dev.header_type()
+        );
+    }
+}
+
+/// `0x` で始まれば 16 進数、それ以外は 10 進数として読む。
+fn parse_number(s: &[u8]) -> Option<u32> {
+    let s = core::str::from_utf8(s).ok()?;
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// `<bus> <dev> <func>` で指定された PCI デバイスの、コンフィギュレーション空間の標準ヘッダを出す。
+fn dump_pci_device(args: &[u8]) {
+    let mut numbers = args
+        .split(|b| b.is_ascii_whitespace())
+        .filter(|arg| !arg.is_empty())
+        .map(parse_number);
+    let (Some(Some(bus)), Some(Some(device)), Some(Some(function)), None) = (
+        numbers.next(),
+        numbers.next(),
+        numbers.next(),
+        numbers.next(),
+    ) else {
+        printkln!("usage: pcidump <bus> <dev> <func>");
+        return;
+    };
+    if bus > 255 || device > 31 || function > 7 {
+        printkln!("pcidump: bus must be 0-255, dev 0-31 and func 0-7");
+        return;
+    }
+    let (bus, device, function) = (bus as u8, device as u8, function as u8);
+    let vendor_id = pci::read_vendor_id(bus, device, function);
+    if vendor_id == 0xffff {
+        printkln!("pcidump: no device at {}.{}.{}", bus, device, function);
+        return;
+    }
+
+    let header_type = pci::read_header_type(bus, device, function);
+    let class_code = pci::read_class_code(bus, device, function);
+    let dev = pci::Device::new(bus, device, function, header_type, class_code);
+    let (command, status) = pci::split_command_status(dev.read_conf_reg(0x04));
+    let revision = dev.read_conf_reg(0x08) as u8;
+    let interrupt = dev.read_conf_reg(0x3c);
+
+    printkln!(
+        "{}.{}.{}: {:04x}:{:04x} rev {:02x}, {}",
+        bus,
+        device,
+        function,
+        vendor_id,
+        pci::read_device_id(bus, device, function),
+        revision,
+        class_code.name()
+    );
+    printkln!(
+        "  class {:06x}, header type {:02x}{}",
+        class_code,
+        header_type & 0x7f,
+        if header_type & 0x80 != 0 {
+            " (multi-function)"
+        } else {
+            ""
+        }
+    );
+    printk!("  command {:04x}:", command.value());
+    for (set, name) in [
+        (command.io_space(), "I/O"),
+        (command.memory_space(), "memory"),
+        (command.bus_master(), "bus-master"),
+        (command.interrupt_disable(), "INTx-off"),
+    ] {
+        if set {
+            printk!(" {}", name);
+        }
+    }
+    printkln!();
+    printk!("  status {:04x}:", status.value());
+    for (set, name) in [
+        (status.interrupt_status(), "INTx"),
+        (status.capabilities_list(), "cap-list"),
+        (status.capable_66mhz(), "66MHz"),
+    ] {
+        if set {
+            printk!(" {}", name);
+        }
+    }
+    printkln!();
+    printkln!(
+        "  interrupt line {}, pin {}",
+        interrupt & 0xff,
+        (interrupt >> 8) & 0xff
+    );
+
+    // ヘッダタイプ 1（PCI-to-PCI ブリッジ）は BAR が 2 つしかなく、その後にバス番号が続く
+    let num_bars = match header_type & 0x7f {
+        0x00 => 6,
+        0x01 => 2,
+        _ => 0,
+    };
+    let mut index = 0;
+    while index < num_bars {
+        let bar = dev.read_bar(index);
+        let decoded = pci::Bar::decode(*bar.value());
+        if (&bar.error()).into() {
+            printkln!("  BAR{}: {}", index, bar.error());
+        } else if decoded != pci::Bar::Unused {
+            printkln!("  BAR{}: {}", index, decoded);
+        }
+        // 64 ビットの BAR は次の BAR を上位に使う
+        index += match decoded {
+            pci::Bar::Memory { is_64bit: true, .. } => 2,
+            _ => 1,
+        };
+    }
+    if header_type & 0x7f == 0x01 {
+        let bus_numbers = pci::read_bus_numbers(bus, device, function);
+        printkln!(
+            "  secondary bus {}, subordinate bus {}",
+            (bus_numbers >> 8) & 0xff,
+            (bus_numbers >> 16) & 0xff
+        );
+    }
+
+    let mut any = false;
+    for (addr, id) in dev.capabilities_in(&pci::PortIo) {
+        printkln!(
+            "  cap {:#04x}: {:#04x} {}",
+            addr,
+            id,
+            pci::capability_name(id)
+        );
+        any = true;
+    }
+    if !any {
+        printkln!("  no capabilities");
+    }
+}
+
+/// メモリプールの空きと、ローダから渡されたメモリマップのうち `filter` に合う範囲を出す。
+fn print_memory_info(filter: MemoryMapFilter) {
+    let memory = introspect::memory();
+    printkln!("memory pool: {} / {} bytes free", memory.free, memory.total);
+    let Some(map) = memory_map::boot_memory_map() else {
+        return;
+    };
+    for range in memory_map::coalesce_memory_map(map).filter(|range| filter.accepts(range.ty)) {
+        printkln!("{}", range);
+    }
+}
@@ -0,0 +1,731 @@
+#![allow(unused)]
+
+//! 起動引数 `selftest` で動かす、カーネルの自己診断。
+//!
+//! 初期化を終えたところで、実機やエミュレータでなければ確かめにくい部分（メモリプール、
+//! メッセージキュー、ピクセルの書き込み、画像の転送、回転した文字の描画、PCI のケーパビリティの
+//! リスト、PCI の BAR とヘッダタイプとクラスコード、ページの属性、数値の書式化、ELF のプログラム
+//! ヘッダ、タイマ割り込みの間隔）を一通り動かし、結果を画面とシリアルポートの両方へ出す。
+//! 終わったら通常の起動には戻らず、止まるかリセットする。CI では `selftest=reboot` と
+//! `-no-reboot` を組み合わせ、シリアルの出力の最終行を見れば合否が分かる。
+
+use core::{
+    fmt::{self, Write},
+    mem::size_of,
+    ptr::{self, addr_of},
+};
+
+use crate::{
+    console::ConsoleBackend,
+    cpu,
+    elf::{self, Elf64Ehdr, Elf64Phdr, ELF_MAGIC, PF_R, PF_W, PF_X, PT_LOAD},
+    error,
+    font::{self, TextOrientation},
+    frame_buffer_config::{FrameBufferConfig, PixelFormat},
+    graphics::{
+        self, BgrResv8BitPerColorPixelWriter, PixelColor, PixelWriter,
+        RgbResv8BitPerColorPixelWriter, Vector2D, BYTES_PER_PIXEL,
+    },
+    halt, interrupt,
+    message::{Message, MessageQueue},
+    paging, panic_action,
+    pci::{self, ConfigSpace, MemoryConfigSpace},
+    pool,
+    string::StringU8,
+    timer,
+};
+
+/// 自己診断を終えた後の動作。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelfTestExit {
+    /// 止まったままにして、結果を画面で読めるようにする
+    Halt,
+    /// マシンをリセットする
+    Reboot,
+}
+
+impl SelfTestExit {
+    pub(crate) fn parse(value: &[u8]) -> Option<Self> {
+        match value {
+            b"halt" => Some(Self::Halt),
+            b"reboot" => Some(Self::Reboot),
+            _ => None,
+        }
+    }
+}
+
+/// 画面とシリアルポートの両方へ出す。起動引数の `serial` の設定に関わらず、結果はシリアルにも残す。
+macro_rules! report {
+    ($($arg:tt)*) => {
+        $crate::write_to_sinks(ConsoleBackend::Both, format_args!($($arg)*))
+    };
+}
+
+/// 検査の合否を数える。
+struct Counter {
+    passed: usize,
+    failed: usize,
+}
+
+impl Counter {
+    const fn new() -> Self {
+        Self {
+            passed: 0,
+            failed: 0,
+        }
+    }
+
+    /// `ok` が偽なら、`name` を失敗として出す。
+    fn check(&mut self, name: &str, ok: bool) {
+        if ok {
+            self.passed += 1;
+        } else {
+            self.failed += 1;
+            report!("selftest: FAIL {}\n", name);
+        }
+    }
+}
+
+/// 自己診断を動かし、結果を出してから `exit` に従って止まるかリセットする。戻らない。
+pub(crate) fn run(exit: SelfTestExit) -> ! {
+    report!("selftest: start\n");
+    let mut counter = Counter::new();
+    for (name, test) in [
+        ("pool", test_pool as fn(&mut Counter)),
+        ("message queue", test_message_queue),
+        ("pixel writer", test_pixel_writer),
+        ("blit", test_blit_convert),
+        ("text orientation", test_text_orientation),
+        ("pci capabilities", test_pci_capabilities),
+        ("pci config", test_pci_config),
+        ("page protection", test_page_protection),
+        ("format", test_format),
+        ("elf", test_elf),
+        ("timer", test_timer),
+    ] {
+        let failed = counter.failed;
+        test(&mut counter);
+        report!(
+            "selftest: {} {}\n",
+            name,
+            if counter.failed == failed {
+                "ok"
+            } else {
+                "FAILED"
+            }
+        );
+    }
+    report!(
+        "selftest: {} passed, {} failed\n",
+        counter.passed,
+        counter.failed
+    );
+
+    match exit {
+        SelfTestExit::Halt => halt(),
+        SelfTestExit::Reboot => panic_action::reboot(),
+    }
+}
+
+/// メモリプールから切り出した領域の揃え方と、容量を超えたときの失敗を確かめる。
+///
+/// プールは解放できないので、切り出すのは小さな領域だけにする。
+fn test_pool(counter: &mut Counter) {
+    let before = pool::remaining();
+    let buf = pool::alloc_bounded(96, 64, 128);
+    counter.check("pool: alloc", !bool::from(&buf.error()));
+    let addr = *buf.value() as usize;
+    counter.check("pool: align", addr & (64 - 1) == 0);
+    counter.check("pool: boundary", addr / 128 == (addr + 96 - 1) / 128);
+    counter.check("pool: remaining", pool::remaining() + 96 <= before);
+    let zeroed = unsafe { core::slice::from_raw_parts(addr as *const u8, 96) }
+        .iter()
+        .all(|&b| b == 0);
+    counter.check("pool: zeroed", zeroed);
+
+    // 失敗したときは何も切り出さない
+    let before = pool::remaining();
+    let buf = pool::alloc_aligned(before + 1, 0);
+    counter.check(
+        "pool: exhausted",
+        buf.error().cause() == error::Code::NoEnoughMemory,
+    );
+    counter.check("pool: exhausted remaining", pool::remaining() == before);
+}
+
+/// キューを溢れさせ、満杯での失敗と取り出す順番を確かめる。
+fn test_message_queue(counter: &mut Counter) {
+    const N: usize = 4;
+    let click = |i: usize| Message::MouseClick {
+        x: i as u32,
+        y: 0,
+        timestamp: 0,
+    };
+
+    let mut queue = MessageQueue::<N>::new();
+    counter.check("queue: empty", queue.is_empty() && queue.pop().is_none());
+    for i in 0..N {
+        counter.check("queue: push", !bool::from(&queue.push(click(i))));
+    }
+    counter.check(
+        "queue: full",
+        queue.push(click(N)).cause() == error::Code::Full,
+    );
+    counter.check("queue: len", queue.len() == N);
+
+    // 読み出し位置が一周しても順番が保たれる
+    counter.check("queue: pop", queue.pop() == Some(click(0)));
+    counter.check("queue: wrap", !bool::from(&queue.push(click(N))));
+    for i in 1..=N {
+        counter.check("queue: order", queue.pop() == Some(click(i)));
+    }
+    counter.check("queue: drained", queue.is_empty() && queue.pop().is_none());
+}
+
+/// 画面外の小さなフレームバッファへ書き込み、読み戻して確かめる。
+fn test_pixel_writer(counter: &mut Counter) {
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 4;
+    // 行末の余白がある場合も確かめる
+    const PIXELS_PER_SCAN_LINE: usize = WIDTH + 2;
+
+    let mut buf = [0u8; PIXELS_PER_SCAN_LINE * HEIGHT * BYTES_PER_PIXEL];
+    let colors = [
+        PixelColor::new(0x12, 0x34, 0x56),
+        PixelColor::new(0xff, 0x00, 0x80),
+    ];
+    for format in [PixelFormat::Rgb, PixelFormat::Bgr] {
+        buf.fill(0);
+        let config = FrameBufferConfig {
+            frame_buffer: buf.as_mut_ptr() as usize,
+            pixels_per_scan_line: PIXELS_PER_SCAN_LINE,
+            horizontal_resolution: WIDTH,
+            vertical_resolution: HEIGHT,
+            pixel_format: format,
+        };
+        let rgb = RgbResv8BitPerColorPixelWriter::new(config);
+        let bgr = BgrResv8BitPerColorPixelWriter::new(config);
+        let writer: &dyn PixelWriter = match format {
+            PixelFormat::Rgb => &rgb,
+            PixelFormat::Bgr => &bgr,
+        };
+
+        let last = Vector2D::new(WIDTH as u32 - 1, HEIGHT as u32 - 1);
+        writer.write(Vector2D::new(0, 0), &colors[0]);
+        writer.write(last, &colors[1]);
+        let pixel_at = |x: usize, y: usize| {
+            let offset = config.pixel_offset(x, y);
+            &buf[offset..offset + BYTES_PER_PIXEL]
+        };
+        counter.check(
+            "pixel: first",
+            pixel_at(0, 0)[..3] == colors[0].adjusted().to_bytes(format)[..3],
+        );
+        counter.check(
+            "pixel: last",
+            pixel_at(WIDTH - 1, HEIGHT - 1)[..3] == colors[1].adjusted().to_bytes(format)[..3],
+        );
+        counter.check(
+            "pixel: round trip",
+            PixelColor::from_bytes(pixel_at(0, 0), format) == colors[0].adjusted(),
+        );
+        // 隣のピクセルと行末の余白は書き換えない
+        counter.check("pixel: neighbour", pixel_at(1, 0) == [0; BYTES_PER_PIXEL]);
+        counter.check(
+            "pixel: padding",
+            pixel_at(WIDTH, HEIGHT - 1) == [0; BYTES_PER_PIXEL],
+        );
+    }
+}
+
+/// 2x2 の画像を、4 通りのフォーマットの組み合わせで画面外のフレームバッファへ転送して確かめる。
+fn test_blit_convert(counter: &mut Counter) {
+    const SIZE: usize = 4;
+    const POS: Vector2D<u32> = Vector2D::new(1, 1);
+
+    let colors = [
+        PixelColor::new(0x12, 0x34, 0x56),
+        PixelColor::new(0xff, 0x00, 0x80),
+        PixelColor::new(0x00, 0xc0, 0x20),
+        PixelColor::new(0x9a, 0xbc, 0xde),
+    ];
+    let image_size = Vector2D::new(2, 2);
+    let mut buf = [0u8; SIZE * SIZE * BYTES_PER_PIXEL];
+    for src_format in [PixelFormat::Rgb, PixelFormat::Bgr] {
+        let mut src = [0u8; 4 * BYTES_PER_PIXEL];
+        for (pixel, color) in src.chunks_exact_mut(BYTES_PER_PIXEL).zip(&colors) {
+            pixel.copy_from_slice(&color.to_bytes(src_format));
+        }
+        for dst_format in [PixelFormat::Rgb, PixelFormat::Bgr] {
+            buf.fill(0);
+            let config = FrameBufferConfig {
+                frame_buffer: buf.as_mut_ptr() as usize,
+                pixels_per_scan_line: SIZE,
+                horizontal_resolution: SIZE,
+                vertical_resolution: SIZE,
+                pixel_format: dst_format,
+            };
+            let rgb = RgbResv8BitPerColorPixelWriter::new(config);
+            let bgr = BgrResv8BitPerColorPixelWriter::new(config);
+            let writer: &dyn PixelWriter = match dst_format {
+                PixelFormat::Rgb => &rgb,
+                PixelFormat::Bgr => &bgr,
+            };
+            let pixel_at = |buf: &[u8], x: usize, y: usize| {
+                let offset = config.pixel_offset(x, y);
+                [buf[offset], buf[offset + 1], buf[offset + 2]]
+            };
+
+            let err = graphics::blit_convert(writer, &src, src_format, POS, image_size);
+            counter.check("blit: success", err.cause() == error::Code::Success);
+            let converted = colors.iter().enumerate().all(|(i, color)| {
+                let (x, y) = (POS.x() as usize + i % 2, POS.y() as usize + i / 2);
+                pixel_at(&buf, x, y)[..] == color.adjusted().to_bytes(dst_format)[..3]
+            });
+            counter.check("blit: convert", converted);
+            // 転送先の外は書き換えない
+            counter.check(
+                "blit: outside",
+                pixel_at(&buf, 0, 0) == [0; 3] && pixel_at(&buf, 3, 3) == [0; 3],
+            );
+
+            // 画像の大きさに足りないバッファは、何も描かずに断る
+            buf.fill(0);
+            let err =
+                graphics::blit_convert(writer, &src[..src.len() - 1], src_format, POS, image_size);
+            counter.check(
+                "blit: too small",
+                err.cause() == error::Code::BufferTooSmall && buf.iter().all(|&b| b == 0),
+            );
+        }
+    }
+}
+
+/// 4 つの向きで文字列を描き、外接矩形（[font::string_size]）に収まることと、回転していない
+/// ものを回したものと一致することを確かめる。
+fn test_text_orientation(counter: &mut Counter) {
+    // 両端の 0x02 は網目の文字で、左右の端の列と一番上の行に点がある
+    const TEXT: &[u8] = b"\x02Mikan\x02";
+    const SIZE: usize = 64;
+    // 外接矩形の外に書いていないか分かるよう、左上に余白を空けて描く
+    const MARGIN: u32 = 4;
+
+    let mut buf = [0u8; SIZE * SIZE * BYTES_PER_PIXEL];
+    let config = FrameBufferConfig {
+        frame_buffer: buf.as_mut_ptr() as usize,
+        pixels_per_scan_line: SIZE,
+        horizontal_resolution: SIZE,
+        vertical_resolution: SIZE,
+        pixel_format: PixelFormat::Rgb,
+    };
+    let color = PixelColor::new(0xff, 0xff, 0xff);
+    let draw = |buf: &mut [u8], orientation| {
+        buf.fill(0);
+        let writer = RgbResv8BitPerColorPixelWriter::new(config);
+        font::write_string(
+            &writer,
+            Vector2D::new(MARGIN, MARGIN),
+            TEXT,
+            &color,
+            orientation,
+        );
+    };
+    let lit = |buf: &[u8], x: u32, y: u32| {
+        let offset = config.pixel_offset(x as usize, y as usize);
+        buf[offset] != 0
+    };
+
+    let normal_size = font::string_size(TEXT.len(), TextOrientation::Normal);
+    let mut normal = [[false; SIZE]; SIZE];
+    draw(&mut buf, TextOrientation::Normal);
+    for (y, row) in normal.iter_mut().enumerate().take(normal_size.y() as usize) {
+        for (x, pixel) in row.iter_mut().enumerate().take(normal_size.x() as usize) {
+            *pixel = lit(&buf, MARGIN + x as u32, MARGIN + y as u32);
+        }
+    }
+
+    for orientation in [
+        TextOrientation::Normal,
+        TextOrientation::Rotate90,
+        TextOrientation::Rotate180,
+        TextOrientation::Rotate270,
+    ] {
+        let size = font::string_size(TEXT.len(), orientation);
+        counter.check(
+            "text: size",
+            match orientation {
+                TextOrientation::Normal | TextOrientation::Rotate180 => size == normal_size,
+                _ => size == Vector2D::new(normal_size.y(), normal_size.x()),
+            },
+        );
+        draw(&mut buf, orientation);
+
+        let (mut inside, mut matches) = (true, true);
+        let (mut min, mut max) = (Vector2D::new(u32::MAX, u32::MAX), Vector2D::new(0, 0));
+        for y in 0..SIZE as u32 {
+            for x in 0..SIZE as u32 {
+                let on = lit(&buf, x, y);
+                let (dx, dy) = (x.wrapping_sub(MARGIN), y.wrapping_sub(MARGIN));
+                if dx >= size.x() || dy >= size.y() {
+                    inside &= !on;
+                    continue;
+                }
+                if on {
+                    min = Vector2D::new(min.x().min(dx), min.y().min(dy));
+                    max = Vector2D::new(max.x().max(dx), max.y().max(dy));
+                }
+                // 回転後の (dx, dy) に来る、回転前の点
+                let (sx, sy) = match orientation {
+                    TextOrientation::Normal => (dx, dy),
+                    TextOrientation::Rotate90 => (dy, size.x() - 1 - dx),
+                    TextOrientation::Rotate180 => (size.x() - 1 - dx, size.y() - 1 - dy),
+                    TextOrientation::Rotate270 => (size.y() - 1 - dy, dx),
+                };
+                matches &= on == normal[sy as usize][sx as usize];
+            }
+        }
+        counter.check("text: inside bounding box", inside);
+        // 文字の下の 2 行はどの文字でも空いているので、回した向きの側ではその分だけ端に届かない
+        counter.check(
+            "text: fills bounding box",
+            min.x() <= 2 && min.y() <= 2 && max.x() + 3 >= size.x() && max.y() + 3 >= size.y(),
+        );
+        counter.check("text: rotation", matches);
+    }
+}
+
+/// メモリ上のコンフィギュレーション空間に置いたケーパビリティのリストを辿らせる。
+///
+/// 実機では作れない、循環するリスト、途中で切れたリスト、下位ビットの立ったポインタを試す。
+fn test_pci_capabilities(counter: &mut Counter) {
+    /// ステータスレジスタの Capabilities List ビットを立てた、コマンド・ステータスレジスタの値
+    const STATUS_CAPABILITIES_LIST: u32 = 1 << (16 + 4);
+
+    let space = MemoryConfigSpace::new();
+    let device = pci::Device::new(0, 0, 0, 0, pci::read_class_code_in(&space, 0, 0, 0));
+    // (ID, 次のケーパビリティの位置) をそれぞれの位置へ書き込む
+    let set_list = |head: u32, entries: &[(u8, u8, u8)]| {
+        space.write(0, 0, 0, 0x04, STATUS_CAPABILITIES_LIST);
+        space.write(0, 0, 0, 0x34, head);
+        for &(addr, cap_id, next) in entries {
+            space.write(0, 0, 0, addr, cap_id as u32 | (next as u32) << 8);
+        }
+    };
+    let matches =
+        |expected: &[(u8, u8)]| device.capabilities_in(&space).eq(expected.iter().copied());
+
+    set_list(0x40, &[(0x40, 0x05, 0x50), (0x50, 0x11, 0x00)]);
+    counter.check("pci: list", matches(&[(0x40, 0x05), (0x50, 0x11)]));
+    counter.check(
+        "pci: find",
+        device.find_capability_in(&space, 0x11) == Some(0x50)
+            && device.find_capability_in(&space, 0x10).is_none(),
+    );
+
+    // ステータスのビットが下りていれば、0x34 に何があっても辿らない
+    space.write(0, 0, 0, 0x04, 0);
+    counter.check("pci: no list", matches(&[]));
+
+    // 循環していても、置ける数を辿ったところで止まる
+    set_list(0x40, &[(0x40, 0x05, 0x50), (0x50, 0x11, 0x40)]);
+    let count = device.capabilities_in(&space).count();
+    counter.check("pci: loop", count == (0x100 - 0x40) / 4);
+
+    // 次の位置が何も無いレジスタを指していれば、ID 0 の項目で終わる
+    set_list(0x40, &[(0x40, 0x05, 0xfc), (0xfc, 0x00, 0x00)]);
+    counter.check("pci: truncated", matches(&[(0x40, 0x05), (0xfc, 0x00)]));
+
+    // ポインタの下位 2 ビットは予約なので無視する
+    set_list(0x43, &[(0x40, 0x05, 0x52), (0x50, 0x11, 0x03)]);
+    counter.check("pci: misaligned", matches(&[(0x40, 0x05), (0x50, 0x11)]));
+}
+
+/// メモリ上のコンフィギュレーション空間に置いた BAR、ヘッダタイプ、クラスコードを読ませる。
+fn test_pci_config(counter: &mut Counter) {
+    let space = MemoryConfigSpace::new();
+    let device = pci::Device::new(0, 0, 0, 0, pci::read_class_code_in(&space, 0, 0, 0));
+    let bar = |index: u32| {
+        let bar = device.read_bar_in(&space, index);
+        (*bar.value(), bar.error().cause())
+    };
+
+    // BAR0: 32 ビットのメモリ空間、BAR1-2: 64 ビットのメモリ空間、BAR3: I/O 空間
+    space.write(0, 0, 0, 0x10, 0xfebf_0000);
+    space.write(0, 0, 0, 0x14, 0xfe00_000c);
+    space.write(0, 0, 0, 0x18, 0x0000_0001);
+    space.write(0, 0, 0, 0x1c, 0x0000_c005);
+    counter.check(
+        "pci: 32-bit bar",
+        bar(0) == (0xfebf_0000, error::Code::Success),
+    );
+    counter.check(
+        "pci: 64-bit bar",
+        bar(1) == (0x1_fe00_000c, error::Code::Success),
+    );
+    // I/O 空間ではビット 2 が立っていても 64 ビットの BAR ではない
+    counter.check("pci: io bar", bar(3) == (0xc005, error::Code::Success));
+    // 最後の BAR は 64 ビットにできず、BAR は 6 つまで
+    space.write(0, 0, 0, 0x24, 0x0000_0004);
+    counter.check(
+        "pci: 64-bit last bar",
+        bar(5).1 == error::Code::IndexOutOfRange,
+    );
+    counter.check("pci: bar index", bar(6).1 == error::Code::IndexOutOfRange);
+
+    // ヘッダタイプの最上位ビットがマルチファンクションを表す
+    let header_type = |value: u32| {
+        space.write(0, 0, 0, 0x0c, value);
+        pci::read_header_type_in(&space, 0, 0, 0)
+    };
+    let multi = header_type(0x0080_0000);
+    counter.check(
+        "pci: multi function",
+        multi == 0x80 && !pci::is_single_function_device(multi),
+    );
+    let bridge = header_type(0x0001_0000);
+    counter.check(
+        "pci: single function",
+        bridge == 0x01 && pci::is_single_function_device(bridge),
+    );
+
+    // xHC（ベース 0x0c、サブ 0x03、インターフェース 0x30）
+    space.write(0, 0, 0, 0x08, 0x0c03_3001);
+    let class_code = pci::read_class_code_in(&space, 0, 0, 0);
+    counter.check("pci: class match", class_code.r#match(0x0c, 0x03, 0x30));
+    counter.check(
+        "pci: class mismatch",
+        !class_code.r#match(0x0c, 0x03, 0x20)
+            && !class_code.r#match(0x0c, 0x04, 0x30)
+            && !class_code.r#match(0x02, 0x03, 0x30),
+    );
+}
+
+/// カーネルのイメージがセグメントごとの属性で写され、上位半分の写しが実行できないことを確かめる。
+///
+/// 書き込んだり実行したりして例外を起こすとそこで止まってしまうので、ページテーブルをたどって属性だけを見る。
+fn test_page_protection(counter: &mut Counter) {
+    static mut WRITABLE: u8 = 0;
+
+    let no_execute = cpu::has_nx();
+    let check = |counter: &mut Counter, name: &str, addr: u64, writable: bool, executable: bool| {
+        let expected = paging::PageAttributes {
+            writable,
+            executable,
+            user: false,
+        };
+        counter.check(name, paging::page_attributes(addr) == Some(expected));
+    };
+    check(
+        counter,
+        "paging: code",
+        test_page_protection as fn(&mut Counter) as usize as u64,
+        false,
+        true,
+    );
+    check(
+        counter,
+        "paging: rodata",
+        "selftest".as_ptr() as u64,
+        false,
+        !no_execute,
+    );
+    check(
+        counter,
+        "paging: data",
+        addr_of!(WRITABLE) as u64,
+        true,
+        !no_execute,
+    );
+    check(
+        counter,
+        "paging: direct map",
+        paging::phys_to_virt(0),
+        true,
+        !no_execute,
+    );
+    // カーネルのページはユーザモードから触れない
+    let data = addr_of!(WRITABLE) as u64;
+    counter.check("paging: user range", !paging::is_user_range(data, data + 1));
+    // 起動時に写した範囲の外も、変換したときに写される
+    check(
+        counter,
+        "paging: beyond mapped",
+        paging::phys_to_virt(paging::mapped_size()),
+        true,
+        !no_execute,
+    );
+}
+
+/// [StringU8] で数値を書式化し、期待する文字列になるか確かめる。
+fn test_format(counter: &mut Counter) {
+    let mut buf = [0u8; 32];
+    let mut check = |name: &str, args: fmt::Arguments, expected: &[u8]| {
+        let mut s = StringU8::new(&mut buf);
+        let ok = s.write_fmt(args).is_ok() && s.to_string() == expected;
+        counter.check(name, ok);
+    };
+    check(
+        "format: decimal",
+        format_args!("{}", 1234567890u32),
+        b"1234567890",
+    );
+    check(
+        "format: negative",
+        format_args!("{}", i64::MIN),
+        b"-9223372036854775808",
+    );
+    check(
+        "format: hex",
+        format_args!("{:#x}", 0xdead_beefu32),
+        b"0xdeadbeef",
+    );
+    check(
+        "format: padding",
+        format_args!("{:>6}|{:<4}|", 42, 7),
+        b"    42|7   |",
+    );
+    check("format: zero pad", format_args!("{:08b}", 5u8), b"00000101");
+    check(
+        "format: fraction",
+        format_args!("{}.{:03}", 12, 5),
+        b"12.005",
+    );
+
+    // 溢れた分は切り捨てる
+    let mut small = [0u8; 4];
+    let mut s = StringU8::new(&mut small);
+    let ok = write!(s, "{}", 123456).is_ok() && s.to_string() == b"1234";
+    counter.check("format: truncate", ok);
+}
+
+/// バッファの中に作った ELF ヘッダとプログラムヘッダを読ませる。
+fn test_elf(counter: &mut Counter) {
+    /// GNU_STACK セグメントを表す [Elf64Phdr::type]
+    const PT_GNU_STACK: u32 = 0x6474_e551;
+    const PHOFF: usize = size_of::<Elf64Ehdr>();
+    const PHNUM: usize = 3;
+    const SIZE: usize = PHOFF + PHNUM * size_of::<Elf64Phdr>();
+
+    /// プログラムヘッダを読めるよう、8 バイト境界に揃えたバッファ
+    #[repr(C, align(8))]
+    struct Image([u8; SIZE]);
+
+    let mut ident = [0u8; 16];
+    ident[..4].copy_from_slice(&ELF_MAGIC);
+    let ehdr = Elf64Ehdr {
+        ident,
+        r#type: 2,
+        machine: 0x3e,
+        version: 1,
+        entry: 0x10_1000,
+        phoff: PHOFF as u64,
+        shoff: 0,
+        flags: 0,
+        ehsize: size_of::<Elf64Ehdr>() as u16,
+        phentsize: size_of::<Elf64Phdr>() as u16,
+        phnum: PHNUM as u16,
+        shentsize: 0,
+        shnum: 0,
+        shstrndx: 0,
+    };
+    let phdr = |r#type: u32, flags: u32, vaddr: usize| Elf64Phdr {
+        r#type,
+        flags,
+        offset: 0,
+        vaddr,
+        paddr: vaddr,
+        filesz: 0x1000,
+        memsz: 0x1000,
+        align: 0x1000,
+    };
+    let phdrs = [
+        phdr(PT_LOAD, PF_R | PF_X, 0x10_1000),
+        phdr(PT_LOAD, PF_R | PF_W, 0x10_2000),
+        phdr(PT_GNU_STACK, PF_R | PF_W, 0),
+    ];
+    let build = |ehdr: Elf64Ehdr| {
+        let mut image = Image([0; SIZE]);
+        unsafe {
+            let base = image.0.as_mut_ptr();
+            ptr::write_unaligned(base as *mut Elf64Ehdr, ehdr);
+            for (i, phdr) in phdrs.iter().enumerate() {
+                let offset = PHOFF + i * size_of::<Elf64Phdr>();
+                ptr::write_unaligned(base.add(offset) as *mut Elf64Phdr, *phdr);
+            }
+        }
+        image
+    };
+
+    let image = build(ehdr);
+    let parsed = elf::program_headers(&image.0);
+    counter.check("elf: count", parsed.is_some_and(|p| p.len() == PHNUM));
+    let parsed = parsed.unwrap_or(&[]);
+    counter.check(
+        "elf: types",
+        parsed
+            .iter()
+            .map(|p| p.r#type)
+            .eq([PT_LOAD, PT_LOAD, PT_GNU_STACK]),
+    );
+    counter.check(
+        "elf: flags",
+        parsed
+            .iter()
+            .map(|p| p.flags)
+            .eq([PF_R | PF_X, PF_R | PF_W, PF_R | PF_W]),
+    );
+    counter.check(
+        "elf: vaddr",
+        parsed.first().is_some_and(|p| p.vaddr == 0x10_1000),
+    );
+
+    // 読めないヘッダは受け付けない
+    counter.check(
+        "elf: truncated",
+        elf::program_headers(&image.0[..SIZE - 1]).is_none(),
+    );
+    let mut bad_magic = ehdr;
+    bad_magic.ident[0] = 0;
+    counter.check(
+        "elf: magic",
+        elf::program_headers(&build(bad_magic).0).is_none(),
+    );
+    let mut bad_size = ehdr;
+    bad_size.phentsize -= 1;
+    counter.check(
+        "elf: entry size",
+        elf::program_headers(&build(bad_size).0).is_none(),
+    );
+    // 自分自身のヘッダも読めている
+    counter.check("elf: kernel", !elf::kernel_program_headers().is_empty());
+}
+
+/// 100 ミリ秒待つ間に、タイマ割り込みがおよそ [timer::TICK_HZ] の割合で届くことを確かめる。
+///
+/// 自己診断はタイマ割り込みを始める前に動くので、ここで始める。自己診断からは戻らないので、
+/// 割り込みは禁止し直すだけにする。
+fn test_timer(counter: &mut Counter) {
+    const SLEEP_MS: u64 = 100;
+    /// 待ち始めと終わりの半端な分と、TSC と Local APIC タイマの測定の誤差を見込む
+    const TOLERANCE: u64 = 2;
+
+    let started = !timer::is_ticking();
+    if started {
+        let err = timer::start_tick();
+        counter.check("timer: start", err.cause() == error::Code::Success);
+        if !timer::is_ticking() {
+            return;
+        }
+        interrupt::enable();
+    }
+
+    let expected = SLEEP_MS * timer::TICK_HZ / 1000;
+    let before = timer::ticks();
+    timer::sleep_ms(SLEEP_MS);
+    let elapsed = timer::ticks() - before;
+    counter.check("timer: ticks", elapsed.abs_diff(expected) <= TOLERANCE);
+
+    if started {
+        interrupt::disable();
+    }
+}
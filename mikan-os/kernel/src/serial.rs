@@ -0,0 +1,114 @@
+#![allow(unused)]
+
+use core::fmt::{self, Write};
+
+use crate::{
+    error,
+    io::{io_in_8, io_out_8},
+    make_error,
+};
+
+/// COM1 の IO ポートアドレス
+pub(crate) const COM1: u16 = 0x03f8;
+
+/// 送受信バッファ（DLAB = 1 のときは除数の下位バイト）
+const DATA: u16 = 0;
+/// 割り込み許可レジスタ（DLAB = 1 のときは除数の上位バイト）
+const INTERRUPT_ENABLE: u16 = 1;
+/// FIFO 制御レジスタ
+const FIFO_CONTROL: u16 = 2;
+/// ライン制御レジスタ
+const LINE_CONTROL: u16 = 3;
+/// モデム制御レジスタ
+const MODEM_CONTROL: u16 = 4;
+/// ラインステータスレジスタ
+const LINE_STATUS: u16 = 5;
+
+/// 16550 互換 UART によるシリアルポート。
+pub(crate) struct SerialPort {
+    port: u16,
+}
+
+impl SerialPort {
+    /// 初期化。
+    pub(crate) const fn new(port: u16) -> Self {
+        Self { port }
+    }
+
+    /// 115200 bps、8N1 で UART を設定する。
+    ///
+    /// ループバックで送受信を確認し、応答が無ければ [error::Code::UnknownDevice] を返す。
+    pub(crate) fn initialize(&self) -> error::Error {
+        // 割り込みは使わない
+        self.write_reg(INTERRUPT_ENABLE, 0x00);
+        // 除数 1（115200 bps）を設定
+        self.write_reg(LINE_CONTROL, 0x80);
+        self.write_reg(DATA, 0x01);
+        self.write_reg(INTERRUPT_ENABLE, 0x00);
+        // 8 bit、パリティ無し、ストップビット 1
+        self.write_reg(LINE_CONTROL, 0x03);
+        // FIFO を有効化してクリア
+        self.write_reg(FIFO_CONTROL, 0xc7);
+
+        // ループバックモードで送った値が返ってくるか確認する
+        self.write_reg(MODEM_CONTROL, 0x1e);
+        self.write_reg(DATA, 0xae);
+        if self.read_reg(DATA) != 0xae {
+            return make_error!(error::Code::UnknownDevice);
+        }
+
+        // 通常モードへ戻す（DTR、RTS、OUT1、OUT2）
+        self.write_reg(MODEM_CONTROL, 0x0f);
+        make_error!(error::Code::Success)
+    }
+
+    /// 受信したバイトがあれば 1 バイト返す。待たずにすぐ戻る。
+    pub(crate) fn read_byte(&self) -> Option<u8> {
+        if self.read_reg(LINE_STATUS) & 0x01 == 0 {
+            return None;
+        }
+        Some(self.read_reg(DATA))
+    }
+
+    /// 送信バッファが空くのを待ってから 1 バイト送信する。
+    pub(crate) fn write_byte(&self, b: u8) {
+        while self.read_reg(LINE_STATUS) & 0x20 == 0 {}
+        self.write_reg(DATA, b);
+    }
+
+    fn write_reg(&self, offset: u16, value: u8) {
+        unsafe { io_out_8(self.port + offset, value) }
+    }
+
+    fn read_reg(&self, offset: u16) -> u8 {
+        unsafe { io_in_8(self.port + offset) }
+    }
+}
+
+/// 端末から受信したバイトを、キーボード入力と同じ ASCII 文字に揃える。
+///
+/// 端末は Enter で CR を、Backspace で DEL を送ってくることが多いので、それぞれ
+/// 改行とバックスペースに読み替える。Ctrl + C（0x03）、Ctrl + L（0x0c）、Ctrl + V（0x16）はそのまま通し、
+/// それ以外の扱えない制御文字やエスケープシーケンスは 0 にする。
+pub(crate) const fn normalize_input(b: u8) -> u8 {
+    match b {
+        b'\r' | b'\n' => b'\n',
+        0x7f | 0x08 => 0x08,
+        0x03 | 0x0c | 0x16 => b,
+        b'\t' | 0x20..=0x7e => b,
+        _ => 0,
+    }
+}
+
+impl Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            // 端末側で改行が崩れないように CR を補う
+            if b == b'\n' {
+                self.write_byte(b'\r');
+            }
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,159 @@
+#![allow(unused)]
+
+use crate::{
+    error,
+    graphics::{self, PixelWriter, Vector2D, BYTES_PER_PIXEL},
+    make_error,
+};
+
+/// ロゴの一辺のピクセル数
+const LOGO_SIZE: u32 = 64;
+/// 縁を滑らかにするため、1 ピクセルを縦横この数に分けて、図形に含まれる割合を求める
+const SUBSAMPLES: i64 = 4;
+/// 図形の座標の単位。1 ピクセルの 1/8 にすると、分割した各点の中心が整数になる。
+const UNIT: i64 = 2 * SUBSAMPLES;
+
+/// ロゴを組み立てる図形。座標と大きさはピクセル単位。
+#[derive(Clone, Copy)]
+enum Shape {
+    Circle { cx: i64, cy: i64, r: i64 },
+    Ellipse { cx: i64, cy: i64, rx: i64, ry: i64 },
+    Rectangle { x0: i64, y0: i64, x1: i64, y1: i64 },
+}
+
+impl Shape {
+    /// [UNIT] 単位の点 (x, y) が図形に含まれれば true を返す。
+    const fn contains(&self, x: i64, y: i64) -> bool {
+        match *self {
+            Shape::Circle { cx, cy, r } => {
+                let (dx, dy, r) = (x - cx * UNIT, y - cy * UNIT, r * UNIT);
+                dx * dx + dy * dy <= r * r
+            }
+            Shape::Ellipse { cx, cy, rx, ry } => {
+                let (dx, dy, rx, ry) = (x - cx * UNIT, y - cy * UNIT, rx * UNIT, ry * UNIT);
+                dx * dx * ry * ry + dy * dy * rx * rx <= rx * rx * ry * ry
+            }
+            Shape::Rectangle { x0, y0, x1, y1 } => {
+                x0 * UNIT <= x && x < x1 * UNIT && y0 * UNIT <= y && y < y1 * UNIT
+            }
+        }
+    }
+
+    /// ピクセル (x, y) のうち図形に含まれる割合を 0〜255 で返す。
+    const fn coverage(&self, x: u32, y: u32) -> u32 {
+        let mut count = 0;
+        let mut sy = 0;
+        while sy < SUBSAMPLES {
+            let mut sx = 0;
+            while sx < SUBSAMPLES {
+                if self.contains(x as i64 * UNIT + sx * 2 + 1, y as i64 * UNIT + sy * 2 + 1) {
+                    count += 1;
+                }
+                sx += 1;
+            }
+            sy += 1;
+        }
+        count * 255 / (SUBSAMPLES * SUBSAMPLES) as u32
+    }
+}
+
+/// 奥から順に重ねる図形と、その色。
+const LOGO_SHAPES: [(Shape, [u8; 3]); 4] = [
+    // 実
+    (
+        Shape::Circle {
+            cx: 32,
+            cy: 36,
+            r: 26,
+        },
+        [240, 131, 0],
+    ),
+    // 光の当たっているところ
+    (
+        Shape::Circle {
+            cx: 23,
+            cy: 27,
+            r: 6,
+        },
+        [255, 183, 77],
+    ),
+    // へた
+    (
+        Shape::Rectangle {
+            x0: 30,
+            y0: 4,
+            x1: 34,
+            y1: 13,
+        },
+        [121, 85, 72],
+    ),
+    // 葉
+    (
+        Shape::Ellipse {
+            cx: 43,
+            cy: 10,
+            rx: 10,
+            ry: 5,
+        },
+        [76, 175, 80],
+    ),
+];
+
+/// [LOGO_SHAPES] を重ねて RGBA の画像を作る。
+const fn render_logo() -> [u8; (LOGO_SIZE * LOGO_SIZE) as usize * BYTES_PER_PIXEL] {
+    let mut image = [0u8; (LOGO_SIZE * LOGO_SIZE) as usize * BYTES_PER_PIXEL];
+    let mut y = 0;
+    while y < LOGO_SIZE {
+        let mut x = 0;
+        while x < LOGO_SIZE {
+            let offset = (y * LOGO_SIZE + x) as usize * BYTES_PER_PIXEL;
+            let mut i = 0;
+            while i < LOGO_SHAPES.len() {
+                let (shape, color) = LOGO_SHAPES[i];
+                let alpha = shape.coverage(x, y);
+                if alpha != 0 {
+                    // 下の色の上に、アルファ値 alpha の色を重ねる
+                    let below = image[offset + 3] as u32 * (255 - alpha) / 255;
+                    let out = alpha + below;
+                    let mut c = 0;
+                    while c < 3 {
+                        image[offset + c] =
+                            ((color[c] as u32 * alpha + image[offset + c] as u32 * below + out / 2)
+                                / out) as u8;
+                        c += 1;
+                    }
+                    image[offset + 3] = out as u8;
+                }
+                i += 1;
+            }
+            x += 1;
+        }
+        y += 1;
+    }
+    image
+}
+
+/// 起動時に表示するロゴ（みかん）。ビルド時に図形から描いておく。
+static LOGO: [u8; (LOGO_SIZE * LOGO_SIZE) as usize * BYTES_PER_PIXEL] = render_logo();
+
+/// 画面の中央にロゴを透過させて描く。
+///
+/// レイヤとしては登録しないので、コンソールやマウスカーソルが重なって再描画されると消える。
+/// 画面がロゴより小さければ何もせず [error::Code::FrameTooSmall] を返す。
+pub(crate) fn draw_splash(writer: &dyn PixelWriter) -> error::Error {
+    let config = writer.config();
+    let frame_width = config.horizontal_resolution as u32;
+    let frame_height = config.vertical_resolution as u32;
+    if frame_width < LOGO_SIZE || frame_height < LOGO_SIZE {
+        return make_error!(error::Code::FrameTooSmall);
+    }
+    graphics::blit_rgba(
+        writer,
+        &LOGO,
+        Vector2D::new(
+            (frame_width - LOGO_SIZE) / 2,
+            (frame_height - LOGO_SIZE) / 2,
+        ),
+        Vector2D::new(LOGO_SIZE, LOGO_SIZE),
+    )
+}
@@ -2,6 +2,11 @@
 
 use core::fmt::{self, Write};
 
+use crate::{printk, printkln};
+
+/// [hexdump] の 1 行に表示するバイト数
+pub(crate) const HEXDUMP_LINE_WIDTH: usize = 16;
+
 /// バッファによる ASCII 文字列の保持を行う。
 pub(crate) struct StringU8<'a> {
     buf: &'a mut [u8],
@@ -47,3 +52,58 @@ impl<'a> Write for StringU8<'a> {
         Ok(())
     }
 }
+
+/// `bytes` の先頭から、UTF-8 として正しいところまでを文字列として返す。
+///
+/// 途中で壊れていたり、最後の文字が途中で切れていたりすれば、その手前までになる。
+pub(crate) fn valid_utf8_prefix(bytes: &[u8]) -> &str {
+    match core::str::from_utf8(bytes) {
+        Ok(text) => text,
+        // valid_up_to の手前までは正しいと確かめてある
+        Err(e) => unsafe { core::str::from_utf8_unchecked(&bytes[..e.valid_up_to()]) },
+    }
+}
+
+/// バイト列をアドレス・16 進・ASCII の 3 列に分けて表示する。
+///
+/// `base_addr` は先頭バイトのアドレスとして表示される値で、実際のアドレスである必要はない。
+pub(crate) fn hexdump(bytes: &[u8], base_addr: u64) {
+    for (i, line) in bytes.chunks(HEXDUMP_LINE_WIDTH).enumerate() {
+        // 1 行分をまとめてから出力する
+        let mut buf = [0u8; 128];
+        let mut s = StringU8::new(&mut buf);
+
+        let _ = write!(s, "{:08x}  ", base_addr + (i * HEXDUMP_LINE_WIDTH) as u64);
+        for j in 0..HEXDUMP_LINE_WIDTH {
+            match line.get(j) {
+                Some(b) => {
+                    let _ = write!(s, "{:02x} ", b);
+                }
+                None => {
+                    let _ = write!(s, "   ");
+                }
+            }
+            // 8 バイトごとに区切りを入れる
+            if j == HEXDUMP_LINE_WIDTH / 2 - 1 {
+                let _ = write!(s, " ");
+            }
+        }
+
+        let _ = write!(s, " |");
+        for &b in line {
+            let c = if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            };
+            let _ = write!(s, "{}", c);
+        }
+        let _ = write!(s, "|");
+
+        // StringU8 は ASCII しか保持しない
+        printkln!(
+            "{}",
+            core::str::from_utf8(s.to_string()).unwrap_or_default()
+        );
+    }
+}
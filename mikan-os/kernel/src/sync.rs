@@ -0,0 +1,333 @@
+#![allow(unused)]
+
+use core::{
+    cell::UnsafeCell,
+    hint,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    panic::Location,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+use spin::{Mutex, MutexGuard};
+
+/// まだ値が無い
+const STATE_EMPTY: u8 = 0;
+/// 値を作っている最中
+const STATE_INITIALIZING: u8 = 1;
+/// 値が入っていて、読み出せる
+const STATE_READY: u8 = 2;
+
+/// 一度だけ値を設定できるセル。
+///
+/// `static mut` の [core::cell::OnceCell] と違い、`unsafe` を使わずに static へ置ける。
+/// 初期化の競合は状態を原子的に切り替えて防ぐ。初期化中に別の文脈から
+/// [OnceLock::get_or_init] を呼ぶと、初期化が終わるまで待つ。
+/// 中身を書き換えたい場合は `OnceLock<SpinLock<T>>` にして [OnceLock::lock] を使う。
+pub(crate) struct OnceLock<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// 値は STATE_READY になってからしか共有しないので、T が Sync であれば共有してよい
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+unsafe impl<T: Send> Send for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(STATE_EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// 初期化済みなら値への参照を返す。
+    pub(crate) fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == STATE_READY {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// 値を設定する。既に設定済みか初期化中なら、渡された値をそのまま返す。
+    pub(crate) fn set(&self, value: T) -> Result<(), T> {
+        if self
+            .state
+            .compare_exchange(
+                STATE_EMPTY,
+                STATE_INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+        unsafe { (*self.value.get()).write(value) };
+        self.state.store(STATE_READY, Ordering::Release);
+        Ok(())
+    }
+
+    /// 初期化済みなら値への参照を、そうでなければ `f` で初期化してから参照を返す。
+    ///
+    /// `f` の中から同じセルの [OnceLock::get_or_init] を呼ぶと、戻ってこなくなる。
+    pub(crate) fn get_or_init(&self, f: impl FnOnce() -> T) -> &T {
+        match self.state.compare_exchange(
+            STATE_EMPTY,
+            STATE_INITIALIZING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                unsafe { (*self.value.get()).write(f()) };
+                self.state.store(STATE_READY, Ordering::Release);
+            }
+            Err(_) => {
+                while self.state.load(Ordering::Acquire) != STATE_READY {
+                    hint::spin_loop();
+                }
+            }
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+}
+
+impl<T> OnceLock<SpinLock<T>> {
+    /// 初期化済みなら、ロックを取ってから中身を返す。
+    #[track_caller]
+    pub(crate) fn lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.get().map(SpinLock::lock)
+    }
+
+    /// 初期化済みで、かつ他の誰もロックを持っていなければ中身を返す。
+    ///
+    /// 出力中のパニックのように、ロックを持ったまま同じ値を使うかもしれない場面で使う。
+    #[track_caller]
+    pub(crate) fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        self.get().and_then(SpinLock::try_lock)
+    }
+}
+
+impl<T> Drop for OnceLock<T> {
+    fn drop(&mut self) {
+        if *self.state.get_mut() == STATE_READY {
+            unsafe { self.value.get_mut().assume_init_drop() };
+        }
+    }
+}
+
+/// 取れるまで回って待つロック。[spin::Mutex] と同じように使う。
+///
+/// `lock-debug` フィーチャを有効にすると、ロックを持っている CPU と取った場所を覚えておき、
+/// 同じ CPU が取り直そうとしたときや、[deadlock::SPIN_WARN_MS] 以上待たされたときに、
+/// 両方の場所をシリアルポートへ直接書き出す。無効なら [spin::Mutex] そのものと変わらない。
+pub(crate) struct SpinLock<T> {
+    inner: Mutex<T>,
+    #[cfg(feature = "lock-debug")]
+    owner: deadlock::Owner,
+}
+
+/// [SpinLock::lock] で取ったロック。手放すと解放する。
+pub(crate) struct SpinLockGuard<'a, T> {
+    guard: MutexGuard<'a, T>,
+    #[cfg(feature = "lock-debug")]
+    owner: &'a deadlock::Owner,
+}
+
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        Self {
+            inner: Mutex::new(value),
+            #[cfg(feature = "lock-debug")]
+            owner: deadlock::Owner::new(),
+        }
+    }
+
+    /// ロックが取れるまで待ち、中身を返す。
+    #[track_caller]
+    pub(crate) fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(feature = "lock-debug")]
+        {
+            let site = Location::caller();
+            let guard = deadlock::lock_with_check(&self.inner, &self.owner, site);
+            self.owner.acquired(site);
+            SpinLockGuard {
+                guard,
+                owner: &self.owner,
+            }
+        }
+        #[cfg(not(feature = "lock-debug"))]
+        SpinLockGuard {
+            guard: self.inner.lock(),
+        }
+    }
+
+    /// 他の誰もロックを持っていなければ、ロックを取って中身を返す。
+    #[track_caller]
+    pub(crate) fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let guard = self.inner.try_lock()?;
+        #[cfg(feature = "lock-debug")]
+        self.owner.acquired(Location::caller());
+        Some(SpinLockGuard {
+            guard,
+            #[cfg(feature = "lock-debug")]
+            owner: &self.owner,
+        })
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// 持ち主に関わらずロックを解放する。
+    ///
+    /// # Safety
+    ///
+    /// ロックを持っていた処理が、もう中身に触れないこと。パニックの後始末でだけ使う。
+    pub(crate) unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.owner.released();
+        self.inner.force_unlock();
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+#[cfg(feature = "lock-debug")]
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        // 中のガードがロックを解放するより先に、持ち主の記録を消す
+        self.owner.released();
+    }
+}
+
+/// `lock-debug` フィーチャで有効になる、デッドロックの検出。
+#[cfg(feature = "lock-debug")]
+pub(crate) mod deadlock {
+    use core::{
+        arch::x86_64::_rdtsc,
+        fmt::Write,
+        hint,
+        panic::Location,
+        ptr,
+        sync::atomic::{AtomicPtr, AtomicU32, Ordering},
+    };
+
+    use spin::{Mutex, MutexGuard};
+
+    use crate::{
+        serial::{SerialPort, COM1},
+        timer,
+    };
+
+    /// これ以上待たされたら、デッドロックを疑って知らせる
+    pub(crate) const SPIN_WARN_MS: u64 = 1000;
+    /// TSC の周波数を測る前に使う、[SPIN_WARN_MS] の代わりのサイクル数
+    const SPIN_WARN_CYCLES_UNCALIBRATED: u64 = 1 << 32;
+    /// 誰もロックを持っていないことを表す CPU 番号
+    const NO_OWNER: u32 = u32::MAX;
+
+    /// 今動いている CPU の番号。
+    ///
+    /// 今のところ BSP しか動かさず、タスクも無いので常に 0。同じ CPU がロックを取り直そうと
+    /// することは、割り込みやパニックの処理から入り直したことを意味する。
+    fn current_cpu() -> u32 {
+        0
+    }
+
+    /// ロックを持っている CPU と、ロックを取った場所。
+    pub(crate) struct Owner {
+        cpu: AtomicU32,
+        site: AtomicPtr<Location<'static>>,
+    }
+
+    impl Owner {
+        pub(crate) const fn new() -> Self {
+            Self {
+                cpu: AtomicU32::new(NO_OWNER),
+                site: AtomicPtr::new(ptr::null_mut()),
+            }
+        }
+
+        pub(crate) fn acquired(&self, site: &'static Location<'static>) {
+            self.site.store(
+                site as *const _ as *mut Location<'static>,
+                Ordering::Relaxed,
+            );
+            self.cpu.store(current_cpu(), Ordering::Relaxed);
+        }
+
+        pub(crate) fn released(&self) {
+            self.cpu.store(NO_OWNER, Ordering::Relaxed);
+            self.site.store(ptr::null_mut(), Ordering::Relaxed);
+        }
+
+        fn site(&self) -> Option<&'static Location<'static>> {
+            unsafe { self.site.load(Ordering::Relaxed).as_ref() }
+        }
+    }
+
+    /// `inner` のロックを取る。取れずに待つ間、デッドロックの兆候があれば知らせる。
+    ///
+    /// 知らせた後も待ち続けるので、本当にデッドロックしていれば戻らない。
+    pub(crate) fn lock_with_check<'a, T>(
+        inner: &'a Mutex<T>,
+        owner: &Owner,
+        site: &'static Location<'static>,
+    ) -> MutexGuard<'a, T> {
+        if let Some(guard) = inner.try_lock() {
+            return guard;
+        }
+        if owner.cpu.load(Ordering::Relaxed) == current_cpu() {
+            report("lock re-acquired on the same CPU", site, owner.site());
+        }
+
+        let frequency = timer::tsc_frequency();
+        let limit = if frequency == 0 {
+            SPIN_WARN_CYCLES_UNCALIBRATED
+        } else {
+            frequency / 1000 * SPIN_WARN_MS
+        };
+        let start = unsafe { _rdtsc() };
+        let mut reported = false;
+        loop {
+            if let Some(guard) = inner.try_lock() {
+                return guard;
+            }
+            if !reported && unsafe { _rdtsc() } - start > limit {
+                report("lock spinning for too long", site, owner.site());
+                reported = true;
+            }
+            hint::spin_loop();
+        }
+    }
+
+    /// 診断をシリアルポートへ直接書き出す。
+    ///
+    /// コンソールやシリアルポートのロック自体が原因かもしれないので、どちらのロックも取らない。
+    fn report(
+        what: &str,
+        site: &'static Location<'static>,
+        holder: Option<&'static Location<'static>>,
+    ) {
+        let mut serial = SerialPort::new(COM1);
+        let _ = write!(serial, "\n[deadlock] {}: wanted at {}", what, site);
+        let _ = match holder {
+            Some(holder) => writeln!(serial, ", held since {}", holder),
+            None => writeln!(serial, ", holder unknown"),
+        };
+    }
+}
@@ -0,0 +1,232 @@
+#![allow(unused)]
+
+//! SYSCALL/SYSRET によるシステムコール。
+//!
+//! 呼び出し方は Linux に倣う。RAX にシステムコール番号、RDI、RSI、RDX、R10、R8、R9 に引数を入れて
+//! `syscall` を実行すると、RAX に戻り値が返る。負の値はエラーで、Linux と同じ errno を符号反転したもの。
+//! RCX と R11 は CPU が壊し、それ以外のレジスタは保たれる。
+//!
+//! ユーザモードで動かすプログラムは、[paging::map_user] で写したページだけに触れる。
+//! ユーザモードでは割り込みを許可しない。例外が起きれば、CPU は TSS（[gdt::initialize]）の RSP0 の
+//! スタックへ切り替えて IDT のハンドラへ入り、そこから [exit_on_exception] でプログラムを終わらせる。
+
+use core::{
+    arch::global_asm,
+    ptr::{self, addr_of, addr_of_mut},
+    slice,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use crate::{
+    asmfunc::{enter_user, exit_to_kernel, syscall_entry},
+    cpu,
+    error::{self, WithError},
+    gdt, kassert, log, log_irq,
+    logger::LogLevel,
+    make_error, msr, paging, printk, printk_irq, printkln, printkln_irq, string, timer,
+};
+
+/// `write(fd, buf, len)`：`buf` から `len` バイトを書き出し、書いたバイト数を返す。
+pub(crate) const SYS_WRITE: u64 = 0;
+/// `exit(code)`：[run_user] から `code` を返す。戻らない。
+pub(crate) const SYS_EXIT: u64 = 1;
+/// `get_tick()`：起動からの経過時間（ミリ秒）を返す。
+pub(crate) const SYS_GET_TICK: u64 = 2;
+
+/// 知らないファイルディスクリプタ
+const EBADF: i64 = -9;
+/// ユーザモードから触れない番地
+const EFAULT: i64 = -14;
+/// 知らないシステムコール番号
+const ENOSYS: i64 = -38;
+
+/// 書き出せるファイルディスクリプタ。どちらもコンソールへ出る。
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// SYSCALL で下ろす RFLAGS のビット（TF、IF、DF、AC）
+const SYSCALL_RFLAGS_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 18);
+/// ユーザモードへ移るときの RFLAGS。予約ビットの 1 番だけを立て、割り込みは許可しない。
+pub(crate) const USER_RFLAGS: u64 = 1 << 1;
+
+/// 例外で終わったプログラムの終了コードは、これにベクタ番号を足したもの
+pub(crate) const EXCEPTION_EXIT_BASE: i64 = 128;
+
+/// システムコールの処理中と、[run_user_stub] で使うスタックの大きさ（バイト）
+const STACK_SIZE: usize = 16 * 1024;
+/// [run_user_stub] のコードを置くページの大きさ（バイト）
+const USER_CODE_SIZE: usize = 4096;
+/// [run_user_stub] のコードを写す番地
+const USER_CODE_BASE: u64 = paging::USER_BASE;
+/// [run_user_stub] のスタックを写す番地。コードとの間に 1 ページ空け、溢れたら例外になるようにする。
+const USER_STACK_BASE: u64 = USER_CODE_BASE + 2 * USER_CODE_SIZE as u64;
+
+#[repr(C, align(16))]
+struct Stack([u8; STACK_SIZE]);
+
+/// ユーザモードへ写すページ。写し先と同じく 4 KiB 境界に揃える。
+#[repr(C, align(4096))]
+struct UserPages<const N: usize>([u8; N]);
+
+/// システムコールの処理中に使うカーネルのスタック
+static mut SYSCALL_STACK: Stack = Stack([0; STACK_SIZE]);
+/// [run_user_stub] のコードを写し、[USER_CODE_BASE] へ写すページ
+static mut USER_CODE: UserPages<USER_CODE_SIZE> = UserPages([0; USER_CODE_SIZE]);
+/// [run_user_stub] でユーザモードに渡すスタック。[USER_STACK_BASE] へ写す。
+static mut USER_STACK: UserPages<STACK_SIZE> = UserPages([0; STACK_SIZE]);
+
+/// `syscall_entry` が切り替える先の RSP。0 なら [initialize] の前。
+pub(crate) static SYSCALL_KERNEL_RSP: AtomicU64 = AtomicU64::new(0);
+/// `syscall_entry` が退避したユーザモードの RSP
+pub(crate) static SYSCALL_USER_RSP: AtomicU64 = AtomicU64::new(0);
+/// `enter_user` を呼んだときのカーネルの RSP。`exit_to_kernel` でここへ戻る。
+pub(crate) static USER_RETURN_RSP: AtomicU64 = AtomicU64::new(0);
+
+/// システムコールの処理。引数は `syscall_entry` がスタックに積んだ RDI、RSI、RDX、R10、R8、R9。
+type SyscallHandler = fn(args: &[u64; 6]) -> i64;
+
+/// システムコール番号で引く処理の表
+static SYSCALL_TABLE: [SyscallHandler; 3] = [sys_write, sys_exit, sys_get_tick];
+
+extern "sysv64" {
+    fn user_stub();
+    /// [user_stub] の終わり
+    static user_stub_end: u8;
+}
+
+// ユーザモードで動かす動作確認用のプログラム。カーネルのページはユーザモードから触れないので、
+// [run_user_stub] が user_stub から user_stub_end までをユーザモードのページへ写してから実行する。
+// 写した先でも動くよう、メッセージも同じ範囲に置き、RIP 相対でだけ参照する。
+global_asm! { r#"
+.global user_stub
+.global user_stub_end
+user_stub:
+    mov eax, {sys_write}
+    mov edi, {stdout}
+    lea rsi, [rip + user_stub_message]
+    lea rdx, [rip + user_stub_message_end]
+    sub rdx, rsi
+    syscall
+    mov rdi, rax
+    mov eax, {sys_exit}
+    syscall
+    ud2
+user_stub_message:
+    .ascii "Hello from user mode!\n"
+user_stub_message_end:
+user_stub_end:
+"#,
+    sys_write = const SYS_WRITE,
+    sys_exit = const SYS_EXIT,
+    stdout = const STDOUT,
+}
+
+/// SYSCALL の MSR を設定し、システムコールを受け付けられるようにする。
+///
+/// [gdt::initialize] の後に呼ぶこと。CPU が SYSCALL に対応していないか、GDT がまだなら
+/// [error::Code::NotImplemented] を返す。
+pub(crate) fn initialize() -> error::Error {
+    if !cpu::has_syscall() || !gdt::is_initialized() {
+        return make_error!(error::Code::NotImplemented);
+    }
+    let stack_top = unsafe { addr_of!(SYSCALL_STACK) } as u64 + STACK_SIZE as u64;
+    SYSCALL_KERNEL_RSP.store(stack_top, Ordering::Relaxed);
+    // SYSRET は STAR[63:48] + 8 をユーザの SS、+ 16 をユーザの CS として読み込む
+    let star = ((gdt::kernel_ss() as u64) << 48) | ((gdt::kernel_cs() as u64) << 32);
+    unsafe {
+        msr::write(msr::IA32_STAR, star);
+        msr::write(msr::IA32_LSTAR, syscall_entry as *const () as u64);
+        msr::write(msr::IA32_FMASK, SYSCALL_RFLAGS_MASK);
+        msr::update(msr::IA32_EFER, |efer| efer | msr::EFER_SCE);
+    }
+    log!(
+        LogLevel::Debug,
+        "syscall: STAR={:#x}, stack top={:#x}",
+        star,
+        stack_top
+    );
+    make_error!(error::Code::Success)
+}
+
+/// `entry` からユーザモードで実行し、`exit` で渡された値を返す。
+///
+/// `entry` と `user_rsp` は [paging::map_user] で写したページにあること。実行中はカーネルの処理は止まる。
+/// 例外が起きたら、[EXCEPTION_EXIT_BASE] にベクタ番号を足した値を返す。
+/// [initialize] の前なら [error::Code::NotImplemented] を返す。
+pub(crate) fn run_user(entry: u64, user_rsp: u64) -> WithError<i64> {
+    if SYSCALL_KERNEL_RSP.load(Ordering::Relaxed) == 0 {
+        return WithError::new(0, make_error!(error::Code::NotImplemented));
+    }
+    let code = unsafe { enter_user(entry, user_rsp) };
+    WithError::new(code, make_error!(error::Code::Success))
+}
+
+/// `write` で挨拶を出し、その戻り値で `exit` するだけのユーザモードのプログラムを実行する。
+///
+/// コードは読み込みと実行だけ、スタックは読み書きだけができるページとして写す。
+pub(crate) fn run_user_stub() -> WithError<i64> {
+    let start = user_stub as *const () as usize;
+    let len = unsafe { addr_of!(user_stub_end) } as usize - start;
+    kassert!(len <= USER_CODE_SIZE, "user stub is {} bytes", len);
+    let code = unsafe { addr_of_mut!(USER_CODE) } as *mut u8;
+    unsafe { ptr::copy_nonoverlapping(start as *const u8, code, len) };
+
+    let err = paging::map_user(USER_CODE_BASE, code as u64, USER_CODE_SIZE, false, true);
+    if (&err).into() {
+        return WithError::new(0, err);
+    }
+    let stack = unsafe { addr_of_mut!(USER_STACK) } as u64;
+    let err = paging::map_user(USER_STACK_BASE, stack, STACK_SIZE, true, false);
+    if (&err).into() {
+        return WithError::new(0, err);
+    }
+    run_user(USER_CODE_BASE, USER_STACK_BASE + STACK_SIZE as u64)
+}
+
+/// ユーザモードで例外が起きたとき、割り込みのハンドラから呼ばれる。
+///
+/// プログラムを終わらせ、[run_user] から [EXCEPTION_EXIT_BASE] にベクタ番号を足した値を返す。
+pub(crate) fn exit_on_exception(vector: u8, rip: u64) -> ! {
+    log_irq!(
+        LogLevel::Warn,
+        "user mode: exception {:#04x} at {:#x}",
+        vector,
+        rip
+    );
+    unsafe { exit_to_kernel(EXCEPTION_EXIT_BASE + vector as i64) }
+}
+
+/// `syscall_entry` から呼ばれ、番号に対応する処理を呼び出す。
+pub(crate) extern "sysv64" fn syscall_dispatch(number: u64, args: &[u64; 6]) -> i64 {
+    match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(args),
+        None => ENOSYS,
+    }
+}
+
+fn sys_write(args: &[u64; 6]) -> i64 {
+    let [fd, buf, len, ..] = *args;
+    if fd != STDOUT && fd != STDERR {
+        return EBADF;
+    }
+    if len == 0 {
+        return 0;
+    }
+    // ユーザモードのページとして写っている範囲だけを読む
+    match buf.checked_add(len) {
+        Some(end) if paging::is_user_range(buf, end) => {}
+        _ => return EFAULT,
+    }
+    let bytes = unsafe { slice::from_raw_parts(buf as *const u8, len as usize) };
+    // 途中で UTF-8 として壊れていたら、そこまでを出す
+    printk!("{}", string::valid_utf8_prefix(bytes));
+    len as i64
+}
+
+fn sys_exit(args: &[u64; 6]) -> i64 {
+    unsafe { exit_to_kernel(args[0] as i64) }
+}
+
+fn sys_get_tick(_args: &[u64; 6]) -> i64 {
+    timer::uptime_ms() as i64
+}
@@ -0,0 +1,150 @@
+#![allow(unused)]
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::graphics::PixelColor;
+
+/// デスクトップやコンソールの配色をまとめたもの。
+pub(crate) struct Theme {
+    /// デスクトップ背景の色
+    pub(crate) background: PixelColor,
+    /// デスクトップ背景の模様の色。背景の色と見分けにくいくらいにしておく。
+    pub(crate) background_texture: PixelColor,
+    /// デスクトップ前景（コンソールの文字）の色
+    pub(crate) foreground: PixelColor,
+    /// タスクバーの色
+    pub(crate) taskbar: PixelColor,
+    /// タスクバー上の検索窓の色
+    pub(crate) search_box: PixelColor,
+    /// スタートボタンなど、目立たせたい部分の色
+    pub(crate) accent: PixelColor,
+    /// Warn のログの文字の色。コンソールでは SGR の 33（黄）として扱う。
+    pub(crate) log_warn: PixelColor,
+    /// Error のログの文字の色。コンソールでは SGR の 31（赤）として扱う。
+    pub(crate) log_error: PixelColor,
+}
+
+impl Theme {
+    /// 標準の配色。
+    pub(crate) const DEFAULT: Theme = Theme {
+        background: PixelColor::new(45, 118, 237),
+        background_texture: PixelColor::new(52, 124, 240),
+        foreground: PixelColor::new(255, 255, 255),
+        taskbar: PixelColor::new(1, 8, 17),
+        search_box: PixelColor::new(80, 80, 80),
+        accent: PixelColor::new(160, 160, 160),
+        log_warn: PixelColor::new(255, 255, 85),
+        log_error: PixelColor::new(255, 150, 150),
+    };
+
+    /// 黒地に白の、コントラストを最大にした配色。
+    pub(crate) const HIGH_CONTRAST: Theme = Theme {
+        background: PixelColor::new(0, 0, 0),
+        background_texture: PixelColor::new(0, 0, 0),
+        foreground: PixelColor::new(255, 255, 255),
+        taskbar: PixelColor::new(0, 0, 0),
+        search_box: PixelColor::new(0, 0, 0),
+        accent: PixelColor::new(255, 255, 0),
+        log_warn: PixelColor::new(255, 255, 0),
+        log_error: PixelColor::new(255, 64, 64),
+    };
+
+    /// 赤と緑を見分けにくい人（2 型色覚）向けの配色。
+    ///
+    /// 色相ではなく明るさの差で見分けられるよう、Warn は明るい黄、Error は暗い朱色にする。
+    pub(crate) const DEUTERANOPIA: Theme = Theme {
+        background: PixelColor::new(32, 32, 48),
+        background_texture: PixelColor::new(36, 36, 54),
+        foreground: PixelColor::new(255, 255, 255),
+        taskbar: PixelColor::new(0, 0, 0),
+        search_box: PixelColor::new(80, 80, 80),
+        accent: PixelColor::new(86, 180, 233),
+        log_warn: PixelColor::new(240, 228, 66),
+        log_error: PixelColor::new(213, 94, 0),
+    };
+
+    /// `name` の配色を返す。知らない名前なら None。
+    pub(crate) fn preset(name: &[u8]) -> Option<&'static Theme> {
+        ThemePreset::parse(name).map(ThemePreset::theme)
+    }
+}
+
+/// 名前で選べる配色。起動引数の `theme=` やセーフモードの `theme` コマンドで指定する。
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum ThemePreset {
+    Default,
+    HighContrast,
+    Deuteranopia,
+}
+
+impl ThemePreset {
+    pub(crate) const ALL: [ThemePreset; 3] =
+        [Self::Default, Self::HighContrast, Self::Deuteranopia];
+
+    pub(crate) fn parse(name: &[u8]) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|preset| preset.name().as_bytes() == name)
+    }
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Self::Default => "default",
+            Self::HighContrast => "high-contrast",
+            Self::Deuteranopia => "deuteranopia",
+        }
+    }
+
+    pub(crate) const fn theme(self) -> &'static Theme {
+        match self {
+            Self::Default => &Theme::DEFAULT,
+            Self::HighContrast => &Theme::HIGH_CONTRAST,
+            Self::Deuteranopia => &Theme::DEUTERANOPIA,
+        }
+    }
+}
+
+static CURRENT: AtomicU8 = AtomicU8::new(ThemePreset::Default as u8);
+
+/// 現在の配色を返す。
+pub(crate) fn current() -> &'static Theme {
+    current_preset().theme()
+}
+
+pub(crate) fn current_preset() -> ThemePreset {
+    let value = CURRENT.load(Ordering::Relaxed);
+    ThemePreset::ALL
+        .into_iter()
+        .find(|preset| *preset as u8 == value)
+        .unwrap_or(ThemePreset::Default)
+}
+
+/// 配色を切り替える。描いてあるものはそのままなので、反映するには描画し直すこと。
+pub(crate) fn set_current(preset: ThemePreset) {
+    CURRENT.store(preset as u8, Ordering::Relaxed);
+}
+
+/// 2 色のコントラスト比（WCAG の定義、1〜21）の 100 倍を返す。
+const fn contrast_ratio_x100(a: PixelColor, b: PixelColor) -> u32 {
+    // 輝度 0.05 に当たる分を足してから比をとる
+    const OFFSET: u32 = 65025 / 20;
+    let (la, lb) = (a.luminance(), b.luminance());
+    let (light, dark) = if la > lb { (la, lb) } else { (lb, la) };
+    (light + OFFSET) * 100 / (dark + OFFSET)
+}
+
+/// どの配色でも、文字とログの色が背景から読み取れ、Warn と Error を明るさで見分けられること。
+///
+/// `min_text` は文字と背景の、`min_log` はログの色と背景の最小のコントラスト比（100 倍）。
+const fn is_readable(theme: &Theme, min_text: u32, min_log: u32) -> bool {
+    contrast_ratio_x100(theme.foreground, theme.background) >= min_text
+        && contrast_ratio_x100(theme.log_warn, theme.background) >= min_log
+        && contrast_ratio_x100(theme.log_error, theme.background) >= min_log
+        && contrast_ratio_x100(theme.log_warn, theme.log_error) >= 150
+}
+
+// 標準の配色は背景が明るめの青なので、文字は大きな文字向けの基準（3:1）で確かめる
+const _: () = assert!(is_readable(&Theme::DEFAULT, 300, 180));
+const _: () = assert!(is_readable(&Theme::HIGH_CONTRAST, 700, 450));
+const _: () = assert!(is_readable(&Theme::DEUTERANOPIA, 700, 300));
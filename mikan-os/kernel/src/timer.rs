@@ -0,0 +1,233 @@
+#![allow(unused)]
+
+use core::{
+    arch::x86_64::_rdtsc,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    error,
+    interrupt::{self, InterruptFrame},
+    io::{io_in_8, io_out_8},
+    keyboard, lapic, make_error, profiler, watchdog,
+};
+
+/// PIT の入力クロック周波数（Hz）
+const PIT_FREQUENCY: u64 = 1_193_182;
+/// PIT のチャンネル 2 のカウンタ
+const PIT_CHANNEL2: u16 = 0x42;
+/// PIT のモード設定レジスタ
+const PIT_COMMAND: u16 = 0x43;
+/// チャンネル 2 のゲートと出力がつながっている IO ポート
+const PIT_CHANNEL2_GATE: u16 = 0x61;
+/// PIT で一度に待てる最大の時間（ミリ秒）。カウンタが 16 bit なので約 54 ms が限界。
+const PIT_MAX_WAIT_MS: u64 = 50;
+
+/// Local APIC タイマの周波数を測定するときに待つ時間（ミリ秒）
+const CALIBRATION_MS: u64 = 10;
+/// 分周比設定レジスタに書く値（1 分周）
+const LAPIC_TIMER_DIVIDE_BY_1: u32 = 0b1011;
+
+/// タイマ割り込みの周波数（Hz）
+pub(crate) const TICK_HZ: u64 = 100;
+
+/// 測定した Local APIC タイマの周波数（Hz）。0 なら未測定。
+static LAPIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// 測定したタイムスタンプカウンタの周波数（Hz）。0 なら未測定。
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// [start_tick] で周期割り込みを始めたら true
+static TICKING: AtomicBool = AtomicBool::new(false);
+/// [start_tick] からのティック数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// PIT のチャンネル 2 を使って、`ms` ミリ秒（最大 [PIT_MAX_WAIT_MS]）だけビジーウェイトする。
+///
+/// 割り込みを使わないので、割り込みの準備ができる前から使える。
+fn pit_wait_ms(ms: u64) {
+    let count = (PIT_FREQUENCY * ms.min(PIT_MAX_WAIT_MS) / 1000) as u16;
+    unsafe {
+        // ゲートを下ろし、スピーカへの出力を止める
+        let gate = io_in_8(PIT_CHANNEL2_GATE) & !0x03;
+        io_out_8(PIT_CHANNEL2_GATE, gate);
+        // チャンネル 2、下位・上位バイトの順で書き込み、モード 0（カウント終了で出力が上がる）
+        io_out_8(PIT_COMMAND, 0b1011_0000);
+        io_out_8(PIT_CHANNEL2, count as u8);
+        io_out_8(PIT_CHANNEL2, (count >> 8) as u8);
+        // ゲートを上げるとカウントダウンが始まる
+        io_out_8(PIT_CHANNEL2_GATE, gate | 0x01);
+        // bit 5 がチャンネル 2 の出力
+        while io_in_8(PIT_CHANNEL2_GATE) & 0x20 == 0 {
+            core::hint::spin_loop();
+        }
+        io_out_8(PIT_CHANNEL2_GATE, gate);
+    }
+}
+
+/// Local APIC タイマを割り込み無しのワンショットに設定し、PIT を基準に周波数を測定する。
+///
+/// 測定した周波数（Hz）を返す。以降の [sleep_ms] は Local APIC タイマを使う。
+pub(crate) fn initialize_lapic_timer() -> u64 {
+    lapic::set_timer_divide_config(LAPIC_TIMER_DIVIDE_BY_1);
+    lapic::set_lvt_timer(interrupt::vector::LAPIC_TIMER, lapic::LVT_MASKED);
+
+    lapic::set_timer_initial_count(u32::MAX);
+    pit_wait_ms(CALIBRATION_MS);
+    let elapsed = u32::MAX - lapic::timer_current_count();
+    lapic::set_timer_initial_count(0);
+
+    let frequency = elapsed as u64 * 1000 / CALIBRATION_MS;
+    LAPIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// 測定済みの Local APIC タイマの周波数（Hz）を返す。未測定なら 0。
+pub(crate) fn lapic_timer_frequency() -> u64 {
+    LAPIC_TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// Local APIC タイマを周期モードにし、[TICK_HZ] でタイマ割り込みを起こす。
+///
+/// 割り込みは [interrupt::enable] で許可してから届く。1 ティックごとにプロファイラとウォッチドッグを
+/// 進める。以降、Local APIC タイマはこの割り込みに使うので、[sleep_ms] は TSC で待つ。
+/// [initialize_lapic_timer] と [initialize_tsc] で測定していなければ [error::Code::NotImplemented] を返す。
+pub(crate) fn start_tick() -> error::Error {
+    let frequency = lapic_timer_frequency();
+    if frequency == 0 || tsc_frequency() == 0 {
+        return make_error!(error::Code::NotImplemented);
+    }
+    let count = (frequency / TICK_HZ).clamp(1, u32::MAX as u64) as u32;
+    interrupt::set_handler(interrupt::vector::LAPIC_TIMER, on_tick);
+    TICKING.store(true, Ordering::Relaxed);
+    lapic::set_lvt_timer(interrupt::vector::LAPIC_TIMER, lapic::LVT_TIMER_PERIODIC);
+    lapic::set_timer_initial_count(count);
+    make_error!(error::Code::Success)
+}
+
+/// タイマ割り込みのハンドラ
+fn on_tick(frame: &InterruptFrame) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    profiler::on_timer_interrupt(frame);
+    watchdog::watchdog_tick();
+    keyboard::key_repeat_tick();
+}
+
+/// [start_tick] でタイマ割り込みを始めたかどうか。
+pub(crate) fn is_ticking() -> bool {
+    TICKING.load(Ordering::Relaxed)
+}
+
+/// [start_tick] からのティック数を返す。
+pub(crate) fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// PIT を基準にタイムスタンプカウンタ（TSC）の周波数を測定する。
+///
+/// 測定した周波数（Hz）を返す。以降は [uptime_ms] で経過時間を得られる。
+/// TSC は電源管理で周波数が変わらない（invariant TSC）ことを前提にしている。
+pub(crate) fn initialize_tsc() -> u64 {
+    let start = unsafe { _rdtsc() };
+    pit_wait_ms(CALIBRATION_MS);
+    let elapsed = unsafe { _rdtsc() } - start;
+
+    let frequency = elapsed * 1000 / CALIBRATION_MS;
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+    frequency
+}
+
+/// [initialize_tsc] で測定した TSC の周波数（Hz）を返す。測定する前は 0。
+pub(crate) fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// TSC の `cycles` カウント分の時間をマイクロ秒に直す。[initialize_tsc] で測定する前は None。
+///
+/// 測定より前に [_rdtsc] で取っておいた区間の長さを、後からログに出すのに使う。
+pub(crate) fn tsc_to_us(cycles: u64) -> Option<u64> {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return None;
+    }
+    Some((cycles as u128 * 1_000_000 / frequency as u128) as u64)
+}
+
+/// CPU のリセットからの経過時間（ミリ秒）を返す。
+///
+/// [initialize_tsc] で測定する前は 0 を返す。割り込みハンドラからも呼べる。
+pub(crate) fn uptime_ms() -> u64 {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return 0;
+    }
+    (unsafe { _rdtsc() } as u128 * 1000 / frequency as u128) as u64
+}
+
+/// CPU のリセットからの経過時間（マイクロ秒）を返す。
+///
+/// [initialize_tsc] で測定する前は None を返す。
+pub(crate) fn uptime_us() -> Option<u64> {
+    let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+    if frequency == 0 {
+        return None;
+    }
+    Some((unsafe { _rdtsc() } as u128 * 1_000_000 / frequency as u128) as u64)
+}
+
+/// ブロックを実行し、かかった時間を `label` とともに Info でログに出す。ブロックの値を返す。
+///
+/// 起動のどこに時間がかかっているかを調べるためのもの。[initialize_tsc] で
+/// 測定する前は、時間を測らずに実行だけする。
+#[macro_export]
+macro_rules! time_it {
+    ($label:expr, $body:block) => {{
+        let start = $crate::timer::uptime_us();
+        let value = $body;
+        if let (Some(start), Some(end)) = (start, $crate::timer::uptime_us()) {
+            let elapsed = end - start;
+            $crate::log!(
+                $crate::logger::LogLevel::Info,
+                "{}: {}.{:03} ms",
+                $label,
+                elapsed / 1000,
+                elapsed % 1000
+            );
+        }
+        value
+    }};
+}
+
+/// `ms` ミリ秒が経つまで待つ。
+///
+/// スケジューラがまだ無いのでビジーウェイトする。[start_tick] の後は TSC で、その前に
+/// [initialize_lapic_timer] で Local APIC タイマを測定済みならそれを使い、どちらでもなければ PIT で待つ。
+/// [start_tick] の前は Local APIC タイマを占有するので、割り込みハンドラから呼んではいけない。
+pub(crate) fn sleep_ms(ms: u64) {
+    if is_ticking() {
+        let end = uptime_us().unwrap_or(0) + ms * 1000;
+        while uptime_us().is_some_and(|now| now < end) {
+            core::hint::spin_loop();
+        }
+        return;
+    }
+
+    let frequency = lapic_timer_frequency();
+    if frequency == 0 {
+        let mut remaining = ms;
+        while remaining > 0 {
+            let chunk = remaining.min(PIT_MAX_WAIT_MS);
+            pit_wait_ms(chunk);
+            remaining -= chunk;
+        }
+        return;
+    }
+
+    let mut remaining = frequency * ms / 1000;
+    while remaining > 0 {
+        let count = remaining.min(u32::MAX as u64) as u32;
+        lapic::set_timer_initial_count(count);
+        while lapic::timer_current_count() != 0 {
+            core::hint::spin_loop();
+        }
+        remaining -= count as u64;
+    }
+}
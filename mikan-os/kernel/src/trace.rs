@@ -0,0 +1,130 @@
+#![allow(unused)]
+
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::timer;
+
+/// リングバッファに残しておけるイベント数（2 のべき乗）
+const TRACE_SIZE: usize = 256;
+
+const _: () = assert!(TRACE_SIZE.is_power_of_two());
+
+/// [trace!] で記録するイベント。
+///
+/// 1 つのイベントは 8 バイトに詰めて記録するので、引数は 32 ビットに収まるものだけにする。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TraceEvent {
+    /// 割り込みが起きた。引数はベクタ番号。
+    Interrupt(u8),
+    /// メインループのキューにメッセージを積んだ。引数は [crate::message::Message::kind]。
+    MessagePushed(u8),
+    /// メインループのキューからメッセージを取り出した
+    MessagePopped(u8),
+    /// USB のポートを設定した。引数はポート番号。
+    PortConfigured(u8),
+    /// タイマ割り込みの 1 ティック
+    TimerTick,
+}
+
+impl TraceEvent {
+    /// 上位 32 ビットに種類、下位 32 ビットに引数を入れる。0 は記録中の印に使うので避ける。
+    const fn encode(self) -> u64 {
+        let (kind, arg) = match self {
+            Self::Interrupt(vector) => (1, vector as u32),
+            Self::MessagePushed(kind) => (2, kind as u32),
+            Self::MessagePopped(kind) => (3, kind as u32),
+            Self::PortConfigured(port) => (4, port as u32),
+            Self::TimerTick => (5, 0),
+        };
+        (kind << 32) | arg as u64
+    }
+
+    const fn decode(value: u64) -> Option<Self> {
+        let arg = value as u32;
+        match value >> 32 {
+            1 => Some(Self::Interrupt(arg as u8)),
+            2 => Some(Self::MessagePushed(arg as u8)),
+            3 => Some(Self::MessagePopped(arg as u8)),
+            4 => Some(Self::PortConfigured(arg as u8)),
+            5 => Some(Self::TimerTick),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TraceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Interrupt(vector) => write!(f, "interrupt {:#04x}", vector),
+            Self::MessagePushed(kind) => write!(f, "message pushed {}", kind),
+            Self::MessagePopped(kind) => write!(f, "message popped {}", kind),
+            Self::PortConfigured(port) => write!(f, "port {} configured", port),
+            Self::TimerTick => write!(f, "timer tick"),
+        }
+    }
+}
+
+/// 記録した時点の TSC
+static TIMESTAMPS: [AtomicU64; TRACE_SIZE] = [const { AtomicU64::new(0) }; TRACE_SIZE];
+/// [TraceEvent::encode] したイベント。0 なら空きか記録中。
+static EVENTS: [AtomicU64; TRACE_SIZE] = [const { AtomicU64::new(0) }; TRACE_SIZE];
+/// 次に記録する位置の通し番号
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+/// イベントを 1 つ記録する。[trace!] から呼ばれる。
+///
+/// ロックを取らないので、割り込みハンドラからも呼べる。一杯になったら古いものから上書きする。
+pub(crate) fn record(event: TraceEvent) {
+    let slot = NEXT.fetch_add(1, Ordering::Relaxed) % TRACE_SIZE;
+    // 書き換えている間に読まれても、イベントが 0 なら読み飛ばされる
+    EVENTS[slot].store(0, Ordering::Release);
+    TIMESTAMPS[slot].store(unsafe { _rdtsc() }, Ordering::Relaxed);
+    EVENTS[slot].store(event.encode(), Ordering::Release);
+}
+
+/// 記録したイベントを古い順に `writer` へ 1 行ずつ書き出す。
+///
+/// 時刻は [timer::initialize_tsc] で測定した周波数でマイクロ秒に直す。測定する前は TSC のまま出す。
+pub(crate) fn dump_trace(writer: &mut dyn Write) -> fmt::Result {
+    let next = NEXT.load(Ordering::Acquire);
+    let frequency = timer::tsc_frequency();
+    for i in next.saturating_sub(TRACE_SIZE)..next {
+        let slot = i % TRACE_SIZE;
+        let encoded = EVENTS[slot].load(Ordering::Acquire);
+        let tsc = TIMESTAMPS[slot].load(Ordering::Relaxed);
+        // 読んでいる間に上書きされたものは捨てる
+        if EVENTS[slot].load(Ordering::Acquire) != encoded {
+            continue;
+        }
+        let Some(event) = TraceEvent::decode(encoded) else {
+            continue;
+        };
+        if frequency == 0 {
+            writeln!(writer, "[tsc {:>16}] {}", tsc, event)?;
+        } else {
+            let us = (tsc as u128 * 1_000_000 / frequency as u128) as u64;
+            writeln!(
+                writer,
+                "[{:>6}.{:06}] {}",
+                us / 1_000_000,
+                us % 1_000_000,
+                event
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// イベントをトレースのリングバッファへ記録する。
+///
+/// ログと違って文字列を組み立てないので、割り込みハンドラのような頻繁に通る場所にも置ける。
+#[macro_export]
+macro_rules! trace {
+    ($event:expr) => {
+        $crate::trace::record($event)
+    };
+}
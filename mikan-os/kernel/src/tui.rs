@@ -0,0 +1,593 @@
+#![allow(unused)]
+
+use spin::Mutex;
+
+use crate::{
+    error,
+    font::{self, TextOrientation},
+    graphics::{PixelColor, PixelWriter, Rectangle, Vector2D},
+    keyboard, log,
+    logger::LogLevel,
+    make_error, printk, printkln, render,
+    theme::{self, Theme},
+};
+
+/// ウィジェットが入力を受け取った結果。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Response {
+    /// 入力を使わなかった。親が続けて処理してよい。
+    Ignored,
+    /// 入力を使い、見た目が変わったので描き直しが必要
+    Redraw,
+    /// ボタンなどが押された。値はウィジェットの ID。
+    Activated(u32),
+}
+
+/// 画面上の部品。
+///
+/// 描画は [render] のレイヤから呼ばれるので、`area` と重なる部分だけを描くこと。
+pub(crate) trait Widget {
+    /// ウィジェットが占める領域を返す。
+    fn bounds(&self) -> Rectangle;
+
+    /// `bounds` のうち `area` と重なる部分を描く。
+    fn draw(&self, writer: &dyn PixelWriter, area: &Rectangle);
+
+    /// Tab キーでフォーカスを受け取れるか。
+    fn focusable(&self) -> bool {
+        false
+    }
+
+    /// フォーカスを受け取ったり失ったりしたときに呼ばれる。
+    fn set_focused(&mut self, focused: bool) {}
+
+    /// フォーカスを持っているときに、押されたキーを受け取る。
+    fn handle_key(&mut self, modifier: u8, keycode: u8, ascii: u8) -> Response {
+        Response::Ignored
+    }
+
+    /// マウスカーソルが `pos` へ動いたときに呼ばれる。
+    fn handle_mouse(&mut self, pos: Vector2D<u32>) -> Response {
+        Response::Ignored
+    }
+
+    /// `bounds` の中の `pos` で左ボタンが押されたときに呼ばれる。
+    fn handle_click(&mut self, pos: Vector2D<u32>) -> Response {
+        Response::Ignored
+    }
+}
+
+/// `pos` が `rect` の中にあれば true を返す。
+pub(crate) fn contains(rect: &Rectangle, pos: Vector2D<u32>) -> bool {
+    Rectangle::new(pos, Vector2D::new(1, 1))
+        .intersection(rect)
+        .is_some()
+}
+
+/// `rect` のうち `area` と重なる部分だけを塗る。
+fn fill_clipped(writer: &dyn PixelWriter, rect: Rectangle, area: &Rectangle, c: &PixelColor) {
+    if let Some(rect) = rect.intersection(area) {
+        writer.fill_rectangle(rect.pos, rect.size, c);
+    }
+}
+
+/// `rect` の幅 1 ピクセルの枠のうち、`area` と重なる部分だけを塗る。
+fn draw_clipped(writer: &dyn PixelWriter, rect: Rectangle, area: &Rectangle, c: &PixelColor) {
+    let (pos, size) = (rect.pos, rect.size);
+    if size.x() == 0 || size.y() == 0 {
+        return;
+    }
+    for edge in [
+        Rectangle::new(pos, Vector2D::new(size.x(), 1)),
+        Rectangle::new(
+            pos + Vector2D::new(0, size.y() - 1),
+            Vector2D::new(size.x(), 1),
+        ),
+        Rectangle::new(pos, Vector2D::new(1, size.y())),
+        Rectangle::new(
+            pos + Vector2D::new(size.x() - 1, 0),
+            Vector2D::new(1, size.y()),
+        ),
+    ] {
+        fill_clipped(writer, edge, area, c);
+    }
+}
+
+/// `rect` の中央に文字列を描く。文字単位では切り抜かず、`area` と重なるときだけ描く。
+fn draw_text_centered(
+    writer: &dyn PixelWriter,
+    rect: Rectangle,
+    area: &Rectangle,
+    text: &[u8],
+    c: &PixelColor,
+) {
+    let size = font::string_size(text.len(), TextOrientation::Normal);
+    let pos = rect.pos
+        + Vector2D::new(
+            rect.size.x().saturating_sub(size.x()) / 2,
+            rect.size.y().saturating_sub(size.y()) / 2,
+        );
+    if Rectangle::new(pos, size).intersection(area).is_some() {
+        font::write_string(writer, pos, text, c, TextOrientation::Normal);
+    }
+}
+
+/// 文字列を表示するだけのウィジェット。
+pub(crate) struct Label {
+    bounds: Rectangle,
+    text: &'static [u8],
+}
+
+impl Label {
+    pub(crate) const fn new(bounds: Rectangle, text: &'static [u8]) -> Self {
+        Self { bounds, text }
+    }
+}
+
+impl Widget for Label {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn draw(&self, writer: &dyn PixelWriter, area: &Rectangle) {
+        draw_text_centered(
+            writer,
+            self.bounds,
+            area,
+            self.text,
+            &theme::current().foreground,
+        );
+    }
+}
+
+/// Enter か Space で押せるボタン。押されると [Response::Activated] で `id` を返す。
+pub(crate) struct Button {
+    bounds: Rectangle,
+    text: &'static [u8],
+    id: u32,
+    focused: bool,
+    /// マウスカーソルが上にある
+    hovered: bool,
+}
+
+impl Button {
+    pub(crate) const fn new(bounds: Rectangle, text: &'static [u8], id: u32) -> Self {
+        Self {
+            bounds,
+            text,
+            id,
+            focused: false,
+            hovered: false,
+        }
+    }
+}
+
+impl Widget for Button {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn draw(&self, writer: &dyn PixelWriter, area: &Rectangle) {
+        let theme = theme::current();
+        let fill = if self.hovered {
+            &theme.accent
+        } else {
+            &theme.search_box
+        };
+        fill_clipped(writer, self.bounds, area, fill);
+        let border = if self.focused {
+            &theme.foreground
+        } else {
+            &theme.accent
+        };
+        draw_clipped(writer, self.bounds, area, border);
+        draw_text_centered(writer, self.bounds, area, self.text, &theme.foreground);
+    }
+
+    fn focusable(&self) -> bool {
+        true
+    }
+
+    fn set_focused(&mut self, focused: bool) {
+        self.focused = focused;
+    }
+
+    fn handle_key(&mut self, modifier: u8, keycode: u8, ascii: u8) -> Response {
+        match ascii {
+            b'\n' | b' ' => Response::Activated(self.id),
+            _ => Response::Ignored,
+        }
+    }
+
+    fn handle_mouse(&mut self, pos: Vector2D<u32>) -> Response {
+        let hovered = contains(&self.bounds, pos);
+        if hovered == self.hovered {
+            return Response::Ignored;
+        }
+        self.hovered = hovered;
+        Response::Redraw
+    }
+
+    fn handle_click(&mut self, pos: Vector2D<u32>) -> Response {
+        Response::Activated(self.id)
+    }
+}
+
+/// [Container] に入れられるウィジェット。
+///
+/// ヒープが無いので、子を種類ごとの列挙型として値で持つ。新しいウィジェットはここへ足す。
+pub(crate) enum Element {
+    Label(Label),
+    Button(Button),
+}
+
+impl Element {
+    fn widget(&self) -> &dyn Widget {
+        match self {
+            Self::Label(label) => label,
+            Self::Button(button) => button,
+        }
+    }
+
+    fn widget_mut(&mut self) -> &mut dyn Widget {
+        match self {
+            Self::Label(label) => label,
+            Self::Button(button) => button,
+        }
+    }
+}
+
+/// 子のウィジェットを並べ、フォーカスを管理する枠。
+///
+/// Tab で次の、Shift + Tab で前のフォーカスできる子へ移る。順番は追加した順。
+/// その他のキーはフォーカスを持つ子へ渡す。
+pub(crate) struct Container<const N: usize> {
+    bounds: Rectangle,
+    children: [Option<Element>; N],
+    len: usize,
+    /// フォーカスを持つ子の添字
+    focus: Option<usize>,
+}
+
+impl<const N: usize> Container<N> {
+    pub(crate) const fn new(bounds: Rectangle) -> Self {
+        Self {
+            bounds,
+            children: [const { None }; N],
+            len: 0,
+            focus: None,
+        }
+    }
+
+    /// 子を末尾に追加する。満杯なら [error::Code::Full] を返す。
+    ///
+    /// 最初に追加したフォーカスできる子が、最初のフォーカスを受け取る。
+    pub(crate) fn add(&mut self, child: Element) -> error::Error {
+        if self.len == N {
+            return make_error!(error::Code::Full);
+        }
+        self.children[self.len] = Some(child);
+        if self.focus.is_none() {
+            self.set_focus(Some(self.len));
+        }
+        self.len += 1;
+        make_error!(error::Code::Success)
+    }
+
+    fn set_focus(&mut self, index: Option<usize>) {
+        let index = index.filter(|&i| {
+            self.children[i]
+                .as_ref()
+                .is_some_and(|child| child.widget().focusable())
+        });
+        if index.is_none() && self.focus.is_none() {
+            return;
+        }
+        if let Some(child) = self.focus.and_then(|i| self.children[i].as_mut()) {
+            child.widget_mut().set_focused(false);
+        }
+        self.focus = index;
+        if let Some(child) = index.and_then(|i| self.children[i].as_mut()) {
+            child.widget_mut().set_focused(true);
+        }
+    }
+
+    /// フォーカスを `forward` の向きに、次のフォーカスできる子へ移す。末尾の次は先頭へ戻る。
+    fn move_focus(&mut self, forward: bool) {
+        let Some(current) = self.focus else {
+            return;
+        };
+        for step in 1..=self.len {
+            let i = if forward {
+                (current + step) % self.len
+            } else {
+                (current + self.len - step) % self.len
+            };
+            if self.children[i]
+                .as_ref()
+                .is_some_and(|child| child.widget().focusable())
+            {
+                self.set_focus(Some(i));
+                return;
+            }
+        }
+    }
+
+    fn children(&self) -> impl Iterator<Item = &dyn Widget> {
+        self.children[..self.len]
+            .iter()
+            .flatten()
+            .map(Element::widget)
+    }
+}
+
+impl<const N: usize> Widget for Container<N> {
+    fn bounds(&self) -> Rectangle {
+        self.bounds
+    }
+
+    fn draw(&self, writer: &dyn PixelWriter, area: &Rectangle) {
+        let theme = theme::current();
+        fill_clipped(writer, self.bounds, area, &theme.taskbar);
+        draw_clipped(writer, self.bounds, area, &theme.accent);
+        for child in self.children() {
+            if let Some(area) = child.bounds().intersection(area) {
+                child.draw(writer, &area);
+            }
+        }
+    }
+
+    fn handle_key(&mut self, modifier: u8, keycode: u8, ascii: u8) -> Response {
+        if ascii == b'\t' {
+            let shift = modifier & (keyboard::L_SHIFT_BIT | keyboard::R_SHIFT_BIT) != 0;
+            self.move_focus(!shift);
+            return Response::Redraw;
+        }
+        match self.focus.and_then(|i| self.children[i].as_mut()) {
+            Some(child) => child.widget_mut().handle_key(modifier, keycode, ascii),
+            None => Response::Ignored,
+        }
+    }
+
+    fn handle_mouse(&mut self, pos: Vector2D<u32>) -> Response {
+        // 離れた子もホバーを解除できるよう、全員に知らせる
+        let mut response = Response::Ignored;
+        for child in self.children[..self.len].iter_mut().flatten() {
+            match child.widget_mut().handle_mouse(pos) {
+                Response::Ignored => {}
+                r => response = r,
+            }
+        }
+        response
+    }
+
+    fn handle_click(&mut self, pos: Vector2D<u32>) -> Response {
+        match self.children[..self.len]
+            .iter_mut()
+            .flatten()
+            .find(|child| contains(&child.widget().bounds(), pos))
+        {
+            Some(child) => child.widget_mut().handle_click(pos),
+            None => Response::Ignored,
+        }
+    }
+}
+
+/// デモのダイアログの OK ボタンの ID
+const DEMO_OK: u32 = 1;
+/// デモのダイアログの Cancel ボタンの ID
+const DEMO_CANCEL: u32 = 2;
+
+/// 開いているダイアログ。一度に 1 つだけ開ける。
+static DIALOG: Mutex<Option<Container<4>>> = Mutex::new(None);
+
+/// 画面の中央にデモのダイアログを開く。既に開いていれば何もしない。
+///
+/// 描画は [draw_dialog] のレイヤが担うので、ここでは再描画を依頼するだけ。
+pub(crate) fn open_demo_dialog(screen: Vector2D<u32>) {
+    let mut dialog = DIALOG.lock();
+    if dialog.is_some() {
+        return;
+    }
+    let size = Vector2D::new(240, 112);
+    let pos = Vector2D::new(
+        screen.x().saturating_sub(size.x()) / 2,
+        screen.y().saturating_sub(size.y()) / 2,
+    );
+    let mut container = Container::new(Rectangle::new(pos, size));
+    for child in [
+        Element::Label(Label::new(
+            Rectangle::new(pos + Vector2D::new(8, 12), Vector2D::new(224, 16)),
+            b"Hello from MikanOS!",
+        )),
+        Element::Label(Label::new(
+            Rectangle::new(pos + Vector2D::new(8, 32), Vector2D::new(224, 16)),
+            b"Tab to move, Enter to press",
+        )),
+        Element::Button(Button::new(
+            Rectangle::new(pos + Vector2D::new(32, 68), Vector2D::new(80, 28)),
+            b"OK",
+            DEMO_OK,
+        )),
+        Element::Button(Button::new(
+            Rectangle::new(pos + Vector2D::new(128, 68), Vector2D::new(80, 28)),
+            b"Cancel",
+            DEMO_CANCEL,
+        )),
+    ] {
+        container.add(child);
+    }
+    render::request_redraw(container.bounds());
+    *dialog = Some(container);
+}
+
+/// ダイアログが開いていれば閉じる。
+pub(crate) fn close_dialog() {
+    if let Some(dialog) = DIALOG.lock().take() {
+        render::request_redraw(dialog.bounds());
+    }
+}
+
+/// ダイアログが開いていれば、押されたキーを渡して true を返す。
+///
+/// Esc か、いずれかのボタンが押されたらダイアログを閉じる。
+pub(crate) fn handle_key(modifier: u8, keycode: u8, ascii: u8) -> bool {
+    let response = {
+        let mut dialog = DIALOG.lock();
+        let Some(dialog) = dialog.as_mut() else {
+            return false;
+        };
+        if ascii == 0x1b {
+            Response::Activated(DEMO_CANCEL)
+        } else {
+            let response = dialog.handle_key(modifier, keycode, ascii);
+            if response == Response::Redraw {
+                render::request_redraw(dialog.bounds());
+            }
+            response
+        }
+    };
+    if let Response::Activated(id) = response {
+        log!(
+            LogLevel::Info,
+            "dialog closed with {}",
+            if id == DEMO_OK { "OK" } else { "Cancel" }
+        );
+        close_dialog();
+    }
+    true
+}
+
+/// ダイアログやスタートメニューが開いていれば、マウスカーソルの位置を渡す。
+pub(crate) fn handle_mouse(pos: Vector2D<u32>) {
+    if let Some(dialog) = DIALOG.lock().as_mut() {
+        if dialog.handle_mouse(pos) != Response::Ignored {
+            render::request_redraw(dialog.bounds());
+        }
+    }
+    if let Some(menu) = START_MENU.lock().as_mut() {
+        if menu.handle_mouse(pos) != Response::Ignored {
+            render::request_redraw(menu.bounds());
+        }
+    }
+}
+
+/// 開いているダイアログを描くレイヤ。
+pub(crate) fn draw_dialog(writer: &dyn PixelWriter, area: &Rectangle) {
+    if let Some(dialog) = DIALOG.lock().as_ref() {
+        if let Some(area) = dialog.bounds().intersection(area) {
+            dialog.draw(writer, &area);
+        }
+    }
+}
+
+/// スタートメニューで選べる操作。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum MenuAction {
+    /// コンソールを最新の画面へ戻し、入力を促す
+    Shell,
+    /// コンソールを消去する
+    Clear,
+    /// マシンをリセットする
+    Reboot,
+}
+
+impl MenuAction {
+    const ALL: [Self; 3] = [Self::Shell, Self::Clear, Self::Reboot];
+
+    const fn label(self) -> &'static [u8] {
+        match self {
+            Self::Shell => b"Shell",
+            Self::Clear => b"Clear",
+            Self::Reboot => b"Reboot",
+        }
+    }
+}
+
+/// スタートメニューの項目 1 つの大きさ
+const MENU_ITEM_SIZE: Vector2D<u32> = Vector2D::new(112, 28);
+/// スタートメニューの枠と項目の間隔
+const MENU_PADDING: u32 = 4;
+
+/// 開いているスタートメニュー。項目のボタンの ID は [MenuAction::ALL] の添字。
+static START_MENU: Mutex<Option<Container<3>>> = Mutex::new(None);
+
+/// `button` の真上に、左端を揃えてスタートメニューを開く。
+fn open_start_menu(button: &Rectangle) {
+    let count = MenuAction::ALL.len() as u32;
+    let size = Vector2D::new(
+        MENU_ITEM_SIZE.x() + 2 * MENU_PADDING,
+        count * (MENU_ITEM_SIZE.y() + MENU_PADDING) + MENU_PADDING,
+    );
+    let pos = Vector2D::new(button.pos.x(), button.pos.y().saturating_sub(size.y()));
+    let mut menu = Container::new(Rectangle::new(pos, size));
+    for (i, action) in MenuAction::ALL.iter().enumerate() {
+        let offset = MENU_PADDING + i as u32 * (MENU_ITEM_SIZE.y() + MENU_PADDING);
+        menu.add(Element::Button(Button::new(
+            Rectangle::new(pos + Vector2D::new(MENU_PADDING, offset), MENU_ITEM_SIZE),
+            action.label(),
+            i as u32,
+        )));
+    }
+    render::request_redraw(menu.bounds());
+    *START_MENU.lock() = Some(menu);
+}
+
+fn close_start_menu() {
+    if let Some(menu) = START_MENU.lock().take() {
+        render::request_redraw(menu.bounds());
+    }
+}
+
+/// `pos` が、開いているダイアログかスタートメニューの上にあるかどうか。
+pub(crate) fn covers(pos: Vector2D<u32>) -> bool {
+    let dialog = DIALOG.lock().as_ref().map(|dialog| dialog.bounds());
+    let menu = START_MENU.lock().as_ref().map(|menu| menu.bounds());
+    [dialog, menu]
+        .iter()
+        .flatten()
+        .any(|bounds| contains(bounds, pos))
+}
+
+/// 左ボタンのクリックを処理する。`start_button` はタスクバーのスタートボタンの領域。
+///
+/// スタートボタンを押すとスタートメニューを開閉する。開いている間は、項目を押すとメニューを
+/// 閉じてその操作を返し、メニューの外を押すと何も選ばずに閉じる。
+pub(crate) fn handle_click(pos: Vector2D<u32>, start_button: &Rectangle) -> Option<MenuAction> {
+    let response = {
+        let mut menu = START_MENU.lock();
+        match menu.as_mut() {
+            Some(menu) if contains(&menu.bounds(), pos) => Some(menu.handle_click(pos)),
+            Some(_) => None,
+            None => {
+                drop(menu);
+                if contains(start_button, pos) {
+                    open_start_menu(start_button);
+                }
+                return None;
+            }
+        }
+    };
+    match response {
+        // 外側のクリック。スタートボタンのクリックもここで閉じるだけにして、開き直さない。
+        None => {
+            close_start_menu();
+            None
+        }
+        Some(Response::Activated(id)) => {
+            close_start_menu();
+            MenuAction::ALL.get(id as usize).copied()
+        }
+        // 項目の間の余白など
+        Some(_) => None,
+    }
+}
+
+/// 開いているスタートメニューを描くレイヤ。
+pub(crate) fn draw_start_menu(writer: &dyn PixelWriter, area: &Rectangle) {
+    if let Some(menu) = START_MENU.lock().as_ref() {
+        if let Some(area) = menu.bounds().intersection(area) {
+            menu.draw(writer, &area);
+        }
+    }
+}
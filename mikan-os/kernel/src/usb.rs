@@ -1,9 +1,102 @@
+#![allow(unused)]
+
+pub(crate) mod registers;
+
 use core::{
-    ffi::{c_char, c_int, c_schar, c_uchar, c_ulong, c_void, CStr},
+    ffi::{c_char, c_int, c_schar, c_uchar, c_uint, c_ulong, c_void, CStr},
     mem::MaybeUninit,
+    ptr,
 };
 
-use crate::error;
+use spin::Mutex;
+
+use registers::{CapabilityRegisters, OperationalRegisters, Usbsts};
+
+use crate::{
+    collections::FixedMap,
+    error::{self, WithError},
+    kassert, log,
+    logger::LogLevel,
+    make_error, paging, pool, printk, printkln,
+    wait::{wait_until, DEFAULT_POLL_LIMIT},
+};
+
+/// xHCI のデータ構造の多くが要求するアライメント（バイト）
+pub(crate) const DMA_ALIGN: usize = 64;
+/// xHCI のデータ構造が跨いではいけない境界（バイト）
+pub(crate) const DMA_BOUNDARY: usize = 4096;
+
+/// xHC が DMA で読み書きするための領域。
+///
+/// 領域はカーネルのイメージの中にあり、恒等写像の範囲から使うので、今は仮想アドレスと物理アドレスが等しい。
+/// C++ 側はポインタをそのまま xHC へ渡すので、この前提が崩れないよう [paging::virt_to_phys] で物理アドレスを求める。
+/// 領域はメモリプールから切り出すので、物理的に連続していて、解放されることはない。
+#[derive(Clone, Copy)]
+pub(crate) struct DmaBuffer {
+    virt: *mut u8,
+    phys: u64,
+    size: usize,
+}
+
+impl DmaBuffer {
+    /// CPU から読み書きするためのポインタを返す。
+    pub(crate) fn virt(&self) -> *mut u8 {
+        self.virt
+    }
+
+    /// xHC のレジスタやデータ構造に書き込むアドレスを返す。
+    pub(crate) fn phys(&self) -> u64 {
+        self.phys
+    }
+
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+/// `align` に揃い、`boundary` を跨がない、0 で初期化された `size` バイトの DMA 用の領域を確保する。
+///
+/// `align` と `boundary` は 2 のべき乗で、0 なら制約しない。
+/// 容量が足りない場合は [error::Code::NoEnoughMemory] を返す。
+pub(crate) fn alloc_dma(size: usize, align: usize, boundary: usize) -> WithError<DmaBuffer> {
+    let allocated = pool::alloc_bounded(size, align, boundary);
+    let (virt, error) = (*allocated.value(), allocated.error());
+    if (&error).into() {
+        return WithError::new(
+            DmaBuffer {
+                virt: ptr::null_mut(),
+                phys: 0,
+                size: 0,
+            },
+            error,
+        );
+    }
+    kassert!(!virt.is_null());
+    debug_assert!(align == 0 || (virt as usize).is_multiple_of(align));
+    debug_assert!(
+        boundary == 0
+            || size > boundary
+            || virt as usize / boundary == (virt as usize + size - 1) / boundary
+    );
+    // プールの領域は未使用なら 0 のはずだが、.bss の初期化をローダに頼らないよう明示的に消す
+    unsafe { ptr::write_bytes(virt, 0, size) };
+    WithError::new(
+        DmaBuffer {
+            virt,
+            phys: paging::virt_to_phys(virt as u64),
+            size,
+        },
+        error,
+    )
+}
+
+/// C++ 側の `usb::AllocMem` から呼ばれる。確保できなければ null を返す。
+#[no_mangle]
+extern "C" fn usb_alloc_dma(size: usize, alignment: c_uint, boundary: c_uint) -> *mut c_void {
+    alloc_dma(size, alignment as usize, boundary as usize)
+        .value()
+        .virt() as *mut c_void
+}
 
 #[repr(C)]
 pub(crate) struct Controller {
@@ -14,12 +107,67 @@ pub(crate) struct Controller {
     devmgr: DeviceManager,
     cr: Ring,
     er: EventRing,
+    er2: EventRing,
+    has_secondary_er: bool,
+    commands: [CommandStatus; COMMAND_RING_SIZE],
+    /// ポート番号ごとの USB のバージョン。上位 8 ビットがメジャー、下位がマイナー（BCD）。
+    port_revisions: [u16; 256],
+    /// 確保したスクラッチパッドバッファの配列とその数。初期化し直すときに使い回す。
+    scratchpad_buf_arr: *mut *mut c_void,
+    num_scratchpad_bufs: u16,
+}
+
+/// コマンドリングの TRB 数
+const COMMAND_RING_SIZE: usize = 32;
+
+/// コマンドリングへ発行したコマンドの完了状況。
+#[repr(C)]
+struct CommandStatus {
+    trb: *const (), // 本当は const TRB*
+    completed: bool,
+    completion_code: c_uchar,
+    slot_id: c_uchar,
+}
+
+/// 発行したコマンドを表すハンドル。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CommandHandle(*const ());
+
+/// コントロール転送の完了状況。C++ 側の `usb::ControlStatus` と同じ並び。
+#[repr(C)]
+struct ControlStatus {
+    setup_data: [u8; 8], // 本当は SetupData
+    pending: bool,
+    completed: bool,
+    completion_code: c_uchar,
+    transfer_length: c_int,
+}
+
+/// [Controller::control_transfer] で発行した転送を表すハンドル。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TransferHandle(*const ControlStatus);
+
+/// 終わったコントロール転送の結果。
+#[derive(Clone, Copy)]
+pub(crate) struct TransferCompletion {
+    /// xHC が返した Completion Code
+    pub(crate) completion_code: u8,
+    /// データステージで実際に転送したバイト数
+    pub(crate) transferred: usize,
+}
+
+impl TransferCompletion {
+    /// Completion Code が Success（1）か Short Packet（13）なら true を返す。
+    pub(crate) fn is_success(&self) -> bool {
+        matches!(self.completion_code, 1 | 13)
+    }
 }
 
 #[repr(C)]
 pub(crate) struct DeviceManager {
     device_context_pointers: *mut *mut (), // 本当は DeviceContext**
     max_slots: c_ulong,
+    devices: *mut *mut (), // 本当は Device**
 }
 
 #[repr(C)]
@@ -46,9 +194,6 @@ extern "C" {
     #[link_name = "_ZN3usb4xhci10Controller10InitializeEv"]
     fn contoller_initialize(this: *mut Controller) -> CxxError;
 
-    #[link_name = "_ZN3usb4xhci10Controller3RunEv"]
-    fn controller_run(this: *mut Controller) -> CxxError;
-
     #[link_name = "_ZN3usb4xhci10Controller6PortAtEh"]
     fn controller_port_at(this: *mut Controller, port_num: c_uchar) -> Port;
 
@@ -58,13 +203,51 @@ extern "C" {
     #[link_name = "_ZN3usb4xhci12ProcessEventERNS0_10ControllerE"]
     fn xhci_process_event(xhc: *mut Controller) -> CxxError;
 
-    #[link_name = "_ZN3usb14HIDMouseDriver18SetDefaultObserverEPFvaaE"]
+    #[link_name = "_ZN3usb4xhci21ProcessSecondaryEventERNS0_10ControllerE"]
+    fn xhci_process_secondary_event(xhc: *mut Controller) -> CxxError;
+
+    #[link_name = "_ZN3usb4xhci15HasPendingEventERNS0_10ControllerE"]
+    fn xhci_has_pending_event(xhc: *mut Controller) -> bool;
+
+    #[link_name = "_ZN3usb4xhci21ResetPortConfigPhasesEv"]
+    fn xhci_reset_port_config_phases();
+
+    #[link_name = "_ZN3usb4xhci16IssueNoOpCommandERNS0_10ControllerE"]
+    fn xhci_issue_no_op_command(xhc: *mut Controller) -> *const ();
+
+    #[link_name = "_ZNK3usb4xhci10Controller11FindCommandEPKNS0_3TRBE"]
+    fn controller_find_command(this: *const Controller, handle: *const ()) -> *const CommandStatus;
+
+    #[link_name = "_ZN3usb4xhci15ControlTransferERNS0_10ControllerEhhhttPviPPKNS_13ControlStatusE"]
+    fn xhci_control_transfer(
+        xhc: *mut Controller,
+        slot_id: c_uchar,
+        request_type: c_uchar,
+        request: c_uchar,
+        value: u16,
+        index: u16,
+        buf: *mut c_void,
+        len: c_int,
+        status: *mut *const ControlStatus,
+    ) -> CxxError;
+
+    #[link_name = "_ZN3usb14HIDMouseDriver18SetDefaultObserverEPFvhaaE"]
     fn hid_mouse_driver_set_default_observer(observer: *const c_void);
 
+    #[link_name = "_ZN3usb17HIDKeyboardDriver18SetDefaultObserverEPFvhhbE"]
+    fn hid_keyboard_driver_set_default_observer(observer: *const c_void);
+
+    #[link_name = "_ZN3usb17HIDKeyboardDriver10SetAllLEDsEh"]
+    fn hid_keyboard_driver_set_all_leds(leds: c_uchar);
+
     #[link_name = "_ZNK3usb4xhci4Port11IsConnectedEv"]
     fn port_is_connected(this: *const Port) -> bool;
 }
 
+// レジスタや C++ 側のオブジェクトを指すポインタを持つが、どれもこのコントローラ専用で、
+// 他から共有されてはいない。コントローラごと別の文脈へ渡してよい。
+unsafe impl Send for Controller {}
+
 impl Controller {
     pub(crate) fn new(mmio_base: u64) -> Self {
         let mut this = MaybeUninit::<Controller>::uninit();
@@ -74,19 +257,245 @@ impl Controller {
         }
     }
 
+    /// xHC をリセットし、コマンドリングやイベントリングを用意する。
+    ///
+    /// 初期化の本体は C++ 側で行い、ここでは設定された結果をログに出す。
     pub(crate) fn initialize(&mut self) -> error::Error {
-        unsafe { contoller_initialize(self as *mut Self) }.into()
+        let err: error::Error = unsafe { contoller_initialize(self as *mut Self) }.into();
+        if (&err).into() {
+            return err;
+        }
+
+        let cap = self.capability_registers();
+        let op = self.operational_registers();
+        log!(
+            LogLevel::Debug,
+            "xHC: {}/{} slots enabled, {} ports, {} scratchpad buffers, {}-byte contexts, DCBAA {:#x}",
+            op.config().read().max_device_slots_enabled(),
+            cap.hcsparams1().max_device_slots(),
+            cap.hcsparams1().max_ports(),
+            cap.hcsparams2().max_scratchpad_buffers(),
+            if cap.hccparams1().context_size() { 64 } else { 32 },
+            op.dcbaap().read().pointer()
+        );
+        err
     }
 
+    /// Run/Stop ビットを立てて、xHC が動き始める（HCHalted が下りる）のを待つ。
+    ///
+    /// 一定回数待っても動き始めなければ [error::Code::Timeout] を返す。
     pub(crate) fn run(&mut self) -> error::Error {
-        unsafe { controller_run(self as *mut Self) }.into()
+        let op = self.operational_registers();
+        op.usbcmd().write(op.usbcmd().read().with_run_stop(true));
+        wait_until(
+            || !op.usbsts().read().host_controller_halted(),
+            DEFAULT_POLL_LIMIT,
+        )
+    }
+
+    /// Run/Stop ビットを下ろして、xHC が停止（HCHalted）するのを待つ。
+    ///
+    /// 一定回数待っても停止しなければ [error::Code::Timeout] を返す。
+    pub(crate) fn stop(&mut self) -> error::Error {
+        let op = self.operational_registers();
+        op.usbcmd().write(op.usbcmd().read().with_run_stop(false));
+        wait_until(
+            || op.usbsts().read().host_controller_halted(),
+            DEFAULT_POLL_LIMIT,
+        )
+    }
+
+    /// xHC が Host System Error か Host Controller Error で止まっていれば true を返す。
+    ///
+    /// どちらもリセットしない限り解けないので、イベントを処理し続けても意味が無い。
+    pub(crate) fn has_fatal_error(&self) -> bool {
+        let usbsts = self.operational_registers().usbsts().read();
+        usbsts.host_system_error() || usbsts.host_controller_error()
+    }
+
+    /// xHC をリセットして初期化し直し、動かし始める。
+    ///
+    /// コマンドリングやイベントリングは空にするので、それまでに発行したコマンドのハンドルは無効になる。
+    /// 確保済みのリングやスクラッチパッドバッファは使い回し、登録されていたデバイスは取り除く。
+    /// ポートの設定の進み具合は未接続に戻すが、設定そのものはやり直さないので、呼び出し側で行うこと。
+    pub(crate) fn reinitialize(&mut self) -> error::Error {
+        unsafe { xhci_reset_port_config_phases() };
+        let err = self.initialize();
+        if (&err).into() {
+            return err;
+        }
+        self.run()
+    }
+
+    /// USBCMD の Interrupter Enable ビットを下ろし、xHC から割り込みが来ないようにする。
+    pub(crate) fn disable_interrupt(&mut self) {
+        let usbcmd = self.operational_registers().usbcmd();
+        usbcmd.write(usbcmd.read().with_interrupter_enable(false));
+    }
+
+    pub(crate) fn capability_registers(&self) -> CapabilityRegisters {
+        unsafe { CapabilityRegisters::new(self.cap as usize) }
+    }
+
+    pub(crate) fn operational_registers(&self) -> OperationalRegisters {
+        unsafe { OperationalRegisters::new(self.op as usize) }
+    }
+
+    /// No Op コマンドを発行する。コマンドリングとイベントリングの動作確認に使う。
+    pub(crate) fn issue_no_op_command(&mut self) -> CommandHandle {
+        CommandHandle(unsafe { xhci_issue_no_op_command(self as *mut Self) })
+    }
+
+    /// コマンドが完了していれば、その Completion Code を返す。
+    ///
+    /// まだ完了していない場合や、コマンドリングが一周してハンドルが無効になった場合は None を返す。
+    pub(crate) fn command_completion(&self, handle: CommandHandle) -> Option<u8> {
+        let status = unsafe { controller_find_command(self as *const Self, handle.0).as_ref() }?;
+        if status.completed {
+            Some(status.completion_code)
+        } else {
+            None
+        }
+    }
+
+    /// イベントを処理しながら、コマンドの完了を待つ。
+    ///
+    /// 完了したら Completion Code を返す。`max_polls` 回イベントを処理しても完了しなければ
+    /// [error::Code::Timeout] を返す。
+    pub(crate) fn wait_command(
+        &mut self,
+        handle: CommandHandle,
+        max_polls: usize,
+    ) -> WithError<u8> {
+        let err = wait_until(
+            || {
+                let _ = self.process_event();
+                self.command_completion(handle).is_some()
+            },
+            max_polls,
+        );
+        if (&err).into() {
+            return WithError::new(0, err);
+        }
+        WithError::new(
+            self.command_completion(handle).unwrap_or(0),
+            make_error!(error::Code::Success),
+        )
+    }
+
+    /// `slot_id` のデバイスのデフォルトコントロールパイプへ、任意のリクエストを発行する。
+    ///
+    /// 転送の向きは `request_type`（bmRequestType）の最上位ビットで決まり、`buf` の長さが
+    /// wLength になる。`buf` は転送が終わるまで有効で、xHC から DMA で触れる場所（[alloc_dma] で
+    /// 確保した領域など）になければならない。デバイスの初期化が終わる前なら
+    /// [error::Code::InvalidPhase]、`buf` が 65535 バイトより長ければ [error::Code::BufferTooSmall]
+    /// を返す。
+    pub(crate) fn control_transfer(
+        &mut self,
+        slot_id: u8,
+        request_type: u8,
+        request: u8,
+        value: u16,
+        index: u16,
+        buf: &mut [u8],
+    ) -> WithError<TransferHandle> {
+        let Ok(len) = u16::try_from(buf.len()) else {
+            return WithError::new(
+                TransferHandle(ptr::null()),
+                make_error!(error::Code::BufferTooSmall),
+            );
+        };
+        let mut status = ptr::null();
+        let err: error::Error = unsafe {
+            xhci_control_transfer(
+                self as *mut Self,
+                slot_id,
+                request_type,
+                request,
+                value,
+                index,
+                buf.as_mut_ptr() as *mut c_void,
+                len as c_int,
+                &mut status,
+            )
+        }
+        .into();
+        WithError::new(TransferHandle(status), err)
+    }
+
+    /// 転送が終わっていれば、その結果を返す。
+    ///
+    /// まだ終わっていなければ None を返す。同じデバイスへ後から発行した転送で完了状況が
+    /// 上書きされると、別の転送の結果が返ったり None のままになったりするので、早めに確かめること。
+    pub(crate) fn transfer_completion(&self, handle: TransferHandle) -> Option<TransferCompletion> {
+        let status = unsafe { handle.0.as_ref() }?;
+        if status.completed {
+            Some(TransferCompletion {
+                completion_code: status.completion_code,
+                transferred: status.transfer_length as usize,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// イベントを処理しながら、転送が終わるのを待つ。
+    ///
+    /// `max_polls` 回イベントを処理しても終わらなければ [error::Code::Timeout]、
+    /// 終わったが失敗していれば [error::Code::TransferFailed] を返す。
+    pub(crate) fn wait_transfer(
+        &mut self,
+        handle: TransferHandle,
+        max_polls: usize,
+    ) -> WithError<TransferCompletion> {
+        let mut completion = TransferCompletion {
+            completion_code: 0,
+            transferred: 0,
+        };
+        let err = wait_until(
+            || {
+                let _ = self.process_event();
+                self.transfer_completion(handle).is_some()
+            },
+            max_polls,
+        );
+        if (&err).into() {
+            return WithError::new(completion, err);
+        }
+        if let Some(c) = self.transfer_completion(handle) {
+            completion = c;
+        }
+        if !completion.is_success() {
+            return WithError::new(completion, make_error!(error::Code::TransferFailed));
+        }
+        WithError::new(completion, make_error!(error::Code::Success))
     }
 
     pub(crate) fn max_ports(&self) -> u8 {
         self.max_ports
     }
 
+    /// `port_num` 番のポートが対応する USB のバージョンを (メジャー, マイナー) で返す。
+    ///
+    /// マイナーは BCD（USB 3.1 なら 0x10）。xHC の Supported Protocol Capability に
+    /// 載っていないポートでは None を返す。[Controller::initialize] の後に使うこと。
+    pub(crate) fn port_revision(&self, port_num: u8) -> Option<(u8, u8)> {
+        let revision = self.port_revisions[port_num as usize];
+        if revision == 0 {
+            return None;
+        }
+        Some(((revision >> 8) as u8, revision as u8))
+    }
+
+    /// `port_num` 番（1 始まり）のポートを返す。
     pub(crate) fn port_at(&mut self, port_num: u8) -> Port {
+        // C++ 側は範囲を確かめずに PORTSC のレジスタ配列を指すので、範囲外だと他のレジスタを触る
+        kassert!(
+            (1..=self.max_ports).contains(&port_num),
+            "port {} out of 1..={}",
+            port_num,
+            self.max_ports
+        );
         unsafe { controller_port_at(self as *mut Self, port_num) }
     }
 
@@ -94,12 +503,63 @@ impl Controller {
         unsafe { xhci_configure_port(self as *mut Self, port as *mut Port) }.into()
     }
 
+    /// プライマリイベントリングのイベントを高々 1 つ処理する。
+    ///
+    /// xHC が致命的なエラーで止まっていれば、イベントリングには触らず
+    /// [error::Code::HostControllerError] を返す。
     pub(crate) fn process_event(&mut self) -> error::Error {
+        if self.has_fatal_error() {
+            return make_error!(error::Code::HostControllerError);
+        }
         unsafe { xhci_process_event(self as *mut Self) }.into()
     }
+
+    /// プライマリイベントリングに未処理のイベントがあれば true を返す。
+    pub(crate) fn has_pending_event(&mut self) -> bool {
+        unsafe { xhci_has_pending_event(self as *mut Self) }
+    }
+
+    /// プライマリイベントリングのイベントを、空になるか `max_events` 個処理するまで処理する。
+    ///
+    /// 処理したイベントの数を返す。エラーが起きたら、そこで止めてエラーを返す。
+    pub(crate) fn drain_events(&mut self, max_events: usize) -> WithError<usize> {
+        let mut count = 0;
+        while count < max_events && self.has_pending_event() {
+            let err = self.process_event();
+            count += 1;
+            if (&err).into() {
+                return WithError::new(count, err);
+            }
+        }
+        WithError::new(count, make_error!(error::Code::Success))
+    }
+
+    /// セカンダリイベントリングを返す。インタラプタが 1 つしか無い xHC では [None]。
+    pub(crate) fn secondary_event_ring(&mut self) -> Option<&mut EventRing> {
+        if self.has_secondary_er {
+            Some(&mut self.er2)
+        } else {
+            None
+        }
+    }
+
+    /// セカンダリイベントリングのイベントを高々 1 つ処理する。
+    pub(crate) fn process_secondary_event(&mut self) -> error::Error {
+        if self.has_fatal_error() {
+            return make_error!(error::Code::HostControllerError);
+        }
+        unsafe { xhci_process_secondary_event(self as *mut Self) }.into()
+    }
 }
 
-type ObserverType = fn(c_schar, c_schar);
+/// マウスの入力を受け取る関数。引数はボタンの状態（[MOUSE_BUTTON_LEFT] など）と移動量。
+type ObserverType = fn(c_uchar, c_schar, c_schar);
+/// マウスのボタンの状態の、左ボタンのビット
+pub(crate) const MOUSE_BUTTON_LEFT: u8 = 1 << 0;
+/// キーボードの入力を受け取る関数。引数は修飾キーの状態とキーコード、押されたかどうか。
+///
+/// 修飾キーの変化も [crate::keyboard::KEY_MODIFIER_BASE] 以降のキーコードで届く。
+type KeyObserverType = fn(c_uchar, c_uchar, bool);
 
 #[repr(C)]
 struct Function {
@@ -117,6 +577,121 @@ impl HIDMouseDriver {
         unsafe {
             hid_mouse_driver_set_default_observer(observer as *const c_void);
         }
+        let _ = register_class_driver(
+            InterfaceClass::HID_BOOT_MOUSE,
+            ClassDriverKind::HidMouse,
+            "HID boot mouse",
+        );
+    }
+}
+
+/// インターフェースディスクリプタのクラス、サブクラス、プロトコルの組。クラスドライバを選ぶのに使う。
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct InterfaceClass {
+    pub(crate) class: u8,
+    pub(crate) sub_class: u8,
+    pub(crate) protocol: u8,
+}
+
+impl InterfaceClass {
+    pub(crate) const HID_BOOT_KEYBOARD: Self = Self::new(3, 1, 1);
+    pub(crate) const HID_BOOT_MOUSE: Self = Self::new(3, 1, 2);
+
+    pub(crate) const fn new(class: u8, sub_class: u8, protocol: u8) -> Self {
+        Self {
+            class,
+            sub_class,
+            protocol,
+        }
+    }
+}
+
+/// 登録できるクラスドライバの数
+const MAX_CLASS_DRIVERS: usize = 8;
+
+/// C++ 側で生成できるクラスドライバの種類。C++ 側の device.cpp の `ClassDriverKind` と同じ値。
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ClassDriverKind {
+    HidKeyboard = 1,
+    HidMouse = 2,
+}
+
+/// 登録されたクラスドライバ。
+#[derive(Clone, Copy)]
+struct ClassDriverEntry {
+    kind: ClassDriverKind,
+    name: &'static str,
+}
+
+/// 使えるようにしたクラスドライバ。インターフェースのクラスで引く。
+///
+/// C++ 側の NewClassDriver はデバイスのインターフェースごとにこれを引き、登録されている種類の
+/// ドライバを生成する。登録されていないクラスのインターフェースは使わない。
+static CLASS_DRIVERS: Mutex<FixedMap<InterfaceClass, ClassDriverEntry, MAX_CLASS_DRIVERS>> =
+    Mutex::new(FixedMap::new());
+
+/// `class` のインターフェースに `kind` のクラスドライバを使うよう、`name` として登録する。
+///
+/// 満杯なら [error::Code::Full] を返す。
+pub(crate) fn register_class_driver(
+    class: InterfaceClass,
+    kind: ClassDriverKind,
+    name: &'static str,
+) -> error::Error {
+    CLASS_DRIVERS
+        .lock()
+        .insert(class, ClassDriverEntry { kind, name })
+}
+
+/// `class` に対応するクラスドライバの名前を返す。登録されていなければ None。
+pub(crate) fn class_driver_name(class: InterfaceClass) -> Option<&'static str> {
+    CLASS_DRIVERS.lock().get(&class).map(|entry| entry.name)
+}
+
+/// 登録されているクラスドライバを `f` に渡す。
+pub(crate) fn for_each_class_driver(mut f: impl FnMut(InterfaceClass, &'static str)) {
+    for (class, entry) in CLASS_DRIVERS.lock().iter() {
+        f(*class, entry.name);
+    }
+}
+
+/// C++ 側の NewClassDriver から呼ばれる。登録されているクラスドライバの種類を返し、無ければ 0 を返す。
+#[no_mangle]
+extern "C" fn usb_find_class_driver(
+    class: c_uchar,
+    sub_class: c_uchar,
+    protocol: c_uchar,
+) -> c_uchar {
+    CLASS_DRIVERS
+        .lock()
+        .get(&InterfaceClass::new(class, sub_class, protocol))
+        .map_or(0, |entry| entry.kind as c_uchar)
+}
+
+#[repr(C)]
+pub(crate) struct HIDKeyboardDriver {
+    observers: [Function; 4], // 本当は Function<ObserverType>
+    num_observers: i32,
+}
+
+impl HIDKeyboardDriver {
+    pub(crate) fn set_default_observer(observer: KeyObserverType) {
+        unsafe {
+            hid_keyboard_driver_set_default_observer(observer as *const c_void);
+        }
+        let _ = register_class_driver(
+            InterfaceClass::HID_BOOT_KEYBOARD,
+            ClassDriverKind::HidKeyboard,
+            "HID boot keyboard",
+        );
+    }
+
+    /// 接続されているすべてのキーボードの LED を設定する。後から接続されたものにも反映される。
+    ///
+    /// `leds` は [crate::keyboard::LED_CAPS_LOCK_BIT] などの組み合わせ。
+    pub(crate) fn set_all_leds(leds: u8) {
+        unsafe { hid_keyboard_driver_set_all_leds(leds) }
     }
 }
 
@@ -25,17 +25,12 @@ namespace usb {
   }
 
   Error HIDBaseDriver::OnEndpointsConfigured() {
-    SetupData setup_data{};
-    setup_data.request_type.bits.direction = request_type::kOut;
-    setup_data.request_type.bits.type = request_type::kClass;
-    setup_data.request_type.bits.recipient = request_type::kInterface;
-    setup_data.request = request::kSetProtocol;
-    setup_data.value = 0; // boot protocol
-    setup_data.index = interface_index_;
-    setup_data.length = 0;
-
     initialize_phase_ = 1;
-    return ParentDevice()->ControlOut(kDefaultControlPipeID, setup_data, nullptr, 0, this);
+    return ControlRequest(
+        *ParentDevice(),
+        MakeRequestType(request_type::kOut, request_type::kClass, request_type::kInterface),
+        request::kSetProtocol, 0 /* boot protocol */, interface_index_,
+        nullptr, 0, this);
   }
 
   Error HIDBaseDriver::OnControlCompleted(EndpointID ep_id, SetupData setup_data,
@@ -46,10 +41,26 @@ namespace usb {
       initialize_phase_ = 2;
       return ParentDevice()->InterruptIn(ep_interrupt_in_, buf_.data(), in_packet_size_);
     }
+    if (setup_data.request == request::kSetReport) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
 
     return MAKE_ERROR(Error::kNotImplemented);
   }
 
+  Error HIDBaseDriver::SetReport(uint8_t report_type, uint8_t report_id,
+                                 const void* buf, int len) {
+    if (initialize_phase_ != 2) {
+      return MAKE_ERROR(Error::kInvalidPhase);
+    }
+
+    return ControlRequest(
+        *ParentDevice(),
+        MakeRequestType(request_type::kOut, request_type::kClass, request_type::kInterface),
+        request::kSetReport, (static_cast<uint16_t>(report_type) << 8) | report_id,
+        interface_index_, const_cast<void*>(buf), len, this);
+  }
+
   Error HIDBaseDriver::OnInterruptCompleted(EndpointID ep_id, const void* buf, int len) {
     if (ep_id.IsIn()) {
       OnDataReceived();
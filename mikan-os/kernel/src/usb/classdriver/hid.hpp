@@ -20,6 +20,15 @@ namespace usb {
     Error OnInterruptCompleted(EndpointID ep_id, const void* buf, int len) override;
 
     virtual Error OnDataReceived() = 0;
+
+    /** @brief SET_REPORT リクエストで出力レポートなどをデバイスへ送る．
+     *
+     * buf は転送が終わるまで有効でなければならない．
+     * エンドポイントの設定が終わる前に呼ぶと kInvalidPhase を返す．
+     */
+    Error SetReport(uint8_t report_type, uint8_t report_id, const void* buf, int len);
+    const static uint8_t kOutputReport = 2;
+
     const static size_t kBufferSize = 1024;
     const std::array<uint8_t, kBufferSize>& Buffer() const { return buf_; }
     const std::array<uint8_t, kBufferSize>& PreviousBuffer() const { return previous_buf_; }
@@ -3,27 +3,77 @@
 #include <algorithm>
 #include "usb/memory.hpp"
 #include "usb/device.hpp"
+#include "logger.hpp"
 
 namespace usb {
   HIDKeyboardDriver::HIDKeyboardDriver(Device* dev, int interface_index)
       : HIDBaseDriver{dev, interface_index, 8} {
+    if (num_keyboards_ < keyboards_.size()) {
+      keyboards_[num_keyboards_++] = this;
+    }
+  }
+
+  HIDKeyboardDriver::~HIDKeyboardDriver() {
+    // 抜かれたキーボードへ LED の設定を送らないよう，一覧から外す
+    auto end = keyboards_.begin() + num_keyboards_;
+    auto it = std::remove(keyboards_.begin(), end, this);
+    num_keyboards_ -= end - it;
+    std::fill(it, end, nullptr);
   }
 
   Error HIDKeyboardDriver::OnDataReceived() {
-    for (int i = 2; i < 8; ++i) {
-      const uint8_t key = Buffer()[i];
-      if (key == 0) {
-        continue;
+    const auto& prev_buf = PreviousBuffer();
+    const uint8_t modifier = Buffer()[0];
+    const uint8_t changed = modifier ^ prev_buf[0];
+    for (int bit = 0; bit < 8; ++bit) {
+      if (changed & (1u << bit)) {
+        NotifyKeyPush(modifier, kModifierKeycodeBase + bit, modifier & (1u << bit));
+      }
+    }
+
+    const auto prev_keys_begin = prev_buf.begin() + 2, prev_keys_end = prev_buf.begin() + 8;
+    const auto keys_begin = Buffer().begin() + 2, keys_end = Buffer().begin() + 8;
+    for (auto it = prev_keys_begin; it != prev_keys_end; ++it) {
+      if (*it != 0 && std::find(keys_begin, keys_end, *it) == keys_end) {
+        NotifyKeyPush(modifier, *it, false);
       }
-      const auto& prev_buf = PreviousBuffer();
-      if (std::find(prev_buf.begin(), prev_buf.end(), key) != prev_buf.end()) {
-        continue;
+    }
+    for (auto it = keys_begin; it != keys_end; ++it) {
+      if (*it != 0 && std::find(prev_keys_begin, prev_keys_end, *it) == prev_keys_end) {
+        NotifyKeyPush(modifier, *it, true);
       }
-      NotifyKeyPush(key);
     }
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  Error HIDKeyboardDriver::OnControlCompleted(EndpointID ep_id, SetupData setup_data,
+                                              const void* buf, int len) {
+    auto err = HIDBaseDriver::OnControlCompleted(ep_id, setup_data, buf, len);
+    // ブートプロトコルへの切り替えが終われば出力レポートを送れる
+    if (!err && setup_data.request == request::kSetProtocol && leds_ != 0) {
+      return SetLEDs(leds_);
+    }
+    return err;
+  }
+
+  Error HIDKeyboardDriver::SetLEDs(uint8_t leds) {
+    led_report_ = leds;
+    return SetReport(kOutputReport, 0, &led_report_, 1);
+  }
+
+  std::array<HIDKeyboardDriver*, 4> HIDKeyboardDriver::keyboards_{};
+  int HIDKeyboardDriver::num_keyboards_ = 0;
+  uint8_t HIDKeyboardDriver::leds_ = 0;
+
+  void HIDKeyboardDriver::SetAllLEDs(uint8_t leds) {
+    leds_ = leds;
+    for (int i = 0; i < num_keyboards_; ++i) {
+      if (auto err = keyboards_[i]->SetLEDs(leds)) {
+        Log(kDebug, "HIDKeyboardDriver::SetAllLEDs: %s\n", err.Name());
+      }
+    }
+  }
+
   void* HIDKeyboardDriver::operator new(size_t size) {
     return AllocMem(sizeof(HIDKeyboardDriver), 0, 0);
   }
@@ -33,15 +83,19 @@ namespace usb {
   }
 
   void HIDKeyboardDriver::SubscribeKeyPush(
-      std::function<void (uint8_t keycode)> observer) {
+      std::function<ObserverType> observer) {
     observers_[num_observers_++] = observer;
   }
 
   std::function<HIDKeyboardDriver::ObserverType> HIDKeyboardDriver::default_observer;
 
-  void HIDKeyboardDriver::NotifyKeyPush(uint8_t keycode) {
+  void HIDKeyboardDriver::SetDefaultObserver(HIDKeyboardDriver::ObserverType *observer) {
+    HIDKeyboardDriver::default_observer = *observer;
+  }
+
+  void HIDKeyboardDriver::NotifyKeyPush(uint8_t modifier, uint8_t keycode, bool press) {
     for (int i = 0; i < num_observers_; ++i) {
-      observers_[i](keycode);
+      observers_[i](modifier, keycode, press);
     }
   }
 }
@@ -13,20 +13,50 @@ namespace usb {
   class HIDKeyboardDriver : public HIDBaseDriver {
    public:
     HIDKeyboardDriver(Device* dev, int interface_index);
+    ~HIDKeyboardDriver() override;
 
     void* operator new(size_t size);
     void operator delete(void* ptr) noexcept;
 
     Error OnDataReceived() override;
-
-    using ObserverType = void (uint8_t keycode);
+    Error OnControlCompleted(EndpointID ep_id, SetupData setup_data,
+                             const void* buf, int len) override;
+
+    /** @brief キーが押されたか離されたときに呼ばれる．
+     *
+     * 修飾キーの変化は，keycode に修飾キーのキーコード（0xe0〜0xe7）を入れて通知する．
+     * modifier は変化した後の修飾キーの状態．
+     */
+    using ObserverType = void (uint8_t modifier, uint8_t keycode, bool press);
     void SubscribeKeyPush(std::function<ObserverType> observer);
     static std::function<ObserverType> default_observer;
+    static void SetDefaultObserver(ObserverType *observer);
+
+    /** @brief キーボードの LED を点灯/消灯する．
+     *
+     * leds はブートプロトコルの出力レポートの形式（bit0: Num Lock, bit1: Caps Lock,
+     * bit2: Scroll Lock）．
+     */
+    Error SetLEDs(uint8_t leds);
+    /** @brief 接続されているすべてのキーボードの LED を設定する．
+     *
+     * 後から接続されたキーボードにも，設定が終わった時点で同じ状態を反映する．
+     */
+    static void SetAllLEDs(uint8_t leds);
+
+    /** @brief 左 Ctrl のキーコード．修飾キーのビット i は，このキーコード + i に対応する． */
+    const static uint8_t kModifierKeycodeBase = 0xe0;
 
    private:
     std::array<std::function<ObserverType>, 4> observers_;
     int num_observers_ = 0;
+    // SET_REPORT の転送が終わるまで読まれるので，メンバとして持っておく
+    uint8_t led_report_ = 0;
+
+    static std::array<HIDKeyboardDriver*, 4> keyboards_;
+    static int num_keyboards_;
+    static uint8_t leds_;
 
-    void NotifyKeyPush(uint8_t keycode);
+    void NotifyKeyPush(uint8_t modifier, uint8_t keycode, bool press);
   };
 }
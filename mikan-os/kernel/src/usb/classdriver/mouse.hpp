@@ -19,7 +19,7 @@ namespace usb {
 
     Error OnDataReceived() override;
 
-    using ObserverType = void (int8_t displacement_x, int8_t displacement_y);
+    using ObserverType = void (uint8_t buttons, int8_t displacement_x, int8_t displacement_y);
     void SubscribeMouseMove(std::function<ObserverType> observer);
     static std::function<ObserverType> default_observer;
     static void SetDefaultObserver(ObserverType *observer);
@@ -28,6 +28,6 @@ namespace usb {
     std::array<std::function<ObserverType>, 4> observers_;
     int num_observers_ = 0;
 
-    void NotifyMouseMove(int8_t displacement_x, int8_t displacement_y);
+    void NotifyMouseMove(uint8_t buttons, int8_t displacement_x, int8_t displacement_y);
   };
 }
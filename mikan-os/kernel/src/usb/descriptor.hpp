@@ -42,6 +42,30 @@ namespace usb {
     uint8_t max_power;          // offset 8
   } __attribute__((packed));
 
+  struct StringDescriptor {
+    static const uint8_t kType = 3;
+
+    uint8_t length;             // offset 0
+    uint8_t descriptor_type;    // offset 1
+    // offset 2 以降に UTF-16LE の文字列が続く．
+    // インデックス 0 のディスクリプタだけは，文字列の代わりに LANGID の配列を持つ．
+
+    /** @brief 文字列（LANGID）の要素数を返す．
+     *
+     * @param len  実際に受信したバイト数．length より短い場合はこちらに従う．
+     */
+    int NumCodeUnits(int len) const {
+      const int n = length < len ? length : len;
+      return n < 2 ? 0 : (n - 2) / 2;
+    }
+
+    /** @brief i 番目の UTF-16 コードユニット（LANGID）を返す． */
+    uint16_t CodeUnit(int i) const {
+      const auto p = reinterpret_cast<const uint8_t*>(this) + 2 + 2 * i;
+      return p[0] | (static_cast<uint16_t>(p[1]) << 8);
+    }
+  } __attribute__((packed));
+
   struct InterfaceDescriptor {
     static const uint8_t kType = 4;
 
@@ -1,5 +1,6 @@
 #include "usb/device.hpp"
 
+#include <algorithm>
 #include "usb/descriptor.hpp"
 #include "usb/setupdata.hpp"
 #include "usb/classdriver/base.hpp"
@@ -8,7 +9,18 @@
 
 #include "logger.hpp"
 
+// カーネル（Rust）側のクラスドライバの登録表を引く．登録されていなければ 0
+extern "C" uint8_t usb_find_class_driver(uint8_t class_code, uint8_t sub_class,
+                                         uint8_t protocol);
+
 namespace {
+  /** usb_find_class_driver が返すクラスドライバの種類．usb.rs の ClassDriverKind と同じ値． */
+  enum class ClassDriverKind : uint8_t {
+    kNone = 0,
+    kHIDKeyboard = 1,
+    kHIDMouse = 2,
+  };
+
   class ConfigurationDescriptorReader {
    public:
     ConfigurationDescriptorReader(const uint8_t* desc_buf, int len)
@@ -53,24 +65,46 @@ namespace {
     return conf;
   }
 
+  /** インターフェースのクラスでカーネル側の登録表を引き，登録されている種類のクラスドライバを作る．
+   * 登録されていなければ nullptr を返す．
+   */
   usb::ClassDriver* NewClassDriver(usb::Device* dev, const usb::InterfaceDescriptor& if_desc) {
-    if (if_desc.interface_class == 3 &&
-        if_desc.interface_sub_class == 1) {  // HID boot interface
-      if (if_desc.interface_protocol == 1) {  // keyboard
-        auto keyboard_driver = new usb::HIDKeyboardDriver{dev, if_desc.interface_number};
-        if (usb::HIDKeyboardDriver::default_observer) {
-          keyboard_driver->SubscribeKeyPush(usb::HIDKeyboardDriver::default_observer);
-        }
-        return keyboard_driver;
-      } else if (if_desc.interface_protocol == 2) {  // mouse
-        auto mouse_driver = new usb::HIDMouseDriver{dev, if_desc.interface_number};
-        if (usb::HIDMouseDriver::default_observer) {
-          mouse_driver->SubscribeMouseMove(usb::HIDMouseDriver::default_observer);
-        }
-        return mouse_driver;
+    const auto kind = static_cast<ClassDriverKind>(usb_find_class_driver(
+        if_desc.interface_class, if_desc.interface_sub_class, if_desc.interface_protocol));
+    switch (kind) {
+    case ClassDriverKind::kHIDKeyboard: {
+      auto keyboard_driver = new usb::HIDKeyboardDriver{dev, if_desc.interface_number};
+      if (usb::HIDKeyboardDriver::default_observer) {
+        keyboard_driver->SubscribeKeyPush(usb::HIDKeyboardDriver::default_observer);
       }
+      return keyboard_driver;
+    }
+    case ClassDriverKind::kHIDMouse: {
+      auto mouse_driver = new usb::HIDMouseDriver{dev, if_desc.interface_number};
+      if (usb::HIDMouseDriver::default_observer) {
+        mouse_driver->SubscribeMouseMove(usb::HIDMouseDriver::default_observer);
+      }
+      return mouse_driver;
+    }
+    default:
+      return nullptr;
     }
-    return nullptr;
+  }
+
+  /** @brief 文字列ディスクリプタの中身を，NUL 終端の ASCII 文字列として dst へ書き込む．
+   *
+   * ASCII の表示可能文字以外は '?' に置き換え，入り切らない分は切り捨てる．
+   */
+  template <size_t N>
+  void CopyString(const usb::StringDescriptor& str_desc, int len,
+                  std::array<char, N>& dst) {
+    const int n = str_desc.NumCodeUnits(len);
+    size_t i = 0;
+    for (; i < N - 1 && i < static_cast<size_t>(n); ++i) {
+      const auto c = str_desc.CodeUnit(i);
+      dst[i] = (0x20 <= c && c < 0x7f) ? static_cast<char>(c) : '?';
+    }
+    dst[i] = '\0';
   }
 
   void Log(LogLevel level, const usb::InterfaceDescriptor& if_desc) {
@@ -102,6 +136,19 @@ namespace {
 
 namespace usb {
   Device::~Device() {
+    // 1 つのクラスドライバが複数のエンドポイントに登録されているので，同じものは 1 度だけ消す
+    for (size_t i = 0; i < class_drivers_.size(); ++i) {
+      auto class_driver = class_drivers_[i];
+      if (class_driver == nullptr) {
+        continue;
+      }
+      for (size_t j = i; j < class_drivers_.size(); ++j) {
+        if (class_drivers_[j] == class_driver) {
+          class_drivers_[j] = nullptr;
+        }
+      }
+      delete class_driver;
+    }
   }
 
   Error Device::ControlIn(EndpointID ep_id, SetupData setup_data,
@@ -159,6 +206,11 @@ namespace usb {
 
     const uint8_t* buf8 = reinterpret_cast<const uint8_t*>(buf);
     if (initialize_phase_ == 1) {
+      if (setup_data.request == request::kGetDescriptor &&
+          (setup_data.value >> 8) == StringDescriptor::kType) {
+        // 空の応答もあり得るので，受信内容ではなく要求の種類で判断する
+        return OnStringDescriptorReceived(buf8, len);
+      }
       if (setup_data.request == request::kGetDescriptor &&
           DescriptorDynamicCast<DeviceDescriptor>(buf8)) {
         return InitializePhase1(buf8, len);
@@ -180,6 +232,17 @@ namespace usb {
     return MAKE_ERROR(Error::kNotImplemented);
   }
 
+  bool Device::IsSkippableRequest(SetupData setup_data) const {
+    return !is_initialized_ && initialize_phase_ == 1 &&
+      setup_data.request == request::kGetDescriptor &&
+      (setup_data.value >> 8) == StringDescriptor::kType;
+  }
+
+  Error Device::OnSkippedRequestRecovered() {
+    Log(kWarn, "GetDesc(String) failed, skipping strings\n");
+    return RequestConfigurationDescriptor();
+  }
+
   Error Device::OnInterruptCompleted(EndpointID ep_id, const void* buf, int len) {
     Log(kDebug, "Device::OnInterruptCompleted: ep addr %d\n", ep_id.Address());
     if (auto w = class_drivers_[ep_id.Number()]) {
@@ -191,6 +254,55 @@ namespace usb {
   Error Device::InitializePhase1(const uint8_t* buf, int len) {
     const auto device_desc = DescriptorDynamicCast<DeviceDescriptor>(buf);
     num_configurations_ = device_desc->num_configurations;
+    vendor_id_ = device_desc->vendor_id;
+    product_id_ = device_desc->product_id;
+    manufacturer_index_ = device_desc->manufacturer;
+    product_index_ = device_desc->product;
+
+    if (manufacturer_index_ == 0 && product_index_ == 0) {
+      // 文字列ディスクリプタを持たないデバイス
+      return RequestConfigurationDescriptor();
+    }
+    // 文字列を得るには，まず対応している言語（LANGID）を調べる
+    string_phase_ = 0;
+    Log(kDebug, "issuing GetDesc(String): index=0\n");
+    return GetStringDescriptor(*this, kDefaultControlPipeID, 0, 0,
+                               buf_.data(), buf_.size());
+  }
+
+  Error Device::OnStringDescriptorReceived(const uint8_t* buf, int len) {
+    const StringDescriptor* str_desc = nullptr;
+    if (len >= 2) {
+      str_desc = DescriptorDynamicCast<StringDescriptor>(buf);
+    }
+
+    if (string_phase_ == 0) {
+      if (str_desc == nullptr || str_desc->NumCodeUnits(len) == 0) {
+        Log(kDebug, "no LANGID is supported, skipping strings\n");
+        return RequestConfigurationDescriptor();
+      }
+      // 最初に挙げられている言語を使う
+      lang_id_ = str_desc->CodeUnit(0);
+    } else if (str_desc) {
+      CopyString(*str_desc, len, string_phase_ == 1 ? manufacturer_ : product_);
+    }
+    return RequestNextString();
+  }
+
+  Error Device::RequestNextString() {
+    while (++string_phase_ <= 2) {
+      const auto index = string_phase_ == 1 ? manufacturer_index_ : product_index_;
+      if (index != 0) {
+        Log(kDebug, "issuing GetDesc(String): index=%d, lang=0x%04x\n",
+            index, lang_id_);
+        return GetStringDescriptor(*this, kDefaultControlPipeID, index, lang_id_,
+                                   buf_.data(), buf_.size());
+      }
+    }
+    return RequestConfigurationDescriptor();
+  }
+
+  Error Device::RequestConfigurationDescriptor() {
     config_index_ = 0;
     initialize_phase_ = 2;
     Log(kDebug, "issuing GetDesc(Config): index=%d)\n", config_index_);
@@ -236,6 +348,7 @@ namespace usb {
     }
 
     if (!class_driver) {
+      LogIdentity(kInfo, "no supported interface");
       return MAKE_ERROR(Error::kSuccess);
     }
     initialize_phase_ = 3;
@@ -251,9 +364,82 @@ namespace usb {
     }
     initialize_phase_ = 4;
     is_initialized_ = true;
+    LogIdentity(kInfo, "configured");
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  void Device::LogIdentity(LogLevel level, const char* state) {
+    Log(level, "USB device %04x:%04x \"%s\" \"%s\": %s\n",
+        vendor_id_, product_id_,
+        manufacturer_[0] ? manufacturer_.data() : "(no manufacturer)",
+        product_[0] ? product_.data() : "(no product)",
+        state);
+  }
+
+  Error Device::ControlTransfer(uint8_t request_type, uint8_t request,
+                                uint16_t value, uint16_t index,
+                                void* buf, int len, const ControlStatus** status) {
+    if (!is_initialized_) {
+      return MAKE_ERROR(Error::kInvalidPhase);
+    }
+    auto it = std::find_if(control_status_.begin(), control_status_.end(),
+                           [](const ControlStatus& s) { return !s.pending; });
+    if (it == control_status_.end()) {
+      return MAKE_ERROR(Error::kFull);
+    }
+
+    ControlStatus issued{};
+    issued.setup_data.request_type.data = request_type;
+    issued.setup_data.request = request;
+    issued.setup_data.value = value;
+    issued.setup_data.index = index;
+    issued.setup_data.length = len;
+    issued.pending = true;
+    *it = issued;
+    if (auto err = ControlRequest(*this, request_type, request, value, index,
+                                  buf, len, nullptr)) {
+      it->pending = false;
+      return err;
+    }
+    *status = &*it;
+    return MAKE_ERROR(Error::kSuccess);
+  }
+
+  bool Device::RecordControlCompletion(SetupData setup_data, uint8_t completion_code,
+                                       int len) {
+    if (event_waiters_.Get(setup_data)) {
+      return false;
+    }
+    for (auto& s : control_status_) {
+      if (s.pending && s.setup_data == setup_data) {
+        s.pending = false;
+        s.completed = true;
+        s.completion_code = completion_code;
+        s.transfer_length = len;
+        return true;
+      }
+    }
+    return false;
+  }
+
+  Error ControlRequest(Device& dev, uint8_t request_type, uint8_t request,
+                       uint16_t value, uint16_t index,
+                       void* buf, int len, ClassDriver* issuer) {
+    SetupData setup_data{};
+    setup_data.request_type.data = request_type;
+    setup_data.request = request;
+    setup_data.value = value;
+    setup_data.index = index;
+    setup_data.length = len;
+    if (len == 0) {
+      buf = nullptr;
+    }
+    if (setup_data.request_type.bits.direction == request_type::kIn) {
+      return dev.ControlIn(kDefaultControlPipeID, setup_data, buf, len, issuer);
+    }
+    return dev.ControlOut(kDefaultControlPipeID, setup_data, buf, len, issuer);
+  }
+
   Error GetDescriptor(Device& dev, EndpointID ep_id,
                       uint8_t desc_type, uint8_t desc_index,
                       void* buf, int len, bool debug) {
@@ -268,6 +454,20 @@ namespace usb {
     return dev.ControlIn(ep_id, setup_data, buf, len, nullptr);
   }
 
+  Error GetStringDescriptor(Device& dev, EndpointID ep_id,
+                            uint8_t desc_index, uint16_t lang_id,
+                            void* buf, int len) {
+    SetupData setup_data{};
+    setup_data.request_type.bits.direction = request_type::kIn;
+    setup_data.request_type.bits.type = request_type::kStandard;
+    setup_data.request_type.bits.recipient = request_type::kDevice;
+    setup_data.request = request::kGetDescriptor;
+    setup_data.value = (static_cast<uint16_t>(StringDescriptor::kType) << 8) | desc_index;
+    setup_data.index = lang_id;
+    setup_data.length = len;
+    return dev.ControlIn(ep_id, setup_data, buf, len, nullptr);
+  }
+
   Error SetConfiguration(Device& dev, EndpointID ep_id,
                          uint8_t config_value, bool debug) {
     SetupData setup_data{};
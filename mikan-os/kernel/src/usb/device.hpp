@@ -9,6 +9,7 @@
 #include <array>
 
 #include "error.hpp"
+#include "logger.hpp"
 #include "usb/setupdata.hpp"
 #include "usb/endpoint.hpp"
 #include "usb/arraymap.hpp"
@@ -16,6 +17,19 @@
 namespace usb {
   class ClassDriver;
 
+  /** @brief ControlTransfer で発行したコントロール転送の完了状況． */
+  struct ControlStatus {
+    SetupData setup_data{};
+    /** @brief 発行して完了を待っている間 true */
+    bool pending = false;
+    /** @brief 転送が終われば（失敗した場合も）true */
+    bool completed = false;
+    /** @brief xHC が返した Completion Code */
+    uint8_t completion_code = 0;
+    /** @brief データステージで実際に転送したバイト数 */
+    int transfer_length = 0;
+  };
+
   class Device {
    public:
     virtual ~Device();
@@ -32,12 +46,43 @@ namespace usb {
     int NumEndpointConfigs() { return num_ep_configs_; }
     Error OnEndpointsConfigured();
 
+    /** @brief デフォルトコントロールパイプへ任意のリクエストを発行し，その完了状況を返す．
+     *
+     * クラスドライバを介さずに標準リクエストなどを送るためのもの．
+     * 転送が終わると *status の completed が立つ．
+     * buf は転送が終わるまで有効でなければならない．
+     * 初期化が終わる前なら kInvalidPhase，完了待ちの転送が多すぎれば kFull を返す．
+     * 完了した状況は，後から発行した転送で上書きされることがある．
+     */
+    Error ControlTransfer(uint8_t request_type, uint8_t request,
+                          uint16_t value, uint16_t index,
+                          void* buf, int len, const ControlStatus** status);
+
     uint8_t* Buffer() { return buf_.data(); }
 
+    /** @brief 失敗しても初期化を続けられる要求なら true を返す．
+     *
+     * 文字列ディスクリプタは無くても動くため，初期化中に GET_DESCRIPTOR(String)
+     * が STALL されたら読み飛ばして構成ディスクリプタへ進む．
+     */
+    bool IsSkippableRequest(SetupData setup_data) const;
+    /** @brief IsSkippableRequest な要求の失敗から EP0 が復旧したので，初期化を先へ進める． */
+    Error OnSkippedRequestRecovered();
+
+    /** @brief 製造者名．文字列ディスクリプタが無ければ空文字列． */
+    const char* Manufacturer() const { return manufacturer_.data(); }
+    /** @brief 製品名．文字列ディスクリプタが無ければ空文字列． */
+    const char* Product() const { return product_.data(); }
+
    protected:
     Error OnControlCompleted(EndpointID ep_id, SetupData setup_data,
                              const void* buf, int len);
     Error OnInterruptCompleted(EndpointID ep_id, const void* buf, int len);
+    /** @brief ControlTransfer で発行した転送が終わったことを記録する．
+     *
+     * 発行元のクラスドライバがある転送や，対応する完了待ちの転送が無ければ false を返す．
+     */
+    bool RecordControlCompletion(SetupData setup_data, uint8_t completion_code, int len);
 
    private:
     /** @brief エンドポイントに割り当て済みのクラスドライバ．
@@ -49,9 +94,20 @@ namespace usb {
 
     std::array<uint8_t, 256> buf_{};
 
+    uint16_t vendor_id_ = 0;
+    uint16_t product_id_ = 0;
+    /** 文字列ディスクリプタを ASCII に変換したもの．ASCII 以外の文字は '?' になる． */
+    std::array<char, 64> manufacturer_{};
+    std::array<char, 64> product_{};
+
     // following fields are used during initialization
     uint8_t num_configurations_;
     uint8_t config_index_;
+    uint8_t manufacturer_index_ = 0;
+    uint8_t product_index_ = 0;
+    uint16_t lang_id_ = 0;
+    /** 文字列ディスクリプタの取得の進み具合．0: LANGID，1: 製造者名，2: 製品名． */
+    int string_phase_ = 0;
 
     Error OnDeviceDescriptorReceived(const uint8_t* buf, int len);
     Error OnConfigurationDescriptorReceived(const uint8_t* buf, int len);
@@ -65,16 +121,40 @@ namespace usb {
     Error InitializePhase2(const uint8_t* buf, int len);
     Error InitializePhase3(uint8_t config_value);
     Error InitializePhase4();
+    Error OnStringDescriptorReceived(const uint8_t* buf, int len);
+    Error RequestNextString();
+    Error RequestConfigurationDescriptor();
+    void LogIdentity(LogLevel level, const char* state);
 
     /** OnControlCompleted の中で要求の発行元を特定するためのマップ構造．
      * ControlOut または ControlIn を発行したときに発行元が登録される．
      */
     ArrayMap<SetupData, ClassDriver*, 4> event_waiters_{};
+
+    /** ControlTransfer で発行した，発行元の無い転送の完了状況． */
+    std::array<ControlStatus, 4> control_status_{};
   };
 
+  /** @brief bmRequestType を向き，種類，宛先から組み立てる． */
+  constexpr uint8_t MakeRequestType(int direction, int type, int recipient) {
+    return (direction << 7) | (type << 5) | recipient;
+  }
+
+  /** @brief デフォルトコントロールパイプへ任意のリクエストを発行する．
+   *
+   * 転送の向きは request_type の最上位ビットで決まる．len が 0 ならデータステージを省く．
+   * 完了は issuer の OnControlCompleted へ通知される．
+   */
+  Error ControlRequest(Device& dev, uint8_t request_type, uint8_t request,
+                       uint16_t value, uint16_t index,
+                       void* buf, int len, ClassDriver* issuer);
+
   Error GetDescriptor(Device& dev, EndpointID ep_id,
                       uint8_t desc_type, uint8_t desc_index,
                       void* buf, int len, bool debug = false);
+  Error GetStringDescriptor(Device& dev, EndpointID ep_id,
+                            uint8_t desc_index, uint16_t lang_id,
+                            void* buf, int len);
   Error SetConfiguration(Device& dev, EndpointID ep_id,
                          uint8_t config_value, bool debug = false);
 }
@@ -9,11 +9,10 @@
 #include <cstddef>
 
 namespace usb {
-  /** @brief 動的メモリ確保のためのメモリプールの最大容量（バイト） */
-  static const size_t kMemoryPoolSize = 4096 * 32;
-
   /** @brief 指定されたバイト数のメモリ領域を確保して先頭ポインタを返す．
    *
+   * カーネルのメモリプールから，0 で初期化された物理的に連続な領域を切り出す．
+   * アイデンティティマッピングなので，返すアドレスはそのまま DMA に使える．
    * 先頭アドレスが alignment に揃ったメモリ領域を確保する．
    * size <= boundary ならメモリ領域が boundary を跨がないことを保証する．
    * boundary は典型的にはページ境界を跨がないように 4096 を指定する．
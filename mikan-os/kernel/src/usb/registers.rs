@@ -0,0 +1,228 @@
+#![allow(unused)]
+
+//! xHCI 仕様 5.3、5.4 節のレジスタ。C++ 側の usb/xhci/registers.hpp と同じものを、
+//! ビットフィールドに名前を付けて Rust から読み書きできるようにする。
+
+use crate::{bit_field, bit_flag, bitfield::BitField, mmio::Mmio};
+
+/// HCSPARAMS1（Structural Parameters 1）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Hcsparams1(u32);
+
+impl Hcsparams1 {
+    bit_field!(max_device_slots, with_max_device_slots, 0..8, u8);
+    bit_field!(max_interrupters, with_max_interrupters, 8..19, u16);
+    bit_field!(max_ports, with_max_ports, 24..32, u8);
+}
+
+/// HCSPARAMS2（Structural Parameters 2）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Hcsparams2(u32);
+
+impl Hcsparams2 {
+    bit_field!(
+        isochronous_scheduling_threshold,
+        with_isochronous_scheduling_threshold,
+        0..4,
+        u8
+    );
+    bit_field!(
+        /// イベントリングセグメントテーブルの最大要素数は 2 のこの値乗
+        erst_max,
+        with_erst_max,
+        4..8,
+        u8
+    );
+
+    bit_flag!(scratchpad_restore, with_scratchpad_restore, 26);
+
+    /// スクラッチパッドバッファの数。上位 5 ビットと下位 5 ビットが別の場所にある。
+    pub(crate) const fn max_scratchpad_buffers(self) -> u16 {
+        let bits = BitField(self.0);
+        ((bits.get_bits(21..26) << 5) | bits.get_bits(27..32)) as u16
+    }
+}
+
+/// HCCPARAMS1（Capability Parameters 1）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Hccparams1(u32);
+
+impl Hccparams1 {
+    bit_flag!(
+        /// 64 ビットのアドレスを扱える
+        addressing_capability_64,
+        with_addressing_capability_64,
+        0
+    );
+    bit_flag!(
+        /// コンテキストが 64 バイト。下りていれば 32 バイト。
+        context_size,
+        with_context_size,
+        2
+    );
+
+    /// 拡張ケーパビリティの先頭の、MMIO 領域の先頭からのオフセット（4 バイト単位）。0 なら無し。
+    pub(crate) const fn xhci_extended_capabilities_pointer(self) -> u16 {
+        BitField(self.0).get_bits(16..32) as u16
+    }
+}
+
+/// USBCMD（USB Command）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Usbcmd(u32);
+
+impl Usbcmd {
+    bit_flag!(run_stop, with_run_stop, 0);
+    bit_flag!(host_controller_reset, with_host_controller_reset, 1);
+    bit_flag!(interrupter_enable, with_interrupter_enable, 2);
+    bit_flag!(host_system_error_enable, with_host_system_error_enable, 3);
+    bit_flag!(enable_wrap_event, with_enable_wrap_event, 10);
+}
+
+/// USBSTS（USB Status）
+///
+/// 状態を表すビットのほか、1 を書いて下ろす（RW1C）ビットがある。読んだ値をそのまま書き戻すと
+/// 立っていた RW1C ビットを下ろしてしまうので、書くときは下ろしたいビットだけを立てた値にすること。
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Usbsts(u32);
+
+impl Usbsts {
+    pub(crate) const fn new() -> Self {
+        Self(0)
+    }
+
+    bit_flag!(host_controller_halted, with_host_controller_halted, 0);
+    bit_flag!(host_system_error, with_host_system_error, 2);
+    bit_flag!(event_interrupt, with_event_interrupt, 3);
+    bit_flag!(port_change_detect, with_port_change_detect, 4);
+    bit_flag!(controller_not_ready, with_controller_not_ready, 11);
+    bit_flag!(host_controller_error, with_host_controller_error, 12);
+}
+
+/// CRCR（Command Ring Control）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Crcr(u64);
+
+impl Crcr {
+    bit_flag!(ring_cycle_state, with_ring_cycle_state, 0);
+    bit_flag!(command_stop, with_command_stop, 1);
+    bit_flag!(command_abort, with_command_abort, 2);
+    bit_flag!(command_ring_running, with_command_ring_running, 3);
+
+    /// コマンドリングの物理アドレス。下位 6 ビットは 0。
+    pub(crate) const fn pointer(self) -> u64 {
+        BitField(self.0).get_bits(6..64) << 6
+    }
+
+    pub(crate) const fn with_pointer(self, pointer: u64) -> Self {
+        Self(BitField(self.0).with_bits(6..64, pointer >> 6).0)
+    }
+}
+
+/// DCBAAP（Device Context Base Address Array Pointer）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Dcbaap(u64);
+
+impl Dcbaap {
+    /// デバイスコンテキストのアドレスの配列の物理アドレス。下位 6 ビットは 0。
+    pub(crate) const fn pointer(self) -> u64 {
+        BitField(self.0).get_bits(6..64) << 6
+    }
+
+    pub(crate) const fn with_pointer(self, pointer: u64) -> Self {
+        Self(BitField(self.0).with_bits(6..64, pointer >> 6).0)
+    }
+}
+
+/// CONFIG（Configure）
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub(crate) struct Config(u32);
+
+impl Config {
+    bit_field!(
+        max_device_slots_enabled,
+        with_max_device_slots_enabled,
+        0..8,
+        u8
+    );
+}
+
+/// MMIO 領域の先頭にあるケーパビリティレジスタ。
+#[derive(Clone, Copy)]
+pub(crate) struct CapabilityRegisters {
+    base: usize,
+}
+
+impl CapabilityRegisters {
+    /// # Safety
+    ///
+    /// `base` は xHC の MMIO 領域の先頭を指していること。
+    pub(crate) const unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    /// CAPLENGTH。オペレーショナルレジスタの、この先頭からのオフセット。
+    pub(crate) fn caplength(&self) -> u8 {
+        unsafe { Mmio::<u8>::new(self.base) }.read()
+    }
+
+    pub(crate) fn hcsparams1(&self) -> Hcsparams1 {
+        unsafe { Mmio::new(self.base + 0x04) }.read()
+    }
+
+    pub(crate) fn hcsparams2(&self) -> Hcsparams2 {
+        unsafe { Mmio::new(self.base + 0x08) }.read()
+    }
+
+    pub(crate) fn hccparams1(&self) -> Hccparams1 {
+        unsafe { Mmio::new(self.base + 0x10) }.read()
+    }
+
+    /// オペレーショナルレジスタを返す。
+    pub(crate) fn operational(&self) -> OperationalRegisters {
+        unsafe { OperationalRegisters::new(self.base + self.caplength() as usize) }
+    }
+}
+
+/// ケーパビリティレジスタの後ろにあるオペレーショナルレジスタ。
+#[derive(Clone, Copy)]
+pub(crate) struct OperationalRegisters {
+    base: usize,
+}
+
+impl OperationalRegisters {
+    /// # Safety
+    ///
+    /// `base` は xHC のオペレーショナルレジスタの先頭を指していること。
+    pub(crate) const unsafe fn new(base: usize) -> Self {
+        Self { base }
+    }
+
+    pub(crate) fn usbcmd(&self) -> Mmio<Usbcmd> {
+        unsafe { Mmio::new(self.base) }
+    }
+
+    pub(crate) fn usbsts(&self) -> Mmio<Usbsts> {
+        unsafe { Mmio::new(self.base + 0x04) }
+    }
+
+    pub(crate) fn crcr(&self) -> Mmio<Crcr> {
+        unsafe { Mmio::new(self.base + 0x18) }
+    }
+
+    pub(crate) fn dcbaap(&self) -> Mmio<Dcbaap> {
+        unsafe { Mmio::new(self.base + 0x30) }
+    }
+
+    pub(crate) fn config(&self) -> Mmio<Config> {
+        unsafe { Mmio::new(self.base + 0x38) }
+    }
+}
@@ -46,6 +46,7 @@ namespace usb {
 
     // HID class specific report values
     const int kGetReport = 1;
+    const int kSetReport = 9;
     const int kSetProtocol = 11;
   }
 
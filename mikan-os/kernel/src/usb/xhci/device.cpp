@@ -51,7 +51,7 @@ namespace {
       Log(level,
           "Transfer (value %08lx) completed: %s, residual length %d, slot %d, ep addr %d\n",
           reinterpret_cast<uint64_t>(trb.Pointer()),
-          kTRBCompletionCodeToName[trb.bits.completion_code],
+          CompletionCodeName(trb.bits.completion_code),
           trb.bits.trb_transfer_length,
           trb.bits.slot_id,
           trb.EndpointID().Address());
@@ -62,7 +62,7 @@ namespace {
     Log(level,
         "%s completed: %s, residual length %d, slot %d, ep addr %d\n",
         kTRBTypeToName[issuer_trb->bits.trb_type],
-        kTRBCompletionCodeToName[trb.bits.completion_code],
+        CompletionCodeName(trb.bits.completion_code),
         trb.bits.trb_transfer_length,
         trb.bits.slot_id,
         trb.EndpointID().Address());
@@ -232,17 +232,30 @@ namespace usb::xhci {
     return MAKE_ERROR(Error::kNotImplemented);
   }
 
+  Error Device::OnControlPipeRecovered() {
+    control_pipe_halted_ = false;
+    return OnSkippedRequestRecovered();
+  }
+
   Error Device::OnTransferEventReceived(const TransferEventTRB& trb) {
     const auto residual_length = trb.bits.trb_transfer_length;
 
-    if (trb.bits.completion_code != 1 /* Success */ &&
-        trb.bits.completion_code != 13 /* Short Packet */) {
+    const auto code = static_cast<CompletionCode>(trb.bits.completion_code);
+    const bool failed = code != CompletionCode::kSuccess &&
+                        code != CompletionCode::kShortPacket;
+    if (failed) {
+      Log(kError, "transfer failed: %s (completion code %d)\n",
+          CompletionCodeName(trb.bits.completion_code),
+          trb.bits.completion_code);
+      Log(kError, trb);
+    } else {
       Log(kDebug, trb);
-      return MAKE_ERROR(Error::kTransferFailed);
     }
-    Log(kDebug, trb);
 
     TRB* issuer_trb = trb.Pointer();
+    if (failed && !setup_stage_map_.Get(issuer_trb)) {
+      return MAKE_ERROR(Error::kTransferFailed);
+    }
     if (auto normal_trb = TRBDynamicCast<NormalTRB>(issuer_trb)) {
       const auto transfer_length =
         normal_trb->bits.trb_transfer_length - residual_length;
@@ -280,6 +293,19 @@ namespace usb::xhci {
     } else {
       return MAKE_ERROR(Error::kNotImplemented);
     }
+
+    // ControlTransfer で発行した転送は，失敗も含めて完了状況に記録するだけ
+    if (RecordControlCompletion(setup_data, trb.bits.completion_code, transfer_length)) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+    if (failed && IsSkippableRequest(setup_data)) {
+      // EP0 は Halted になっているので，呼び出し側に Reset Endpoint から立て直してもらう
+      control_pipe_halted_ = true;
+      return MAKE_ERROR(Error::kSuccess);
+    }
+    if (failed) {
+      return MAKE_ERROR(Error::kTransferFailed);
+    }
     return this->OnControlCompleted(
         trb.EndpointID(), setup_data, data_stage_buffer, transfer_length);
   }
@@ -57,6 +57,12 @@ namespace usb::xhci {
 
     Error OnTransferEventReceived(const TransferEventTRB& trb);
 
+    Ring* TransferRing(DeviceContextIndex dci) const { return transfer_rings_[dci.value - 1]; }
+    /** @brief 読み飛ばせる要求が失敗して，EP0 が Halted のまま復旧を待っていれば true． */
+    bool IsControlPipeHalted() const { return control_pipe_halted_; }
+    /** @brief Reset Endpoint と Set TR Dequeue Pointer で EP0 が復旧したときに呼ぶ． */
+    Error OnControlPipeRecovered();
+
    private:
     alignas(64) struct DeviceContext ctx_;
     alignas(64) struct InputContext input_ctx_;
@@ -72,6 +78,8 @@ namespace usb::xhci {
      */
     ArrayMap<const void*, const SetupStageTRB*, 16> setup_stage_map_{};
 
+    bool control_pipe_halted_ = false;
+
     //usb::Device* usb_device_;
   };
 }
@@ -4,17 +4,28 @@
 
 namespace usb::xhci {
   Error DeviceManager::Initialize(size_t max_slots) {
-    max_slots_ = max_slots;
+    // xHC を初期化し直すときは，スロット数が同じなら確保済みの配列を使い回し，
+    // 登録されていたデバイスだけを取り除く．メモリプールは解放できないため．
+    if (devices_ != nullptr && max_slots_ == max_slots) {
+      for (size_t i = 1; i <= max_slots_; ++i) {
+        if (devices_[i] != nullptr) {
+          Remove(i);
+        }
+      }
+    } else {
+      max_slots_ = max_slots;
 
-    devices_ = AllocArray<Device*>(max_slots_ + 1, 0, 0);
-    if (devices_ == nullptr) {
-      return MAKE_ERROR(Error::kNoEnoughMemory);
-    }
+      devices_ = AllocArray<Device*>(max_slots_ + 1, 0, 0);
+      if (devices_ == nullptr) {
+        return MAKE_ERROR(Error::kNoEnoughMemory);
+      }
 
-    device_context_pointers_ = AllocArray<DeviceContext*>(max_slots_ + 1, 64, 4096);
-    if (device_context_pointers_ == nullptr) {
-      FreeMem(devices_);
-      return MAKE_ERROR(Error::kNoEnoughMemory);
+      device_context_pointers_ = AllocArray<DeviceContext*>(max_slots_ + 1, 64, 4096);
+      if (device_context_pointers_ == nullptr) {
+        FreeMem(devices_);
+        devices_ = nullptr;
+        return MAKE_ERROR(Error::kNoEnoughMemory);
+      }
     }
 
     for (size_t i = 0; i <= max_slots_; ++i) {
@@ -92,8 +103,15 @@ namespace usb::xhci {
   }
 
   Error DeviceManager::Remove(uint8_t slot_id) {
+    if (slot_id == 0 || slot_id > max_slots_) {
+      return MAKE_ERROR(Error::kInvalidSlotID);
+    }
     device_context_pointers_[slot_id] = nullptr;
-    FreeMem(devices_[slot_id]);
+    if (devices_[slot_id] != nullptr) {
+      // AllocDevice で placement new したので，デストラクタを明示的に呼ぶ
+      devices_[slot_id]->~Device();
+      FreeMem(devices_[slot_id]);
+    }
     devices_[slot_id] = nullptr;
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -30,10 +30,10 @@ namespace usb::xhci {
    private:
     // device_context_pointers_ can be used as DCBAAP's value.
     // The number of elements is max_slots_ + 1.
-    DeviceContext** device_context_pointers_;
-    size_t max_slots_;
+    DeviceContext** device_context_pointers_ = nullptr;
+    size_t max_slots_ = 0;
 
     // The number of elements is max_slots_ + 1.
-    Device** devices_;
+    Device** devices_ = nullptr;
   };
 }
@@ -2,6 +2,8 @@
 
 #include "usb/xhci/xhci.hpp"
 #include "usb/xhci/registers.hpp"
+#include "logger.hpp"
+#include "wait.hpp"
 
 namespace usb::xhci {
   uint8_t Port::Number() const {
@@ -33,7 +35,11 @@ namespace usb::xhci {
     portsc.data[0] &= 0x0e00c3e0u;
     portsc.data[0] |= 0x00020010u; // Write 1 to PR and CSC
     port_reg_set_.PORTSC.Write(portsc);
-    while (port_reg_set_.PORTSC.Read().bits.port_reset);
+    if (auto err = WaitUntil([this]() {
+          return !port_reg_set_.PORTSC.Read().bits.port_reset; })) {
+      Log(kError, "port %d reset timed out: %s\n", port_num_, err.Name());
+      return err;
+    }
     return MAKE_ERROR(Error::kSuccess);
   }
 
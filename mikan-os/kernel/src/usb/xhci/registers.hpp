@@ -420,4 +420,22 @@ namespace usb::xhci {
       uint32_t : 7;
     } __attribute__((packed)) bits;
   } __attribute__((packed));
+
+  /** @brief xHCI Supported Protocol Capability（capability_id = 2） */
+  union SupportedProtocol_Bitmap {
+    uint32_t data[3];
+    struct {
+      uint32_t capability_id : 8;
+      uint32_t next_pointer : 8;
+      uint32_t minor_revision : 8;
+      uint32_t major_revision : 8;
+
+      uint32_t name_string;  // "USB " を ASCII で並べたもの
+
+      uint32_t compatible_port_offset : 8;
+      uint32_t compatible_port_count : 8;
+      uint32_t protocol_defined : 12;
+      uint32_t protocol_speed_id_count : 4;
+    } __attribute__((packed)) bits;
+  } __attribute__((packed));
 }
@@ -10,15 +10,20 @@ namespace usb::xhci {
   }
 
   Error Ring::Initialize(size_t buf_size) {
-    if (buf_ != nullptr) {
+    // 同じ大きさで初期化し直すときは，確保済みのバッファを使い回す．
+    // メモリプールは解放できないので，確保し直すと xHC をリセットするたびに減っていく．
+    if (buf_ != nullptr && buf_size_ != buf_size) {
       FreeMem(buf_);
+      buf_ = nullptr;
     }
 
     cycle_bit_ = true;
     write_index_ = 0;
     buf_size_ = buf_size;
 
-    buf_ = AllocArray<TRB>(buf_size_, 64, 64 * 1024);
+    if (buf_ == nullptr) {
+      buf_ = AllocArray<TRB>(buf_size_, 64, 64 * 1024);
+    }
     if (buf_ == nullptr) {
       return MAKE_ERROR(Error::kNoEnoughMemory);
     }
@@ -55,21 +60,27 @@ namespace usb::xhci {
 
   Error EventRing::Initialize(size_t buf_size,
                               InterrupterRegisterSet* interrupter) {
-    if (buf_ != nullptr) {
+    // Ring::Initialize と同じく，同じ大きさなら確保済みのバッファを使い回す
+    if (buf_ != nullptr && buf_size_ != buf_size) {
       FreeMem(buf_);
+      buf_ = nullptr;
     }
 
     cycle_bit_ = true;
     buf_size_ = buf_size;
     interrupter_ = interrupter;
 
-    buf_ = AllocArray<TRB>(buf_size_, 64, 64 * 1024);
+    if (buf_ == nullptr) {
+      buf_ = AllocArray<TRB>(buf_size_, 64, 64 * 1024);
+    }
     if (buf_ == nullptr) {
       return MAKE_ERROR(Error::kNoEnoughMemory);
     }
     memset(buf_, 0, buf_size_ * sizeof(TRB));
 
-    erst_ = AllocArray<EventRingSegmentTableEntry>(1, 64, 64 * 1024);
+    if (erst_ == nullptr) {
+      erst_ = AllocArray<EventRingSegmentTableEntry>(1, 64, 64 * 1024);
+    }
     if (erst_ == nullptr) {
       FreeMem(buf_);
       return MAKE_ERROR(Error::kNoEnoughMemory);
@@ -37,6 +37,11 @@ namespace usb::xhci {
 
     TRB* Buffer() const { return buf_; }
 
+    /** @brief 次に TRB を書き込む位置．Set TR Dequeue Pointer で転送を読み飛ばすのに使う． */
+    TRB* EnqueuePointer() const { return &buf_[write_index_]; }
+    /** @brief 次に書き込む TRB に設定する cycle bit． */
+    bool CycleBit() const { return cycle_bit_; }
+
    private:
     TRB* buf_ = nullptr;
     size_t buf_size_ = 0;
@@ -96,11 +101,11 @@ namespace usb::xhci {
     void Pop();
 
    private:
-    TRB* buf_;
-    size_t buf_size_;
+    TRB* buf_ = nullptr;
+    size_t buf_size_ = 0;
 
     bool cycle_bit_;
-    EventRingSegmentTableEntry* erst_;
+    EventRingSegmentTableEntry* erst_ = nullptr;
     InterrupterRegisterSet* interrupter_;
   };
 }
@@ -12,4 +12,16 @@ namespace usb::xhci {
   const int kHighSpeed = 3;
   const int kSuperSpeed = 4;
   const int kSuperSpeedPlus = 5;
+
+  /** @brief Protocol Speed ID の名前を返す． */
+  inline const char* SpeedName(int speed) {
+    switch (speed) {
+    case kFullSpeed: return "Full Speed";
+    case kLowSpeed: return "Low Speed";
+    case kHighSpeed: return "High Speed";
+    case kSuperSpeed: return "Super Speed";
+    case kSuperSpeedPlus: return "Super Speed Plus";
+    default: return "Unknown";
+    }
+  }
 }
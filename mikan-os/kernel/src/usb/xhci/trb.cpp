@@ -107,4 +107,17 @@ namespace usb::xhci {
     "Vendor Defined",
     "Vendor Defined",
   };
+
+  const char* CompletionCodeName(int code) {
+    if (0 <= code && code < kTRBCompletionCodeToName.size()) {
+      return kTRBCompletionCodeToName[code];
+    }
+    if (192 <= code && code <= 223) {
+      return "Vendor Defined Error";
+    }
+    if (224 <= code && code <= 255) {
+      return "Vendor Defined Info";
+    }
+    return "Reserved";
+  }
 }
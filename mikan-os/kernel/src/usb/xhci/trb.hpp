@@ -14,6 +14,18 @@ namespace usb::xhci {
   extern const std::array<const char*, 37> kTRBCompletionCodeToName;
   extern const std::array<const char*, 64> kTRBTypeToName;
 
+  /** @brief Completion Code の値 */
+  enum class CompletionCode : uint8_t {
+    kSuccess = 1,
+    kShortPacket = 13,
+  };
+
+  /** @brief Completion Code の名前を返す．
+   *
+   * kTRBCompletionCodeToName に無い値（予約値やベンダ定義の値）も扱える．
+   */
+  const char* CompletionCodeName(int code);
+
   union TRB {
     std::array<uint32_t, 4> data{};
     struct {
@@ -240,6 +252,29 @@ namespace usb::xhci {
     }
   };
 
+  union DisableSlotCommandTRB {
+    static const unsigned int Type = 10;
+    std::array<uint32_t, 4> data{};
+    struct {
+      uint32_t : 32;
+
+      uint32_t : 32;
+
+      uint32_t : 32;
+
+      uint32_t cycle_bit : 1;
+      uint32_t : 9;
+      uint32_t trb_type : 6;
+      uint32_t : 8;
+      uint32_t slot_id : 8;
+    } __attribute__((packed)) bits;
+
+    DisableSlotCommandTRB(uint8_t slot_id) {
+      bits.trb_type = Type;
+      bits.slot_id = slot_id;
+    }
+  };
+
   union AddressDeviceCommandTRB {
     static const unsigned int Type = 11;
     std::array<uint32_t, 4> data{};
@@ -304,6 +339,36 @@ namespace usb::xhci {
     }
   };
 
+  union ResetEndpointCommandTRB {
+    static const unsigned int Type = 14;
+    std::array<uint32_t, 4> data{};
+    struct {
+      uint32_t : 32;
+
+      uint32_t : 32;
+
+      uint32_t : 32;
+
+      uint32_t cycle_bit : 1;
+      uint32_t : 8;
+      uint32_t transfer_state_preserve : 1;
+      uint32_t trb_type : 6;
+      uint32_t endpoint_id : 5;
+      uint32_t : 3;
+      uint32_t slot_id : 8;
+    } __attribute__((packed)) bits;
+
+    ResetEndpointCommandTRB(EndpointID endpoint_id, uint8_t slot_id) {
+      bits.trb_type = Type;
+      bits.endpoint_id = endpoint_id.Address();
+      bits.slot_id = slot_id;
+    }
+
+    EndpointID EndpointID() const {
+      return usb::EndpointID{bits.endpoint_id};
+    }
+  };
+
   union StopEndpointCommandTRB {
     static const unsigned int Type = 15;
     std::array<uint32_t, 4> data{};
@@ -334,6 +399,47 @@ namespace usb::xhci {
     }
   };
 
+  union SetTRDequeuePointerCommandTRB {
+    static const unsigned int Type = 16;
+    std::array<uint32_t, 4> data{};
+    struct {
+      uint64_t dequeue_cycle_state : 1;
+      uint64_t stream_context_type : 3;
+      uint64_t dequeue_pointer : 60;
+
+      uint32_t : 16;
+      uint32_t stream_id : 16;
+
+      uint32_t cycle_bit : 1;
+      uint32_t : 9;
+      uint32_t trb_type : 6;
+      uint32_t endpoint_id : 5;
+      uint32_t : 3;
+      uint32_t slot_id : 8;
+    } __attribute__((packed)) bits;
+
+    SetTRDequeuePointerCommandTRB(EndpointID endpoint_id, uint8_t slot_id,
+                                  const TRB* dequeue_pointer, bool cycle_state) {
+      bits.trb_type = Type;
+      bits.endpoint_id = endpoint_id.Address();
+      bits.slot_id = slot_id;
+      bits.dequeue_cycle_state = cycle_state;
+      SetPointer(dequeue_pointer);
+    }
+
+    TRB* Pointer() const {
+      return reinterpret_cast<TRB*>(bits.dequeue_pointer << 4);
+    }
+
+    void SetPointer(const TRB* p) {
+      bits.dequeue_pointer = reinterpret_cast<uint64_t>(p) >> 4;
+    }
+
+    EndpointID EndpointID() const {
+      return usb::EndpointID{bits.endpoint_id};
+    }
+  };
+
   union NoOpCommandTRB {
     static const unsigned int Type = 23;
     std::array<uint32_t, 4> data{};
@@ -1,6 +1,7 @@
 #include "usb/xhci/xhci.hpp"
 
 #include "logger.hpp"
+#include "wait.hpp"
 #include "usb/setupdata.hpp"
 #include "usb/device.hpp"
 #include "usb/descriptor.hpp"
@@ -19,6 +20,45 @@ namespace {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  /** @brief PAGESIZE レジスタの値から xHC のページサイズ（バイト）を求める．
+   *
+   * ビット n が立っていれば 2^(n+12) バイト．複数立っていても xHC が使うのは最小のもの．
+   */
+  size_t PageSize(uint32_t pagesize) {
+    for (int n = 0; n < 16; ++n) {
+      if (pagesize & (1u << n)) {
+        return size_t{4096} << n;
+      }
+    }
+    return 4096;  // 仕様上は必ず 1 ビット立っている
+  }
+
+  /** @brief スクラッチパッドバッファとその配列を確保する．
+   *
+   * 各バッファは page_size バイトで，page_size に揃っていなければならない．
+   * 配列（Scratchpad Buffer Array）は 64 バイトに揃え，ページ境界を跨がないようにする．
+   *
+   * @param num           バッファの数（HCSPARAMS2 の Max Scratchpad Buffers）
+   * @param page_size     xHC のページサイズ（PAGESIZE レジスタ）
+   * @param array_out     確保した配列の先頭を書き込む先
+   * @return メモリが足りなければ kNoEnoughMemory
+   */
+  Error AllocScratchpadBuffers(uint16_t num, size_t page_size, void*** array_out) {
+    auto arr = usb::AllocArray<void*>(num, 64, page_size);
+    if (arr == nullptr) {
+      return MAKE_ERROR(Error::kNoEnoughMemory);
+    }
+    for (int i = 0; i < num; ++i) {
+      arr[i] = usb::AllocMem(page_size, page_size, page_size);
+      if (arr[i] == nullptr) {
+        return MAKE_ERROR(Error::kNoEnoughMemory);
+      }
+      Log(kDebug, "scratchpad buffer array %d = %p\n", i, arr[i]);
+    }
+    *array_out = arr;
+    return MAKE_ERROR(Error::kSuccess);
+  }
+
   enum class ConfigPhase {
     kNotConnected,
     kWaitingAddressed,
@@ -51,11 +91,16 @@ namespace {
 
   unsigned int DetermineMaxPacketSizeForControlPipe(unsigned int slot_speed) {
     switch (slot_speed) {
-    case 4: // Super Speed
+    case kSuperSpeed:
+    case kSuperSpeedPlus:
         return 512;
-    case 3: // High Speed
+    case kHighSpeed:
         return 64;
-    default:
+    case kFullSpeed:
+        // 8, 16, 32, 64 のいずれかだが，最初のディスクリプタ読み出しが
+        // バブルにならないよう最大の 64 を使う
+        return 64;
+    default: // Low Speed
         return 8;
     }
   }
@@ -85,6 +130,8 @@ namespace {
     ctx.bits.error_count = 3;
   }
 
+  Error EnableSlot(Controller& xhc, Port& port);
+
   Error ResetPort(Controller& xhc, Port& port) {
     const bool is_connected = port.IsConnected();
     Log(kDebug, "ResetPort: port.IsConnected() = %s\n",
@@ -104,7 +151,15 @@ namespace {
       }
       addressing_port = port.Number();
       port_config_phase[port.Number()] = ConfigPhase::kResettingPort;
-      port.Reset();
+      // USB3 のポートはリンクの確立と同時に自分で有効になるので，リセットは要らない．
+      if (xhc.PortProtocol(port.Number()) == 3 && port.IsEnabled()) {
+        return EnableSlot(xhc, port);
+      }
+      if (auto err = port.Reset()) {
+        addressing_port = 0;
+        port_config_phase[port.Number()] = ConfigPhase::kNotConnected;
+        return err;
+      }
     }
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -116,14 +171,17 @@ namespace {
         is_enabled ? "true" : "false",
         reset_completed ? "true" : "false");
 
-    if (is_enabled && reset_completed) {
-      port.ClearPortResetChange();
+    // USB3 のポートはリセットせずに有効になるので，リセットの完了を待たない
+    const bool usb3 = xhc.PortProtocol(port.Number()) == 3;
+    if (is_enabled && (reset_completed || usb3)) {
+      if (reset_completed) {
+        port.ClearPortResetChange();
+      }
 
       port_config_phase[port.Number()] = ConfigPhase::kEnablingSlot;
 
       EnableSlotCommandTRB cmd{};
-      xhc.CommandRing()->Push(cmd);
-      xhc.DoorbellRegisterAt(0)->Ring(0);
+      xhc.IssueCommand(cmd);
     }
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -146,6 +204,12 @@ namespace {
     auto ep0_ctx = dev->InputContext()->EnableEndpoint(ep0_dci);
 
     auto port = xhc.PortAt(port_id);
+    const int port_speed = port.Speed();
+    if (port_speed == 0 || port_speed > kSuperSpeedPlus) {
+      Log(kError, "port %d: unknown speed %d\n", port_id, port_speed);
+      return MAKE_ERROR(Error::kUnknownXHCISpeedID);
+    }
+    Log(kInfo, "port %d: %s device\n", port_id, SpeedName(port_speed));
     InitializeSlotContext(*slot_ctx, port);
 
     InitializeEP0Context(
@@ -157,8 +221,7 @@ namespace {
     port_config_phase[port_id] = ConfigPhase::kAddressingDevice;
 
     AddressDeviceCommandTRB addr_dev_cmd{dev->InputContext(), slot_id};
-    xhc.CommandRing()->Push(addr_dev_cmd);
-    xhc.DoorbellRegisterAt(0)->Ring(0);
+    xhc.IssueCommand(addr_dev_cmd);
 
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -191,11 +254,32 @@ namespace {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  /** 設定を終えたポートからデバイスが抜かれたので，スロットを無効にして xHC へ返す．
+   * デバイスは Disable Slot コマンドの完了を待ってから取り除く．
+   */
+  Error DetachDevice(Controller& xhc, Port& port) {
+    port.ClearConnectStatusChanged();
+    port_config_phase[port.Number()] = ConfigPhase::kNotConnected;
+
+    auto dev = xhc.DeviceManager()->FindByPort(port.Number(), 0);
+    if (dev == nullptr) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+    Log(kInfo, "port %d: device on slot %d detached\n", port.Number(), dev->SlotID());
+    DisableSlotCommandTRB cmd{dev->SlotID()};
+    xhc.IssueCommand(cmd);
+    return MAKE_ERROR(Error::kSuccess);
+  }
+
   Error OnEvent(Controller& xhc, PortStatusChangeEventTRB& trb) {
     Log(kDebug, "PortStatusChangeEvent: port_id = %d\n", trb.bits.port_id);
     auto port_id = trb.bits.port_id;
     auto port = xhc.PortAt(port_id);
 
+    if (!port.IsConnected() && port_config_phase[port_id] == ConfigPhase::kConfigured) {
+      return DetachDevice(xhc, port);
+    }
+
     switch (port_config_phase[port_id]) {
     case ConfigPhase::kNotConnected:
       return ResetPort(xhc, port);
@@ -215,6 +299,12 @@ namespace {
     if (auto err = dev->OnTransferEventReceived(trb)) {
       return err;
     }
+    if (dev->IsControlPipeHalted()) {
+      // Halted になった EP0 を Reset Endpoint で戻し，残った TRB は Set TR Dequeue Pointer で読み飛ばす
+      ResetEndpointCommandTRB cmd{usb::kDefaultControlPipeID, slot_id};
+      xhc.IssueCommand(cmd);
+      return MAKE_ERROR(Error::kSuccess);
+    }
 
     const auto port_id = dev->DeviceContext()->slot_context.bits.root_hub_port_num;
     if (dev->IsInitialized() &&
@@ -224,11 +314,71 @@ namespace {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  /** kWaitingAddressed のポートがあれば，そのうち 1 つのリセットを始める．
+   * addressing_port が 0 のときに呼ぶこと．
+   */
+  Error StartWaitingPort(Controller& xhc) {
+    for (int i = 0; i < port_config_phase.size(); ++i) {
+      if (port_config_phase[i] == ConfigPhase::kWaitingAddressed) {
+        auto port = xhc.PortAt(i);
+        return ResetPort(xhc, port);
+      }
+    }
+    return MAKE_ERROR(Error::kSuccess);
+  }
+
+  /** 設定の途中で失敗したコマンドのポートを kNotConnected に戻す．
+   * そのポートがアドレスの割り当て中だったなら，待っている次のポートを始める．
+   * 失敗を 1 つのポートの設定の失敗で済ませ，ほかのポートまで止めないようにする．
+   */
+  void AbandonPort(Controller& xhc, unsigned int issuer_type, uint8_t slot_id) {
+    uint8_t port_id = 0;
+    if (issuer_type == EnableSlotCommandTRB::Type ||
+        issuer_type == AddressDeviceCommandTRB::Type) {
+      port_id = addressing_port;
+    } else if (issuer_type == ConfigureEndpointCommandTRB::Type ||
+               issuer_type == ResetEndpointCommandTRB::Type ||
+               issuer_type == SetTRDequeuePointerCommandTRB::Type) {
+      if (auto dev = xhc.DeviceManager()->FindBySlot(slot_id)) {
+        port_id = dev->DeviceContext()->slot_context.bits.root_hub_port_num;
+      }
+    }
+    if (port_id == 0) {
+      return;
+    }
+
+    port_config_phase[port_id] = ConfigPhase::kNotConnected;
+    if (port_id != addressing_port) {
+      return;
+    }
+    addressing_port = 0;
+    if (auto err = StartWaitingPort(xhc)) {
+      Log(kError, "failed to start waiting port: %s\n", err.Name());
+    }
+  }
+
   Error OnEvent(Controller& xhc, CommandCompletionEventTRB& trb) {
     const auto issuer_type = trb.Pointer()->bits.trb_type;
     const auto slot_id = trb.bits.slot_id;
-    Log(kDebug, "CommandCompletionEvent: slot_id = %d, issuer = %s\n",
-        trb.bits.slot_id, kTRBTypeToName[issuer_type]);
+    Log(kDebug, "CommandCompletionEvent: slot_id = %d, issuer = %s, code = %s\n",
+        trb.bits.slot_id, kTRBTypeToName[issuer_type],
+        CompletionCodeName(trb.bits.completion_code));
+    xhc.OnCommandCompleted(trb);
+
+    // 完了を待っている側が結果を確認する
+    if (issuer_type == NoOpCommandTRB::Type) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+
+    if (static_cast<CompletionCode>(trb.bits.completion_code) !=
+        CompletionCode::kSuccess) {
+      Log(kError, "%s failed: %s (completion code %d)\n",
+          kTRBTypeToName[issuer_type],
+          CompletionCodeName(trb.bits.completion_code),
+          trb.bits.completion_code);
+      AbandonPort(xhc, issuer_type, slot_id);
+      return MAKE_ERROR(Error::kTransferFailed);
+    }
 
     if (issuer_type == EnableSlotCommandTRB::Type) {
       if (port_config_phase[addressing_port] != ConfigPhase::kEnablingSlot) {
@@ -252,14 +402,8 @@ namespace {
       }
 
       addressing_port = 0;
-      for (int i = 0; i < port_config_phase.size(); ++i) {
-        if (port_config_phase[i] == ConfigPhase::kWaitingAddressed) {
-          auto port = xhc.PortAt(i);
-          if (auto err = ResetPort(xhc, port); err) {
-            return err;
-          }
-          break;
-        }
+      if (auto err = StartWaitingPort(xhc)) {
+        return err;
       }
 
       return InitializeDevice(xhc, port_id, slot_id);
@@ -275,6 +419,28 @@ namespace {
       }
 
       return CompleteConfiguration(xhc, port_id, slot_id);
+    } else if (issuer_type == ResetEndpointCommandTRB::Type) {
+      auto dev = xhc.DeviceManager()->FindBySlot(slot_id);
+      if (dev == nullptr) {
+        return MAKE_ERROR(Error::kInvalidSlotID);
+      }
+
+      // 失敗した転送の残りを捨て，次に積む TRB から再開させる
+      auto tr = dev->TransferRing(DeviceContextIndex{usb::kDefaultControlPipeID});
+      SetTRDequeuePointerCommandTRB cmd{usb::kDefaultControlPipeID, slot_id,
+                                        tr->EnqueuePointer(), tr->CycleBit()};
+      xhc.IssueCommand(cmd);
+      return MAKE_ERROR(Error::kSuccess);
+    } else if (issuer_type == SetTRDequeuePointerCommandTRB::Type) {
+      auto dev = xhc.DeviceManager()->FindBySlot(slot_id);
+      if (dev == nullptr) {
+        return MAKE_ERROR(Error::kInvalidSlotID);
+      }
+
+      return dev->OnControlPipeRecovered();
+    } else if (issuer_type == DisableSlotCommandTRB::Type) {
+      // xHC がスロットを手放したので，デバイスとそのクラスドライバを取り除いてよい
+      return xhc.DeviceManager()->Remove(slot_id);
     }
 
     return MAKE_ERROR(Error::kInvalidPhase);
@@ -302,12 +468,34 @@ namespace {
     Log(kDebug, "waiting until OS owns xHC...\n");
     reg.Write(r);
 
-    do {
-      r = reg.Read();
-    } while (r.bits.hc_bios_owned_semaphore ||
-             !r.bits.hc_os_owned_semaphore);
+    auto err = WaitUntil([&reg]() {
+      auto r = reg.Read();
+      return !r.bits.hc_bios_owned_semaphore && r.bits.hc_os_owned_semaphore;
+    });
+    if (err) {
+      Log(kWarn, "BIOS did not release xHC: %s\n", err.Name());
+      return;
+    }
     Log(kDebug, "OS has owned xHC\n");
   }
+  Error ProcessEventOn(Controller& xhc, EventRing* er) {
+    if (er == nullptr || !er->HasFront()) {
+      return MAKE_ERROR(Error::kSuccess);
+    }
+
+    Error err = MAKE_ERROR(Error::kNotImplemented);
+    auto event_trb = er->Front();
+    if (auto trb = TRBDynamicCast<TransferEventTRB>(event_trb)) {
+      err = OnEvent(xhc, *trb);
+    } else if (auto trb = TRBDynamicCast<PortStatusChangeEventTRB>(event_trb)) {
+      err = OnEvent(xhc, *trb);
+    } else if (auto trb = TRBDynamicCast<CommandCompletionEventTRB>(event_trb)) {
+      err = OnEvent(xhc, *trb);
+    }
+    er->Pop();
+
+    return err;
+  }
 }
 
 namespace usb::xhci {
@@ -327,6 +515,7 @@ namespace usb::xhci {
     }
 
     RequestHCOwnership(mmio_base_, cap_->HCCPARAMS1.Read());
+    ReadSupportedProtocols();
 
     auto usbcmd = op_->USBCMD.Read();
     usbcmd.bits.interrupter_enable = false;
@@ -338,14 +527,22 @@ namespace usb::xhci {
     }
 
     op_->USBCMD.Write(usbcmd);
-    while (!op_->USBSTS.Read().bits.host_controller_halted);
+    if (auto err = WaitUntil([this]() {
+          return op_->USBSTS.Read().bits.host_controller_halted; })) {
+      Log(kError, "xHC did not halt: %s\n", err.Name());
+      return err;
+    }
 
     // Reset controller
     usbcmd = op_->USBCMD.Read();
     usbcmd.bits.host_controller_reset = true;
     op_->USBCMD.Write(usbcmd);
-    while (op_->USBCMD.Read().bits.host_controller_reset);
-    while (op_->USBSTS.Read().bits.controller_not_ready);
+    if (auto err = WaitUntil([this]() {
+          return !op_->USBCMD.Read().bits.host_controller_reset &&
+                 !op_->USBSTS.Read().bits.controller_not_ready; })) {
+      Log(kError, "xHC did not finish reset: %s\n", err.Name());
+      return err;
+    }
 
     Log(kDebug, "MaxSlots: %u\n", cap_->HCSPARAMS1.Read().bits.max_device_slots);
     // Set "Max Slots Enabled" field in CONFIG.
@@ -358,15 +555,23 @@ namespace usb::xhci {
       hcsparams2.bits.max_scratchpad_buffers_low
       | (hcsparams2.bits.max_scratchpad_buffers_high << 5);
     if (max_scratchpad_buffers > 0) {
-      auto scratchpad_buf_arr = AllocArray<void*>(max_scratchpad_buffers, 64, 4096);
-      for (int i = 0; i < max_scratchpad_buffers; ++i) {
-        scratchpad_buf_arr[i] = AllocMem(4096, 4096, 4096);
-        Log(kDebug, "scratchpad buffer array %d = %p\n",
-            i, scratchpad_buf_arr[i]);
+      const size_t page_size = PageSize(op_->PAGESIZE.Read());
+      // 初期化し直すときは前回確保したものを使い回す．メモリプールは解放できないため．
+      if (scratchpad_buf_arr_ == nullptr ||
+          num_scratchpad_bufs_ != max_scratchpad_buffers) {
+        void** scratchpad_buf_arr = nullptr;
+        if (auto err = AllocScratchpadBuffers(
+              max_scratchpad_buffers, page_size, &scratchpad_buf_arr)) {
+          Log(kError, "failed to allocate %u scratchpad buffers of %lu bytes: %s\n",
+              max_scratchpad_buffers, page_size, err.Name());
+          return err;
+        }
+        scratchpad_buf_arr_ = scratchpad_buf_arr;
+        num_scratchpad_bufs_ = max_scratchpad_buffers;
       }
-      devmgr_.DeviceContexts()[0] = reinterpret_cast<DeviceContext*>(scratchpad_buf_arr);
-      Log(kInfo, "wrote scratchpad buffer array %p to dev ctx array 0\n",
-          scratchpad_buf_arr);
+      devmgr_.DeviceContexts()[0] = reinterpret_cast<DeviceContext*>(scratchpad_buf_arr_);
+      Log(kInfo, "wrote scratchpad buffer array %p (%u x %lu bytes) to dev ctx array 0\n",
+          scratchpad_buf_arr_, max_scratchpad_buffers, page_size);
     }
 
     DCBAAP_Bitmap dcbaap{};
@@ -374,7 +579,9 @@ namespace usb::xhci {
     op_->DCBAAP.Write(dcbaap);
 
     auto primary_interrupter = &InterrupterRegisterSets()[0];
-    if (auto err = cr_.Initialize(32)) {
+    // コマンドリングのバッファは使い回すので，前に発行したコマンドのハンドルを新しいコマンドと取り違えないよう消しておく
+    commands_ = {};
+    if (auto err = cr_.Initialize(kCommandRingSize)) {
         return err;
     }
     if (auto err = RegisterCommandRing(&cr_, &op_->CRCR)) {
@@ -389,6 +596,22 @@ namespace usb::xhci {
     iman.bits.interrupt_enable = true;
     primary_interrupter->IMAN.Write(iman);
 
+    // Use the second interrupter as a secondary event ring if available
+    if (MaxInterrupters() > 1) {
+      auto secondary_interrupter = &InterrupterRegisterSets()[1];
+      if (auto err = er2_.Initialize(32, secondary_interrupter)) {
+        return err;
+      }
+
+      auto iman2 = secondary_interrupter->IMAN.Read();
+      iman2.bits.interrupt_pending = true;
+      iman2.bits.interrupt_enable = true;
+      secondary_interrupter->IMAN.Write(iman2);
+      has_secondary_er_ = true;
+    }
+    Log(kDebug, "MaxIntrs: %u, secondary event ring: %s\n",
+        MaxInterrupters(), has_secondary_er_ ? "enabled" : "none");
+
     // Enable interrupt for the controller
     usbcmd = op_->USBCMD.Read();
     usbcmd.bits.interrupter_enable = true;
@@ -397,6 +620,26 @@ namespace usb::xhci {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  void Controller::ReadSupportedProtocols() {
+    ExtendedRegisterList extregs{ mmio_base_, cap_->HCCPARAMS1.Read() };
+    for (auto& reg : extregs) {
+      if (reg.Read().bits.capability_id != 2) {
+        continue;
+      }
+      auto& proto =
+        reinterpret_cast<MemMapRegister<SupportedProtocol_Bitmap>&>(reg);
+      auto p = proto.Read();
+      const uint16_t revision =
+        (p.bits.major_revision << 8) | p.bits.minor_revision;
+      // 範囲は 1 始まりのポート番号．max_ports_ を超える分は無視する．
+      const unsigned first = p.bits.compatible_port_offset;
+      const unsigned last = first + p.bits.compatible_port_count;
+      for (unsigned port = first; port < last && port <= max_ports_; ++port) {
+        port_revisions_[port] = revision;
+      }
+    }
+  }
+
   Error Controller::Run() {
     // Run the controller
     auto usbcmd = op_->USBCMD.Read();
@@ -404,7 +647,11 @@ namespace usb::xhci {
     op_->USBCMD.Write(usbcmd);
     op_->USBCMD.Read();
 
-    while (op_->USBSTS.Read().bits.host_controller_halted);
+    if (auto err = WaitUntil([this]() {
+          return !op_->USBSTS.Read().bits.host_controller_halted; })) {
+      Log(kError, "xHC did not start running: %s\n", err.Name());
+      return err;
+    }
 
     return MAKE_ERROR(Error::kSuccess);
   }
@@ -413,6 +660,41 @@ namespace usb::xhci {
     return &DoorbellRegisters()[index];
   }
 
+  const CommandStatus* Controller::FindCommand(const TRB* handle) const {
+    const auto index = handle - cr_.Buffer();
+    if (index < 0 || index >= kCommandRingSize || commands_[index].trb != handle) {
+      return nullptr;
+    }
+    return &commands_[index];
+  }
+
+  void Controller::OnCommandCompleted(const CommandCompletionEventTRB& trb) {
+    const TRB* issued = trb.Pointer();
+    const auto index = issued - cr_.Buffer();
+    if (index < 0 || index >= kCommandRingSize || commands_[index].trb != issued) {
+      return;
+    }
+    commands_[index].completed = true;
+    commands_[index].completion_code = trb.bits.completion_code;
+    commands_[index].slot_id = trb.bits.slot_id;
+  }
+
+  const TRB* IssueNoOpCommand(Controller& xhc) {
+    NoOpCommandTRB cmd{};
+    return xhc.IssueCommand(cmd);
+  }
+
+  Error ControlTransfer(Controller& xhc, uint8_t slot_id,
+                        uint8_t request_type, uint8_t request,
+                        uint16_t value, uint16_t index,
+                        void* buf, int len, const ControlStatus** status) {
+    auto dev = xhc.DeviceManager()->FindBySlot(slot_id);
+    if (dev == nullptr) {
+      return MAKE_ERROR(Error::kInvalidSlotID);
+    }
+    return dev->ControlTransfer(request_type, request, value, index, buf, len, status);
+  }
+
   Error ConfigurePort(Controller& xhc, Port& port) {
     if (port_config_phase[port.Number()] == ConfigPhase::kNotConnected) {
       return ResetPort(xhc, port);
@@ -420,6 +702,13 @@ namespace usb::xhci {
     return MAKE_ERROR(Error::kSuccess);
   }
 
+  void ResetPortConfigPhases() {
+    for (auto& phase : port_config_phase) {
+      phase = ConfigPhase::kNotConnected;
+    }
+    addressing_port = 0;
+  }
+
   Error ConfigureEndpoints(Controller& xhc, Device& dev) {
     const auto configs = dev.EndpointConfigs();
     const auto len = dev.NumEndpointConfigs();
@@ -479,28 +768,20 @@ namespace usb::xhci {
     port_config_phase[port_id] = ConfigPhase::kConfiguringEndpoints;
 
     ConfigureEndpointCommandTRB cmd{dev.InputContext(), dev.SlotID()};
-    xhc.CommandRing()->Push(cmd);
-    xhc.DoorbellRegisterAt(0)->Ring(0);
+    xhc.IssueCommand(cmd);
 
     return MAKE_ERROR(Error::kSuccess);
   }
 
   Error ProcessEvent(Controller& xhc) {
-    if (!xhc.PrimaryEventRing()->HasFront()) {
-      return MAKE_ERROR(Error::kSuccess);
-    }
+    return ProcessEventOn(xhc, xhc.PrimaryEventRing());
+  }
 
-    Error err = MAKE_ERROR(Error::kNotImplemented);
-    auto event_trb = xhc.PrimaryEventRing()->Front();
-    if (auto trb = TRBDynamicCast<TransferEventTRB>(event_trb)) {
-      err = OnEvent(xhc, *trb);
-    } else if (auto trb = TRBDynamicCast<PortStatusChangeEventTRB>(event_trb)) {
-      err = OnEvent(xhc, *trb);
-    } else if (auto trb = TRBDynamicCast<CommandCompletionEventTRB>(event_trb)) {
-      err = OnEvent(xhc, *trb);
-    }
-    xhc.PrimaryEventRing()->Pop();
+  Error ProcessSecondaryEvent(Controller& xhc) {
+    return ProcessEventOn(xhc, xhc.SecondaryEventRing());
+  }
 
-    return err;
+  bool HasPendingEvent(Controller& xhc) {
+    return xhc.PrimaryEventRing()->HasFront();
   }
 }
@@ -14,6 +14,16 @@
 #include "usb/xhci/devmgr.hpp"
 
 namespace usb::xhci {
+  /** @brief コマンドリングへ発行したコマンドの完了状況． */
+  struct CommandStatus {
+    /** @brief 発行したコマンド TRB．nullptr なら未使用 */
+    const TRB* trb = nullptr;
+    /** @brief Command Completion Event を受け取ったら true */
+    bool completed = false;
+    uint8_t completion_code = 0;
+    uint8_t slot_id = 0;
+  };
+
   class Controller {
    public:
     Controller(uintptr_t mmio_base);
@@ -21,15 +31,52 @@ namespace usb::xhci {
     Error Run();
     Ring* CommandRing() { return &cr_; }
     EventRing* PrimaryEventRing() { return &er_; }
+    /** @brief セカンダリイベントリングを返す．
+     *
+     * インタラプタを 1 つしか持たない xHC では nullptr を返す．
+     */
+    EventRing* SecondaryEventRing() {
+      return has_secondary_er_ ? &er2_ : nullptr;
+    }
+    uint16_t MaxInterrupters() const {
+      return cap_->HCSPARAMS1.Read().bits.max_interrupters;
+    }
     DoorbellRegister* DoorbellRegisterAt(uint8_t index);
     Port PortAt(uint8_t port_num) {
       return Port{port_num, PortRegisterSets()[port_num - 1]};
     }
     uint8_t MaxPorts() const { return max_ports_; }
+    /** @brief ポートが対応する USB のメジャーバージョン（2 や 3）を返す．
+     *
+     * Supported Protocol Capability に載っていないポートでは 0 を返す．
+     */
+    uint8_t PortProtocol(uint8_t port_num) const {
+      return port_revisions_[port_num] >> 8;
+    }
     DeviceManager* DeviceManager() { return &devmgr_; }
 
+    /** @brief コマンドをコマンドリングへ積み，ドアベルを鳴らす．
+     *
+     * @return 発行したコマンドを表すハンドル．FindCommand で完了状況を調べられる．
+     */
+    template <typename TRBType>
+    const TRB* IssueCommand(const TRBType& trb) {
+      const TRB* issued = cr_.Push(trb);
+      commands_[issued - cr_.Buffer()] = CommandStatus{issued, false, 0, 0};
+      DoorbellRegisterAt(0)->Ring(0);
+      return issued;
+    }
+    /** @brief IssueCommand で発行したコマンドの完了状況を返す．
+     *
+     * コマンドリングが一周してハンドルが指す TRB が再利用された場合は nullptr を返す．
+     */
+    const CommandStatus* FindCommand(const TRB* handle) const;
+    /** @brief Command Completion Event を受け取ったことを記録する． */
+    void OnCommandCompleted(const CommandCompletionEventTRB& trb);
+
    private:
     static const size_t kDeviceSize = 8;
+    static const size_t kCommandRingSize = 32;
 
     const uintptr_t mmio_base_;
     CapabilityRegisters* const cap_;
@@ -39,6 +86,17 @@ namespace usb::xhci {
     class DeviceManager devmgr_;
     Ring cr_;
     EventRing er_;
+    EventRing er2_;
+    bool has_secondary_er_ = false;
+    std::array<CommandStatus, kCommandRingSize> commands_{};
+    /** @brief ポート番号ごとの USB のバージョン．上位 8 ビットがメジャー，下位がマイナー（BCD）． */
+    std::array<uint16_t, 256> port_revisions_{};
+    /** @brief 確保したスクラッチパッドバッファの配列とその数．初期化し直すときに使い回す． */
+    void** scratchpad_buf_arr_ = nullptr;
+    uint16_t num_scratchpad_bufs_ = 0;
+
+    /** @brief Supported Protocol Capability を読み，port_revisions_ を埋める． */
+    void ReadSupportedProtocols();
 
     InterrupterRegisterSetArray InterrupterRegisterSets() const {
       return {mmio_base_ + cap_->RTSOFF.Read().Offset() + 0x20u, 1024};
@@ -54,8 +112,34 @@ namespace usb::xhci {
   };
 
   Error ConfigurePort(Controller& xhc, Port& port);
+
+  /** @brief すべてのポートの設定の進み具合を未接続に戻す．
+   *
+   * xHC をリセットして初期化し直した後，ポートを設定し直す前に呼ぶ．
+   * 設定の途中だったポートが残っていると，そのポートも後に続くポートも設定できなくなる．
+   */
+  void ResetPortConfigPhases();
+
+  /** @brief No Op コマンドを発行する．
+   *
+   * コマンドリングとイベントリングが動いているかの確認に使う．
+   *
+   * @return 発行したコマンドのハンドル
+   */
+  const TRB* IssueNoOpCommand(Controller& xhc);
   Error ConfigureEndpoints(Controller& xhc, Device& dev);
 
+  /** @brief slot_id のデバイスのデフォルトコントロールパイプへ任意のリクエストを発行する．
+   *
+   * 転送の終わりは *status の completed で分かる．詳しくは usb::Device::ControlTransfer を参照．
+   *
+   * @return 発行できたら Error::kSuccess．slot_id のデバイスが無ければ Error::kInvalidSlotID
+   */
+  Error ControlTransfer(Controller& xhc, uint8_t slot_id,
+                        uint8_t request_type, uint8_t request,
+                        uint16_t value, uint16_t index,
+                        void* buf, int len, const ControlStatus** status);
+
   /** @brief イベントリングに登録されたイベントを高々1つ処理する．
    *
    * xhc のプライマリイベントリングの先頭のイベントを処理する．
@@ -64,4 +148,16 @@ namespace usb::xhci {
    * @return イベントを正常に処理できたら Error::kSuccess
    */
   Error ProcessEvent(Controller& xhc);
+
+  /** @brief セカンダリイベントリングに登録されたイベントを高々1つ処理する．
+   *
+   * セカンダリイベントリングが無い場合やイベントが無い場合は
+   * 即座に Error::kSuccess を返す．
+   *
+   * @return イベントを正常に処理できたら Error::kSuccess
+   */
+  Error ProcessSecondaryEvent(Controller& xhc);
+
+  /** @brief プライマリイベントリングに未処理のイベントがあるかを返す． */
+  bool HasPendingEvent(Controller& xhc);
 }
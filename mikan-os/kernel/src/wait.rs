@@ -0,0 +1,20 @@
+#![allow(unused)]
+
+use crate::{error, make_error};
+
+/// [wait_until] で条件を確認する回数の既定値
+pub(crate) const DEFAULT_POLL_LIMIT: usize = 1_000_000;
+
+/// `predicate` が true を返すまで繰り返し呼び出す。
+///
+/// タイマがまだ無いので、時間ではなく確認の回数で打ち切る。
+/// `max_polls` 回呼んでも true にならなければ [error::Code::Timeout] を返す。
+pub(crate) fn wait_until(mut predicate: impl FnMut() -> bool, max_polls: usize) -> error::Error {
+    for _ in 0..max_polls {
+        if predicate() {
+            return make_error!(error::Code::Success);
+        }
+        core::hint::spin_loop();
+    }
+    make_error!(error::Code::Timeout)
+}
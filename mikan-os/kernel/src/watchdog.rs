@@ -0,0 +1,57 @@
+#![allow(unused)]
+
+use core::{
+    fmt::Write,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use crate::{
+    log_irq,
+    logger::LogLevel,
+    printk_irq, printkln_irq,
+    serial::{SerialPort, COM1},
+    trace,
+    trace::TraceEvent,
+};
+
+/// キックが途絶えてから警告を出すまでのティック数の既定値。[crate::timer::TICK_HZ] で 5 秒。
+pub(crate) const DEFAULT_WATCHDOG_TIMEOUT: u64 = 500;
+
+/// 最後のキックから数えたティック数
+static TICKS_SINCE_KICK: AtomicU64 = AtomicU64::new(0);
+/// 警告を出すまでのティック数
+static TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_WATCHDOG_TIMEOUT);
+/// 現在のハングについて、既に警告を出したかどうか
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// 警告を出すまでのティック数を設定する。
+pub(crate) fn set_watchdog_timeout(ticks: u64) {
+    TIMEOUT.store(ticks, Ordering::Relaxed);
+}
+
+/// メインループが動いていることをウォッチドッグへ知らせる。メインループの毎周回で呼ぶ。
+pub(crate) fn watchdog_kick() {
+    TICKS_SINCE_KICK.store(0, Ordering::Relaxed);
+    if WARNED.swap(false, Ordering::Relaxed) {
+        log_irq!(LogLevel::Warn, "watchdog: main loop resumed");
+    }
+}
+
+/// タイマ割り込み（[crate::timer::start_tick]）から 1 ティックごとに呼ばれる。
+///
+/// 設定されたティック数の間キックが無ければ、メインループが止まっているとみなして警告を出す。
+/// log_irq! のリングはメインループが吐き出すので、止まっている間は表示されない。
+/// そのため警告はロックを取らずにシリアルポートへ直接書き出す。
+/// 警告は 1 回のハングにつき 1 度だけ出す。
+pub(crate) fn watchdog_tick() {
+    trace!(TraceEvent::TimerTick);
+    let ticks = TICKS_SINCE_KICK.fetch_add(1, Ordering::Relaxed) + 1;
+    if ticks >= TIMEOUT.load(Ordering::Relaxed) && !WARNED.swap(true, Ordering::Relaxed) {
+        let mut serial = SerialPort::new(COM1);
+        let _ = writeln!(
+            serial,
+            "\n[watchdog] main loop has not run for {} ticks",
+            ticks
+        );
+    }
+}
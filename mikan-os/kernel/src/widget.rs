@@ -0,0 +1,78 @@
+#![allow(unused)]
+
+use crate::graphics::{PixelColor, PixelWriter, Vector2D};
+
+/// 枠付きの横向きプログレスバー。
+pub(crate) struct ProgressBar<'a> {
+    writer: &'a dyn PixelWriter,
+    pos: Vector2D<u32>,
+    size: Vector2D<u32>,
+    fg: PixelColor,
+    bg: PixelColor,
+    /// 現在の進捗（0〜100）
+    progress: u8,
+}
+
+impl<'a> ProgressBar<'a> {
+    /// 初期化。枠と空のバーを描画する。
+    ///
+    /// `size` は枠を含めた大きさで、縦横とも 3 以上であること。
+    pub(crate) fn new(
+        writer: &'a dyn PixelWriter,
+        pos: Vector2D<u32>,
+        size: Vector2D<u32>,
+        fg: PixelColor,
+        bg: PixelColor,
+    ) -> Self {
+        let ret = Self {
+            writer,
+            pos,
+            size,
+            fg,
+            bg,
+            progress: 0,
+        };
+        ret.writer.draw_rectangle(pos, size, &fg);
+        ret.writer.fill_rectangle(
+            pos + Vector2D::new(1, 1),
+            Vector2D::new(size.x() - 2, size.y() - 2),
+            &bg,
+        );
+        ret
+    }
+
+    /// 現在の進捗を返す。
+    pub(crate) fn progress(&self) -> u8 {
+        self.progress
+    }
+
+    /// 進捗を設定する。100 を超える値は 100 として扱う。
+    ///
+    /// 前回から変化した部分だけを描き直す。
+    pub(crate) fn set_progress(&mut self, progress: u8) {
+        let progress = progress.min(100);
+        let old_width = self.filled_width(self.progress);
+        let new_width = self.filled_width(progress);
+        self.progress = progress;
+
+        let (start, end, color) = if new_width > old_width {
+            (old_width, new_width, &self.fg)
+        } else {
+            (new_width, old_width, &self.bg)
+        };
+        if start == end {
+            return;
+        }
+
+        self.writer.fill_rectangle(
+            self.pos + Vector2D::new(1 + start, 1),
+            Vector2D::new(end - start, self.size.y() - 2),
+            color,
+        );
+    }
+
+    /// 進捗に対応する、枠の内側で塗りつぶす幅を返す。
+    fn filled_width(&self, progress: u8) -> u32 {
+        (self.size.x() - 2) * progress as u32 / 100
+    }
+}
@@ -0,0 +1,22 @@
+use core::ptr;
+
+/// カーネルへ渡すアプリのバイナリの最大長（バイト）。カーネル側の定義と合わせること。
+pub const APP_IMAGE_SIZE: usize = 16 * 1024;
+
+/// `\app.bin` を読み込んだ領域。カーネル側の `app::AppImage` と同じ並び。
+///
+/// ファイルが無ければ `base` は null、`len` は 0 になる。
+#[repr(C)]
+pub struct AppImage {
+    pub base: *const u8,
+    pub len: usize,
+}
+
+impl AppImage {
+    pub const fn empty() -> Self {
+        Self {
+            base: ptr::null(),
+            len: 0,
+        }
+    }
+}
@@ -0,0 +1,57 @@
+/// カーネルへ渡す起動引数の最大長（バイト）
+pub const BOOT_ARGS_SIZE: usize = 256;
+
+/// カーネルへ渡す起動引数。`key=value` や `flag` を空白区切りで並べた ASCII 文字列。
+#[repr(C)]
+pub struct BootArgs {
+    pub len: usize,
+    pub buf: [u8; BOOT_ARGS_SIZE],
+}
+
+impl BootArgs {
+    /// 空の起動引数を作る。
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            buf: [0; BOOT_ARGS_SIZE],
+        }
+    }
+
+    /// `key=value` の形で指定された `key` の値を返す。複数あれば最後のものを使う。
+    pub fn value(&self, key: &[u8]) -> Option<&[u8]> {
+        self.buf[..self.len.min(BOOT_ARGS_SIZE)]
+            .split(|b| b.is_ascii_whitespace())
+            .rev()
+            .find_map(|token| {
+                let i = token.iter().position(|&b| b == b'=')?;
+                (&token[..i] == key).then(|| &token[i + 1..])
+            })
+    }
+
+    /// `resolution=<横>x<縦>` で指定された画面の解像度を返す。
+    pub fn resolution(&self) -> Option<(usize, usize)> {
+        let value = core::str::from_utf8(self.value(b"resolution")?).ok()?;
+        let (width, height) = value.split_once('x')?;
+        Some((width.parse().ok()?, height.parse().ok()?))
+    }
+
+    /// `videomode=ask` で、起動時に画面モードを選ばせるかどうか。
+    pub fn video_mode_prompt(&self) -> bool {
+        self.value(b"videomode") == Some(b"ask")
+    }
+
+    /// `videotimeout=<秒>` で指定された、画面モードの選択を待つ時間。
+    pub fn video_mode_timeout(&self) -> Option<u64> {
+        core::str::from_utf8(self.value(b"videotimeout")?)
+            .ok()?
+            .parse()
+            .ok()
+    }
+
+    /// `memmapformat=csv` で、メモリマップを `\memmap` へ CSV で書き出すかどうか。
+    ///
+    /// 指定が無ければ `\memmap.bin` へバイナリ形式で書き出す。
+    pub fn memory_map_csv(&self) -> bool {
+        self.value(b"memmapformat") == Some(b"csv")
+    }
+}
@@ -0,0 +1,93 @@
+#![allow(unused)]
+
+/// CRC32（IEEE 802.3、反転多項式 0xedb88320）の多項式
+const CRC32_POLYNOMIAL: u32 = 0xedb8_8320;
+
+/// 1 バイトごとの CRC32 の値を並べた表。コンパイル時に作る。
+const CRC32_TABLE: [u32; 256] = make_crc32_table();
+
+const fn make_crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CRC32_POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// CRC32 を少しずつ計算する。
+///
+/// ファイルを分けて読むときなど、データが一度に揃わない場合に使う。
+/// 値は zlib や `crc32` コマンドと同じになる。
+pub struct Crc32 {
+    state: u32,
+}
+
+impl Crc32 {
+    pub const fn new() -> Self {
+        Self { state: !0 }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        let mut crc = self.state;
+        for &byte in data {
+            crc = CRC32_TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.state = crc;
+    }
+
+    pub const fn finish(&self) -> u32 {
+        !self.state
+    }
+}
+
+/// `data` の CRC32 を返す。
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(data);
+    crc.finish()
+}
+
+/// `data` の全バイトを足し合わせた値を返す。桁溢れは無視する。
+///
+/// CRC32 より弱いが、手で計算しやすい。
+pub fn sum32(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0u32, |sum, &byte| sum.wrapping_add(byte as u32))
+}
+
+/// チェックサムファイルの中身から、先頭の 16 進数を読み取る。
+///
+/// `crc32` コマンドの出力のように、16 進数の後ろにファイル名などが続いていてもよい。
+/// 16 進数が無いか、8 桁を超える場合は None を返す。
+pub fn parse_hex_u32(text: &[u8]) -> Option<u32> {
+    let text = text.trim_ascii_start();
+    let text = text
+        .strip_prefix(b"0x")
+        .or_else(|| text.strip_prefix(b"0X"))
+        .unwrap_or(text);
+    let digits = text.iter().take_while(|c| c.is_ascii_hexdigit()).count();
+    if digits == 0 || digits > 8 {
+        return None;
+    }
+    let value = text[..digits].iter().fold(0u32, |value, &c| {
+        let digit = match c {
+            b'0'..=b'9' => c - b'0',
+            b'a'..=b'f' => c - b'a' + 10,
+            _ => c - b'A' + 10,
+        };
+        (value << 4) | digit as u32
+    });
+    Some(value)
+}
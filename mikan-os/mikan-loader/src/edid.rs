@@ -0,0 +1,86 @@
+#![allow(unused)]
+
+//! モニタの EDID（VESA E-EDID）を UEFI の EDID プロトコルから読み、推奨する解像度を取り出す。
+//!
+//! uefi クレートには EDID のプロトコルが無いので、UEFI 仕様 12.9 節の定義をここで書く。
+//! どちらのプロトコルも GOP と同じハンドルに付いている。
+
+use core::slice;
+
+use uefi::proto::unsafe_protocol;
+
+/// EFI_EDID_ACTIVE_PROTOCOL。実際に使われている EDID で、上書きされていればその内容になる。
+#[repr(C)]
+#[unsafe_protocol("bd8c1056-9f36-44ec-92a8-a6337f817986")]
+pub struct EdidActive {
+    size_of_edid: u32,
+    edid: *const u8,
+}
+
+/// EFI_EDID_DISCOVERED_PROTOCOL。モニタから読み出したままの EDID。
+#[repr(C)]
+#[unsafe_protocol("1c0c34f6-d380-41fa-a049-8ad06c1a66aa")]
+pub struct EdidDiscovered {
+    size_of_edid: u32,
+    edid: *const u8,
+}
+
+impl EdidActive {
+    /// EDID のバイト列を返す。EDID が無ければ空。
+    pub fn bytes(&self) -> &[u8] {
+        edid_bytes(self.edid, self.size_of_edid)
+    }
+}
+
+impl EdidDiscovered {
+    /// EDID のバイト列を返す。EDID が無ければ空。
+    pub fn bytes(&self) -> &[u8] {
+        edid_bytes(self.edid, self.size_of_edid)
+    }
+}
+
+fn edid_bytes(edid: *const u8, size: u32) -> &'static [u8] {
+    if edid.is_null() || size == 0 {
+        return &[];
+    }
+    // ファームウェアの領域にあり、ブートサービスを終えるまでは有効
+    unsafe { slice::from_raw_parts(edid, size as usize) }
+}
+
+/// EDID の基本ブロックの大きさ（バイト）。拡張ブロックが続くことがあるが、読まない。
+pub const EDID_BLOCK_SIZE: usize = 128;
+/// 基本ブロックの先頭に置かれる固定の並び
+const EDID_HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+/// 最初の詳細タイミング記述子の位置。EDID 1.3 以降では、これがモニタの推奨するタイミング。
+const PREFERRED_TIMING_OFFSET: usize = 54;
+/// 詳細タイミング記述子の大きさ（バイト）
+const DETAILED_TIMING_SIZE: usize = 18;
+
+/// EDID の基本ブロックを確かめ、推奨タイミングの解像度を (横, 縦) で返す。
+///
+/// 先頭の並びかチェックサムがおかしいとき、最初の記述子がタイミングでない（モニタ名などの）とき、
+/// 解像度が 0 のときは None を返す。
+pub fn preferred_resolution(edid: &[u8]) -> Option<(usize, usize)> {
+    let block = edid.get(..EDID_BLOCK_SIZE)?;
+    if block[..EDID_HEADER.len()] != EDID_HEADER {
+        return None;
+    }
+    // 128 バイトの和が 0 になるよう、最後のバイトで調整されている
+    if block.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+        return None;
+    }
+
+    let timing = &block[PREFERRED_TIMING_OFFSET..PREFERRED_TIMING_OFFSET + DETAILED_TIMING_SIZE];
+    // ピクセルクロックが 0 なら、タイミングではない記述子
+    if timing[0] == 0 && timing[1] == 0 {
+        return None;
+    }
+    // 表示する画素数は下位 8 ビットと、別のバイトの上位 4 ビットに分かれている
+    let width = timing[2] as usize | ((timing[4] as usize & 0xf0) << 4);
+    let mut height = timing[5] as usize | ((timing[7] as usize & 0xf0) << 4);
+    // インターレースでは 1 フィールド分の行数なので、フレームの行数に直す
+    if timing[17] & 0x80 != 0 {
+        height *= 2;
+    }
+    (width > 0 && height > 0).then_some((width, height))
+}
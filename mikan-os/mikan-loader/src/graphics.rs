@@ -15,6 +15,7 @@ pub enum PixelFormat {
 #[repr(C)]
 pub struct FrameBufferConfig {
     pub frame_buffer: usize,
+    /// GOP の PixelsPerScanLine。横幅より大きいことがあるので、横幅で代用しないこと。
     pub pixels_per_scan_line: usize,
     pub horizontal_resolution: usize,
     pub vertical_resolution: usize,
@@ -1,14 +1,24 @@
 #![no_std]
 #![no_main]
 
+mod app_image;
+mod boot_args;
 mod chars;
+mod checksum;
+mod edid;
 mod elf;
 mod graphics;
+mod memory_map;
 
+use crate::app_image::{AppImage, APP_IMAGE_SIZE};
+use crate::boot_args::BootArgs;
 use crate::chars::*;
+use crate::edid::{EdidActive, EdidDiscovered};
 use crate::elf::Elf64Ehdr;
+use crate::memory_map::{BootMemoryMap, MemoryMapFileHeader, MemoryMapRecord};
 use core::{
     arch::asm,
+    ffi::c_void,
     fmt::Write,
     mem::{size_of, transmute},
     ptr::{copy_nonoverlapping, write_bytes},
@@ -17,12 +27,15 @@ use core::{
 use elf::{Elf64Phdr, ProgramType};
 use graphics::FrameBufferConfig;
 use graphics::GraphicsInfo;
-use log::error;
+use log::{error, warn};
 use uefi::{
     data_types::Identify,
     prelude::*,
     proto::{
-        console::gop::{GraphicsOutput, PixelFormat},
+        console::{
+            gop::{GraphicsOutput, ModeInfo, PixelBitmask, PixelFormat},
+            text::Key,
+        },
         loaded_image::LoadedImage,
         media::{
             file::{Directory, File, FileAttribute, FileInfo, FileMode, RegularFile},
@@ -31,13 +44,16 @@ use uefi::{
     },
     table::{
         boot::{
-            AllocateType, MemoryDescriptor, MemoryMap, MemoryType, OpenProtocolAttributes,
-            OpenProtocolParams, SearchType,
+            AllocateType, EventType, MemoryAttribute, MemoryDescriptor, MemoryMap, MemoryType,
+            OpenProtocolAttributes, OpenProtocolParams, SearchType, TimerTrigger, Tpl, PAGE_SIZE,
         },
+        cfg::ACPI2_GUID,
         runtime::Time,
+        Runtime,
     },
     CStr16,
 };
+use uefi_services::println;
 
 /// メモリマップを渡されたファイルに保存する。
 fn save_memory_map(
@@ -88,13 +104,113 @@ fn save_memory_map(
     Status::SUCCESS
 }
 
-/// メモリマップを取得してそれを [MemoryMap] として返す。
-fn get_memory_map<'a>(services: &BootServices, map: &'a mut [u8]) -> uefi::Result<MemoryMap<'a>> {
-    if map.len() == 0 {
-        return Err(uefi::Error::new(Status::BUFFER_TOO_SMALL, ()));
+/// [save_memory_map_binary] で一度に書き込むレコードの数
+const MEMORY_MAP_RECORDS_PER_WRITE: usize = 32;
+
+/// メモリマップを [MemoryMapFileHeader] と [MemoryMapRecord] の並びとして、渡されたファイルに保存する。
+///
+/// CSV より小さく、ホスト側のツールで読みやすい。
+fn save_memory_map_binary(map: &MemoryMap, file: &mut RegularFile) -> Status {
+    let header = MemoryMapFileHeader::new(map.entries().count());
+    if let Err(e) = file.write(&header.to_bytes()) {
+        return e.status();
+    }
+
+    // 1 レコードずつ書くと遅いので、いくつかまとめてから書く
+    let mut buf = [0u8; MemoryMapRecord::SIZE * MEMORY_MAP_RECORDS_PER_WRITE];
+    let mut len = 0;
+    for desc in map.entries() {
+        let record = MemoryMapRecord {
+            ty: desc.ty.0,
+            phys_start: desc.phys_start,
+            page_count: desc.page_count,
+            attribute: desc.att.bits(),
+        };
+        buf[len..len + MemoryMapRecord::SIZE].copy_from_slice(&record.to_bytes());
+        len += MemoryMapRecord::SIZE;
+        if len == buf.len() {
+            if let Err(e) = file.write(&buf) {
+                return e.status();
+            }
+            len = 0;
+        }
     }
+    if let Err(e) = file.write(&buf[..len]) {
+        return e.status();
+    }
+
+    Status::SUCCESS
+}
+
+/// メモリマップを取り直す最大の回数
+const MEMORY_MAP_RETRY: usize = 4;
+/// メモリマップの大きさを問い合わせてから取得するまでに増えうるエントリ数の見込み
+const MEMORY_MAP_EXTRA_ENTRIES: usize = 8;
 
-    Ok(services.memory_map(map)?)
+/// メモリマップを格納するために、ブートサービスから確保したページ。
+///
+/// 使い終わったら [MemoryMapBuffer::free] で返すこと。
+struct MemoryMapBuffer {
+    addr: u64,
+    pages: usize,
+}
+
+impl MemoryMapBuffer {
+    const fn new() -> Self {
+        Self { addr: 0, pages: 0 }
+    }
+
+    const fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    /// 少なくとも `size` バイトになるよう、必要なら確保し直す。
+    fn reserve(&mut self, services: &BootServices, size: usize) -> uefi::Result {
+        if size <= self.len() {
+            return Ok(());
+        }
+        self.free(services);
+        let pages = size.div_ceil(PAGE_SIZE);
+        self.addr =
+            services.allocate_pages(AllocateType::AnyPages, MemoryType::LOADER_DATA, pages)?;
+        self.pages = pages;
+        Ok(())
+    }
+
+    /// 確保したページを返す。このバッファから取得した [MemoryMap] は使えなくなる。
+    fn free(&mut self, services: &BootServices) {
+        if self.pages == 0 {
+            return;
+        }
+        if let Err(e) = unsafe { services.free_pages(self.addr, self.pages) } {
+            error!("Failed to free memmap pages: {}", e);
+        }
+        self.addr = 0;
+        self.pages = 0;
+    }
+}
+
+/// メモリマップを取得してそれを [MemoryMap] として返す。
+///
+/// 必要な大きさを問い合わせてから `buffer` にページを確保する。確保したことでも
+/// エントリは増えるので余裕を持たせ、それでも足りなければ大きくして取り直す。
+fn get_memory_map<'a>(
+    services: &BootServices,
+    buffer: &'a mut MemoryMapBuffer,
+) -> uefi::Result<MemoryMap<'a>> {
+    let size = services.memory_map_size();
+    let mut needed = size.map_size + MEMORY_MAP_EXTRA_ENTRIES * size.entry_size;
+    for _ in 0..MEMORY_MAP_RETRY {
+        buffer.reserve(services, needed)?;
+        // ページ単位で確保しているので、MemoryDescriptor の境界に揃っている
+        let map: &'a mut [u8] =
+            unsafe { slice::from_raw_parts_mut(buffer.addr as *mut u8, buffer.len()) };
+        match services.memory_map(map) {
+            Err(e) if e.status() == Status::BUFFER_TOO_SMALL => needed = buffer.len() * 2,
+            result => return result,
+        }
+    }
+    Err(uefi::Error::new(Status::BUFFER_TOO_SMALL, ()))
 }
 
 /// メモリのタイプ情報から、意味を表す 16 bit 文字列を返す。
@@ -164,10 +280,121 @@ fn open_root_dir(services: &BootServices, image_handle: Handle) -> uefi::Result<
     Ok(fs.open_volume()?)
 }
 
+/// ルートディレクトリの `\boot.cfg` から起動引数を読み込む。
+/// ファイルが無い場合は、起動引数を空のままにする。
+fn load_boot_args(root_dir: &mut Directory, args: &mut BootArgs) {
+    let file = match root_dir.open(
+        cstr16!("\\boot.cfg"),
+        FileMode::Read,
+        FileAttribute::empty(),
+    ) {
+        Err(_) => return,
+        Ok(file) => file,
+    };
+    let mut file = match file.into_regular_file() {
+        None => return,
+        Some(file) => file,
+    };
+
+    // 長すぎる場合は先頭の部分だけを使う
+    args.len = file.read(&mut args.buf).unwrap_or(0);
+    file.close();
+}
+
+/// ルートディレクトリの `\app.bin` を、カーネルへ渡せるよう LOADER_DATA の領域へ読み込む。
+///
+/// ファイルが無いか読めない場合と、[APP_IMAGE_SIZE] より大きい場合は空のまま返す。
+fn load_app_image(services: &BootServices, root_dir: &mut Directory) -> AppImage {
+    let file = match root_dir.open(cstr16!("\\app.bin"), FileMode::Read, FileAttribute::empty()) {
+        Err(_) => return AppImage::empty(),
+        Ok(file) => file,
+    };
+    let mut file = match file.into_regular_file() {
+        None => return AppImage::empty(),
+        Some(file) => file,
+    };
+
+    let buf = match services.allocate_pool(MemoryType::LOADER_DATA, APP_IMAGE_SIZE) {
+        Err(e) => {
+            warn!("Failed to allocate pool for app.bin: {}", e);
+            file.close();
+            return AppImage::empty();
+        }
+        Ok(buf) => buf,
+    };
+    let image = unsafe { slice::from_raw_parts_mut(buf, APP_IMAGE_SIZE) };
+    let len = file.read(image).unwrap_or(0);
+    // ちょうど一杯まで読めたときは、まだ続きがあるかもしれない
+    let mut rest = [0u8; 1];
+    let too_large = len == APP_IMAGE_SIZE && file.read(&mut rest).unwrap_or(0) > 0;
+    file.close();
+    if len == 0 || too_large {
+        if too_large {
+            warn!("app.bin is larger than {} bytes, ignored", APP_IMAGE_SIZE);
+        }
+        unsafe {
+            let _ = services.free_pool(buf);
+        }
+        return AppImage::empty();
+    }
+    AppImage { base: buf, len }
+}
+
+/// カーネルのチェックサムファイルの内容の最大長（バイト）
+const KERNEL_CHECKSUM_FILE_SIZE: usize = 64;
+
+/// ルートディレクトリの `\kernel.crc32` に書かれた CRC32 と、読み込んだカーネルを照合する。
+///
+/// ファイルの先頭に 16 進数で CRC32 を書いておく。ファイルが無い場合は照合しない。
+/// 一致しなければエラーを表示して false を返す。壊れたカーネルへ飛び込んで原因の分からない
+/// 異常終了をするよりも、ここで止めた方が分かりやすい。
+fn verify_kernel_checksum(root_dir: &mut Directory, kernel: &[u8]) -> bool {
+    let file = match root_dir.open(
+        cstr16!("\\kernel.crc32"),
+        FileMode::Read,
+        FileAttribute::empty(),
+    ) {
+        Err(_) => return true,
+        Ok(file) => file,
+    };
+    let mut file = match file.into_regular_file() {
+        None => return true,
+        Some(file) => file,
+    };
+    let mut buf = [0u8; KERNEL_CHECKSUM_FILE_SIZE];
+    let len = file.read(&mut buf).unwrap_or(0);
+    file.close();
+
+    let expected = match checksum::parse_hex_u32(&buf[..len]) {
+        None => {
+            error!("kernel.crc32 doesn't start with a hex number");
+            return false;
+        }
+        Some(crc) => crc,
+    };
+    let actual = checksum::crc32(kernel);
+    if actual != expected {
+        error!(
+            "kernel checksum mismatch: expected {:08x}, got {:08x} (sum {:08x}, {} bytes)",
+            expected,
+            actual,
+            checksum::sum32(kernel),
+            kernel.len()
+        );
+        return false;
+    }
+    true
+}
+
 /// 画面出力情報を取得する。
+///
+/// 起動引数とモニタの EDID に従って、先に画面モードを選び直す（[choose_gop_mode]）。
+/// カーネルは受け取った解像度を前提に画面やコンソールを作り、GOP は ExitBootServices の後は
+/// 使えないので、解像度を変えられるのはここだけ。
 fn get_gop_info(
     image_handle: Handle,
     system_table: &mut SystemTable<Boot>,
+    args: &BootArgs,
 ) -> uefi::Result<GraphicsInfo> {
     // GOP を操作するためのオブジェクト
     let gop_handles = system_table
@@ -188,9 +415,17 @@ fn get_gop_info(
             )?
     };
 
-    let pixel_info = match gop.get() {
+    let native = read_native_resolution(
+        system_table.boot_services(),
+        image_handle,
+        (*gop_handles)[0],
+    );
+    let pixel_info = match gop.get_mut() {
         None => return Err(uefi::Error::new(Status::ABORTED, ())),
-        Some(gop) => gop.current_mode_info(),
+        Some(gop) => {
+            choose_gop_mode(gop, system_table.boot_services(), args, native);
+            gop.current_mode_info()
+        }
     };
 
     Ok(GraphicsInfo {
@@ -200,6 +435,228 @@ fn get_gop_info(
     })
 }
 
+/// GOP のハンドル `handle` に付いた EDID プロトコルから、モニタの推奨する解像度を読む。
+///
+/// 上書きを反映した EDID_ACTIVE を優先し、無ければ EDID_DISCOVERED を使う。
+/// どちらも無いか、EDID を解釈できなければ None を返す。
+fn read_native_resolution(
+    services: &BootServices,
+    image_handle: Handle,
+    handle: Handle,
+) -> Option<(usize, usize)> {
+    let params = || OpenProtocolParams {
+        handle,
+        agent: image_handle,
+        controller: None,
+    };
+    let active = || {
+        let edid = unsafe {
+            services.open_protocol::<EdidActive>(params(), OpenProtocolAttributes::GetProtocol)
+        }
+        .ok()?;
+        edid::preferred_resolution(edid.get()?.bytes())
+    };
+    let discovered = || {
+        let edid = unsafe {
+            services.open_protocol::<EdidDiscovered>(params(), OpenProtocolAttributes::GetProtocol)
+        }
+        .ok()?;
+        edid::preferred_resolution(edid.get()?.bytes())
+    };
+    let resolution = active().or_else(discovered);
+    match resolution {
+        Some((width, height)) => println!("EDID: native resolution {}x{}", width, height),
+        None => println!("EDID: not available"),
+    }
+    resolution
+}
+
+/// 画面モードの一覧で選べる数。キーは 0〜9 と a〜z。
+const MODE_CHOICES_MAX: usize = 36;
+/// `videomode=ask` で選択を待つ時間の既定値（秒）
+const MODE_PROMPT_TIMEOUT_SECS: u64 = 5;
+
+/// 起動引数とモニタの推奨する解像度 `native` に従って画面モードを選び、切り替える。
+///
+/// `resolution=<横>x<縦>` があれば [best_gop_mode] で最も合うものを選ぶ。無ければ `native` と同じ
+/// 解像度のモードにして、液晶モニタで拡大されてぼやけないようにする。そのモードが無いか EDID が
+/// 読めなければ今のモードのままだが、今のモードがカーネルの扱えないピクセル形式なら、
+/// 扱える中で最も大きいものにする。
+/// `videomode=ask` なら一覧を出し、`videotimeout=<秒>` の間キー入力を待って選ばせる。
+/// 選んだモードへの切り替えに失敗したときは、警告を出して今のモードのままにする。
+fn choose_gop_mode(
+    gop: &mut GraphicsOutput,
+    services: &BootServices,
+    args: &BootArgs,
+    native: Option<(usize, usize)>,
+) {
+    let current_info = gop.current_mode_info();
+    let current_usable = to_kernel_pixel_format(&current_info).is_some();
+    let native_mode = native
+        .filter(|&resolution| !current_usable || current_info.resolution() != resolution)
+        .and_then(|resolution| {
+            usable_gop_modes(gop, services)
+                .find(|(_, info)| info.resolution() == resolution)
+                .map(|(index, _)| index)
+        });
+    let mut choice = match args.resolution() {
+        Some(resolution) => {
+            let index = best_gop_mode(gop, services, Some(resolution));
+            if index.is_none() {
+                warn!(
+                    "No usable graphics mode for {}x{}",
+                    resolution.0, resolution.1
+                );
+            }
+            index
+        }
+        None if native_mode.is_some() => native_mode,
+        None if current_usable => None,
+        None => best_gop_mode(gop, services, None),
+    };
+    if args.video_mode_prompt() {
+        let timeout = args
+            .video_mode_timeout()
+            .unwrap_or(MODE_PROMPT_TIMEOUT_SECS);
+        if let Some(index) = prompt_gop_mode(gop, services, choice, timeout) {
+            choice = Some(index);
+        }
+    }
+
+    let Some(index) = choice else {
+        return;
+    };
+    let result = gop
+        .query_mode(index, services)
+        .and_then(|mode| gop.set_mode(&mode));
+    if let Err(e) = result {
+        warn!("Failed to set graphics mode {}: {}", index, e);
+    }
+}
+
+/// カーネルが扱えるピクセル形式の画面モードを、(番号, 情報) の組で列挙する。
+fn usable_gop_modes<'a>(
+    gop: &'a GraphicsOutput,
+    services: &'a BootServices,
+) -> impl Iterator<Item = (u32, ModeInfo)> + 'a {
+    (0..gop.modes(services).len() as u32).filter_map(move |index| {
+        let info = *gop.query_mode(index, services).ok()?.info();
+        to_kernel_pixel_format(&info).map(|_| (index, info))
+    })
+}
+
+/// `resolution` に最も合う、カーネルが扱える画面モードの番号を返す。
+///
+/// 同じ解像度のものがあればそれを、無ければ `resolution` に収まる中で最も面積の大きいものを選ぶ。
+/// `resolution` が None なら、扱える中で最も面積の大きいものを選ぶ。
+fn best_gop_mode(
+    gop: &GraphicsOutput,
+    services: &BootServices,
+    resolution: Option<(usize, usize)>,
+) -> Option<u32> {
+    let mut best: Option<(u32, usize)> = None;
+    for (index, info) in usable_gop_modes(gop, services) {
+        let (width, height) = info.resolution();
+        if let Some((max_width, max_height)) = resolution {
+            if (width, height) == (max_width, max_height) {
+                return Some(index);
+            }
+            if width > max_width || height > max_height {
+                continue;
+            }
+        }
+        if best.is_none_or(|(_, area)| width * height > area) {
+            best = Some((index, width * height));
+        }
+    }
+    best.map(|(index, _)| index)
+}
+
+/// 選択肢のキーの文字を返す。
+fn mode_choice_key(i: usize) -> char {
+    match i {
+        0..=9 => (b'0' + i as u8) as char,
+        _ => (b'a' + (i - 10) as u8) as char,
+    }
+}
+
+/// カーネルが扱える画面モードを一覧にして、`timeout_secs` 秒の間キー入力を待つ。
+///
+/// 選ばれたモードの番号を返す。Enter を押すか時間切れなら `default` を返す。一覧に無いキーは無視する。
+/// 一覧には先頭から [MODE_CHOICES_MAX] 個まで並べる。
+fn prompt_gop_mode(
+    gop: &GraphicsOutput,
+    services: &BootServices,
+    default: Option<u32>,
+    timeout_secs: u64,
+) -> Option<u32> {
+    let mut choices = [0u32; MODE_CHOICES_MAX];
+    let mut len = 0;
+    let current = gop.current_mode_info().resolution();
+    println!("Graphics modes:");
+    for (index, info) in usable_gop_modes(gop, services).take(MODE_CHOICES_MAX) {
+        let (width, height) = info.resolution();
+        let mark = if Some(index) == default || (default.is_none() && (width, height) == current) {
+            " *"
+        } else {
+            ""
+        };
+        println!("  [{}] {}x{}{}", mode_choice_key(len), width, height, mark);
+        choices[len] = index;
+        len += 1;
+    }
+    if len == 0 {
+        return default;
+    }
+    println!(
+        "Press a key to choose a mode, Enter to keep *, {} seconds left",
+        timeout_secs
+    );
+
+    // キー入力を待ち、時間切れを知らせるタイマと並べて待つ
+    let mut system_table = uefi_services::system_table();
+    let stdin = system_table.stdin();
+    let Some(key_event) = stdin.wait_for_key_event() else {
+        return default;
+    };
+    let timer =
+        match unsafe { services.create_event(EventType::TIMER, Tpl::APPLICATION, None, None) } {
+            Err(e) => {
+                warn!("Failed to create a timer event: {}", e);
+                return default;
+            }
+            Ok(event) => event,
+        };
+    // 単位は 100 ns。videotimeout に大きすぎる値が書かれていても溢れないよう、上限で止める
+    let timeout = timeout_secs.saturating_mul(10_000_000);
+    if let Err(e) = services.set_timer(&timer, TimerTrigger::Relative(timeout)) {
+        warn!("Failed to set the timer: {}", e);
+        let _ = services.close_event(timer);
+        return default;
+    }
+
+    let mut events = unsafe { [key_event, timer.unsafe_clone()] };
+    let choice = loop {
+        match services.wait_for_event(&mut events) {
+            // キーが押された
+            Ok(0) => {}
+            _ => break default,
+        }
+        match stdin.read_key() {
+            Ok(Some(Key::Printable(c))) if char::from(c) == '\r' => break default,
+            Ok(Some(Key::Printable(c))) => {
+                let c = char::from(c).to_ascii_lowercase();
+                if let Some(i) = (0..len).find(|&i| mode_choice_key(i) == c) {
+                    break Some(choices[i]);
+                }
+            }
+            _ => {}
+        }
+    };
+    let _ = services.close_event(timer);
+    choice
+}
+
 /// ピクセルのデータ形式情報を文字列にする。
 fn get_pixel_format_unicode(fmt: PixelFormat) -> &'static CStr16 {
     match fmt {
@@ -210,6 +667,33 @@ fn get_pixel_format_unicode(fmt: PixelFormat) -> &'static CStr16 {
     }
 }
 
+/// GOP のピクセル形式を、カーネルが扱える形式へ変換する。
+///
+/// Bitmask 形式でも、各色が 8 bit ずつバイト境界に並んでいれば RGB か BGR と同じなので、
+/// そちらとして扱う。それ以外（BltOnly など）は None を返す。
+fn to_kernel_pixel_format(info: &ModeInfo) -> Option<graphics::PixelFormat> {
+    match info.pixel_format() {
+        PixelFormat::Rgb => Some(graphics::PixelFormat::Rgb),
+        PixelFormat::Bgr => Some(graphics::PixelFormat::Bgr),
+        PixelFormat::Bitmask => match info.pixel_bitmask() {
+            Some(PixelBitmask {
+                red: 0x0000_00ff,
+                green: 0x0000_ff00,
+                blue: 0x00ff_0000,
+                ..
+            }) => Some(graphics::PixelFormat::Rgb),
+            Some(PixelBitmask {
+                red: 0x00ff_0000,
+                green: 0x0000_ff00,
+                blue: 0x0000_00ff,
+                ..
+            }) => Some(graphics::PixelFormat::Bgr),
+            _ => None,
+        },
+        PixelFormat::BltOnly => None,
+    }
+}
+
 /// ELF ファイルを展開するときの、最下位アドレスと最上位アドレスを返す。
 /// 戻り値は (最下位, 最上位)。
 fn calc_load_address_range(phdrs: &[Elf64Phdr]) -> (usize, usize) {
@@ -279,7 +763,7 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     }
 
     // メモリマップの取得
-    let mut memmap_buf = [0u8; 4096 * 4];
+    let mut memmap_buf = MemoryMapBuffer::new();
     let memmap = match get_memory_map(system_table.boot_services(), &mut memmap_buf) {
         Err(e) => {
             error!("Failed to get memmap: {}", e);
@@ -297,9 +781,21 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         Ok(dir) => dir,
     };
 
+    // 起動引数の読み込み
+    // efi_main のスタック上に置くので、カーネルに制御を移した後も有効なまま
+    let mut boot_args = BootArgs::new();
+    load_boot_args(&mut root_dir, &mut boot_args);
+
     // メモリマップ保存用ファイルを操作するオブジェクトの取得
+    // 普段は小さいバイナリ形式で書き、人が読むときは memmapformat=csv で CSV にする
+    let csv = boot_args.memory_map_csv();
+    let memmap_path = if csv {
+        cstr16!("\\memmap")
+    } else {
+        cstr16!("\\memmap.bin")
+    };
     let mut memmap_file = match root_dir.open(
-        cstr16!("\\memmap"),
+        memmap_path,
         FileMode::CreateReadWrite,
         FileAttribute::empty(),
     ) {
@@ -318,11 +814,19 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
     };
 
     // メモリマップを上で取得したファイルに保存する
-    let _ = save_memory_map(&mut system_table, &memmap, &mut memmap_file);
+    let _ = if csv {
+        save_memory_map(&mut system_table, &memmap, &mut memmap_file)
+    } else {
+        save_memory_map_binary(&memmap, &mut memmap_file)
+    };
     memmap_file.close();
+    // memmap はここから先で使わないので、借用が終わってバッファを返せる
+    memmap_buf.free(system_table.boot_services());
+
+    let app_image = load_app_image(system_table.boot_services(), &mut root_dir);
 
     // 画面情報の取得
-    let graphics_info = match get_gop_info(image_handle, &mut system_table) {
+    let graphics_info = match get_gop_info(image_handle, &mut system_table, &boot_args) {
         Err(e) => {
             error!("Failed to get gop info: {}", e);
             halt();
@@ -355,6 +859,20 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         Ok(_) => (),
     };
 
+    // ピクセル形式の確認
+    // ブートサービスを終了するとファームウェアのコンソールへ出力できなくなるので、ここで調べておく
+    let pixel_format = match to_kernel_pixel_format(&graphics_info.pixel_info) {
+        Some(format) => format,
+        None => {
+            error!(
+                "Unsupported pixel format: {:?} (bitmask {:?})",
+                graphics_info.pixel_info.pixel_format(),
+                graphics_info.pixel_info.pixel_bitmask()
+            );
+            halt();
+        }
+    };
+
     // `\kernel.elf` を開く
     let mut kernel_file =
         match root_dir.open(cstr16!("\\kernel"), FileMode::Read, FileAttribute::empty()) {
@@ -418,6 +936,11 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         Ok(_) => (),
     }
 
+    // 読み込んだカーネルが壊れていないか確かめる
+    if !verify_kernel_checksum(&mut root_dir, kernel_buffer) {
+        halt();
+    }
+
     // kernel.elf の ELF ヘッダを取得
     let kernel_ehdr = unsafe { *(kernel_buffer_addr as *const Elf64Ehdr) };
     let phdr_addr = kernel_buffer_addr as usize + kernel_ehdr.phoff as usize;
@@ -480,23 +1003,18 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
         }
     }
 
+    // ACPI のテーブルはブートサービスの終了後も残るので、場所だけ覚えておく
+    let acpi_rsdp = find_acpi_rsdp(&system_table);
+
     // UEFI のブートサービスを終了する
-    let _ = system_table.exit_boot_services(MemoryType(0));
+    // 終了時点のメモリマップをカーネルへ渡す
+    let (runtime_table, final_memmap) = system_table.exit_boot_services(MemoryType(0));
+    let boot_memmap = BootMemoryMap::new(&final_memmap);
+    let runtime_services = enter_runtime_identity_map(runtime_table, &final_memmap);
 
     let frame_buffer = graphics_info.frame_buffer_base;
     let pixels_per_scan_line = graphics_info.pixel_info.stride();
     let (horizontal_resolution, vertical_resolution) = graphics_info.pixel_info.resolution();
-    let pixel_format = match graphics_info.pixel_info.pixel_format() {
-        PixelFormat::Rgb => graphics::PixelFormat::Rgb,
-        PixelFormat::Bgr => graphics::PixelFormat::Bgr,
-        _ => {
-            error!(
-                "Unimplemented pixel format: {:?}",
-                graphics_info.pixel_info.pixel_format()
-            );
-            halt();
-        }
-    };
     let config = FrameBufferConfig {
         frame_buffer,
         pixels_per_scan_line,
@@ -507,13 +1025,74 @@ fn efi_main(image_handle: Handle, mut system_table: SystemTable<Boot>) -> Status
 
     // カーネルの呼び出し
     // ELF ファイルの 24 byte 目から 64 bit でエントリーポイントの番地が書いてある
-    let entry_point: extern "sysv64" fn(FrameBufferConfig) =
-        unsafe { transmute(kernel_ehdr.entry) };
-    entry_point(config);
+    let entry_point: extern "sysv64" fn(
+        FrameBufferConfig,
+        *const BootArgs,
+        *const BootMemoryMap,
+        *const c_void,
+        *const c_void,
+        *const AppImage,
+    ) = unsafe { transmute(kernel_ehdr.entry) };
+    entry_point(
+        config,
+        &boot_args,
+        &boot_memmap,
+        acpi_rsdp,
+        runtime_services,
+        &app_image,
+    );
 
     halt()
 }
 
+/// UEFI の構成テーブルから ACPI 2.0 以降の RSDP を探す。見つからなければ null を返す。
+fn find_acpi_rsdp(system_table: &SystemTable<Boot>) -> *const c_void {
+    system_table
+        .config_table()
+        .iter()
+        .find(|entry| entry.guid == ACPI2_GUID)
+        .map_or(core::ptr::null(), |entry| entry.address)
+}
+
+/// SetVirtualAddressMap で指定できるランタイム用の領域の最大数
+const MAX_RUNTIME_DESCRIPTORS: usize = 64;
+
+/// ランタイムサービスの領域を恒等写像のまま使うことをファームウェアへ知らせ、
+/// カーネルへ渡すランタイムサービスのテーブルを返す。
+///
+/// カーネルは物理アドレスの低い方を恒等写像で残すので、仮想アドレスは物理アドレスと同じにする。
+/// SetVirtualAddressMap が失敗しても、ファームウェアは物理アドレスのまま動くので同じテーブルを渡す。
+/// ブートサービスの終了後に呼ぶので、失敗してもログは出せない。
+fn enter_runtime_identity_map(
+    runtime_table: SystemTable<Runtime>,
+    memmap: &MemoryMap,
+) -> *const c_void {
+    let runtime_services = unsafe { runtime_table.runtime_services() } as *const _ as *const c_void;
+    let system_table = runtime_table.as_ptr() as u64;
+
+    // ファームウェアのメモリマップはエントリの大きさが MemoryDescriptor と異なることがあるので、
+    // 配列へ詰め直して渡す
+    let mut descriptors = [MemoryDescriptor::default(); MAX_RUNTIME_DESCRIPTORS];
+    let mut len = 0;
+    for desc in memmap
+        .entries()
+        .filter(|desc| desc.att.contains(MemoryAttribute::RUNTIME))
+    {
+        if len == MAX_RUNTIME_DESCRIPTORS {
+            // 一部だけを渡すとファームウェアが使えなくなるので、何も設定しない
+            return runtime_services;
+        }
+        descriptors[len] = MemoryDescriptor {
+            virt_start: desc.phys_start,
+            ..*desc
+        };
+        len += 1;
+    }
+
+    let _ = unsafe { runtime_table.set_virtual_address_map(&mut descriptors[..len], system_table) };
+    runtime_services
+}
+
 fn halt() -> ! {
     unsafe {
         loop {
@@ -0,0 +1,126 @@
+use uefi::table::boot::MemoryMap;
+
+/// カーネルへ渡すメモリマップのエントリの最大数
+pub const MEMORY_MAP_MAX_ENTRIES: usize = 256;
+
+/// カーネルへ渡すメモリマップの 1 エントリ。
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemoryMapEntry {
+    /// UEFI のメモリタイプ
+    pub ty: u32,
+    pub phys_start: u64,
+    pub page_count: u64,
+}
+
+/// カーネルへ渡すメモリマップ。
+///
+/// ブートサービスの終了後は UEFI の [MemoryMap] を置いているバッファの扱いが
+/// 保証されないので、必要な情報だけをこちらへ写してから渡す。
+#[repr(C)]
+pub struct BootMemoryMap {
+    pub len: usize,
+    pub entries: [MemoryMapEntry; MEMORY_MAP_MAX_ENTRIES],
+}
+
+impl BootMemoryMap {
+    /// UEFI のメモリマップから作る。入り切らないエントリは捨てる。
+    pub fn new(map: &MemoryMap) -> Self {
+        let mut boot_map = Self {
+            len: 0,
+            entries: [MemoryMapEntry {
+                ty: 0,
+                phys_start: 0,
+                page_count: 0,
+            }; MEMORY_MAP_MAX_ENTRIES],
+        };
+        for (entry, desc) in boot_map.entries.iter_mut().zip(map.entries()) {
+            *entry = MemoryMapEntry {
+                ty: desc.ty.0,
+                phys_start: desc.phys_start,
+                page_count: desc.page_count,
+            };
+            boot_map.len += 1;
+        }
+        boot_map
+    }
+}
+
+/// `\memmap.bin` の先頭を表す印
+pub const MEMORY_MAP_FILE_MAGIC: [u8; 8] = *b"MIKANMAP";
+/// `\memmap.bin` の形式の版。レコードの並びを変えたら上げる。
+pub const MEMORY_MAP_FILE_VERSION: u32 = 1;
+
+/// `\memmap.bin` の先頭に置くヘッダ。値はすべてリトルエンディアンで、詰め物は無い。
+///
+/// ヘッダの直後に [MemoryMapRecord] が `num_records` 個並ぶ。読む側は `record_size` ずつ
+/// 進めば、後の版でレコードの末尾にフィールドが増えても先頭のフィールドは読める。
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemoryMapFileHeader {
+    /// [MEMORY_MAP_FILE_MAGIC]
+    pub magic: [u8; 8],
+    /// [MEMORY_MAP_FILE_VERSION]
+    pub version: u32,
+    /// 1 レコードの大きさ（バイト）
+    pub record_size: u32,
+    /// レコードの数
+    pub num_records: u64,
+}
+
+impl MemoryMapFileHeader {
+    /// ファイルに書き込む大きさ（バイト）。詰め物が無いので、構造体の大きさと等しい。
+    pub const SIZE: usize = 24;
+
+    pub fn new(num_records: usize) -> Self {
+        Self {
+            magic: MEMORY_MAP_FILE_MAGIC,
+            version: MEMORY_MAP_FILE_VERSION,
+            record_size: MemoryMapRecord::SIZE as u32,
+            num_records: num_records as u64,
+        }
+    }
+
+    /// ファイルに書き込むバイト列にする。
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.magic);
+        bytes[8..12].copy_from_slice(&self.version.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.record_size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.num_records.to_le_bytes());
+        bytes
+    }
+}
+
+/// `\memmap.bin` の 1 レコード。UEFI の EFI_MEMORY_DESCRIPTOR から、仮想アドレスと詰め物を除いたもの。
+///
+/// ファイル上ではフィールドを詰めて [Self::SIZE] バイトのリトルエンディアンで並べる。`ty` の後ろに
+/// 詰め物が入るメモリ上の並びとは違うので、構造体をそのまま書き出さず [Self::to_bytes] を使う。
+#[derive(Clone, Copy)]
+pub struct MemoryMapRecord {
+    /// UEFI のメモリタイプ
+    pub ty: u32,
+    pub phys_start: u64,
+    pub page_count: u64,
+    /// UEFI のメモリ属性（EFI_MEMORY_WB など）
+    pub attribute: u64,
+}
+
+impl MemoryMapRecord {
+    /// ファイルに書き込む大きさ（バイト）
+    pub const SIZE: usize = 28;
+
+    /// ファイルに書き込むバイト列にする。
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&self.ty.to_le_bytes());
+        bytes[4..12].copy_from_slice(&self.phys_start.to_le_bytes());
+        bytes[12..20].copy_from_slice(&self.page_count.to_le_bytes());
+        bytes[20..28].copy_from_slice(&self.attribute.to_le_bytes());
+        bytes
+    }
+}
+
+const _: () = assert!(core::mem::size_of::<MemoryMapFileHeader>() == MemoryMapFileHeader::SIZE);
+const _: () =
+    assert!(MemoryMapRecord::SIZE == core::mem::size_of::<u32>() + 3 * core::mem::size_of::<u64>());